@@ -0,0 +1,43 @@
+// Draws chunk meshes with one layer of the block texture array per face.
+
+#import bevy_pbr::mesh_functions::{get_model_matrix, mesh_position_local_to_clip}
+
+@group(1) @binding(0) var atlas_texture: texture_2d_array<f32>;
+@group(1) @binding(1) var atlas_sampler: sampler;
+
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) layer: u32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) normal: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) @interpolate(flat) layer: u32,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = mesh_position_local_to_clip(
+        get_model_matrix(vertex.instance_index),
+        vec4<f32>(vertex.position, 1.0),
+    );
+    out.normal = vertex.normal;
+    out.uv = vertex.uv;
+    out.layer = vertex.layer;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(atlas_texture, atlas_sampler, in.uv, in.layer);
+    // fixed light from above and to the side, so faces are told apart
+    let light = normalize(vec3<f32>(0.4, 1.0, 0.7));
+    let shade = 0.55 + 0.45 * max(dot(in.normal, light), 0.0);
+    return vec4<f32>(color.rgb * shade, color.a);
+}
@@ -0,0 +1,85 @@
+use bevy::prelude::*;
+use modcraft_lib::{
+    blocks::{BlockId, BlockProperties, BlockRegistry, BlockTextures},
+    chunk::{Chunk, CHUNK_SIZE},
+    commands::{ChatCommand, CommandArgs, CommandContext, CommandResult},
+    declare_mod,
+    mods::{Mod, ModAppExt},
+    world::ChunkPos,
+    worldgen::{hash, GenerationPhase, GeneratorStage},
+};
+
+#[derive(Resource, Debug, Default)]
+struct TicksSeen(u64);
+
+fn count_ticks(mut ticks_seen: ResMut<TicksSeen>) {
+    ticks_seen.0 += 1;
+}
+
+fn ticks_command(
+    world: &mut World,
+    _context: &CommandContext,
+    _args: &CommandArgs,
+) -> CommandResult {
+    Ok(format!(
+        "example_mod has seen {} ticks",
+        world.resource::<TicksSeen>().0
+    ))
+}
+
+// replaces a few stone blocks in every chunk with ore
+struct OreStage {
+    stone: BlockId,
+    ore: BlockId,
+}
+
+impl GeneratorStage for OreStage {
+    fn name(&self) -> &str {
+        "example_mod:ore"
+    }
+
+    fn phase(&self) -> GenerationPhase {
+        GenerationPhase::Ores
+    }
+
+    fn generate(&self, seed: u64, pos: ChunkPos, chunk: &mut Chunk) {
+        for attempt in 0..8 {
+            let roll = hash(seed, pos.x, pos.y, pos.z.wrapping_add(attempt));
+            let (x, y, z) = (
+                roll as usize % CHUNK_SIZE,
+                (roll >> 8) as usize % CHUNK_SIZE,
+                (roll >> 16) as usize % CHUNK_SIZE,
+            );
+            if chunk.get(x, y, z) == self.stone {
+                chunk.set(x, y, z, self.ore);
+            }
+        }
+    }
+}
+
+struct ExampleMod;
+impl Mod for ExampleMod {
+    fn register(&self, app: &mut App) {
+        let ore = app
+            .register_block(
+                "example_mod:ore",
+                BlockProperties::solid(BlockTextures::all("stone2")).with_hardness(3.0),
+            )
+            .expect("Failed to register example_mod:ore");
+        let stone = app
+            .world
+            .resource::<BlockRegistry>()
+            .id("modcraft:stone")
+            .expect("Stone is a built-in block");
+        app.add_generator_stage(OreStage { stone, ore });
+        app.register_chat_command(ChatCommand::new(
+            "ticks",
+            "Shows how many ticks example_mod has seen",
+            ticks_command,
+        ));
+        app.init_resource::<TicksSeen>()
+            .add_systems(FixedUpdate, count_ticks);
+    }
+}
+
+declare_mod!(ExampleMod);
@@ -0,0 +1,211 @@
+//! Players are known by a keypair their client keeps instead of by their
+//! name, so nobody can join as someone else and no central service is
+//! needed.
+//!
+//! Once a client's hello is accepted the server sends it a random challenge,
+//! which the client signs with its join. The server remembers every key it
+//! has seen along with a UUID that stays the player's across names and
+//! restarts, and a name belongs to the first key that joined with it.
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use bevy::{prelude::*, utils::Uuid};
+use modcraft_lib::save::PlayerRecord;
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::handshake::RejectReason;
+
+const MAX_NAME_LENGTH: usize = 16;
+// signed along with the challenge, so the signature means nothing elsewhere
+const SIGNATURE_CONTEXT: &[u8] = b"modcraft join";
+
+pub(crate) type Challenge = [u8; 32];
+
+pub(crate) fn new_challenge() -> Challenge {
+    rand::random()
+}
+
+pub(crate) fn signed_message(challenge: &Challenge) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, challenge].concat()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes a file only its owner can read, for private keys. A file that
+/// already exists is made private too.
+pub(crate) fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_ref())
+}
+
+/// An Ed25519 public key, shown in hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub(crate) [u8; 32]);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", to_hex(&self.0))
+    }
+}
+
+/// Names are what other players see, so they are kept short and plain.
+pub(crate) fn check_name(name: &str) -> Result<(), RejectReason> {
+    let valid = (1..=MAX_NAME_LENGTH).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(RejectReason::InvalidName(name.to_string()))
+    }
+}
+
+/// Everyone that has joined the world, saved with it.
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct KnownPlayers(pub(crate) Vec<PlayerRecord>);
+
+impl KnownPlayers {
+    pub(crate) fn get(&self, uuid: Uuid) -> Option<&PlayerRecord> {
+        self.0.iter().find(|record| record.uuid == uuid)
+    }
+
+    /// The player that last joined with `name`, ignoring case.
+    pub(crate) fn find_mut(&mut self, name: &str) -> Option<&mut PlayerRecord> {
+        self.0
+            .iter_mut()
+            .find(|record| record.name.eq_ignore_ascii_case(name))
+    }
+
+    pub(crate) fn set_position(&mut self, uuid: Uuid, position: Vec3) {
+        if let Some(record) = self.0.iter_mut().find(|record| record.uuid == uuid) {
+            record.position = Some(position);
+        }
+    }
+
+    /// Checks a join against the challenge it answers and returns the
+    /// player's UUID, taking the name for their key.
+    pub(crate) fn join(
+        &mut self,
+        challenge: &Challenge,
+        name: &str,
+        public_key: &PublicKey,
+        signature: &[u8],
+        is_online: impl Fn(Uuid) -> bool,
+    ) -> Result<Uuid, RejectReason> {
+        UnparsedPublicKey::new(&ED25519, public_key.0)
+            .verify(&signed_message(challenge), signature)
+            .map_err(|_| RejectReason::FailedAuthentication)?;
+        check_name(name)?;
+
+        let key = public_key.to_string();
+        let known = self.0.iter().position(|record| record.public_key == key);
+        if known.is_some_and(|i| is_online(self.0[i].uuid)) {
+            return Err(RejectReason::AlreadyConnected);
+        }
+        if self
+            .0
+            .iter()
+            .any(|record| record.public_key != key && record.name.eq_ignore_ascii_case(name))
+        {
+            return Err(RejectReason::NameTaken(name.to_string()));
+        }
+
+        match known {
+            Some(i) => {
+                let record = &mut self.0[i];
+                if record.name != name {
+                    info!("{} is now called {}", record.name, name);
+                    record.name = name.to_string();
+                }
+                Ok(record.uuid)
+            }
+            None => {
+                let uuid = Uuid::new_v4();
+                info!("New player {} has UUID {}", name, uuid);
+                self.0.push(PlayerRecord {
+                    uuid,
+                    public_key: key,
+                    name: name.to_string(),
+                    position: None,
+                    operator: false,
+                });
+                Ok(uuid)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{identity::Identity, protocol::ClientMessage};
+
+    fn join(
+        known_players: &mut KnownPlayers,
+        identity: &Identity,
+        online: &[Uuid],
+    ) -> Result<Uuid, RejectReason> {
+        let challenge = new_challenge();
+        let ClientMessage::Join {
+            name,
+            public_key,
+            signature,
+        } = identity.join(&challenge)
+        else {
+            unreachable!();
+        };
+        known_players.join(&challenge, &name, &public_key, &signature, |uuid| {
+            online.contains(&uuid)
+        })
+    }
+
+    #[test]
+    fn players_are_known_by_their_keys() {
+        let mut known_players = KnownPlayers::default();
+        let mut steve = Identity::generate("Steve");
+        let uuid = join(&mut known_players, &steve, &[]).unwrap();
+        assert_eq!(join(&mut known_players, &steve, &[]), Ok(uuid));
+        assert_eq!(
+            join(&mut known_players, &steve, &[uuid]),
+            Err(RejectReason::AlreadyConnected)
+        );
+
+        // the name belongs to the first key, whatever the case
+        let impostor = Identity::generate("steve");
+        assert_eq!(
+            join(&mut known_players, &impostor, &[]),
+            Err(RejectReason::NameTaken("steve".to_string()))
+        );
+
+        // renaming keeps the UUID and frees the old name
+        steve.set_name("Alex").unwrap();
+        assert_eq!(join(&mut known_players, &steve, &[]), Ok(uuid));
+        assert_eq!(known_players.get(uuid).unwrap().name, "Alex");
+        assert!(join(&mut known_players, &impostor, &[]).is_ok_and(|other| other != uuid));
+
+        // a signature for another challenge proves nothing
+        let ClientMessage::Join {
+            public_key,
+            signature,
+            ..
+        } = steve.join(&new_challenge())
+        else {
+            unreachable!();
+        };
+        assert_eq!(
+            known_players.join(&new_challenge(), "Alex", &public_key, &signature, |_| false),
+            Err(RejectReason::FailedAuthentication)
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Who may join: bans by identity and the whitelist, both saved with the
+//! world.
+//!
+//! Bans hold the player's UUID, so they stick to the player's key whatever
+//! name they use. With the whitelist on, only the names on it may join, and since a
+//! name belongs to the first key that joined with it, that is the key let in.
+
+use bevy::{prelude::*, utils::Uuid};
+use modcraft_lib::save::{BanList, PlayerBan};
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct Bans(pub(crate) BanList);
+
+impl Bans {
+    pub(crate) fn player(&self, uuid: Uuid) -> Option<&PlayerBan> {
+        self.0.players.iter().find(|ban| ban.uuid == uuid)
+    }
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct Whitelist(pub(crate) Vec<String>);
+
+impl Whitelist {
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|other| other.eq_ignore_ascii_case(name))
+    }
+}
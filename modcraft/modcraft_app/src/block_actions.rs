@@ -0,0 +1,326 @@
+//! Breaking and placing blocks.
+//!
+//! Clients change their copy of the world right away and tell the server.
+//! The server checks that the player could really have done it before
+//! changing the world, which is then sent to everyone with the chunk loaded.
+//! Rejected actions are answered with the block as the server has it, so the
+//! client can undo its change.
+
+use std::{fmt, time::Duration};
+
+use bevy::prelude::*;
+use modcraft_lib::{
+    blocks::{BlockFace, BlockId, BlockRegistry, AIR},
+    physics::{is_solid, raycast, PlayerBody, REACH},
+    world::{BlockPos, VoxelWorld},
+};
+
+use crate::{players::Player, protocol::ServerMessage, transport::ServerTransport};
+
+// extra reach allowed, since the server's idea of where the player is lags
+// behind the client's
+const REACH_SLACK: f32 = 1.0;
+// breaking is allowed a little before a block's hardness has passed since
+// the last break, so jittery connections aren't punished
+const MINING_SLACK: f32 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockAction {
+    Break(BlockPos),
+    Place(BlockPos, BlockId),
+}
+
+impl BlockAction {
+    fn pos(self) -> BlockPos {
+        match self {
+            BlockAction::Break(pos) | BlockAction::Place(pos, _) => pos,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockActionError {
+    NotLoaded,
+    OutOfReach,
+    NotVisible,
+    Unbreakable,
+    TooFast,
+    UnknownBlock(BlockId),
+    Occupied,
+    NothingToPlaceAgainst,
+}
+
+impl fmt::Display for BlockActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockActionError::NotLoaded => write!(f, "the block is not loaded"),
+            BlockActionError::OutOfReach => write!(f, "the block is out of reach"),
+            BlockActionError::NotVisible => write!(f, "the block can't be seen"),
+            BlockActionError::Unbreakable => write!(f, "the block can't be broken"),
+            BlockActionError::TooFast => write!(f, "blocks are being broken too fast"),
+            BlockActionError::UnknownBlock(block) => write!(f, "block {} doesn't exist", block),
+            BlockActionError::Occupied => write!(f, "something is in the way"),
+            BlockActionError::NothingToPlaceAgainst => {
+                write!(f, "there is nothing to place the block against")
+            }
+        }
+    }
+}
+
+// what the server knows when checking an action
+struct ActionContext<'a> {
+    voxel_world: &'a VoxelWorld,
+    block_registry: &'a BlockRegistry,
+    bodies: &'a [PlayerBody],
+}
+
+impl ActionContext<'_> {
+    fn is_solid(&self, pos: BlockPos) -> bool {
+        is_solid(self.voxel_world, self.block_registry, pos)
+    }
+
+    // the block has to be in reach and the line from the player's eyes to
+    // its middle can't pass through other blocks
+    fn check_line_of_sight(
+        &self,
+        body: &PlayerBody,
+        pos: BlockPos,
+        target_is_solid: bool,
+    ) -> Result<(), BlockActionError> {
+        let eye = body.eye_position();
+        let nearest = eye.clamp(pos.center() - 0.5, pos.center() + 0.5);
+        if eye.distance(nearest) > REACH + REACH_SLACK {
+            return Err(BlockActionError::OutOfReach);
+        }
+        let to_center = pos.center() - eye;
+        let hit = raycast(eye, to_center, to_center.length(), |block| {
+            self.is_solid(block)
+        });
+        match hit {
+            Some(hit) if target_is_solid && hit.block == pos => Ok(()),
+            None if !target_is_solid => Ok(()),
+            _ => Err(BlockActionError::NotVisible),
+        }
+    }
+
+    fn check(
+        &self,
+        player: &Player,
+        action: BlockAction,
+        elapsed: Duration,
+    ) -> Result<BlockId, BlockActionError> {
+        let pos = action.pos();
+        let current = self
+            .voxel_world
+            .get_block(pos)
+            .ok_or(BlockActionError::NotLoaded)?;
+
+        match action {
+            BlockAction::Break(_) => {
+                let properties = self
+                    .block_registry
+                    .properties(current)
+                    .filter(|_| current != AIR)
+                    .ok_or(BlockActionError::NotVisible)?;
+                if !properties.is_breakable() {
+                    return Err(BlockActionError::Unbreakable);
+                }
+                self.check_line_of_sight(&player.body, pos, self.is_solid(pos))?;
+                if let Some(last_break) = player.last_break {
+                    let since = elapsed.saturating_sub(last_break).as_secs_f32();
+                    if since < properties.hardness * MINING_SLACK {
+                        return Err(BlockActionError::TooFast);
+                    }
+                }
+                Ok(AIR)
+            }
+            BlockAction::Place(_, block) => {
+                if block == AIR || self.block_registry.get(block).is_none() {
+                    return Err(BlockActionError::UnknownBlock(block));
+                }
+                if current != AIR || self.bodies.iter().any(|body| body.intersects(pos)) {
+                    return Err(BlockActionError::Occupied);
+                }
+                if !BlockFace::ALL
+                    .iter()
+                    .any(|face| self.is_solid(pos.neighbor(*face)))
+                {
+                    return Err(BlockActionError::NothingToPlaceAgainst);
+                }
+                self.check_line_of_sight(&player.body, pos, false)?;
+                Ok(block)
+            }
+        }
+    }
+}
+
+pub(crate) fn apply_block_actions(
+    time: Res<Time<Fixed>>,
+    transport: ServerTransport,
+    mut voxel_world: ResMut<VoxelWorld>,
+    block_registry: Res<BlockRegistry>,
+    mut players: Query<&mut Player>,
+) {
+    let elapsed = time.elapsed();
+    let bodies: Vec<PlayerBody> = players.iter().map(|player| player.body).collect();
+
+    for mut player in &mut players {
+        if player.block_actions.is_empty() {
+            continue;
+        }
+        for action in std::mem::take(&mut player.block_actions) {
+            let context = ActionContext {
+                voxel_world: &voxel_world,
+                block_registry: &block_registry,
+                bodies: &bodies,
+            };
+            match context.check(&player, action, elapsed) {
+                Ok(block) => {
+                    if let BlockAction::Break(_) = action {
+                        player.last_break = Some(elapsed);
+                    }
+                    voxel_world.set_block(action.pos(), block);
+                }
+                Err(e) => {
+                    debug!("Rejected {:?} from {}: {}", action, player.client_id, e);
+                    let pos = action.pos();
+                    // nothing to correct in chunks the client doesn't have
+                    let Some(block) = voxel_world.get_block(pos) else {
+                        continue;
+                    };
+                    let message = ServerMessage::BlockCorrection { pos, block };
+                    if let Err(e) = transport.send(player.client_id, message) {
+                        warn!(
+                            "Failed to send block correction to {}: {}",
+                            player.client_id, e
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use modcraft_lib::{chunk::Chunk, world::ChunkPos};
+
+    use super::*;
+    use crate::{
+        players::{move_players, sync_players},
+        protocol::ClientMessage,
+        server::{handle_client_messages, join_loopback, loopback_server_app},
+        transport::loopback::LoopbackClient,
+    };
+
+    fn corrections(client: &mut LoopbackClient) -> Vec<(BlockPos, BlockId)> {
+        std::iter::from_fn(|| client.try_receive())
+            .filter_map(|message| match message {
+                ServerMessage::BlockCorrection { pos, block } => Some((pos, block)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn actions_are_validated() {
+        let (mut app, mut client) = loopback_server_app();
+        app.add_systems(
+            Update,
+            (
+                handle_client_messages,
+                sync_players,
+                apply_deferred,
+                move_players,
+                apply_block_actions,
+            )
+                .chain(),
+        );
+        // the player spawns at the origin on top of dirt, with bedrock below
+        let registry = app.world.resource::<BlockRegistry>();
+        let [bedrock, stone, dirt] =
+            ["bedrock", "stone", "dirt"].map(|name| registry.id(&format!("modcraft:{}", name)));
+        let mut voxel_world = app.world.resource_mut::<VoxelWorld>();
+        voxel_world.insert_chunk(ChunkPos::new(0, -1, 0), Chunk::filled(bedrock.unwrap()));
+        voxel_world.insert_chunk(ChunkPos::new(0, 0, 0), Chunk::default());
+        for x in 0..16 {
+            voxel_world.set_block(BlockPos::new(x, -1, 0), dirt.unwrap());
+        }
+        voxel_world.take_changes();
+
+        join_loopback(&mut app, &mut client, "local");
+        app.update();
+
+        let send = |client: &LoopbackClient, message| client.send(message).unwrap();
+        send(
+            &client,
+            ClientMessage::BreakBlock {
+                pos: BlockPos::new(1, -1, 0),
+            },
+        );
+        // bedrock can't be broken
+        send(
+            &client,
+            ClientMessage::BreakBlock {
+                pos: BlockPos::new(1, -2, 0),
+            },
+        );
+        // too far away
+        send(
+            &client,
+            ClientMessage::BreakBlock {
+                pos: BlockPos::new(9, -1, 0),
+            },
+        );
+        // inside the player
+        send(
+            &client,
+            ClientMessage::PlaceBlock {
+                pos: BlockPos::new(0, 1, 0),
+                block: stone.unwrap(),
+            },
+        );
+        send(
+            &client,
+            ClientMessage::PlaceBlock {
+                pos: BlockPos::new(2, 0, 0),
+                block: stone.unwrap(),
+            },
+        );
+        // floating in the air
+        send(
+            &client,
+            ClientMessage::PlaceBlock {
+                pos: BlockPos::new(2, 3, 2),
+                block: stone.unwrap(),
+            },
+        );
+        app.update();
+
+        let voxel_world = app.world.resource::<VoxelWorld>();
+        assert_eq!(voxel_world.get_block(BlockPos::new(1, -1, 0)), Some(AIR));
+        assert_eq!(voxel_world.get_block(BlockPos::new(2, 0, 0)), stone);
+        assert_eq!(
+            corrections(&mut client),
+            [
+                (BlockPos::new(1, -2, 0), bedrock.unwrap()),
+                (BlockPos::new(9, -1, 0), dirt.unwrap()),
+                (BlockPos::new(0, 1, 0), AIR),
+                (BlockPos::new(2, 3, 2), AIR),
+            ]
+        );
+
+        // dirt takes half a second to break
+        send(
+            &client,
+            ClientMessage::BreakBlock {
+                pos: BlockPos::new(0, -1, 0),
+            },
+        );
+        app.update();
+        assert_eq!(
+            corrections(&mut client),
+            [(BlockPos::new(0, -1, 0), dirt.unwrap())]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Puts the world messages from the server's two world channels back in
+//! order.
+//!
+//! Chunk data comes on the bulk channel, and changes and unloads on the world
+//! edits one, so either can overtake the other. The server numbers every
+//! time it sends a chunk as a load, and an unload says which load it ends.
+//! Changes arrive in order with the unloads, so a change belongs to the load
+//! after the last unload of its chunk: it is applied if that load's data is
+//! here, kept until it arrives if not, and dropped if newer data already
+//! replaced it.
+//!
+//! Servers from before load numbers send 0 instead, and chunks in order with
+//! the changes, so those chunks and unloads are all for the current load.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use modcraft_lib::{
+    blocks::BlockId,
+    world::{BlockPos, ChunkPos},
+};
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct ChunkLoads {
+    // the load of each chunk the client has
+    loaded: HashMap<ChunkPos, u32>,
+    // the last load the server unloaded of each chunk
+    unloaded: HashMap<ChunkPos, u32>,
+    // changes to loads whose data hasn't arrived yet, in order
+    early: HashMap<ChunkPos, Vec<(BlockPos, BlockId)>>,
+}
+
+impl ChunkLoads {
+    // the load changes arriving now belong to
+    fn current(&self, pos: ChunkPos) -> u32 {
+        self.unloaded.get(&pos).map_or(1, |load| load + 1)
+    }
+
+    /// None if the chunk data was unloaded before it arrived and should be
+    /// dropped, otherwise the changes that arrived before it, to apply on top.
+    pub(crate) fn chunk_arrived(
+        &mut self,
+        pos: ChunkPos,
+        load: u32,
+    ) -> Option<Vec<(BlockPos, BlockId)>> {
+        let current = self.current(pos);
+        let load = if load == 0 { current } else { load };
+        if load < current {
+            return None;
+        }
+        self.loaded.insert(pos, load);
+        // early changes for an older load are already in newer data
+        let early = self.early.remove(&pos).unwrap_or_default();
+        Some(if load == current { early } else { Vec::new() })
+    }
+
+    /// Whether the chunk the client has should be removed.
+    pub(crate) fn unload(&mut self, pos: ChunkPos, load: u32) -> bool {
+        let load = if load == 0 { self.current(pos) } else { load };
+        self.early.remove(&pos);
+        self.unloaded.insert(pos, load);
+        // the next load's data may already be here
+        let remove = self.loaded.get(&pos).is_some_and(|loaded| *loaded <= load);
+        if remove {
+            self.loaded.remove(&pos);
+        }
+        remove
+    }
+
+    /// The changes to apply now, keeping the ones for chunks still on their
+    /// way.
+    pub(crate) fn changes(
+        &mut self,
+        changes: impl IntoIterator<Item = (BlockPos, BlockId)>,
+    ) -> Vec<(BlockPos, BlockId)> {
+        let mut ready = Vec::new();
+        for (pos, block) in changes {
+            let chunk = pos.chunk();
+            let current = self.current(chunk);
+            match self.loaded.get(&chunk) {
+                Some(loaded) if *loaded == current => ready.push((pos, block)),
+                Some(loaded) if *loaded > current => {}
+                _ => self.early.entry(chunk).or_default().push((pos, block)),
+            }
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn world_messages_apply_in_the_order_they_were_sent() {
+        let chunk = ChunkPos::new(0, 0, 0);
+        let block = BlockPos::new(1, 2, 3);
+        let mut loads = ChunkLoads::default();
+
+        // a change overtaking its chunk waits for it
+        assert_eq!(loads.changes([(block, 1)]), []);
+        assert_eq!(loads.chunk_arrived(chunk, 1), Some(vec![(block, 1)]));
+        assert_eq!(loads.changes([(block, 2)]), [(block, 2)]);
+
+        // the second load arriving before the first one's unload replaces it,
+        // so the first load's last changes are dropped, and the unload keeps it
+        assert_eq!(loads.chunk_arrived(chunk, 2), Some(vec![]));
+        assert_eq!(loads.changes([(block, 3)]), []);
+        assert!(!loads.unload(chunk, 1));
+        assert_eq!(loads.changes([(block, 4)]), [(block, 4)]);
+
+        // data unloaded before it arrived is dropped, with its early changes
+        assert!(loads.unload(chunk, 2));
+        assert_eq!(loads.changes([(block, 5)]), []);
+        assert!(!loads.unload(chunk, 3));
+        assert_eq!(loads.chunk_arrived(chunk, 3), None);
+        assert_eq!(loads.chunk_arrived(chunk, 4), Some(vec![]));
+
+        // unnumbered ones from older servers are for the current load
+        assert!(loads.unload(chunk, 0));
+        assert_eq!(loads.chunk_arrived(chunk, 0), Some(vec![]));
+        assert_eq!(loads.changes([(block, 6)]), [(block, 6)]);
+    }
+}
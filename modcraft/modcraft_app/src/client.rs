@@ -1,31 +1,100 @@
-use std::{collections::HashMap, thread};
+use std::{collections::HashMap, fmt, path::Path, thread, time::Duration};
 
-use bevy::{log::LogPlugin, prelude::*};
+#[cfg(not(feature = "render"))]
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
 use bevy_quinnet::{
     client::{
-        certificate::CertificateVerificationMode,
-        connection::{ConnectionConfiguration, ConnectionEvent, ConnectionId},
+        certificate::{
+            CertConnectionAbortEvent, CertTrustUpdateEvent, CertVerificationInfo,
+            CertVerificationStatus, CertVerifierAction, CertVerifierBehaviour,
+            CertificateVerificationMode, KnownHosts, TrustOnFirstUseConfig,
+        },
+        connection::{ConnectionConfiguration, ConnectionEvent, ConnectionId, ConnectionLostEvent},
         Client, QuinnetClientPlugin,
     },
-    shared::ClientId,
+    shared::{channel::ChannelType, ClientId, QuinnetError},
+};
+use modcraft_lib::{
+    blocks::{BlockFace, BlockId, BlockRegistry, BlockRegistryError},
+    chunk::Chunk,
+    commands::is_command,
+    replication::ReplicationRegistry,
+    world::{BlockPos, ChunkPos, VoxelWorld},
 };
-use rand::{distributions::Alphanumeric, Rng};
 use tokio::sync::mpsc;
 
 use crate::{
-    protocol::{ClientMessage, ServerMessage},
-    server::{InternalServerState, InternalServerPlugin},
+    chunk_loads::ChunkLoads,
+    handshake::RejectReason,
+    identity::Identity,
+    mods::LoadedMods,
+    prediction::{predict_local_player, LocalPlayer, MovementInput},
+    protocol::{ClientMessage, ServerMessage, StopReason},
+    remote_entities::{apply_entity_messages, clear_remote_entities, RemoteEntities},
+    server::internal::{InternalServerPlugin, InternalServerState},
+    transport::{
+        client::{ClientChannels, ClientTransport},
+        loopback::LoopbackClient,
+    },
 };
+#[cfg(feature = "render")]
+use crate::{interaction::InteractionPlugin, render::ChunkRenderPlugin};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Default, States)]
-enum ClientState {
+pub(crate) enum ClientState {
     #[default]
     Menu,
     LaunchingInternalServer,
     ConnectingToServer,
+    /// Waiting for the server to accept this client's hello.
+    Handshaking,
+    /// Waiting for the server to send what it has after the join.
+    Joining,
     InGame,
 }
 
+// how long connecting, the handshake and joining may take altogether
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// the certificate fingerprint of every server connected to, pinned the
+// first time
+const KNOWN_SERVERS_FILE: &str = "known_servers";
+// where the client keeps its name and key
+const IDENTITY_FILE: &str = "identity.toml";
+
+#[derive(Debug)]
+enum DisconnectReason {
+    TimedOut,
+    ConnectionLost,
+    CertificateChanged,
+    ServerStopping(StopReason),
+    Rejected(RejectReason),
+    Kicked(String),
+    InvalidBlocks(BlockRegistryError),
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::TimedOut => write!(f, "The server took too long to answer"),
+            DisconnectReason::ConnectionLost => write!(f, "Lost the connection to the server"),
+            DisconnectReason::CertificateChanged => {
+                write!(f, "The server's certificate doesn't match the pinned one")
+            }
+            DisconnectReason::ServerStopping(reason) => write!(f, "{}", reason),
+            DisconnectReason::Rejected(reason) => write!(f, "{}", reason),
+            DisconnectReason::Kicked(reason) => write!(f, "{}", reason),
+            DisconnectReason::InvalidBlocks(e) => {
+                write!(f, "The server sent an invalid block registry: {}", e)
+            }
+        }
+    }
+}
+
+// the client has to go back to the menu without the player asking to
+#[derive(Event, Debug)]
+struct LeftServer(DisconnectReason);
+
 // TODO I don't know if this is initialized fast enough?
 // it is initialized in an OnEnter and is immediately needed for Update
 #[derive(Resource, Debug, Clone, Default)]
@@ -34,6 +103,35 @@ struct Users {
     names: HashMap<ClientId, String>,
 }
 
+// the server's blocks, kept apart from the internal server's own registry
+// since both live in the same world
+#[derive(Resource, Deref)]
+pub(crate) struct ServerBlocks(BlockRegistry);
+
+// the chunks the server has sent, apart from the internal server's world
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct ServerWorld(VoxelWorld);
+
+// a chunk in the ServerWorld was loaded, changed or unloaded, only read when
+// rendering
+#[derive(Event, Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "render"), allow(dead_code))]
+pub(crate) struct ChunkUpdated(pub(crate) ChunkPos);
+
+// the faces at a chunk's edges depend on its neighbors, so they are
+// updated too
+fn send_chunk_updated(events: &mut EventWriter<ChunkUpdated>, pos: ChunkPos) {
+    events.send(ChunkUpdated(pos));
+    for face in BlockFace::ALL {
+        let (dx, dy, dz) = face.offset();
+        events.send(ChunkUpdated(ChunkPos::new(
+            pos.x + dx,
+            pos.y + dy,
+            pos.z + dz,
+        )));
+    }
+}
+
 #[derive(Resource, Deref, DerefMut)]
 struct TerminalReceiver(mpsc::Receiver<String>);
 
@@ -43,43 +141,91 @@ struct ClientConnectionConfig(ConnectionConfiguration);
 #[derive(Resource)]
 struct ClientConnectionId(ConnectionId);
 
-fn prompt() {
+// the last server connected to, kept so it can be retried from the menu
+#[derive(Resource)]
+struct LastServer(ConnectionConfiguration);
+
+// runs out while connecting, handshaking or joining
+#[derive(Resource, Deref, DerefMut)]
+struct ConnectionTimeout(Timer);
+
+// the place in the server's join queue last shown to the player
+#[derive(Resource, Default)]
+struct QueuePosition(Option<usize>);
+
+fn prompt(identity: Res<Identity>, last_server: Option<Res<LastServer>>) {
+    println!(
+        "Playing as {}, enter '/name <name>' to change it.",
+        identity.name()
+    );
     println!("Enter an address and port to connect to. Enter blank to self host.");
+    if last_server.is_some() {
+        println!("Enter '/retry' to connect to the last server again.");
+    }
 }
 
-fn announce_leave_server(client: ResMut<Client>) {
+fn announce_leave_server(transport: ClientTransport) {
     info!("Announcing leaving server!");
 
-    client
-        .connection()
-        .send_message(ClientMessage::Disconnect {})
+    transport
+        .send(ClientMessage::Disconnect {})
         .expect("Client failed to send disconnect server message");
 }
 
 fn close_server_connection(
     mut commands: Commands,
     mut client: ResMut<Client>,
-    connection_id: Res<ClientConnectionId>,
+    connection_id: Option<Res<ClientConnectionId>>,
 ) {
-    info!("Closing server connection");
-
-    client
-        .close_connection(connection_id.0)
-        .expect("Error closing client connection to server");
+    if let Some(connection_id) = connection_id {
+        info!("Closing server connection");
+        // lost and refused connections are already closed
+        match client.close_connection(connection_id.0) {
+            Ok(()) | Err(QuinnetError::ConnectionAlreadyClosed) => {}
+            Err(e) => panic!("Error closing client connection to server: {:?}", e),
+        }
+    }
     commands.remove_resource::<ClientConnectionId>();
+    commands.remove_resource::<ClientChannels>();
+    commands.remove_resource::<LoopbackClient>();
     commands.remove_resource::<ClientConnectionConfig>();
     commands.remove_resource::<Users>();
+    commands.remove_resource::<ServerBlocks>();
+    commands.remove_resource::<ServerWorld>();
+    commands.remove_resource::<ChunkLoads>();
+    commands.remove_resource::<LocalPlayer>();
+    commands.remove_resource::<ConnectionTimeout>();
+    commands.remove_resource::<QueuePosition>();
+}
+
+// changes outside the loaded chunks are ignored
+pub(crate) fn set_blocks(
+    server_world: &mut ServerWorld,
+    chunk_updated_events: &mut EventWriter<ChunkUpdated>,
+    changes: impl IntoIterator<Item = (BlockPos, BlockId)>,
+) {
+    for (pos, block) in changes {
+        if server_world.chunk(pos.chunk()).is_some() {
+            server_world.set_block(pos, block);
+        }
+    }
+    for (pos, _) in server_world.take_changes() {
+        send_chunk_updated(chunk_updated_events, pos.chunk());
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_server_messages(
     mut users: ResMut<Users>,
-    mut client: ResMut<Client>,
-    mut next_client_state: ResMut<NextState<ClientState>>,
+    mut server_world: ResMut<ServerWorld>,
+    mut chunk_loads: ResMut<ChunkLoads>,
+    mut remote_entities: ResMut<RemoteEntities>,
+    mut chunk_updated_events: EventWriter<ChunkUpdated>,
+    mut local_player: ResMut<LocalPlayer>,
+    mut transport: ClientTransport,
+    mut left_server_events: EventWriter<LeftServer>,
 ) {
-    while let Some(message) = client
-        .connection_mut()
-        .try_receive_message::<ServerMessage>()
-    {
+    while let Some(message) = transport.try_receive_message() {
         match message {
             ServerMessage::ClientConnected {
                 client_id,
@@ -104,15 +250,65 @@ fn handle_server_messages(
                     warn!("Chat message from an unknown client_id: {}", client_id);
                 }
             }
-            ServerMessage::InitClient {
-                client_id,
-                usernames,
-            } => {
-                users.self_id = client_id;
-                users.names = usernames;
+            ServerMessage::CommandOutput { message } | ServerMessage::SystemMessage { message } => {
+                println!("{}", message);
             }
-            ServerMessage::ServerStopping => {
-                next_client_state.set(ClientState::Menu);
+            ServerMessage::CommandSuggestions { suggestions } => {
+                if suggestions.is_empty() {
+                    println!("No suggestions");
+                } else {
+                    println!("{}", suggestions.join("  "));
+                }
+            }
+            ServerMessage::ChunkData { pos, data, load } => match Chunk::decode(&data) {
+                Ok(chunk) => {
+                    let Some(early) = chunk_loads.chunk_arrived(pos, load) else {
+                        continue;
+                    };
+                    server_world.insert_chunk(pos, chunk);
+                    send_chunk_updated(&mut chunk_updated_events, pos);
+                    set_blocks(&mut server_world, &mut chunk_updated_events, early);
+                }
+                Err(e) => warn!("Server sent an invalid chunk at {:?}: {}", pos, e),
+            },
+            ServerMessage::UnloadChunk { pos, load } => {
+                if chunk_loads.unload(pos, load) {
+                    server_world.remove_chunk(pos);
+                    send_chunk_updated(&mut chunk_updated_events, pos);
+                }
+            }
+            ServerMessage::BlockChanges { changes } => {
+                let changes = chunk_loads.changes(changes);
+                set_blocks(&mut server_world, &mut chunk_updated_events, changes);
+            }
+            ServerMessage::BlockCorrection { pos, block } => {
+                // undoes a change made before the server rejected it
+                let changes = chunk_loads.changes([(pos, block)]);
+                set_blocks(&mut server_world, &mut chunk_updated_events, changes);
+            }
+            ServerMessage::PlayerState { sequence, body } => {
+                local_player.confirm(sequence, body);
+            }
+            message @ (ServerMessage::EntitySpawned { .. }
+            | ServerMessage::EntityDespawned { .. }
+            | ServerMessage::EntityStates { .. }
+            | ServerMessage::EntityUpdates { .. }) => remote_entities.queue(message),
+            ServerMessage::StoppingSoon { reason, seconds } => {
+                let unit = if seconds == 1 { "second" } else { "seconds" };
+                println!("{}, the server stops in {} {}", reason, seconds, unit);
+            }
+            ServerMessage::ServerStopping { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::ServerStopping(reason)));
+            }
+            ServerMessage::Kicked { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::Kicked(reason)));
+            }
+            ServerMessage::HandshakeAccepted { .. }
+            | ServerMessage::HandshakeRejected { .. }
+            | ServerMessage::JoinRejected { .. }
+            | ServerMessage::QueuePosition { .. }
+            | ServerMessage::InitClient { .. } => {
+                warn!("Got a joining message after joining");
             }
         }
     }
@@ -131,53 +327,269 @@ fn check_internal_server_ready(
 
 fn check_if_connected(
     mut connection_events: EventReader<ConnectionEvent>,
+    connection_id: Option<Res<ClientConnectionId>>,
     mut next_client_state: ResMut<NextState<ClientState>>,
-    client: ResMut<Client>,
+    loaded_mods: Res<LoadedMods>,
+    transport: ClientTransport,
 ) {
-    if !connection_events.is_empty() {
+    let connected = connection_events
+        .read()
+        .any(|event| connection_id.as_ref().is_some_and(|id| id.0 == event.id));
+    if transport.is_loopback() || connected {
         info!("Got a connection event!");
 
-        let username: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(7)
-            .map(char::from)
-            .collect();
+        transport
+            .send(ClientMessage::hello(&loaded_mods))
+            .expect("Could not send hello message to server");
+
+        next_client_state.set(ClientState::Handshaking);
+    }
+}
 
-        println!("Joining with name: {}", username);
-        println!("Type '/quit' to disconnect");
+fn handle_handshake_response(
+    identity: Res<Identity>,
+    mut transport: ClientTransport,
+    mut next_client_state: ResMut<NextState<ClientState>>,
+    mut left_server_events: EventWriter<LeftServer>,
+) {
+    while let Some(message) = transport.try_receive_message() {
+        match message {
+            ServerMessage::HandshakeAccepted { motd, challenge } => {
+                if !motd.is_empty() {
+                    println!("{}", motd);
+                }
 
-        client
-            .connection()
-            .send_message(ClientMessage::Join { name: username })
-            .expect("Could not send join message to server");
+                println!("Joining with name: {}", identity.name());
+                println!("Type '/help' for commands and '/quit' to disconnect");
+                println!("End a command with a tab to see suggestions");
 
-        connection_events.clear();
+                transport
+                    .send(identity.join(&challenge))
+                    .expect("Could not send join message to server");
 
-        next_client_state.set(ClientState::InGame);
+                next_client_state.set(ClientState::Joining);
+                return;
+            }
+            ServerMessage::HandshakeRejected { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::Rejected(reason)));
+                return;
+            }
+            ServerMessage::ServerStopping { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::ServerStopping(reason)));
+                return;
+            }
+            other => warn!("Unexpected message before the handshake: {:?}", other),
+        }
+    }
+}
+
+// anything after the InitClient is left for handle_server_messages
+#[allow(clippy::too_many_arguments)]
+fn handle_join_response(
+    mut commands: Commands,
+    mut users: ResMut<Users>,
+    mut timeout: ResMut<ConnectionTimeout>,
+    mut queue_position: ResMut<QueuePosition>,
+    replication_registry: Res<ReplicationRegistry>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut transport: ClientTransport,
+    mut next_client_state: ResMut<NextState<ClientState>>,
+    mut left_server_events: EventWriter<LeftServer>,
+) {
+    while let Some(message) = transport.try_receive_message() {
+        match message {
+            ServerMessage::InitClient {
+                client_id,
+                usernames,
+                blocks,
+                components,
+                tick_rate,
+            } => {
+                match BlockRegistry::from_blocks(blocks) {
+                    Ok(block_registry) => {
+                        // inputs are predicted a tick at a time, so the ticks
+                        // have to match the server's
+                        fixed_time.set_timestep_hz(tick_rate.max(1) as f64);
+                        users.self_id = client_id;
+                        users.names = usernames;
+                        commands.insert_resource(ServerBlocks(block_registry));
+                        commands.insert_resource(RemoteEntities::new(
+                            &components,
+                            &replication_registry,
+                        ));
+                        next_client_state.set(ClientState::InGame);
+                    }
+                    Err(e) => {
+                        left_server_events.send(LeftServer(DisconnectReason::InvalidBlocks(e)));
+                    }
+                }
+                return;
+            }
+            ServerMessage::JoinRejected { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::Rejected(reason)));
+                return;
+            }
+            // waiting in the queue doesn't count towards the timeout
+            ServerMessage::QueuePosition { position } => {
+                if queue_position.0 != Some(position) {
+                    println!(
+                        "The server is full, you are number {} in the queue",
+                        position
+                    );
+                    queue_position.0 = Some(position);
+                }
+                timeout.reset();
+            }
+            ServerMessage::Kicked { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::Kicked(reason)));
+                return;
+            }
+            ServerMessage::ServerStopping { reason } => {
+                left_server_events.send(LeftServer(DisconnectReason::ServerStopping(reason)));
+                return;
+            }
+            other => warn!("Unexpected message before joining: {:?}", other),
+        }
+    }
+}
+
+fn is_connecting(client_state: Res<State<ClientState>>) -> bool {
+    matches!(
+        client_state.get(),
+        ClientState::ConnectingToServer | ClientState::Handshaking | ClientState::Joining
+    )
+}
+
+fn time_out_connection(
+    time: Res<Time>,
+    mut timeout: ResMut<ConnectionTimeout>,
+    mut left_server_events: EventWriter<LeftServer>,
+) {
+    if timeout.tick(time.delta()).just_finished() {
+        left_server_events.send(LeftServer(DisconnectReason::TimedOut));
+    }
+}
+
+fn warn_certificate_changed(info: &CertVerificationInfo) {
+    let pinned = info
+        .known_fingerprint
+        .as_ref()
+        .map_or("none".to_string(), ToString::to_string);
+    println!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+    println!(
+        "WARNING: THE CERTIFICATE OF {} HAS CHANGED!",
+        info.server_name
+    );
+    println!("Someone could be pretending to be the server, or its owner");
+    println!("replaced the certificate. The connection was not opened.");
+    println!("Pinned fingerprint: {}", pinned);
+    println!("New fingerprint:    {}", info.fingerprint);
+    println!(
+        "If the owner confirms the new fingerprint, remove the server from `{}`.",
+        KNOWN_SERVERS_FILE
+    );
+    println!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+}
+
+fn handle_certificate_events(
+    mut trust_update_events: EventReader<CertTrustUpdateEvent>,
+    mut abort_events: EventReader<CertConnectionAbortEvent>,
+    connection_id: Res<ClientConnectionId>,
+    mut left_server_events: EventWriter<LeftServer>,
+) {
+    for event in trust_update_events.read() {
+        if event.connection_id == connection_id.0 {
+            println!(
+                "Pinned the certificate of new server {}: {}",
+                event.cert_info.server_name, event.cert_info.fingerprint
+            );
+        }
+    }
+    for event in abort_events.read() {
+        if event.connection_id == connection_id.0 {
+            warn_certificate_changed(&event.cert_info);
+            left_server_events.send(LeftServer(DisconnectReason::CertificateChanged));
+        }
+    }
+}
+
+fn handle_connection_lost(
+    mut connection_lost_events: EventReader<ConnectionLostEvent>,
+    connection_id: Res<ClientConnectionId>,
+    mut left_server_events: EventWriter<LeftServer>,
+) {
+    if connection_lost_events
+        .read()
+        .any(|event| event.id == connection_id.0)
+    {
+        left_server_events.send(LeftServer(DisconnectReason::ConnectionLost));
+    }
+}
+
+fn return_to_menu(
+    mut left_server_events: EventReader<LeftServer>,
+    client_state: Res<State<ClientState>>,
+    mut next_client_state: ResMut<NextState<ClientState>>,
+    internal_server_state: Res<State<InternalServerState>>,
+    mut next_internal_server_state: ResMut<NextState<InternalServerState>>,
+) {
+    let Some(LeftServer(reason)) = left_server_events.read().last() else {
+        return;
+    };
+    if let ClientState::Menu = client_state.get() {
+        return;
+    }
+    println!("Disconnected: {}", reason);
+
+    next_client_state.set(ClientState::Menu);
+    if let InternalServerState::Running = **internal_server_state {
+        next_internal_server_state.set(InternalServerState::Stopping);
     }
 }
 
 fn handle_menu_input(
     mut commands: Commands,
     mut next_client_state: ResMut<NextState<ClientState>>,
+    internal_server_state: Res<State<InternalServerState>>,
     mut next_internal_server_state: ResMut<NextState<InternalServerState>>,
+    last_server: Option<Res<LastServer>>,
+    mut identity: ResMut<Identity>,
     message: String,
 ) {
-    if message.is_empty() {
+    if let Some(name) = message.strip_prefix("/name ") {
+        match identity.set_name(name.trim()) {
+            Ok(()) => {
+                if let Err(e) = identity.save(Path::new(IDENTITY_FILE)) {
+                    error!("{}", e);
+                }
+                println!("Your name is now {}", identity.name());
+            }
+            Err(e) => println!("{}", e),
+        }
+    } else if message.is_empty() && **internal_server_state != InternalServerState::Off {
+        println!("The last game is still stopping, try again in a few seconds");
+    } else if message.is_empty() {
         info!("Launching internal server!");
 
         next_client_state.set(ClientState::LaunchingInternalServer);
-        commands.insert_resource(ClientConnectionConfig(
-            ConnectionConfiguration::from_strings("127.0.0.1:6006", "0.0.0.0:0")
-                .expect("The localhost connection config failed"),
-        ));
         next_internal_server_state.set(InternalServerState::Launching);
+    } else if message == "/retry" {
+        match last_server {
+            Some(last_server) => {
+                info!("Connecting to the last server again");
+
+                next_client_state.set(ClientState::ConnectingToServer);
+                commands.insert_resource(ClientConnectionConfig(last_server.0.clone()));
+            }
+            None => println!("There is no server to retry"),
+        }
     } else {
         match ConnectionConfiguration::from_strings(&message, "0.0.0.0:0") {
             Ok(connection) => {
                 info!("Connecting to {}", &message);
 
                 next_client_state.set(ClientState::ConnectingToServer);
+                commands.insert_resource(LastServer(connection.clone()));
                 commands.insert_resource(ClientConnectionConfig(connection));
             }
             Err(e) => {
@@ -191,33 +603,31 @@ fn handle_game_input(
     mut next_client_state: ResMut<NextState<ClientState>>,
     internal_server_state: Res<State<InternalServerState>>,
     mut next_internal_server_state: ResMut<NextState<InternalServerState>>,
-    users: Res<Users>,
-    client: ResMut<Client>,
+    transport: ClientTransport,
     message: String,
 ) {
     if message == "/quit" {
-        announce_leave_server(client);
+        announce_leave_server(transport);
         next_client_state.set(ClientState::Menu);
         if let InternalServerState::Running = **internal_server_state {
-            next_internal_server_state.set(InternalServerState::Off);
+            next_internal_server_state.set(InternalServerState::Stopping);
         }
-    } else if message == "/list" {
-        println!("{} online", &users.names.len());
-        for (c_id, name) in &users.names {
-            println!(
-                "{}{}",
-                name,
-                if c_id == &users.self_id { " (you)" } else { "" }
-            );
+    } else if let Some(line) = message.strip_suffix('\t') {
+        if is_command(line) {
+            transport
+                .send(ClientMessage::CompleteCommand {
+                    line: line.to_string(),
+                })
+                .expect("Failed to send command completion to server");
         }
     } else {
-        client
-            .connection()
-            .send_message(ClientMessage::ChatMessage { message })
+        transport
+            .send(ClientMessage::ChatMessage { message })
             .expect("Failed to send chat message to server");
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_terminal_messages(
     commands: Commands,
     mut terminal_messages: ResMut<TerminalReceiver>,
@@ -225,23 +635,26 @@ fn handle_terminal_messages(
     next_internal_server_state: ResMut<NextState<InternalServerState>>,
     client_state: Res<State<ClientState>>,
     internal_server_state: Res<State<InternalServerState>>,
-    client: ResMut<Client>,
-    users: Option<Res<Users>>,
+    transport: ClientTransport,
+    last_server: Option<Res<LastServer>>,
+    identity: ResMut<Identity>,
 ) {
     if let Ok(message) = terminal_messages.try_recv() {
         match client_state.get() {
             ClientState::Menu => handle_menu_input(
                 commands,
                 next_client_state,
+                internal_server_state,
                 next_internal_server_state,
+                last_server,
+                identity,
                 message,
             ),
             ClientState::InGame => handle_game_input(
                 next_client_state,
                 internal_server_state,
                 next_internal_server_state,
-                users.expect("There was not a users resource"),
-                client,
+                transport,
                 message,
             ),
             _ => warn!("Not in a state to accept messages, disregarding"),
@@ -257,52 +670,133 @@ fn start_terminal_listener(mut commands: Commands) {
         std::io::stdin()
             .read_line(&mut buffer)
             .expect("Failed to read a line from stdin");
+        let line = buffer.trim_end_matches(['\r', '\n']);
+        // a tab at the end asks for suggestions, so it is kept along with
+        // the space that may come before it
+        let line = match line.strip_suffix('\t') {
+            Some(partial) => format!("{}\t", partial),
+            None => line.trim_end().to_string(),
+        };
         from_terminal_sender
-            .try_send(buffer.trim_end().to_string())
+            .try_send(line)
             .expect("Failed to send input buffer to terminal sender?");
     });
 
     commands.insert_resource(TerminalReceiver(from_terminal_receiver));
 }
 
+// trust on first use: new servers are pinned, and servers whose certificate
+// changed since are refused
+fn certificate_verification() -> CertificateVerificationMode {
+    let action = CertVerifierBehaviour::ImmediateAction;
+    CertificateVerificationMode::TrustOnFirstUse(TrustOnFirstUseConfig {
+        known_hosts: KnownHosts::HostsFile(KNOWN_SERVERS_FILE.to_string()),
+        verifier_behaviour: HashMap::from([
+            (
+                CertVerificationStatus::UnknownCertificate,
+                action(CertVerifierAction::TrustAndStore),
+            ),
+            (
+                CertVerificationStatus::TrustedCertificate,
+                action(CertVerifierAction::TrustOnce),
+            ),
+            (
+                CertVerificationStatus::UntrustedCertificate,
+                action(CertVerifierAction::AbortConnection),
+            ),
+        ]),
+    })
+}
+
 fn start_connection(
     mut commands: Commands,
     mut client: ResMut<Client>,
-    connection_config: Res<ClientConnectionConfig>,
+    connection_config: Option<Res<ClientConnectionConfig>>,
 ) {
-    info!("Opening connection to server!");
     commands.init_resource::<Users>();
+    commands.init_resource::<ServerWorld>();
+    commands.init_resource::<ChunkLoads>();
+    commands.init_resource::<LocalPlayer>();
+    commands.init_resource::<QueuePosition>();
+    commands.insert_resource(ConnectionTimeout(Timer::new(
+        CONNECTION_TIMEOUT,
+        TimerMode::Once,
+    )));
+    let Some(connection_config) = connection_config else {
+        info!("Connecting to internal server!");
+        return;
+    };
+
+    info!("Opening connection to server!");
 
     let (connection_id, _) = client
-        .open_connection(
-            connection_config.0.clone(),
-            CertificateVerificationMode::SkipVerification,
-        )
+        .open_connection(connection_config.0.clone(), certificate_verification())
         .expect("Could not open client connection to server");
+    let world_edits = client
+        .get_connection_mut_by_id(connection_id)
+        .and_then(|connection| connection.open_channel(ChannelType::OrderedReliable).ok())
+        .expect("Could not open the world edits channel");
     commands.insert_resource(ClientConnectionId(connection_id));
+    commands.insert_resource(ClientChannels { world_edits });
+}
+
+// the game can't play as anyone else, so it stops if the identity is unusable
+fn load_identity() -> Identity {
+    match Identity::load_or_create(Path::new(IDENTITY_FILE)) {
+        Ok(identity) => identity,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 pub(crate) struct ClientPlugin;
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
         // library plugins
-        app.add_plugins((
-            MinimalPlugins,
-            LogPlugin::default(),
-            QuinnetClientPlugin::default(),
-        ));
+        #[cfg(not(feature = "render"))]
+        app.add_plugins((MinimalPlugins, LogPlugin::default()));
+        #[cfg(feature = "render")]
+        app.add_plugins(
+            DefaultPlugins
+                .set(AssetPlugin {
+                    // assets are shared by the whole workspace, at the repository root
+                    file_path: "../../assets".to_string(),
+                    ..default()
+                })
+                .set(ImagePlugin::default_nearest()),
+        );
+        app.add_plugins(QuinnetClientPlugin::default());
         // crate plugins
         app.add_plugins(InternalServerPlugin);
+        #[cfg(feature = "render")]
+        app.add_plugins((ChunkRenderPlugin, InteractionPlugin));
 
         // add states and events
         app.add_state::<ClientState>();
+        app.add_event::<ChunkUpdated>();
+        app.add_event::<LeftServer>();
+        app.init_resource::<MovementInput>();
+        app.insert_resource(load_identity());
 
         // input systems
         app.add_systems(Startup, start_terminal_listener);
         app.add_systems(Update, handle_terminal_messages);
 
         // menu systems
-        app.add_systems(OnEnter(ClientState::Menu), prompt);
+        app.add_systems(
+            OnEnter(ClientState::Menu),
+            (close_server_connection, clear_remote_entities, prompt),
+        );
+        // after everything that can leave the server, so it sees every reason
+        app.add_systems(
+            Update,
+            return_to_menu
+                .after(handle_server_messages)
+                .after(handle_handshake_response)
+                .after(handle_join_response),
+        );
 
         // hosting systems
         app.add_systems(
@@ -316,12 +810,36 @@ impl Plugin for ClientPlugin {
             Update,
             check_if_connected.run_if(in_state(ClientState::ConnectingToServer)),
         );
+        app.add_systems(
+            Update,
+            handle_handshake_response.run_if(in_state(ClientState::Handshaking)),
+        );
+        app.add_systems(
+            Update,
+            handle_join_response.run_if(in_state(ClientState::Joining)),
+        );
+        app.add_systems(Update, time_out_connection.run_if(is_connecting));
+        // an aborted connection is also lost, the more precise reason goes last,
+        // as do the server's own reasons for closing it
+        app.add_systems(
+            Update,
+            (handle_connection_lost, handle_certificate_events)
+                .chain()
+                .before(handle_server_messages)
+                .run_if(resource_exists::<ClientConnectionId>()),
+        );
 
         // game systems
         app.add_systems(
             Update,
-            handle_server_messages.run_if(in_state(ClientState::InGame)),
+            (handle_server_messages, apply_entity_messages)
+                .chain()
+                .run_if(in_state(ClientState::InGame)),
+        );
+        app.add_systems(
+            FixedUpdate,
+            predict_local_player
+                .run_if(in_state(ClientState::InGame).and_then(resource_exists::<ServerBlocks>())),
         );
-        app.add_systems(OnExit(ClientState::InGame), close_server_connection);
     }
 }
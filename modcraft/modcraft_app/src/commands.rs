@@ -0,0 +1,578 @@
+//! The commands every server has. Mods register theirs after these, so they
+//! can replace them.
+
+use bevy::{ecs::system::SystemState, prelude::*};
+use bevy_quinnet::shared::ClientId;
+use modcraft_lib::{
+    commands::{
+        ArgKind, ChatCommand, ChatCommands, CommandArgs, CommandContext, CommandResult,
+        CommandSender, PermissionLevel,
+    },
+    config::ServerConfig,
+    physics::PlayerBody,
+    save::{PlayerBan, PlayerRecord, WorldSave},
+};
+
+use crate::{
+    auth::KnownPlayers,
+    bans::{Bans, Whitelist},
+    persistence::GameTime,
+    players::Player,
+    protocol::ServerMessage,
+    server::{kick_client, Users},
+    ticks::TickTimes,
+    transport::{ServerTransport, LOCAL_CLIENT_ID},
+};
+
+/// Who a client is when running commands, `None` before they joined. The
+/// player hosting the world is always an operator.
+pub(crate) fn player_context(
+    client_id: ClientId,
+    users: &Users,
+    known_players: &KnownPlayers,
+) -> Option<CommandContext> {
+    let name = users.name(client_id)?.to_string();
+    let is_operator = client_id == LOCAL_CLIENT_ID
+        || users
+            .uuid(client_id)
+            .and_then(|uuid| known_players.get(uuid))
+            .is_some_and(|record| record.operator);
+    Some(CommandContext {
+        sender: CommandSender::Player { client_id, name },
+        level: if is_operator {
+            PermissionLevel::Operator
+        } else {
+            PermissionLevel::Player
+        },
+        players: users.names(),
+    })
+}
+
+pub(crate) fn register_builtin_commands(commands: &mut ChatCommands) {
+    let player = || ArgKind::Player;
+    commands.register(
+        ChatCommand::new("help", "Lists the commands you can use", help_command)
+            .with_optional_arg("command", ArgKind::Word),
+    );
+    commands.register(ChatCommand::new(
+        "list",
+        "Lists the players online",
+        list_command,
+    ));
+    commands.register(
+        ChatCommand::new("msg", "Sends a message only one player sees", msg_command)
+            .with_arg("player", player())
+            .with_arg("message", ArgKind::Text),
+    );
+    commands.register(
+        ChatCommand::new("say", "Sends a message to everyone", say_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("message", ArgKind::Text),
+    );
+    commands.register(
+        ChatCommand::new("kick", "Disconnects a player", kick_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("player", player())
+            .with_optional_arg("reason", ArgKind::Text),
+    );
+    commands.register(
+        ChatCommand::new("ban", "Keeps a player off the server", ban_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("name", ArgKind::Word)
+            .with_optional_arg("reason", ArgKind::Text),
+    );
+    commands.register(
+        ChatCommand::new("pardon", "Lets a banned player back in", pardon_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("name", ArgKind::Word),
+    );
+    commands.register(
+        ChatCommand::new("banlist", "Lists the bans", banlist_command)
+            .with_permission(PermissionLevel::Operator),
+    );
+    commands.register(
+        ChatCommand::new(
+            "whitelist",
+            "Shows or changes who may join while the whitelist is on",
+            whitelist_command,
+        )
+        .with_permission(PermissionLevel::Operator)
+        .with_arg(
+            "action",
+            ArgKind::Choice(vec![
+                "add".to_string(),
+                "remove".to_string(),
+                "list".to_string(),
+            ]),
+        )
+        .with_optional_arg("name", ArgKind::Word),
+    );
+    commands.register(
+        ChatCommand::new("op", "Makes a player an operator", op_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("name", ArgKind::Word),
+    );
+    commands.register(
+        ChatCommand::new("deop", "Makes an operator a player again", deop_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("name", ArgKind::Word),
+    );
+    commands.register(
+        ChatCommand::new("tp", "Moves a player to a position", tp_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_arg("player", player())
+            .with_arg("x", ArgKind::Number)
+            .with_arg("y", ArgKind::Number)
+            .with_arg("z", ArgKind::Number),
+    );
+    commands.register(
+        ChatCommand::new("time", "Shows or changes the world's time", time_command)
+            .with_permission(PermissionLevel::Operator)
+            .with_optional_arg(
+                "action",
+                ArgKind::Choice(vec!["set".to_string(), "add".to_string()]),
+            )
+            .with_optional_arg("ticks", ArgKind::Integer),
+    );
+    commands.register(ChatCommand::new(
+        "tps",
+        "Shows how fast the server is ticking",
+        tps_command,
+    ));
+}
+
+fn send_system_message(world: &mut World, client_ids: &[ClientId], message: String) {
+    let mut transport_state = SystemState::<ServerTransport>::new(world);
+    let transport = transport_state.get_mut(world);
+    if let Err(e) =
+        transport.send_group(client_ids.iter(), ServerMessage::SystemMessage { message })
+    {
+        warn!("Failed to send system message: {}", e);
+    }
+}
+
+fn online(world: &World) -> Vec<ClientId> {
+    world.resource::<Users>().ids().collect()
+}
+
+fn client_id(world: &World, name: &str) -> Result<ClientId, String> {
+    world
+        .resource::<Users>()
+        .find(name)
+        .ok_or_else(|| format!("No player called {} is online", name))
+}
+
+// players that left can still be banned or made operators
+fn known_player<'w>(world: &'w mut World, name: &str) -> Result<&'w mut PlayerRecord, String> {
+    world
+        .resource_mut::<KnownPlayers>()
+        .into_inner()
+        .find_mut(name)
+        .ok_or_else(|| format!("No player called {} has joined this world", name))
+}
+
+fn help_command(world: &mut World, context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let commands = world.resource::<ChatCommands>();
+    let line = |command: &ChatCommand| format!("{} - {}", command.usage(), command.help);
+    match args.text("command") {
+        Some(name) => commands
+            .get(name.trim_start_matches('/'))
+            .filter(|command| command.permission <= context.level)
+            .map(line)
+            .ok_or_else(|| format!("Unknown command /{}", name)),
+        None => Ok(commands
+            .available(context.level)
+            .map(line)
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
+
+fn list_command(
+    world: &mut World,
+    _context: &CommandContext,
+    _args: &CommandArgs,
+) -> CommandResult {
+    let mut names = world.resource::<Users>().names();
+    names.sort_by_key(|name| name.to_lowercase());
+    Ok(format!("{} online: {}", names.len(), names.join(", ")))
+}
+
+fn msg_command(world: &mut World, context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let name = args.text("player").unwrap();
+    let message = args.text("message").unwrap();
+    let client_id = client_id(world, name)?;
+    send_system_message(
+        world,
+        &[client_id],
+        format!("{} whispers to you: {}", context.sender.name(), message),
+    );
+    Ok(format!("You whisper to {}: {}", name, message))
+}
+
+fn say_command(world: &mut World, context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let message = format!(
+        "[{}] {}",
+        context.sender.name(),
+        args.text("message").unwrap()
+    );
+    // the sender sees it as the command's output
+    let others: Vec<ClientId> = online(world)
+        .into_iter()
+        .filter(|client_id| !matches!(context.sender, CommandSender::Player { client_id: sender, .. } if sender == *client_id))
+        .collect();
+    send_system_message(world, &others, message.clone());
+    Ok(message)
+}
+
+// like kick_client, but refuses to remove the host
+fn kick(world: &mut World, client_id: ClientId, reason: String) -> Result<(), String> {
+    if client_id == LOCAL_CLIENT_ID {
+        return Err("The player hosting the world can't be removed".to_string());
+    }
+    let mut state = SystemState::<(ServerTransport, ResMut<Users>)>::new(world);
+    let (mut transport, mut users) = state.get_mut(world);
+    kick_client(&mut transport, &mut users, client_id, reason);
+    Ok(())
+}
+
+fn with_reason(action: &str, context: &CommandContext, reason: Option<&str>) -> String {
+    match reason {
+        Some(reason) => format!("{} by {}: {}", action, context.sender.name(), reason),
+        None => format!("{} by {}", action, context.sender.name()),
+    }
+}
+
+fn kick_command(world: &mut World, context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let name = args.text("player").unwrap();
+    let client_id = client_id(world, name)?;
+    kick(
+        world,
+        client_id,
+        with_reason("Kicked", context, args.text("reason")),
+    )?;
+    Ok(format!("Kicked {}", name))
+}
+
+// bans and the whitelist are saved as soon as they change, so they hold
+// even if the server doesn't stop cleanly
+fn save_bans(world: &World) -> Result<(), String> {
+    let Some(save) = world.get_resource::<WorldSave>() else {
+        return Ok(());
+    };
+    save.save_bans(&world.resource::<Bans>().0)
+        .map_err(|e| format!("Failed to save the ban list: {}", e))
+}
+
+fn save_whitelist(world: &World) -> Result<(), String> {
+    let Some(save) = world.get_resource::<WorldSave>() else {
+        return Ok(());
+    };
+    save.save_whitelist(&world.resource::<Whitelist>().0)
+        .map_err(|e| format!("Failed to save the whitelist: {}", e))
+}
+
+fn ban_command(world: &mut World, context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let record = known_player(world, args.text("name").unwrap())?;
+    let (name, uuid) = (record.name.clone(), record.uuid);
+    if world.resource::<Bans>().player(uuid).is_some() {
+        return Err(format!("{} is already banned", name));
+    }
+    let online = world.resource::<Users>().find_uuid(uuid);
+    if online == Some(LOCAL_CLIENT_ID) {
+        return Err("The player hosting the world can't be banned".to_string());
+    }
+    let reason = args.text("reason").map(str::to_string);
+    world.resource_mut::<Bans>().0.players.push(PlayerBan {
+        uuid,
+        name: name.clone(),
+        reason: reason.clone(),
+    });
+    save_bans(world)?;
+    if let Some(client_id) = online {
+        kick(
+            world,
+            client_id,
+            with_reason("Banned", context, reason.as_deref()),
+        )?;
+    }
+    Ok(format!("Banned {}", name))
+}
+
+fn pardon_command(
+    world: &mut World,
+    _context: &CommandContext,
+    args: &CommandArgs,
+) -> CommandResult {
+    let name = args.text("name").unwrap();
+    let mut bans = world.resource_mut::<Bans>();
+    let index = bans
+        .0
+        .players
+        .iter()
+        .position(|ban| ban.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("{} is not banned", name))?;
+    let ban = bans.0.players.remove(index);
+    save_bans(world)?;
+    Ok(format!("Pardoned {}", ban.name))
+}
+
+fn banlist_command(
+    world: &mut World,
+    _context: &CommandContext,
+    _args: &CommandArgs,
+) -> CommandResult {
+    let bans = &world.resource::<Bans>().0;
+    let line = |target: &str, reason: &Option<String>| match reason {
+        Some(reason) => format!("{} - {}", target, reason),
+        None => target.to_string(),
+    };
+    let lines: Vec<String> = bans
+        .players
+        .iter()
+        .map(|ban| line(&ban.name, &ban.reason))
+        .collect();
+    if lines.is_empty() {
+        return Ok("Nobody is banned".to_string());
+    }
+    Ok(format!("{} banned:\n{}", lines.len(), lines.join("\n")))
+}
+
+fn whitelist_command(
+    world: &mut World,
+    _context: &CommandContext,
+    args: &CommandArgs,
+) -> CommandResult {
+    let action = args.text("action").unwrap();
+    if action == "list" {
+        let whitelist = &world.resource::<Whitelist>().0;
+        let state = if world.resource::<ServerConfig>().whitelist {
+            "on"
+        } else {
+            "off"
+        };
+        return Ok(format!(
+            "The whitelist is {}, {} on it: {}",
+            state,
+            whitelist.len(),
+            whitelist.join(", ")
+        ));
+    }
+    let name = args
+        .text("name")
+        .ok_or_else(|| format!("/whitelist {} needs a name", action))?;
+    let mut whitelist = world.resource_mut::<Whitelist>();
+    let index = whitelist
+        .0
+        .iter()
+        .position(|other| other.eq_ignore_ascii_case(name));
+    let message = match (action, index) {
+        ("add", Some(_)) => return Err(format!("{} is already on the whitelist", name)),
+        ("add", None) => {
+            whitelist.0.push(name.to_string());
+            format!("Added {} to the whitelist", name)
+        }
+        (_, Some(index)) => {
+            let name = whitelist.0.remove(index);
+            format!("Removed {} from the whitelist", name)
+        }
+        (_, None) => return Err(format!("{} is not on the whitelist", name)),
+    };
+    save_whitelist(world)?;
+    Ok(message)
+}
+
+fn set_operator(world: &mut World, name: &str, operator: bool) -> CommandResult {
+    let record = known_player(world, name)?;
+    if record.operator == operator {
+        return Err(if operator {
+            format!("{} is already an operator", record.name)
+        } else {
+            format!("{} is not an operator", record.name)
+        });
+    }
+    record.operator = operator;
+    let (name, uuid) = (record.name.clone(), record.uuid);
+    if let Some(client_id) = world.resource::<Users>().find_uuid(uuid) {
+        let message = if operator {
+            "You are now an operator"
+        } else {
+            "You are no longer an operator"
+        };
+        send_system_message(world, &[client_id], message.to_string());
+    }
+    Ok(if operator {
+        format!("{} is now an operator", name)
+    } else {
+        format!("{} is no longer an operator", name)
+    })
+}
+
+fn op_command(world: &mut World, _context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    set_operator(world, args.text("name").unwrap(), true)
+}
+
+fn deop_command(world: &mut World, _context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    set_operator(world, args.text("name").unwrap(), false)
+}
+
+fn tp_command(world: &mut World, _context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let name = args.text("player").unwrap();
+    let client_id = client_id(world, name)?;
+    let [x, y, z] = ["x", "y", "z"].map(|axis| args.number(axis).unwrap() as f32);
+    let position = Vec3::new(x, y, z);
+    let mut players = world.query::<&mut Player>();
+    let mut player = players
+        .iter_mut(world)
+        .find(|player| player.client_id == client_id)
+        .ok_or_else(|| format!("{} hasn't spawned yet", name))?;
+    player.body = PlayerBody::new(position);
+    Ok(format!("Moved {} to {} {} {}", name, x, y, z))
+}
+
+fn time_command(world: &mut World, _context: &CommandContext, args: &CommandArgs) -> CommandResult {
+    let mut game_time = world.resource_mut::<GameTime>();
+    let Some(action) = args.text("action") else {
+        return Ok(format!("The world has run for {} ticks", game_time.0));
+    };
+    let ticks = args
+        .integer("ticks")
+        .ok_or_else(|| format!("/time {} needs a number of ticks", action))?;
+    let ticks = match action {
+        "set" => ticks,
+        _ => i64::try_from(game_time.0)
+            .ok()
+            .and_then(|time| time.checked_add(ticks))
+            .ok_or_else(|| "The time is out of range".to_string())?,
+    };
+    game_time.0 = u64::try_from(ticks).map_err(|_| "The time can't be negative".to_string())?;
+    Ok(format!("Set the time to {} ticks", game_time.0))
+}
+
+fn tps_command(world: &mut World, _context: &CommandContext, _args: &CommandArgs) -> CommandResult {
+    let target = 1.0 / world.resource::<Time<Fixed>>().timestep().as_secs_f64();
+    let stats = world
+        .resource::<TickTimes>()
+        .stats()
+        .ok_or_else(|| "No ticks have run yet".to_string())?;
+    let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+    Ok(format!(
+        "{:.1} TPS (target {:.0}) over the last {} ticks, mean {:.2} ms, p99 {:.2} ms",
+        stats.ticks_per_second,
+        target,
+        stats.ticks,
+        ms(stats.mean),
+        ms(stats.p99)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        players::sync_players,
+        protocol::ClientMessage,
+        server::{handle_client_messages, join_loopback, loopback_server_app, run_chat_commands},
+        transport::loopback::LoopbackClient,
+    };
+
+    fn run(app: &mut App, client: &mut LoopbackClient, line: &str) -> Vec<String> {
+        client
+            .send(ClientMessage::ChatMessage {
+                message: line.to_string(),
+            })
+            .unwrap();
+        app.update();
+        std::iter::from_fn(|| client.try_receive())
+            .filter_map(|message| match message {
+                ServerMessage::CommandOutput { message }
+                | ServerMessage::SystemMessage { message } => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builtin_commands_run_for_the_host() {
+        let (mut app, mut client) = loopback_server_app();
+        let mut commands = ChatCommands::default();
+        register_builtin_commands(&mut commands);
+        app.insert_resource(commands).add_systems(
+            Update,
+            (
+                handle_client_messages,
+                run_chat_commands,
+                sync_players,
+                apply_deferred,
+            )
+                .chain(),
+        );
+        join_loopback(&mut app, &mut client, "local");
+        app.update();
+        while client.try_receive().is_some() {}
+
+        assert_eq!(run(&mut app, &mut client, "/list"), ["1 online: local"]);
+        assert_eq!(
+            run(&mut app, &mut client, "/tps"),
+            ["No ticks have run yet"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/time set 100"),
+            ["Set the time to 100 ticks"]
+        );
+        assert_eq!(app.world.resource::<GameTime>().0, 100);
+        assert_eq!(
+            run(&mut app, &mut client, "/time add"),
+            ["/time add needs a number of ticks"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, &format!("/time add {}", i64::MAX)),
+            ["The time is out of range"]
+        );
+        assert_eq!(app.world.resource::<GameTime>().0, 100);
+        assert_eq!(
+            run(&mut app, &mut client, "/tp LOCAL 1 2.5 3"),
+            ["Moved local to 1 2.5 3"]
+        );
+        let mut players = app.world.query::<&Player>();
+        assert_eq!(
+            players.single(&app.world).body.position,
+            Vec3::new(1.0, 2.5, 3.0)
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/msg local hi"),
+            ["local whispers to you: hi", "You whisper to local: hi"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/ban local"),
+            ["The player hosting the world can't be banned"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/whitelist add Alex"),
+            ["Added Alex to the whitelist"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/whitelist list"),
+            ["The whitelist is off, 1 on it: Alex"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/op nobody"),
+            ["No player called nobody has joined this world"]
+        );
+        assert_eq!(
+            run(&mut app, &mut client, "/tp local 1"),
+            ["Missing <y>. Usage: /tp <player> <x> <y> <z>"]
+        );
+
+        client
+            .send(ClientMessage::CompleteCommand {
+                line: "/ti".to_string(),
+            })
+            .unwrap();
+        app.update();
+        assert!(matches!(
+            client.try_receive(),
+            Some(ServerMessage::CommandSuggestions { suggestions }) if suggestions == ["/time"]
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+//! The dedicated server's console. Lines typed into the server's terminal
+//! run as commands from the console, which may run every command, and the
+//! leading `/` can be left out.
+
+use std::thread;
+
+use bevy::{app::AppExit, ecs::system::RunSystemOnce, prelude::*};
+use modcraft_lib::{
+    commands::{
+        run_command, ChatCommand, ChatCommands, CommandArgs, CommandContext, CommandResult,
+        CommandSender, PermissionLevel,
+    },
+    save::{LevelData, WorldSave},
+    world::VoxelWorld,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    auth::KnownPlayers,
+    mods::LoadedMods,
+    persistence::{remember_positions, save_world, GameTime},
+    players::Player,
+    server::Users,
+};
+
+#[derive(Resource, Deref, DerefMut)]
+pub(crate) struct ConsoleReceiver(mpsc::Receiver<String>);
+
+pub(crate) fn start_console(mut commands: Commands) {
+    let (sender, receiver) = mpsc::channel::<String>(100);
+
+    thread::spawn(move || loop {
+        let mut buffer = String::new();
+        match std::io::stdin().read_line(&mut buffer) {
+            // servers started without a terminal have nothing to read
+            Ok(0) => break,
+            Ok(_) => {
+                if sender.blocking_send(buffer.trim().to_string()).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("Stopped reading the console: {}", e);
+                break;
+            }
+        }
+    });
+
+    commands.insert_resource(ConsoleReceiver(receiver));
+}
+
+pub(crate) fn run_console_commands(world: &mut World) {
+    let Some(mut receiver) = world.get_resource_mut::<ConsoleReceiver>() else {
+        return;
+    };
+    let lines: Vec<String> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let context = CommandContext {
+            sender: CommandSender::Console,
+            level: PermissionLevel::Console,
+            players: world.resource::<Users>().names(),
+        };
+        let line = format!("/{}", line.trim_start_matches('/'));
+        let (Ok(message) | Err(message)) = run_command(world, &context, &line);
+        println!("{}", message);
+    }
+}
+
+/// Commands only the console can run.
+pub(crate) fn register_console_commands(commands: &mut ChatCommands) {
+    commands.register(
+        ChatCommand::new("stop", "Saves the world and stops the server", stop_command)
+            .with_permission(PermissionLevel::Console),
+    );
+    commands.register(
+        ChatCommand::new("save", "Saves the world now", save_command)
+            .with_permission(PermissionLevel::Console),
+    );
+}
+
+// the same shutdown as when the process is asked to exit
+fn stop_command(
+    world: &mut World,
+    _context: &CommandContext,
+    _args: &CommandArgs,
+) -> CommandResult {
+    world.send_event(AppExit);
+    Ok("Stopping the server".to_string())
+}
+
+// saves everything right away, keeping the world open
+fn save_now(
+    save: Res<WorldSave>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    level: Res<LevelData>,
+    game_time: Res<GameTime>,
+    loaded_mods: Res<LoadedMods>,
+    mut known_players: ResMut<KnownPlayers>,
+    players: Query<&Player>,
+) {
+    remember_positions(&mut known_players, &players);
+    save_world(
+        &save,
+        &mut voxel_world,
+        &level,
+        *game_time,
+        &loaded_mods,
+        &known_players,
+    );
+}
+
+fn save_command(
+    world: &mut World,
+    _context: &CommandContext,
+    _args: &CommandArgs,
+) -> CommandResult {
+    if !world.contains_resource::<WorldSave>() {
+        return Err("The world is not being saved".to_string());
+    }
+    world.run_system_once(save_now);
+    Ok("Saved the world".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::register_builtin_commands,
+        protocol::ServerMessage,
+        server::{handle_client_messages, join_loopback, loopback_server_app},
+    };
+
+    #[test]
+    fn console_commands_run_with_full_permissions() {
+        let (mut app, mut client) = loopback_server_app();
+        let (sender, receiver) = mpsc::channel(8);
+        let mut commands = ChatCommands::default();
+        register_builtin_commands(&mut commands);
+        register_console_commands(&mut commands);
+        app.insert_resource(commands)
+            .insert_resource(ConsoleReceiver(receiver))
+            .add_event::<AppExit>()
+            .add_systems(
+                Update,
+                (handle_client_messages, run_console_commands).chain(),
+            );
+        join_loopback(&mut app, &mut client, "local");
+        app.update();
+        while client.try_receive().is_some() {}
+
+        sender.try_send("say hello".to_string()).unwrap();
+        app.update();
+        assert!(matches!(
+            client.try_receive(),
+            Some(ServerMessage::SystemMessage { message }) if message == "[Server] hello"
+        ));
+        assert!(app.world.resource::<Events<AppExit>>().is_empty());
+
+        // there is no world to save, but stopping still works
+        sender.try_send("save".to_string()).unwrap();
+        sender.try_send("/stop".to_string()).unwrap();
+        app.update();
+        assert!(!app.world.resource::<Events<AppExit>>().is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
+
+use bevy::{
+    prelude::*,
+    tasks::{block_on, AsyncComputeTaskPool, Task},
+};
+use modcraft_lib::{
+    chunk::Chunk,
+    save::{LevelData, SaveError, WorldSave},
+    world::{ChunkPos, VoxelWorld},
+    worldgen::{WorldGenerator, WORLD_HEIGHT_CHUNKS},
+};
+
+// how many chunks may be loading or generating at once
+const MAX_GENERATION_TASKS: usize = 16;
+
+// chunks around the spawn generated when the server starts, in chunks
+const SPAWN_RADIUS: i32 = 2;
+
+// what a generation task came back with
+enum Loaded {
+    Saved(Chunk),
+    Generated(Chunk),
+}
+
+/// Chunks waiting to be loaded or generated and the ones being loaded or
+/// generated on the async compute pool.
+#[derive(Resource, Default)]
+pub(crate) struct ChunkGeneration {
+    queue: VecDeque<ChunkPos>,
+    // everything in the queue, and whether it is kept when nobody wants it
+    queued: HashMap<ChunkPos, bool>,
+    // the tasks, and whether their chunks are kept if they have to be
+    // queued again
+    tasks: HashMap<ChunkPos, (Task<Result<Loaded, SaveError>>, bool)>,
+}
+
+impl ChunkGeneration {
+    pub(crate) fn request(&mut self, pos: ChunkPos) {
+        self.push(pos, false);
+    }
+
+    // spawn chunks are loaded whether or not anyone is around to see them
+    fn request_kept(&mut self, pos: ChunkPos) {
+        self.push(pos, true);
+    }
+
+    fn push(&mut self, pos: ChunkPos, kept: bool) {
+        if self.tasks.contains_key(&pos) {
+            return;
+        }
+        match self.queued.entry(pos) {
+            Entry::Occupied(mut entry) => *entry.get_mut() |= kept,
+            Entry::Vacant(entry) => {
+                entry.insert(kept);
+                self.queue.push_back(pos);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<(ChunkPos, bool)> {
+        let pos = self.queue.pop_front()?;
+        Some((pos, self.queued.remove(&pos)?))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_pending(&self, pos: ChunkPos) -> bool {
+        self.tasks.contains_key(&pos) || self.queued.contains_key(&pos)
+    }
+
+    /// Drops the queued chunks outside `wanted`, so players moving on
+    /// don't leave a trail of chunks to load behind them.
+    pub(crate) fn drop_unwanted(&mut self, wanted: &HashSet<ChunkPos>) {
+        let queued = &mut self.queued;
+        self.queue.retain(|pos| {
+            let keep = queued[pos] || wanted.contains(pos);
+            if !keep {
+                queued.remove(pos);
+            }
+            keep
+        });
+    }
+}
+
+pub(crate) fn request_spawn_chunks(level: Res<LevelData>, mut generation: ResMut<ChunkGeneration>) {
+    let spawn = level.spawn.chunk();
+    for x in -SPAWN_RADIUS..=SPAWN_RADIUS {
+        for z in -SPAWN_RADIUS..=SPAWN_RADIUS {
+            for y in 0..WORLD_HEIGHT_CHUNKS {
+                generation.request_kept(ChunkPos::new(spawn.x + x, y, spawn.z + z));
+            }
+        }
+    }
+}
+
+// reads the chunk if it was saved, and generates it otherwise
+fn load_or_generate(
+    pos: ChunkPos,
+    save: Option<WorldSave>,
+    generator: WorldGenerator,
+) -> Result<Loaded, SaveError> {
+    if let Some(save) = save {
+        match save.load_chunk(pos) {
+            Ok(Some(chunk)) => return Ok(Loaded::Saved(chunk)),
+            Ok(None) => {}
+            Err(e) if e.is_corrupted_chunk() => {
+                warn!("{}, generating it again", e);
+                if let Err(e) = save.quarantine_chunk(pos) {
+                    error!("Failed to set aside corrupted chunk: {}", e);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Loaded::Generated(generator.generate(pos)))
+}
+
+pub(crate) fn start_generation_tasks(
+    generator: Res<WorldGenerator>,
+    save: Option<Res<WorldSave>>,
+    voxel_world: Res<VoxelWorld>,
+    mut generation: ResMut<ChunkGeneration>,
+) {
+    let task_pool = AsyncComputeTaskPool::get();
+    while generation.tasks.len() < MAX_GENERATION_TASKS {
+        let Some((pos, kept)) = generation.pop() else {
+            break;
+        };
+        if voxel_world.chunk(pos).is_some() {
+            continue;
+        }
+        let save = save.as_deref().cloned();
+        let generator = generator.clone();
+        let task = task_pool.spawn(async move { load_or_generate(pos, save, generator) });
+        generation.tasks.insert(pos, (task, kept));
+    }
+}
+
+pub(crate) fn finish_generation_tasks(
+    mut voxel_world: ResMut<VoxelWorld>,
+    mut generation: ResMut<ChunkGeneration>,
+) {
+    let mut failed = Vec::new();
+    generation.tasks.retain(|pos, (task, kept)| {
+        if !task.is_finished() {
+            return true;
+        }
+        match block_on(task) {
+            Ok(Loaded::Saved(chunk)) => {
+                voxel_world.insert_chunk(*pos, chunk);
+            }
+            Ok(Loaded::Generated(chunk)) => {
+                voxel_world.insert_chunk(*pos, chunk);
+                voxel_world.mark_unsaved(*pos);
+            }
+            Err(e) => {
+                // the chunk may well be saved, so generating it would
+                // overwrite it; try again later
+                error!("Failed to load chunk: {}", e);
+                failed.push((*pos, *kept));
+            }
+        }
+        false
+    });
+    for (pos, kept) in failed {
+        generation.push(pos, kept);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::core::TaskPoolPlugin;
+
+    use super::*;
+
+    #[test]
+    fn loads_and_generates_requested_chunks_in_the_background() {
+        let dir = std::env::temp_dir().join("modcraft_generation");
+        let _ = std::fs::remove_dir_all(&dir);
+        let save = WorldSave::open(&dir).unwrap();
+        let saved = ChunkPos::new(-4, 1, 2);
+        save.save_chunk(saved, &Chunk::filled(3)).unwrap();
+
+        let mut app = App::new();
+        app.add_plugins(TaskPoolPlugin::default())
+            .init_resource::<VoxelWorld>()
+            .init_resource::<WorldGenerator>()
+            .init_resource::<ChunkGeneration>()
+            .insert_resource(save)
+            .add_systems(
+                Update,
+                (start_generation_tasks, finish_generation_tasks).chain(),
+            );
+
+        let pos = ChunkPos::new(1, 2, 3);
+        let spawn = ChunkPos::new(0, 0, 0);
+        let passed = ChunkPos::new(9, 0, 9);
+        let mut generation = app.world.resource_mut::<ChunkGeneration>();
+        generation.request(pos);
+        generation.request(saved);
+        generation.request_kept(spawn);
+        generation.request(passed);
+        generation.drop_unwanted(&HashSet::from([pos, saved]));
+        assert!(generation.is_pending(spawn));
+        assert!(!generation.is_pending(passed));
+        for _ in 0..1000 {
+            app.update();
+            let generation = app.world.resource::<ChunkGeneration>();
+            if !generation.is_pending(pos) && !generation.is_pending(saved) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let expected = app.world.resource::<WorldGenerator>().generate(pos);
+        let mut voxel_world = app.world.resource_mut::<VoxelWorld>();
+        assert_eq!(voxel_world.chunk(pos), Some(&expected));
+        assert_eq!(voxel_world.chunk(saved), Some(&Chunk::filled(3)));
+        // only the generated chunk has to be saved
+        assert!(!voxel_world.take_unsaved().contains(&saved));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! The first message a client sends describes its build, so clients that
+//! can't play on a server are turned away with a reason before they join.
+
+use std::fmt;
+
+use modcraft_lib::mods::GAME_VERSION;
+
+use crate::mods::{LoadedMods, ModInfo};
+
+/// Bumped whenever messages change, see the protocol module for how they
+/// can change without breaking older clients.
+pub(crate) const PROTOCOL_VERSION: u32 = 9;
+
+/// The oldest client protocol the server still talks to. Raised when a
+/// change can't be read by older clients, like a message losing a field, or
+/// breaks what they rely on, like version 7 sending chunks apart from the
+/// changes to them.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    ProtocolMismatch {
+        server: u32,
+        client: u32,
+    },
+    GameVersionMismatch {
+        server: String,
+        client: String,
+    },
+    /// Mods the server has that the client is missing or has another
+    /// version of.
+    MissingMods(Vec<ModInfo>),
+    ServerFull {
+        max_players: usize,
+    },
+    /// The join wasn't signed by the key it came with.
+    FailedAuthentication,
+    InvalidName(String),
+    /// Another player's key joined with the name first.
+    NameTaken(String),
+    /// The same key is already playing.
+    AlreadyConnected,
+    /// The player or their address is banned, with the reason if one was
+    /// given.
+    Banned(Option<String>),
+    NotWhitelisted,
+    /// A reason added after this build, by its tag.
+    Unknown(u64),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::ProtocolMismatch { server, client } => write!(
+                f,
+                "Server uses protocol version {}, but this client uses {}",
+                server, client
+            ),
+            RejectReason::GameVersionMismatch { server, client } => write!(
+                f,
+                "Server runs game version {}, but this client is {}",
+                server, client
+            ),
+            RejectReason::MissingMods(mods) => {
+                let mods: Vec<_> = mods
+                    .iter()
+                    .map(|info| format!("{} {}", info.name, info.version))
+                    .collect();
+                write!(f, "Server requires mods {}", mods.join(", "))
+            }
+            RejectReason::ServerFull { max_players } => {
+                write!(f, "Server is full ({} players)", max_players)
+            }
+            RejectReason::FailedAuthentication => {
+                write!(f, "Server could not verify this client's identity")
+            }
+            RejectReason::InvalidName(name) => write!(
+                f,
+                "\"{}\" is not a valid name, names are up to 16 letters, digits and underscores",
+                name
+            ),
+            RejectReason::NameTaken(name) => {
+                write!(f, "The name {} belongs to another player", name)
+            }
+            RejectReason::AlreadyConnected => write!(f, "You are already playing on this server"),
+            RejectReason::Banned(None) => write!(f, "You are banned from this server"),
+            RejectReason::Banned(Some(reason)) => {
+                write!(f, "You are banned from this server: {}", reason)
+            }
+            RejectReason::NotWhitelisted => write!(f, "You are not on this server's whitelist"),
+            RejectReason::Unknown(tag) => write!(
+                f,
+                "Server turned this client away for a reason it doesn't know ({})",
+                tag
+            ),
+        }
+    }
+}
+
+/// Whether a client that sent a hello can join. Clients may be a few
+/// protocol versions behind but not ahead, and may have mods the
+/// server doesn't, since they play with the server's blocks. `players`
+/// counts those waiting in the join queue, which only turns clients away
+/// once it is full too.
+pub(crate) fn check_hello(
+    protocol_version: u32,
+    game_version: &str,
+    mods: &[ModInfo],
+    loaded_mods: &LoadedMods,
+    players: usize,
+    max_players: usize,
+    join_queue: usize,
+) -> Result<(), RejectReason> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        return Err(RejectReason::ProtocolMismatch {
+            server: PROTOCOL_VERSION,
+            client: protocol_version,
+        });
+    }
+    if game_version != GAME_VERSION {
+        return Err(RejectReason::GameVersionMismatch {
+            server: GAME_VERSION.to_string(),
+            client: game_version.to_string(),
+        });
+    }
+    let missing: Vec<ModInfo> = loaded_mods
+        .mods
+        .iter()
+        .filter(|required| !mods.contains(required))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        return Err(RejectReason::MissingMods(missing));
+    }
+    if players >= max_players + join_queue {
+        return Err(RejectReason::ServerFull { max_players });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatched_clients_are_rejected() {
+        let example = ModInfo {
+            name: "example_mod".to_string(),
+            version: "0.1.0".to_string(),
+        };
+        let loaded_mods = LoadedMods {
+            mods: vec![example.clone()],
+        };
+        let (max_players, join_queue) = (2, 1);
+        let installed = [example.clone()];
+        let check = |protocol_version, game_version, mods: &[ModInfo], players| {
+            check_hello(
+                protocol_version,
+                game_version,
+                mods,
+                &loaded_mods,
+                players,
+                max_players,
+                join_queue,
+            )
+        };
+
+        assert_eq!(check(PROTOCOL_VERSION, GAME_VERSION, &installed, 1), Ok(()));
+        assert_eq!(
+            check(PROTOCOL_VERSION + 1, GAME_VERSION, &installed, 0),
+            Err(RejectReason::ProtocolMismatch {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION + 1,
+            })
+        );
+        assert_eq!(
+            check(MIN_PROTOCOL_VERSION, GAME_VERSION, &installed, 0),
+            Ok(())
+        );
+        assert!(matches!(
+            check(MIN_PROTOCOL_VERSION - 1, GAME_VERSION, &installed, 0),
+            Err(RejectReason::ProtocolMismatch { .. })
+        ));
+        assert!(matches!(
+            check(PROTOCOL_VERSION, "0.0.0-old", &installed, 0),
+            Err(RejectReason::GameVersionMismatch { .. })
+        ));
+
+        let outdated = ModInfo {
+            version: "0.0.1".to_string(),
+            ..example.clone()
+        };
+        let rejection = check(PROTOCOL_VERSION, GAME_VERSION, &[outdated], 0).unwrap_err();
+        assert_eq!(rejection, RejectReason::MissingMods(vec![example.clone()]));
+        assert_eq!(
+            rejection.to_string(),
+            "Server requires mods example_mod 0.1.0"
+        );
+
+        assert_eq!(check(PROTOCOL_VERSION, GAME_VERSION, &installed, 2), Ok(()));
+        assert_eq!(
+            check(PROTOCOL_VERSION, GAME_VERSION, &installed, 3),
+            Err(RejectReason::ServerFull { max_players: 2 })
+        );
+    }
+}
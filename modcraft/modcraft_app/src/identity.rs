@@ -0,0 +1,176 @@
+//! The client's side of authentication: the name it plays as and the key it
+//! signs the server's challenge with, kept in a file next to the game.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use rand::{distributions::Alphanumeric, Rng};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{check_name, signed_message, to_hex, write_private, Challenge, PublicKey},
+    handshake::RejectReason,
+    protocol::ClientMessage,
+};
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Debug)]
+pub(crate) enum IdentityError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidKey(PathBuf),
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            IdentityError::Parse(path, e) => {
+                write!(f, "Could not parse {}: {}", path.display(), e)
+            }
+            IdentityError::InvalidKey(path) => {
+                write!(f, "The key in {} is not a valid key", path.display())
+            }
+        }
+    }
+}
+
+// the key is stored as PKCS#8 in hex
+#[derive(Serialize, Deserialize)]
+struct IdentityFile {
+    name: String,
+    key: String,
+}
+
+/// The client's name and the key it proves who it is with.
+#[derive(Resource)]
+pub(crate) struct Identity {
+    name: String,
+    pkcs8: Vec<u8>,
+    key_pair: Ed25519KeyPair,
+}
+
+impl Identity {
+    pub(crate) fn generate(name: &str) -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .expect("The system can generate random keys")
+            .as_ref()
+            .to_vec();
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).expect("Generated keys are valid");
+        Identity {
+            name: name.to_string(),
+            pkcs8,
+            key_pair,
+        }
+    }
+
+    /// Loads the identity at `path`, or creates one with a random name the
+    /// first time the game runs.
+    pub(crate) fn load_or_create(path: &Path) -> Result<Self, IdentityError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let name: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(7)
+                    .map(char::from)
+                    .collect();
+                let identity = Identity::generate(&name);
+                identity.save(path)?;
+                info!("Created a new identity in {}", path.display());
+                return Ok(identity);
+            }
+            Err(e) => return Err(IdentityError::Io(path.to_path_buf(), e)),
+        };
+        let file: IdentityFile =
+            toml::from_str(&text).map_err(|e| IdentityError::Parse(path.to_path_buf(), e))?;
+        let pkcs8 = from_hex(&file.key).ok_or_else(|| IdentityError::InvalidKey(path.into()))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|_| IdentityError::InvalidKey(path.into()))?;
+        Ok(Identity {
+            name: file.name,
+            pkcs8,
+            key_pair,
+        })
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<(), IdentityError> {
+        let file = IdentityFile {
+            name: self.name.clone(),
+            key: to_hex(&self.pkcs8),
+        };
+        let text = toml::to_string(&file).expect("Identities are always valid toml");
+        write_private(path, text).map_err(|e| IdentityError::Io(path.to_path_buf(), e))
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn set_name(&mut self, name: &str) -> Result<(), RejectReason> {
+        check_name(name)?;
+        self.name = name.to_string();
+        Ok(())
+    }
+
+    pub(crate) fn public_key(&self) -> PublicKey {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.key_pair.public_key().as_ref());
+        PublicKey(key)
+    }
+
+    /// The join message answering the server's challenge.
+    pub(crate) fn join(&self, challenge: &Challenge) -> ClientMessage {
+        ClientMessage::Join {
+            name: self.name.clone(),
+            public_key: self.public_key(),
+            signature: self
+                .key_pair
+                .sign(&signed_message(challenge))
+                .as_ref()
+                .to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities_are_kept_between_runs() {
+        let path = std::env::temp_dir().join("modcraft_identity.toml");
+        let _ = fs::remove_file(&path);
+        let mut created = Identity::load_or_create(&path).unwrap();
+        let loaded = Identity::load_or_create(&path).unwrap();
+        assert_eq!(loaded.name(), created.name());
+        assert_eq!(loaded.public_key(), created.public_key());
+        assert_eq!(
+            created.set_name("no spaces"),
+            Err(RejectReason::InvalidName("no spaces".to_string()))
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! Breaking and placing blocks with the mouse.
+//!
+//! Holding the left button on a block breaks it once its hardness in
+//! seconds has passed, the right button places the selected block against
+//! the face being looked at and the number keys pick which block that is.
+//! Changes are made to the client's world straight away and sent to the
+//! server, which undoes them if it disagrees.
+
+use bevy::{
+    prelude::*,
+    window::{CursorGrabMode, PrimaryWindow},
+};
+use modcraft_lib::{
+    blocks::{BlockId, AIR},
+    physics::{is_solid, raycast, RayHit, REACH},
+    world::BlockPos,
+};
+
+use crate::{
+    client::{set_blocks, ChunkUpdated, ClientState, ServerBlocks, ServerWorld},
+    prediction::{LocalPlayer, MovementInput},
+    protocol::ClientMessage,
+    render::read_movement_input,
+    transport::client::ClientTransport,
+};
+
+const BLOCK_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
+
+/// Which of the placeable blocks is placed, counting from 0.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+struct SelectedBlock(usize);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Mining {
+    target: Option<BlockPos>,
+    seconds: f32,
+}
+
+// blocks that are drawn and can be broken again, in id order
+fn placeable_blocks(server_blocks: &ServerBlocks) -> Vec<BlockId> {
+    (0..server_blocks.len() as BlockId)
+        .filter(|id| {
+            server_blocks.properties(*id).is_some_and(|properties| {
+                properties.textures.is_some() && properties.is_breakable()
+            })
+        })
+        .collect()
+}
+
+fn select_block(keys: Res<Input<KeyCode>>, mut selected_block: ResMut<SelectedBlock>) {
+    if let Some(index) = BLOCK_KEYS.iter().position(|key| keys.just_pressed(*key)) {
+        selected_block.0 = index;
+    }
+}
+
+fn send_block_action(transport: &ClientTransport, message: ClientMessage) {
+    if let Err(e) = transport.send(message) {
+        warn!("Failed to send block action to the server: {}", e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn break_and_place_blocks(
+    time: Res<Time>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    local_player: Res<LocalPlayer>,
+    movement_input: Res<MovementInput>,
+    selected_block: Res<SelectedBlock>,
+    server_blocks: Res<ServerBlocks>,
+    mut server_world: ResMut<ServerWorld>,
+    mut chunk_updated_events: EventWriter<ChunkUpdated>,
+    transport: ClientTransport,
+    mut mining: Local<Mining>,
+) {
+    let grabbed = windows
+        .get_single()
+        .is_ok_and(|window| window.cursor.grab_mode != CursorGrabMode::None);
+    let Some(body) = local_player.body.filter(|_| grabbed) else {
+        *mining = Mining::default();
+        return;
+    };
+    let target = raycast(
+        body.eye_position(),
+        movement_input.look_direction(),
+        REACH,
+        |pos| is_solid(&server_world, &server_blocks, pos),
+    );
+
+    match target {
+        Some(RayHit { block: pos, .. }) if mouse_buttons.pressed(MouseButton::Left) => {
+            if mining.target != Some(pos) {
+                *mining = Mining {
+                    target: Some(pos),
+                    seconds: 0.0,
+                };
+            }
+            mining.seconds += time.delta_seconds();
+            let hardness = server_world
+                .get_block(pos)
+                .and_then(|block| server_blocks.properties(block))
+                .filter(|properties| properties.is_breakable())
+                .map(|properties| properties.hardness);
+            if hardness.is_some_and(|hardness| mining.seconds >= hardness) {
+                send_block_action(&transport, ClientMessage::BreakBlock { pos });
+                set_blocks(&mut server_world, &mut chunk_updated_events, [(pos, AIR)]);
+                *mining = Mining::default();
+            }
+        }
+        _ => *mining = Mining::default(),
+    }
+
+    if let Some(RayHit {
+        block,
+        face: Some(face),
+    }) = target
+    {
+        if !mouse_buttons.just_pressed(MouseButton::Right) {
+            return;
+        }
+        let placeable = placeable_blocks(&server_blocks);
+        let Some(&block_id) = placeable.get(selected_block.0) else {
+            return;
+        };
+        let pos = block.neighbor(face);
+        if body.intersects(pos) || server_world.get_block(pos) != Some(AIR) {
+            return;
+        }
+        send_block_action(
+            &transport,
+            ClientMessage::PlaceBlock {
+                pos,
+                block: block_id,
+            },
+        );
+        set_blocks(
+            &mut server_world,
+            &mut chunk_updated_events,
+            [(pos, block_id)],
+        );
+    }
+}
+
+pub(crate) struct InteractionPlugin;
+
+impl Plugin for InteractionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SelectedBlock>();
+        app.add_systems(
+            Update,
+            (select_block, break_and_place_blocks)
+                .after(read_movement_input)
+                .run_if(in_state(ClientState::InGame).and_then(resource_exists::<ServerBlocks>())),
+        );
+    }
+}
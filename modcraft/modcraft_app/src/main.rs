@@ -1,10 +1,41 @@
 use bevy::prelude::App;
 
+mod auth;
+mod bans;
+mod block_actions;
+mod commands;
+#[cfg(any(test, feature = "dedicated-server"))]
+mod console;
+mod generation;
+mod handshake;
+#[cfg(any(test, not(feature = "dedicated-server")))]
+mod identity;
+mod mods;
+mod persistence;
+mod players;
 mod protocol;
+mod rate_limits;
+mod replication;
 mod server;
+mod streaming;
+mod ticks;
+mod transport;
+mod wire;
 
+#[cfg(not(feature = "dedicated-server"))]
+mod chunk_loads;
 #[cfg(not(feature = "dedicated-server"))]
 mod client;
+#[cfg(all(feature = "render", not(feature = "dedicated-server")))]
+mod interaction;
+#[cfg(not(feature = "dedicated-server"))]
+mod prediction;
+#[cfg(not(feature = "dedicated-server"))]
+mod remote_entities;
+#[cfg(all(feature = "render", not(feature = "dedicated-server")))]
+mod render;
+#[cfg(not(feature = "dedicated-server"))]
+mod shutdown;
 
 use modcraft_lib::add;
 
@@ -20,7 +51,7 @@ fn main() {
     let mut app = App::new();
 
     #[cfg(feature = "dedicated-server")]
-    app.add_plugins(server::DedicatedServerPlugin);
+    app.add_plugins(server::dedicated::DedicatedServerPlugin);
 
     #[cfg(not(feature = "dedicated-server"))]
     app.add_plugins(client::ClientPlugin);
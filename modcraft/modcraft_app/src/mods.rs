@@ -0,0 +1,296 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use libloading::{Library, Symbol};
+use modcraft_lib::mods::{ModDeclaration, ABI_VERSION, DECLARATION_SYMBOL, GAME_VERSION};
+
+pub(crate) const MODS_DIR: &str = "mods";
+
+/// Where the server plugins look for mods, defaults to [`MODS_DIR`].
+#[derive(Resource, Debug, Clone)]
+pub(crate) struct ModsDir(pub(crate) PathBuf);
+
+impl Default for ModsDir {
+    fn default() -> Self {
+        ModsDir(PathBuf::from(MODS_DIR))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ModInfo {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct LoadedMods {
+    pub(crate) mods: Vec<ModInfo>,
+}
+
+#[derive(Debug)]
+pub(crate) enum ModLoadError {
+    ReadDir(PathBuf, io::Error),
+    Open(PathBuf, libloading::Error),
+    MissingDeclaration(PathBuf, libloading::Error),
+    AbiMismatch {
+        path: PathBuf,
+        found: u32,
+    },
+    GameVersionMismatch {
+        path: PathBuf,
+        name: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for ModLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModLoadError::ReadDir(path, e) => {
+                write!(f, "Could not read mods directory {}: {}", path.display(), e)
+            }
+            ModLoadError::Open(path, e) => {
+                write!(f, "Could not open mod {}: {}", path.display(), e)
+            }
+            ModLoadError::MissingDeclaration(path, e) => write!(
+                f,
+                "{} is not a mod, it does not use declare_mod!: {}",
+                path.display(),
+                e
+            ),
+            ModLoadError::AbiMismatch { path, found } => write!(
+                f,
+                "{} was built for mod ABI {} but the game uses ABI {}",
+                path.display(),
+                found,
+                ABI_VERSION
+            ),
+            ModLoadError::GameVersionMismatch { path, name, found } => write!(
+                f,
+                "{} ({}) was built for game version {} but this is version {}",
+                name,
+                path.display(),
+                found,
+                GAME_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ModLoadError {}
+
+fn is_library(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == std::env::consts::DLL_EXTENSION)
+}
+
+fn open_mod(path: &Path) -> Result<(Library, &'static ModDeclaration), ModLoadError> {
+    // SAFETY: loading a library runs its initializers, which we have to trust
+    // for any mod the player put in the mods directory
+    let library =
+        unsafe { Library::new(path) }.map_err(|e| ModLoadError::Open(path.to_path_buf(), e))?;
+
+    let declaration: *const ModDeclaration = unsafe {
+        let symbol: Symbol<*const ModDeclaration> = library
+            .get(DECLARATION_SYMBOL)
+            .map_err(|e| ModLoadError::MissingDeclaration(path.to_path_buf(), e))?;
+        *symbol
+    };
+
+    // SAFETY: abi_version is the first field of a repr(C) struct, so it can be
+    // read no matter which ABI the mod was built with
+    let found = unsafe { std::ptr::addr_of!((*declaration).abi_version).read() };
+    if found != ABI_VERSION {
+        return Err(ModLoadError::AbiMismatch {
+            path: path.to_path_buf(),
+            found,
+        });
+    }
+
+    // SAFETY: the ABI matches and the library is never unloaded (see load_mods)
+    let declaration = unsafe { &*declaration };
+    if declaration.game_version != GAME_VERSION {
+        return Err(ModLoadError::GameVersionMismatch {
+            path: path.to_path_buf(),
+            name: declaration.name.to_string(),
+            found: declaration.game_version.to_string(),
+        });
+    }
+
+    Ok((library, declaration))
+}
+
+fn mod_paths(dir: &Path) -> Result<Vec<PathBuf>, ModLoadError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(ModLoadError::ReadDir(dir.to_path_buf(), e)),
+    };
+
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_library(path))
+        .collect::<Vec<_>>();
+    // load in a stable order so mods see the same registration order every run
+    paths.sort();
+    Ok(paths)
+}
+
+/// Loads every mod in the [`ModsDir`] and lets it register itself with
+/// `app`. Mods that fail to load are logged and skipped.
+pub(crate) fn load_mods(app: &mut App) {
+    let mut loaded_mods = LoadedMods::default();
+
+    let dir = app
+        .world
+        .get_resource_or_insert_with(ModsDir::default)
+        .0
+        .clone();
+    let paths = mod_paths(&dir).unwrap_or_else(|e| {
+        error!("{}", e);
+        Vec::new()
+    });
+    for path in paths {
+        match open_mod(&path) {
+            Ok((library, declaration)) => {
+                info!("Loading mod {} {}", declaration.name, declaration.version);
+                (declaration.create)().register(app);
+                loaded_mods.mods.push(ModInfo {
+                    name: declaration.name.to_string(),
+                    version: declaration.version.to_string(),
+                });
+                // code from the mod can end up anywhere in the app, so the
+                // library has to stay loaded until the process exits
+                std::mem::forget(library);
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    if !loaded_mods.mods.is_empty() {
+        info!(
+            "Loaded mods: {}",
+            loaded_mods
+                .mods
+                .iter()
+                .map(|info| format!("{} {}", info.name, info.version))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    app.insert_resource(loaded_mods);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use modcraft_lib::{
+        blocks::BlockRegistry,
+        commands::{run_command, CommandContext, CommandSender, PermissionLevel},
+        config::ServerConfig,
+        worldgen::WorldGenerator,
+    };
+
+    use super::*;
+    use crate::server::dedicated::DedicatedServerPlugin;
+
+    #[test]
+    fn missing_mods_dir_is_empty() {
+        let dir = std::env::temp_dir().join("modcraft_missing_mods_dir");
+        assert!(mod_paths(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_library_is_reported() {
+        let dir = std::env::temp_dir().join("modcraft_invalid_mod");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("broken.{}", std::env::consts::DLL_EXTENSION));
+        fs::write(&path, b"not a library").unwrap();
+
+        assert_eq!(mod_paths(&dir).unwrap(), vec![path.clone()]);
+        assert!(matches!(open_mod(&path), Err(ModLoadError::Open(..))));
+
+        let mut app = App::new();
+        app.insert_resource(ModsDir(dir.clone()));
+        load_mods(&mut app);
+        assert!(app.world.resource::<LoadedMods>().mods.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn example_mod_loads_into_dedicated_server() {
+        // build the mod together with the game so they share the same bevy
+        let mut cargo = Command::new(env!("CARGO"));
+        cargo
+            .args(["build", "-p", "example_mod", "-p", "modcraft_app"])
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/.."));
+        // variables cargo sets for this test would make build scripts rerun
+        for (key, _) in std::env::vars() {
+            if key.starts_with("CARGO_PKG_")
+                || key.starts_with("CARGO_MANIFEST_")
+                || key.starts_with("CARGO_BIN_")
+                || key == "CARGO_CRATE_NAME"
+                || key == "CARGO_PRIMARY_PACKAGE"
+            {
+                cargo.env_remove(key);
+            }
+        }
+        let status = cargo.status().expect("Failed to run cargo");
+        assert!(status.success(), "Failed to build example_mod");
+
+        let file_name = format!(
+            "{}example_mod.{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_EXTENSION
+        );
+        // test binaries live in target/<profile>/deps
+        let library = std::env::current_exe()
+            .unwrap()
+            .parent()
+            .and_then(Path::parent)
+            .unwrap()
+            .join(&file_name);
+        let dir = std::env::temp_dir().join("modcraft_example_mod");
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy(library, dir.join(&file_name)).unwrap();
+
+        let mut app = App::new();
+        app.insert_resource(ModsDir(dir.clone()))
+            .init_resource::<ServerConfig>()
+            .add_plugins(DedicatedServerPlugin);
+
+        let loaded_mods = &app.world.resource::<LoadedMods>().mods;
+        assert_eq!(loaded_mods.len(), 1);
+        assert_eq!(loaded_mods[0].name, "example_mod");
+        assert!(app
+            .world
+            .resource::<BlockRegistry>()
+            .id("example_mod:ore")
+            .is_some());
+        assert!(app
+            .world
+            .resource::<WorldGenerator>()
+            .stages()
+            .any(|stage| stage.name() == "example_mod:ore"));
+
+        for _ in 0..3 {
+            app.world.run_schedule(FixedUpdate);
+        }
+        let context = CommandContext {
+            sender: CommandSender::Console,
+            level: PermissionLevel::Console,
+            players: Vec::new(),
+        };
+        assert_eq!(
+            run_command(&mut app.world, &context, "/ticks"),
+            Ok("example_mod has seen 3 ticks".to_string())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
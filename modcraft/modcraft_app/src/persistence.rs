@@ -0,0 +1,327 @@
+//! Opens the server's world directory when it starts and saves the world
+//! back to it every few minutes and when it stops, along with the players
+//! that have joined it and where they are.
+
+use std::{path::PathBuf, time::Duration};
+
+use bevy::prelude::*;
+use modcraft_lib::{
+    config::DEFAULT_WORLD_DIR,
+    save::{LevelData, LevelMod, SaveError, WorldSave},
+    world::{BlockPos, VoxelWorld},
+    worldgen::{terrain_height, WorldGenerator},
+};
+
+use crate::{
+    auth::KnownPlayers,
+    bans::{Bans, Whitelist},
+    mods::LoadedMods,
+    players::Player,
+};
+
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Where the server keeps its world, defaults to [`DEFAULT_WORLD_DIR`].
+#[derive(Resource, Debug, Clone)]
+pub(crate) struct WorldDir(pub(crate) PathBuf);
+
+impl Default for WorldDir {
+    fn default() -> Self {
+        WorldDir(PathBuf::from(DEFAULT_WORLD_DIR))
+    }
+}
+
+/// The seed a new world is created with, random if None. Existing worlds
+/// keep theirs.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub(crate) struct NewWorldSeed(pub(crate) Option<u64>);
+
+/// Ticks the world has run for, including previous sessions.
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub(crate) struct GameTime(pub(crate) u64);
+
+#[derive(Resource, Debug, Clone)]
+pub(crate) struct AutosaveTimer(pub(crate) Timer);
+
+impl Default for AutosaveTimer {
+    fn default() -> Self {
+        AutosaveTimer(Timer::new(AUTOSAVE_INTERVAL, TimerMode::Repeating))
+    }
+}
+
+fn level_mods(loaded_mods: &LoadedMods) -> Vec<LevelMod> {
+    loaded_mods
+        .mods
+        .iter()
+        .map(|info| LevelMod {
+            name: info.name.clone(),
+            version: info.version.clone(),
+        })
+        .collect()
+}
+
+fn new_level(loaded_mods: &LoadedMods, seed: Option<u64>) -> LevelData {
+    let seed = seed.unwrap_or_else(rand::random);
+    LevelData {
+        seed,
+        spawn: BlockPos::new(0, terrain_height(seed, 0, 0) + 1, 0),
+        game_time: 0,
+        mods: level_mods(loaded_mods),
+    }
+}
+
+fn warn_about_changed_mods(level: &LevelData, loaded_mods: &LoadedMods) {
+    let loaded = level_mods(loaded_mods);
+    for saved in &level.mods {
+        match loaded.iter().find(|loaded| loaded.name == saved.name) {
+            None => warn!(
+                "World was saved with mod {} {}, which is not loaded",
+                saved.name, saved.version
+            ),
+            Some(loaded) if loaded.version != saved.version => warn!(
+                "World was saved with mod {} {}, but {} is loaded",
+                saved.name, saved.version, loaded.version
+            ),
+            Some(_) => {}
+        }
+    }
+}
+
+// a file that can't be read is left alone by not saving the world at all
+fn load_or_stop_saving<T: Default>(
+    save: &mut Option<WorldSave>,
+    load: impl Fn(&WorldSave) -> Result<T, SaveError>,
+) -> T {
+    match save.as_ref().map(load) {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => {
+            error!("{}, the world will not be saved", e);
+            *save = None;
+            T::default()
+        }
+        None => T::default(),
+    }
+}
+
+pub(crate) fn open_world(
+    mut commands: Commands,
+    world_dir: Res<WorldDir>,
+    new_world_seed: Res<NewWorldSeed>,
+    loaded_mods: Res<LoadedMods>,
+    mut generator: ResMut<WorldGenerator>,
+    mut game_time: ResMut<GameTime>,
+) {
+    let mut save = match WorldSave::open(&world_dir.0) {
+        Ok(save) => Some(save),
+        Err(e) => {
+            error!("{}, the world will not be saved", e);
+            None
+        }
+    };
+
+    let saved_level = match save.as_ref().map(WorldSave::load_level) {
+        Some(Ok(level)) => level,
+        Some(Err(e)) => {
+            // saving would replace the level and leave the chunks with a
+            // different seed, so leave everything on disk alone
+            error!("{}, the world will not be saved", e);
+            save = None;
+            None
+        }
+        None => None,
+    };
+    // saving would forget everyone's keys and names, bans or whitelist
+    let players = load_or_stop_saving(&mut save, WorldSave::load_players);
+    let bans = load_or_stop_saving(&mut save, WorldSave::load_bans);
+    let whitelist = load_or_stop_saving(&mut save, WorldSave::load_whitelist);
+    let level = match saved_level {
+        Some(level) => {
+            info!("Loading world from {}", world_dir.0.display());
+            warn_about_changed_mods(&level, &loaded_mods);
+            if new_world_seed.0.is_some_and(|seed| seed != level.seed) {
+                warn!("Ignoring the configured seed, the world already has one");
+            }
+            level
+        }
+        None => {
+            info!("Creating a new world in {}", world_dir.0.display());
+            new_level(&loaded_mods, new_world_seed.0)
+        }
+    };
+
+    info!("World seed is {}", level.seed);
+    generator.set_seed(level.seed);
+    game_time.0 = level.game_time;
+    commands.insert_resource(level);
+    commands.insert_resource(KnownPlayers(players));
+    commands.insert_resource(Bans(bans));
+    commands.insert_resource(Whitelist(whitelist));
+    if let Some(save) = save {
+        commands.insert_resource(save);
+    }
+}
+
+pub(crate) fn advance_game_time(mut game_time: ResMut<GameTime>) {
+    game_time.0 += 1;
+}
+
+/// Saves the world along with the players that have joined it.
+pub(crate) fn save_world(
+    save: &WorldSave,
+    voxel_world: &mut VoxelWorld,
+    level: &LevelData,
+    game_time: GameTime,
+    loaded_mods: &LoadedMods,
+    known_players: &KnownPlayers,
+) {
+    let mut saved = 0;
+    for pos in voxel_world.take_unsaved() {
+        let chunk = voxel_world.chunk(pos).expect("Unsaved chunks are loaded");
+        match save.save_chunk(pos, chunk) {
+            Ok(()) => saved += 1,
+            Err(e) => {
+                error!("Failed to save chunk: {}", e);
+                voxel_world.mark_unsaved(pos);
+            }
+        }
+    }
+
+    let level = LevelData {
+        game_time: game_time.0,
+        mods: level_mods(loaded_mods),
+        ..level.clone()
+    };
+    if let Err(e) = save.save_players(&known_players.0) {
+        error!("Failed to save players: {}", e);
+    }
+    if let Err(e) = save.save_level(&level).and_then(|_| save.flush()) {
+        error!("Failed to save world: {}", e);
+    }
+    info!("Saved {} chunks to {}", saved, save.dir().display());
+}
+
+/// Players still in the game are saved where they are now.
+pub(crate) fn remember_positions(known_players: &mut KnownPlayers, players: &Query<&Player>) {
+    for player in players {
+        known_players.set_position(player.uuid, player.body.position);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn autosave(
+    time: Res<Time>,
+    mut timer: ResMut<AutosaveTimer>,
+    save: Res<WorldSave>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    level: Res<LevelData>,
+    game_time: Res<GameTime>,
+    loaded_mods: Res<LoadedMods>,
+    mut known_players: ResMut<KnownPlayers>,
+    players: Query<&Player>,
+) {
+    if timer.0.tick(time.delta()).just_finished() {
+        remember_positions(&mut known_players, &players);
+        save_world(
+            &save,
+            &mut voxel_world,
+            &level,
+            *game_time,
+            &loaded_mods,
+            &known_players,
+        );
+    }
+}
+
+/// Saves everything and closes the world, so it is safe to clear it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn save_world_on_exit(
+    mut commands: Commands,
+    save: Option<Res<WorldSave>>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    level: Option<Res<LevelData>>,
+    game_time: Res<GameTime>,
+    loaded_mods: Res<LoadedMods>,
+    mut known_players: ResMut<KnownPlayers>,
+    players: Query<&Player>,
+) {
+    if let (Some(save), Some(level)) = (save, level) {
+        remember_positions(&mut known_players, &players);
+        save_world(
+            &save,
+            &mut voxel_world,
+            &level,
+            *game_time,
+            &loaded_mods,
+            &known_players,
+        );
+    }
+    commands.remove_resource::<WorldSave>();
+    commands.remove_resource::<LevelData>();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use bevy::{ecs::system::RunSystemOnce, utils::Uuid};
+    use modcraft_lib::{
+        blocks::BlockRegistry, physics::PlayerBody, save::PlayerRecord, world::ChunkPos,
+    };
+
+    use super::*;
+
+    fn world_app(dir: &Path) -> App {
+        let mut app = App::new();
+        app.insert_resource(WorldDir(dir.to_path_buf()))
+            .init_resource::<NewWorldSeed>()
+            .init_resource::<LoadedMods>()
+            .init_resource::<BlockRegistry>()
+            .init_resource::<WorldGenerator>()
+            .init_resource::<VoxelWorld>()
+            .init_resource::<GameTime>()
+            .add_systems(Startup, open_world);
+        app.update();
+        app
+    }
+
+    #[test]
+    fn world_survives_a_restart() {
+        let dir = std::env::temp_dir().join("modcraft_world_restart");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut app = world_app(&dir);
+        let seed = app.world.resource::<WorldGenerator>().seed();
+        app.world.resource_mut::<GameTime>().0 = 500;
+        app.world
+            .resource_mut::<VoxelWorld>()
+            .set_block(BlockPos::new(3, -20, 5), 2);
+        // players still in the game are saved where they are
+        let uuid = Uuid::new_v4();
+        app.world
+            .resource_mut::<KnownPlayers>()
+            .0
+            .push(PlayerRecord {
+                uuid,
+                public_key: "ab".repeat(32),
+                name: "Steve".to_string(),
+                position: None,
+                operator: false,
+            });
+        let position = Vec3::new(4.5, 30.0, -1.5);
+        app.world
+            .spawn(Player::new(1, uuid, PlayerBody::new(position)));
+        app.world.run_system_once(save_world_on_exit);
+        assert!(!app.world.contains_resource::<WorldSave>());
+
+        let app = world_app(&dir);
+        assert_eq!(app.world.resource::<WorldGenerator>().seed(), seed);
+        assert_eq!(app.world.resource::<GameTime>().0, 500);
+        let known_players = app.world.resource::<KnownPlayers>();
+        assert_eq!(known_players.get(uuid).unwrap().position, Some(position));
+        let save = WorldSave::open(&dir).unwrap();
+        let chunk = save.load_chunk(ChunkPos::new(0, -2, 0)).unwrap().unwrap();
+        assert_eq!(chunk.get(3, 12, 5), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
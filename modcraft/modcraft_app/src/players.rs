@@ -0,0 +1,264 @@
+//! Players in the world, moved by the inputs their clients send.
+//!
+//! Clients send one input per tick and predict the result themselves. The
+//! server applies the inputs in order and answers with where the player
+//! really is and the last input it applied, so clients can replay the rest.
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{prelude::*, utils::Uuid};
+use bevy_quinnet::shared::ClientId;
+use modcraft_lib::{
+    blocks::BlockRegistry,
+    physics::{is_solid, step, PlayerBody, PlayerInput},
+    replication::{PlayerName, Replicated},
+    save::LevelData,
+    world::VoxelWorld,
+};
+
+use crate::{
+    auth::KnownPlayers, block_actions::BlockAction, protocol::ServerMessage, server::Users,
+    transport::ServerTransport,
+};
+
+// each tick lets a player apply one more input, and a few can be saved up so
+// a client can catch up after a lag spike, but never move faster than one
+// input a tick for long
+const MAX_INPUT_CREDIT: u32 = 3;
+// inputs beyond this are dropped instead of queued
+const MAX_QUEUED_INPUTS: usize = 64;
+const MAX_QUEUED_BLOCK_ACTIONS: usize = 16;
+
+#[derive(Component, Debug, Clone)]
+pub(crate) struct Player {
+    pub(crate) client_id: ClientId,
+    pub(crate) uuid: Uuid,
+    pub(crate) body: PlayerBody,
+    inputs: VecDeque<(u32, PlayerInput)>,
+    // inputs the player may still apply, see MAX_INPUT_CREDIT
+    input_credit: u32,
+    // the last input applied, sent back so the client knows what to replay
+    last_sequence: u32,
+    pub(crate) block_actions: Vec<BlockAction>,
+    /// When the player last broke a block, in fixed time.
+    pub(crate) last_break: Option<Duration>,
+}
+
+impl Player {
+    pub(crate) fn new(client_id: ClientId, uuid: Uuid, body: PlayerBody) -> Self {
+        Player {
+            client_id,
+            uuid,
+            body,
+            inputs: VecDeque::new(),
+            input_credit: 0,
+            last_sequence: 0,
+            block_actions: Vec::new(),
+            last_break: None,
+        }
+    }
+
+    pub(crate) fn queue_input(&mut self, sequence: u32, input: PlayerInput) {
+        let newest = self
+            .inputs
+            .back()
+            .map_or(self.last_sequence, |(last, _)| *last);
+        if sequence <= newest || self.inputs.len() == MAX_QUEUED_INPUTS {
+            return;
+        }
+        self.inputs.push_back((sequence, input));
+    }
+
+    pub(crate) fn queue_block_action(&mut self, action: BlockAction) {
+        if self.block_actions.len() < MAX_QUEUED_BLOCK_ACTIONS {
+            self.block_actions.push(action);
+        }
+    }
+}
+
+fn spawn_body(level: Option<&LevelData>) -> PlayerBody {
+    let spawn = level.map_or(Vec3::ZERO, |level| {
+        Vec3::new(
+            level.spawn.x as f32,
+            level.spawn.y as f32,
+            level.spawn.z as f32,
+        )
+    });
+    // the middle of the spawn block
+    PlayerBody::new(spawn + Vec3::new(0.5, 0.0, 0.5))
+}
+
+/// Spawns a player for every user that joined, where they last left, and
+/// despawns the ones that left. Players are replicated to the others with
+/// their name and where they are.
+pub(crate) fn sync_players(
+    mut commands: Commands,
+    users: Res<Users>,
+    mut known_players: ResMut<KnownPlayers>,
+    level: Option<Res<LevelData>>,
+    players: Query<(Entity, &Player)>,
+) {
+    for (entity, player) in &players {
+        if !users.contains(player.client_id) {
+            known_players.set_position(player.uuid, player.body.position);
+            commands.entity(entity).despawn();
+        }
+    }
+    for client_id in users.ids() {
+        if players
+            .iter()
+            .any(|(_, player)| player.client_id == client_id)
+        {
+            continue;
+        }
+        let (Some(uuid), Some(name)) = (users.uuid(client_id), users.name(client_id)) else {
+            continue;
+        };
+        let body = match known_players.get(uuid).and_then(|record| record.position) {
+            Some(position) => PlayerBody::new(position),
+            None => spawn_body(level.as_deref()),
+        };
+        commands.spawn((
+            Player::new(client_id, uuid, body),
+            Replicated,
+            PlayerName(name.to_string()),
+            Transform::from_translation(body.position),
+        ));
+    }
+}
+
+pub(crate) fn move_players(
+    time: Res<Time<Fixed>>,
+    voxel_world: Res<VoxelWorld>,
+    block_registry: Res<BlockRegistry>,
+    mut players: Query<&mut Player>,
+) {
+    let dt = time.timestep().as_secs_f32();
+    for mut player in &mut players {
+        // only players that move count as changed, so unchanged ones aren't
+        // sent again
+        let credit = &mut player.bypass_change_detection().input_credit;
+        *credit = (*credit + 1).min(MAX_INPUT_CREDIT);
+        if player.inputs.is_empty() {
+            continue;
+        }
+        while player.input_credit > 0 {
+            let Some((sequence, input)) = player.inputs.pop_front() else {
+                break;
+            };
+            player.body = step(&player.body, &input, dt, |pos| {
+                is_solid(&voxel_world, &block_registry, pos)
+            });
+            player.last_sequence = sequence;
+            player.input_credit -= 1;
+        }
+    }
+}
+
+pub(crate) fn send_player_states(
+    transport: ServerTransport,
+    players: Query<&Player, Changed<Player>>,
+) {
+    for player in &players {
+        let message = ServerMessage::PlayerState {
+            sequence: player.last_sequence,
+            body: player.body,
+        };
+        if let Err(e) = transport.send(player.client_id, message) {
+            warn!("Failed to send player state to {}: {}", player.client_id, e);
+        }
+    }
+}
+
+// moved players are replicated from their transform, teleports included
+pub(crate) fn sync_player_transforms(
+    mut players: Query<(&Player, &mut Transform), Changed<Player>>,
+) {
+    for (player, mut transform) in &mut players {
+        transform.translation = player.body.position;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use modcraft_lib::{chunk::Chunk, world::ChunkPos};
+
+    use super::*;
+    use crate::{
+        protocol::ClientMessage,
+        server::{handle_client_messages, join_loopback, loopback_server_app},
+        transport::loopback::LoopbackClient,
+    };
+
+    fn player_states(client: &mut LoopbackClient) -> Vec<(u32, PlayerBody)> {
+        std::iter::from_fn(|| client.try_receive())
+            .filter_map(|message| match message {
+                ServerMessage::PlayerState { sequence, body } => Some((sequence, body)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inputs_move_the_player() {
+        let (mut app, mut loopback_client) = loopback_server_app();
+        app.add_systems(
+            Update,
+            (
+                handle_client_messages,
+                sync_players,
+                apply_deferred,
+                move_players,
+                send_player_states,
+            )
+                .chain(),
+        );
+        // the player spawns at the origin, on top of a chunk of stone
+        let stone = app.world.resource::<BlockRegistry>().id("modcraft:stone");
+        let mut voxel_world = app.world.resource_mut::<VoxelWorld>();
+        voxel_world.insert_chunk(ChunkPos::new(0, -1, 0), Chunk::filled(stone.unwrap()));
+        voxel_world.insert_chunk(ChunkPos::new(0, 0, 0), Chunk::default());
+
+        join_loopback(&mut app, &mut loopback_client, "local");
+        app.update();
+        // new players are told where they are before moving
+        let states = player_states(&mut loopback_client);
+        assert_eq!(states, [(0, PlayerBody::new(Vec3::new(0.5, 0.0, 0.5)))]);
+
+        let walk = PlayerInput {
+            forward: 1.0,
+            ..default()
+        };
+        for sequence in 1..=5 {
+            loopback_client
+                .send(ClientMessage::PlayerInput {
+                    sequence,
+                    input: walk,
+                })
+                .unwrap();
+        }
+        // repeated and old inputs are ignored
+        loopback_client
+            .send(ClientMessage::PlayerInput {
+                sequence: 2,
+                input: walk,
+            })
+            .unwrap();
+        app.update();
+        // the tick the player spawned in was saved up
+        let (sequence, body) = player_states(&mut loopback_client)[0];
+        assert_eq!(sequence, 2);
+        assert!(body.position.z < 0.5);
+        assert_eq!(body.position.y, 0.0);
+
+        // the rest are applied one a tick, however fast they came
+        for _ in 0..4 {
+            app.update();
+        }
+        let sequences: Vec<u32> = player_states(&mut loopback_client)
+            .into_iter()
+            .map(|(sequence, _)| sequence)
+            .collect();
+        assert_eq!(sequences, [3, 4, 5]);
+    }
+}
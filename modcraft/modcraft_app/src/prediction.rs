@@ -0,0 +1,159 @@
+//! Moves the local player as soon as input is read instead of waiting a
+//! round trip for the server.
+//!
+//! Every input sent is kept until the server says it has applied it. When
+//! the server's position arrives, the inputs it hasn't applied yet are
+//! replayed on top of it, so the prediction never drifts far from the
+//! server.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use modcraft_lib::{
+    blocks::BlockRegistry,
+    physics::{is_solid, step, PlayerBody, PlayerInput},
+    world::{BlockPos, VoxelWorld},
+};
+
+use crate::{
+    client::{ServerBlocks, ServerWorld},
+    protocol::ClientMessage,
+    transport::client::ClientTransport,
+};
+
+// inputs kept for replaying, older ones are forgotten if the server stops
+// answering
+const MAX_PENDING_INPUTS: usize = 256;
+
+/// What the player wants to do this tick, filled in by whatever reads the
+/// keyboard and mouse. Terminal clients leave it alone and stand still.
+#[derive(Resource, Debug, Clone, Copy, Default, Deref, DerefMut)]
+pub(crate) struct MovementInput(pub(crate) PlayerInput);
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct LocalPlayer {
+    /// None until the server has said where the player is.
+    pub(crate) body: Option<PlayerBody>,
+    last_sequence: u32,
+    // inputs the server hasn't applied yet
+    pending: VecDeque<(u32, PlayerInput)>,
+    // the latest state from the server, replayed on the next tick
+    confirmed: Option<PlayerBody>,
+    confirmed_sequence: u32,
+}
+
+impl LocalPlayer {
+    pub(crate) fn confirm(&mut self, sequence: u32, body: PlayerBody) {
+        if sequence >= self.confirmed_sequence {
+            self.confirmed = Some(body);
+            self.confirmed_sequence = sequence;
+        }
+    }
+
+    fn reconcile(&mut self, dt: f32, is_solid: impl Fn(BlockPos) -> bool) {
+        let Some(mut body) = self.confirmed.take() else {
+            return;
+        };
+        let sequence = self.confirmed_sequence;
+        self.pending.retain(|(pending, _)| *pending > sequence);
+        for (_, input) in &self.pending {
+            body = step(&body, input, dt, &is_solid);
+        }
+        self.body = Some(body);
+    }
+
+    // sends the input and predicts its result
+    fn apply(
+        &mut self,
+        input: PlayerInput,
+        dt: f32,
+        is_solid: impl Fn(BlockPos) -> bool,
+    ) -> (u32, PlayerInput) {
+        self.last_sequence += 1;
+        if self.pending.len() == MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((self.last_sequence, input));
+        if let Some(body) = &mut self.body {
+            *body = step(body, &input, dt, is_solid);
+        }
+        (self.last_sequence, input)
+    }
+}
+
+fn world_is_solid<'a>(
+    world: &'a VoxelWorld,
+    registry: &'a BlockRegistry,
+) -> impl Fn(BlockPos) -> bool + 'a {
+    move |pos| is_solid(world, registry, pos)
+}
+
+pub(crate) fn predict_local_player(
+    time: Res<Time<Fixed>>,
+    movement_input: Res<MovementInput>,
+    server_world: Res<ServerWorld>,
+    server_blocks: Res<ServerBlocks>,
+    mut local_player: ResMut<LocalPlayer>,
+    transport: ClientTransport,
+) {
+    let dt = time.timestep().as_secs_f32();
+    let is_solid = world_is_solid(&server_world, &server_blocks);
+    local_player.reconcile(dt, &is_solid);
+
+    let (sequence, input) = local_player.apply(movement_input.0, dt, &is_solid);
+    if let Err(e) = transport.send(ClientMessage::PlayerInput { sequence, input }) {
+        warn!("Failed to send input to the server: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use modcraft_lib::{chunk::Chunk, world::ChunkPos};
+
+    use super::*;
+
+    #[test]
+    fn replays_unconfirmed_inputs() {
+        let registry = BlockRegistry::default();
+        let mut world = VoxelWorld::default();
+        world.insert_chunk(
+            ChunkPos::new(0, -1, 0),
+            Chunk::filled(registry.id("modcraft:stone").unwrap()),
+        );
+        world.insert_chunk(ChunkPos::new(0, 0, 0), Chunk::default());
+        let is_solid = world_is_solid(&world, &registry);
+        let dt = 1.0 / 64.0;
+        let walk = PlayerInput {
+            forward: -1.0,
+            ..default()
+        };
+
+        // the server's copy of the player, which lags behind by a few inputs
+        let mut server = PlayerBody::new(Vec3::new(4.5, 0.0, 4.5));
+        let mut local_player = LocalPlayer::default();
+        local_player.confirm(0, server);
+        local_player.reconcile(dt, &is_solid);
+        let mut sent = Vec::new();
+        for _ in 0..10 {
+            sent.push(local_player.apply(walk, dt, &is_solid));
+        }
+        let predicted = local_player.body.unwrap();
+
+        for (_, input) in &sent[..4] {
+            server = step(&server, input, dt, &is_solid);
+        }
+        local_player.confirm(4, server);
+        // an older state arriving late is ignored
+        local_player.confirm(2, PlayerBody::new(Vec3::ZERO));
+        local_player.reconcile(dt, &is_solid);
+        assert_eq!(local_player.pending.len(), 6);
+        assert_eq!(local_player.body.unwrap(), predicted);
+
+        // the server disagreed, the player was pushed back a block
+        server.position.z -= 1.0;
+        local_player.confirm(4, server);
+        local_player.reconcile(dt, &is_solid);
+        let corrected = local_player.body.unwrap();
+        assert!((corrected.position.z - (predicted.position.z - 1.0)).abs() < 1e-4);
+    }
+}
@@ -1,19 +1,143 @@
-use std::collections::HashMap;
+//! The messages clients and the server send each other, and the channels
+//! they travel on.
+//!
+//! Each message has a fixed id in the wire format, listed in its `encode`.
+//! Ids are never reused or renumbered, and new fields only go at the end of
+//! a message and are decoded with [`Reader::optional`], so a server can add
+//! messages and fields without breaking clients a protocol version behind.
+//! A change older clients would read but get wrong, like chunks moving to a
+//! channel of their own in version 7, raises the oldest protocol the server
+//! accepts instead, see [`crate::handshake::MIN_PROTOCOL_VERSION`].
+//!
+//! Every message the server sends over the network is followed by the tick
+//! it was sent in, see [`ServerMessage::encode_stamped`].
 
+use std::{collections::HashMap, fmt};
+
+use bevy::prelude::Vec3;
 use bevy_quinnet::shared::ClientId;
-use serde::{Deserialize, Serialize};
+use modcraft_lib::{
+    blocks::{Block, BlockFace, BlockId, BlockProperties, BlockTextures},
+    physics::{PlayerBody, PlayerInput},
+    world::{BlockPos, ChunkPos},
+};
+
+use crate::{
+    auth::{Challenge, PublicKey},
+    handshake::RejectReason,
+    mods::ModInfo,
+    wire::{Decode, DecodeError, Encode, Reader, Writer},
+};
+
+#[cfg(any(test, not(feature = "dedicated-server")))]
+mod client;
+
+/// How a kind of message travels. Each channel is its own stream, so a lost
+/// packet only holds up messages on the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Channel {
+    /// Ordered and reliable, for the handshake, commands and everything else
+    /// whose order matters.
+    Control,
+    /// Reliable but unordered, chat lines don't wait on each other.
+    Chat,
+    /// Unreliable datagrams for movement. Every message carries a sequence
+    /// number and older ones than the newest seen are dropped, so a lost one
+    /// is made up for by the next.
+    Movement,
+    /// Ordered and reliable block changes and chunk unloads.
+    WorldEdits,
+    /// Ordered and reliable chunk data, kept apart so whole chunks never
+    /// hold up edits. Chunks and unloads carry load numbers so the client
+    /// can put the two world channels back in order.
+    Bulk,
+    /// Ordered and reliable entity spawns, despawns and the states entities
+    /// settle in, see the replication module.
+    Entities,
+}
+
+/// What changed about a replicated entity, components by their index in the
+/// server's replication registry.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct EntityChanges {
+    pub(crate) entity: u64,
+    pub(crate) changed: Vec<(usize, Vec<u8>)>,
+    pub(crate) removed: Vec<usize>,
+}
+
+/// Why the server stopped, told to the players still on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    HostQuit,
+    Shutdown,
+    /// A reason added after this build, by its tag.
+    Unknown(u64),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::HostQuit => write!(f, "The host left the game"),
+            StopReason::Shutdown => write!(f, "The server was shut down"),
+            StopReason::Unknown(tag) => write!(f, "The server stopped ({})", tag),
+        }
+    }
+}
 
 // messages from clients
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ClientMessage {
-    Join { name: String },
+    // the first message on a connection, see the handshake module
+    Hello {
+        protocol_version: u32,
+        game_version: String,
+        mods: Vec<ModInfo>,
+    },
+    // only accepted after a HandshakeAccepted, signing its challenge
+    Join {
+        name: String,
+        public_key: PublicKey,
+        signature: Vec<u8>,
+    },
     Disconnect {},
-    ChatMessage { message: String },
+    ChatMessage {
+        message: String,
+    },
+    // asks for suggestions for the last word of a partly typed command
+    CompleteCommand {
+        line: String,
+    },
+    // sent every tick, numbered from 1
+    PlayerInput {
+        sequence: u32,
+        input: PlayerInput,
+    },
+    BreakBlock {
+        pos: BlockPos,
+    },
+    PlaceBlock {
+        pos: BlockPos,
+        block: BlockId,
+    },
 }
 
 // messages from the server
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServerMessage {
+    HandshakeAccepted {
+        /// The server's message of the day.
+        motd: String,
+        /// Signed by the client when it joins, see the auth module.
+        challenge: Challenge,
+    },
+    // the client should show the reason and disconnect
+    HandshakeRejected {
+        reason: RejectReason,
+    },
+    // the client should show the reason and disconnect
+    JoinRejected {
+        reason: RejectReason,
+    },
     ClientConnected {
         client_id: ClientId,
         username: String,
@@ -28,6 +152,820 @@ pub enum ServerMessage {
     InitClient {
         client_id: ClientId,
         usernames: HashMap<ClientId, String>,
+        // the server's block registry in id order, including modded blocks
+        blocks: Vec<Block>,
+        // the names of the server's replicated components in index order
+        components: Vec<String>,
+        // the server's ticks per second, which the client predicts at too
+        tick_rate: u32,
+    },
+    CommandOutput {
+        message: String,
+    },
+    // answers a CompleteCommand
+    CommandSuggestions {
+        suggestions: Vec<String>,
     },
-    ServerStopping,
+    // from the server itself or a command rather than a player's chat
+    SystemMessage {
+        message: String,
+    },
+    ChunkData {
+        pos: ChunkPos,
+        // compressed with Chunk::encode
+        data: Vec<u8>,
+        // counts the times this chunk was sent to the client, from 1, or 0
+        // from servers that don't count them
+        load: u32,
+    },
+    // ends the load of the chunk with this number
+    UnloadChunk {
+        pos: ChunkPos,
+        load: u32,
+    },
+    // changes to chunks the client has loaded, in the order they happened
+    BlockChanges {
+        changes: Vec<(BlockPos, BlockId)>,
+    },
+    // a block action was rejected, this is what the block really is
+    BlockCorrection {
+        pos: BlockPos,
+        block: BlockId,
+    },
+    // where the player really is after the input with this sequence number
+    PlayerState {
+        sequence: u32,
+        body: PlayerBody,
+    },
+    // counts down the seconds until the server stops
+    #[cfg_attr(feature = "dedicated-server", allow(dead_code))]
+    StoppingSoon {
+        reason: StopReason,
+        seconds: u32,
+    },
+    ServerStopping {
+        reason: StopReason,
+    },
+    // the server is full and the client waits for a place, counting from 1;
+    // sent again as it moves up, and every few seconds
+    QueuePosition {
+        position: usize,
+    },
+    // sent right before the server disconnects the client
+    Kicked {
+        reason: String,
+    },
+    // an entity came into the client's view, with every replicated component
+    // it has, as of the tick
+    EntitySpawned {
+        entity: u64,
+        tick: u64,
+        components: Vec<(usize, Vec<u8>)>,
+    },
+    // an entity left the client's view or was despawned
+    EntityDespawned {
+        entity: u64,
+    },
+    // components that were removed or stopped changing, as of the tick
+    EntityStates {
+        tick: u64,
+        entities: Vec<EntityChanges>,
+    },
+    // components that changed in the tick, older ones than the client has
+    // are dropped
+    EntityUpdates {
+        tick: u64,
+        entities: Vec<EntityChanges>,
+    },
+}
+
+impl ClientMessage {
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (id, mut r) = Reader::new(bytes).message()?;
+        Ok(match id {
+            0 => ClientMessage::Hello {
+                protocol_version: r.get()?,
+                game_version: r.get()?,
+                mods: r.get()?,
+            },
+            1 => ClientMessage::Join {
+                name: r.get()?,
+                public_key: r.get()?,
+                signature: r.get()?,
+            },
+            2 => ClientMessage::Disconnect {},
+            3 => ClientMessage::ChatMessage { message: r.get()? },
+            4 => ClientMessage::CompleteCommand { line: r.get()? },
+            5 => ClientMessage::PlayerInput {
+                sequence: r.get()?,
+                input: r.get()?,
+            },
+            6 => ClientMessage::BreakBlock { pos: r.get()? },
+            7 => ClientMessage::PlaceBlock {
+                pos: r.get()?,
+                block: r.get()?,
+            },
+            id => return Err(DecodeError::UnknownMessage(id)),
+        })
+    }
+}
+
+impl ServerMessage {
+    pub(crate) fn channel(&self) -> Channel {
+        match self {
+            ServerMessage::HandshakeAccepted { .. }
+            | ServerMessage::HandshakeRejected { .. }
+            | ServerMessage::JoinRejected { .. }
+            | ServerMessage::ClientConnected { .. }
+            | ServerMessage::ClientDisconnected { .. }
+            | ServerMessage::InitClient { .. }
+            | ServerMessage::CommandOutput { .. }
+            | ServerMessage::CommandSuggestions { .. }
+            | ServerMessage::SystemMessage { .. }
+            | ServerMessage::StoppingSoon { .. }
+            | ServerMessage::ServerStopping { .. }
+            | ServerMessage::QueuePosition { .. }
+            | ServerMessage::Kicked { .. } => Channel::Control,
+            ServerMessage::ChatMessage { .. } => Channel::Chat,
+            ServerMessage::PlayerState { .. } | ServerMessage::EntityUpdates { .. } => {
+                Channel::Movement
+            }
+            ServerMessage::UnloadChunk { .. }
+            | ServerMessage::BlockChanges { .. }
+            | ServerMessage::BlockCorrection { .. } => Channel::WorldEdits,
+            ServerMessage::ChunkData { .. } => Channel::Bulk,
+            ServerMessage::EntitySpawned { .. }
+            | ServerMessage::EntityDespawned { .. }
+            | ServerMessage::EntityStates { .. } => Channel::Entities,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        match self {
+            ServerMessage::HandshakeAccepted { motd, challenge } => writer.message(0, |w| {
+                w.put(motd);
+                w.put(challenge);
+            }),
+            ServerMessage::HandshakeRejected { reason } => writer.message(1, |w| w.put(reason)),
+            ServerMessage::JoinRejected { reason } => writer.message(2, |w| w.put(reason)),
+            ServerMessage::ClientConnected {
+                client_id,
+                username,
+            } => writer.message(3, |w| {
+                w.put(client_id);
+                w.put(username);
+            }),
+            ServerMessage::ClientDisconnected { client_id } => {
+                writer.message(4, |w| w.put(client_id))
+            }
+            ServerMessage::ChatMessage { client_id, message } => writer.message(5, |w| {
+                w.put(client_id);
+                w.put(message);
+            }),
+            ServerMessage::InitClient {
+                client_id,
+                usernames,
+                blocks,
+                components,
+                tick_rate,
+            } => writer.message(6, |w| {
+                // sorted so the same message always encodes the same way
+                let mut usernames: Vec<_> = usernames.iter().collect();
+                usernames.sort();
+                w.put(client_id);
+                w.put(&usernames);
+                w.put(blocks);
+                w.put(components);
+                w.put(tick_rate);
+            }),
+            ServerMessage::CommandOutput { message } => writer.message(7, |w| w.put(message)),
+            ServerMessage::CommandSuggestions { suggestions } => {
+                writer.message(8, |w| w.put(suggestions))
+            }
+            ServerMessage::SystemMessage { message } => writer.message(9, |w| w.put(message)),
+            ServerMessage::ChunkData { pos, data, load } => writer.message(10, |w| {
+                w.put(pos);
+                w.put(data);
+                w.put(load);
+            }),
+            ServerMessage::UnloadChunk { pos, load } => writer.message(11, |w| {
+                w.put(pos);
+                w.put(load);
+            }),
+            ServerMessage::BlockChanges { changes } => writer.message(12, |w| {
+                // changes are usually close together, so each position is
+                // sent as the offset from the one before
+                w.put(&changes.len());
+                let mut previous = BlockPos::new(0, 0, 0);
+                for (pos, block) in changes {
+                    w.signed(i64::from(pos.x) - i64::from(previous.x));
+                    w.signed(i64::from(pos.y) - i64::from(previous.y));
+                    w.signed(i64::from(pos.z) - i64::from(previous.z));
+                    w.put(block);
+                    previous = *pos;
+                }
+            }),
+            ServerMessage::BlockCorrection { pos, block } => writer.message(13, |w| {
+                w.put(pos);
+                w.put(block);
+            }),
+            ServerMessage::PlayerState { sequence, body } => writer.message(14, |w| {
+                w.put(sequence);
+                w.put(body);
+            }),
+            ServerMessage::StoppingSoon { reason, seconds } => writer.message(15, |w| {
+                w.put(reason);
+                w.put(seconds);
+            }),
+            ServerMessage::ServerStopping { reason } => writer.message(16, |w| w.put(reason)),
+            ServerMessage::QueuePosition { position } => writer.message(17, |w| w.put(position)),
+            ServerMessage::Kicked { reason } => writer.message(18, |w| w.put(reason)),
+            ServerMessage::EntitySpawned {
+                entity,
+                tick,
+                components,
+            } => writer.message(19, |w| {
+                w.put(entity);
+                w.put(tick);
+                w.put(components);
+            }),
+            ServerMessage::EntityDespawned { entity } => writer.message(20, |w| w.put(entity)),
+            ServerMessage::EntityStates { tick, entities } => writer.message(21, |w| {
+                w.put(tick);
+                w.put(entities);
+            }),
+            ServerMessage::EntityUpdates { tick, entities } => writer.message(22, |w| {
+                w.put(tick);
+                w.put(entities);
+            }),
+        }
+        writer.into_bytes()
+    }
+
+    /// The message as it goes over the network, followed by the server tick
+    /// it was sent in. Clients that don't read the tick skip it like they
+    /// skip anything after a message.
+    pub(crate) fn encode_stamped(&self, tick: u64) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.put(&tick);
+        [self.encode(), writer.into_bytes()].concat()
+    }
+}
+
+impl Encode for ModInfo {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&self.name);
+        w.put(&self.version);
+    }
+}
+
+impl Decode for ModInfo {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(ModInfo {
+            name: r.get()?,
+            version: r.get()?,
+        })
+    }
+}
+
+impl Encode for PublicKey {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&self.0);
+    }
+}
+
+impl Decode for PublicKey {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(PublicKey(r.get()?))
+    }
+}
+
+// enums inside messages are written as a varint tag followed by their fields,
+// and a tag from a newer build decodes as unknown, skipping the rest of the
+// message since its fields can't be told apart from what follows
+impl Encode for RejectReason {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            RejectReason::ProtocolMismatch { server, client } => {
+                w.varint(0);
+                w.put(server);
+                w.put(client);
+            }
+            RejectReason::GameVersionMismatch { server, client } => {
+                w.varint(1);
+                w.put(server);
+                w.put(client);
+            }
+            RejectReason::MissingMods(mods) => {
+                w.varint(2);
+                w.put(mods);
+            }
+            RejectReason::ServerFull { max_players } => {
+                w.varint(3);
+                w.put(max_players);
+            }
+            RejectReason::FailedAuthentication => w.varint(4),
+            RejectReason::InvalidName(name) => {
+                w.varint(5);
+                w.put(name);
+            }
+            RejectReason::NameTaken(name) => {
+                w.varint(6);
+                w.put(name);
+            }
+            RejectReason::AlreadyConnected => w.varint(7),
+            RejectReason::Banned(reason) => {
+                w.varint(8);
+                w.put(reason);
+            }
+            RejectReason::NotWhitelisted => w.varint(9),
+            RejectReason::Unknown(tag) => w.varint(*tag),
+        }
+    }
+}
+
+impl Decode for RejectReason {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.varint()? {
+            0 => RejectReason::ProtocolMismatch {
+                server: r.get()?,
+                client: r.get()?,
+            },
+            1 => RejectReason::GameVersionMismatch {
+                server: r.get()?,
+                client: r.get()?,
+            },
+            2 => RejectReason::MissingMods(r.get()?),
+            3 => RejectReason::ServerFull {
+                max_players: r.get()?,
+            },
+            4 => RejectReason::FailedAuthentication,
+            5 => RejectReason::InvalidName(r.get()?),
+            6 => RejectReason::NameTaken(r.get()?),
+            7 => RejectReason::AlreadyConnected,
+            8 => RejectReason::Banned(r.get()?),
+            9 => RejectReason::NotWhitelisted,
+            tag => {
+                r.skip_rest();
+                RejectReason::Unknown(tag)
+            }
+        })
+    }
+}
+
+impl Encode for StopReason {
+    fn encode(&self, w: &mut Writer) {
+        w.varint(match self {
+            StopReason::HostQuit => 0,
+            StopReason::Shutdown => 1,
+            StopReason::Unknown(tag) => *tag,
+        });
+    }
+}
+
+impl Decode for StopReason {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.varint()? {
+            0 => StopReason::HostQuit,
+            1 => StopReason::Shutdown,
+            tag => {
+                r.skip_rest();
+                StopReason::Unknown(tag)
+            }
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&self.name);
+        w.put(&self.properties.solid);
+        w.put(&self.properties.transparent);
+        w.put(&self.properties.hardness);
+        w.put(
+            &self
+                .properties
+                .textures
+                .as_ref()
+                .map(|textures| BlockFace::ALL.map(|face| textures.get(face))),
+        );
+    }
+}
+
+impl Decode for Block {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Block {
+            name: r.get()?,
+            properties: BlockProperties {
+                solid: r.get()?,
+                transparent: r.get()?,
+                hardness: r.get()?,
+                textures: r.get::<Option<_>>()?.map(BlockTextures::from_faces),
+            },
+        })
+    }
+}
+
+impl Encode for BlockPos {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&[self.x, self.y, self.z]);
+    }
+}
+
+impl Decode for BlockPos {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(BlockPos::new(r.get()?, r.get()?, r.get()?))
+    }
+}
+
+impl Encode for ChunkPos {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&[self.x, self.y, self.z]);
+    }
+}
+
+impl Decode for ChunkPos {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(ChunkPos::new(r.get()?, r.get()?, r.get()?))
+    }
+}
+
+impl Encode for Vec3 {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&self.to_array());
+    }
+}
+
+impl Decode for Vec3 {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Vec3::from_array(r.get()?))
+    }
+}
+
+// inputs stay exact floats, the server has to simulate what the client
+// predicted
+impl Encode for PlayerInput {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&[self.forward, self.right, self.yaw, self.pitch]);
+        w.put(&self.jump);
+    }
+}
+
+impl Decode for PlayerInput {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(PlayerInput {
+            forward: r.get()?,
+            right: r.get()?,
+            yaw: r.get()?,
+            pitch: r.get()?,
+            jump: r.get()?,
+        })
+    }
+}
+
+impl Encode for EntityChanges {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&self.entity);
+        w.put(&self.changed);
+        w.put(&self.removed);
+    }
+}
+
+impl Decode for EntityChanges {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(EntityChanges {
+            entity: r.get()?,
+            changed: r.get()?,
+            removed: r.get()?,
+        })
+    }
+}
+
+impl Encode for PlayerBody {
+    fn encode(&self, w: &mut Writer) {
+        w.put(&self.position);
+        w.put(&self.velocity);
+        w.put(&self.on_ground);
+    }
+}
+
+impl Decode for PlayerBody {
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(PlayerBody {
+            position: r.get()?,
+            velocity: r.get()?,
+            on_ground: r.get()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> (Vec<ClientMessage>, Vec<ServerMessage>) {
+        let example = ModInfo {
+            name: "ex".to_string(),
+            version: "1".to_string(),
+        };
+        let client = vec![
+            ClientMessage::Hello {
+                protocol_version: 6,
+                game_version: "0.1".to_string(),
+                mods: vec![example.clone()],
+            },
+            ClientMessage::Join {
+                name: "Al".to_string(),
+                public_key: PublicKey([7; 32]),
+                signature: vec![1, 2],
+            },
+            ClientMessage::Disconnect {},
+            ClientMessage::ChatMessage {
+                message: "hi".to_string(),
+            },
+            ClientMessage::CompleteCommand {
+                line: "/k".to_string(),
+            },
+            ClientMessage::PlayerInput {
+                sequence: 300,
+                input: PlayerInput {
+                    forward: 1.0,
+                    right: -1.0,
+                    yaw: 0.0,
+                    pitch: 0.5,
+                    jump: true,
+                },
+            },
+            ClientMessage::BreakBlock {
+                pos: BlockPos::new(1, -1, 64),
+            },
+            ClientMessage::PlaceBlock {
+                pos: BlockPos::new(0, 0, -2),
+                block: 3,
+            },
+        ];
+        let reason = RejectReason::Banned(Some("x".to_string()));
+        let server = vec![
+            ServerMessage::HandshakeAccepted {
+                motd: "m".to_string(),
+                challenge: [9; 32],
+            },
+            ServerMessage::HandshakeRejected {
+                reason: RejectReason::ProtocolMismatch {
+                    server: 6,
+                    client: 7,
+                },
+            },
+            ServerMessage::JoinRejected { reason },
+            ServerMessage::ClientConnected {
+                client_id: 2,
+                username: "Al".to_string(),
+            },
+            ServerMessage::ClientDisconnected { client_id: 2 },
+            ServerMessage::ChatMessage {
+                client_id: 0,
+                message: "hi".to_string(),
+            },
+            ServerMessage::InitClient {
+                client_id: 1,
+                usernames: HashMap::from([(1, "B".to_string()), (0, "A".to_string())]),
+                blocks: vec![
+                    Block {
+                        name: "a".to_string(),
+                        properties: BlockProperties::air(),
+                    },
+                    Block {
+                        name: "s".to_string(),
+                        properties: BlockProperties {
+                            solid: true,
+                            transparent: false,
+                            hardness: 1.0,
+                            textures: Some(BlockTextures::top_side_bottom("t", "s", "b")),
+                        },
+                    },
+                ],
+                components: vec!["c".to_string()],
+                tick_rate: 20,
+            },
+            ServerMessage::CommandOutput {
+                message: "ok".to_string(),
+            },
+            ServerMessage::CommandSuggestions {
+                suggestions: vec!["a".to_string(), "b".to_string()],
+            },
+            ServerMessage::SystemMessage {
+                message: "s".to_string(),
+            },
+            ServerMessage::ChunkData {
+                pos: ChunkPos::new(-1, 0, 1),
+                data: vec![0xff, 0],
+                load: 1,
+            },
+            ServerMessage::UnloadChunk {
+                pos: ChunkPos::new(2, 0, -3),
+                load: 2,
+            },
+            ServerMessage::BlockChanges {
+                changes: vec![
+                    (BlockPos::new(100, 64, -100), 1),
+                    (BlockPos::new(101, 64, -100), 0),
+                    (BlockPos::new(100, 63, -99), 2),
+                ],
+            },
+            ServerMessage::BlockCorrection {
+                pos: BlockPos::new(0, 1, 0),
+                block: 200,
+            },
+            ServerMessage::PlayerState {
+                sequence: 5,
+                body: PlayerBody {
+                    position: Vec3::new(0.5, 1.0, -0.5),
+                    velocity: Vec3::ZERO,
+                    on_ground: true,
+                },
+            },
+            ServerMessage::StoppingSoon {
+                reason: StopReason::Shutdown,
+                seconds: 10,
+            },
+            ServerMessage::ServerStopping {
+                reason: StopReason::HostQuit,
+            },
+            ServerMessage::QueuePosition { position: 3 },
+            ServerMessage::Kicked {
+                reason: "bye".to_string(),
+            },
+            ServerMessage::EntitySpawned {
+                entity: 300,
+                tick: 2,
+                components: vec![(1, vec![7])],
+            },
+            ServerMessage::EntityDespawned { entity: 300 },
+            ServerMessage::EntityStates {
+                tick: 3,
+                entities: vec![EntityChanges {
+                    entity: 1,
+                    changed: vec![],
+                    removed: vec![1],
+                }],
+            },
+            ServerMessage::EntityUpdates {
+                tick: 4,
+                entities: vec![EntityChanges {
+                    entity: 1,
+                    changed: vec![(0, vec![5, 6])],
+                    removed: vec![],
+                }],
+            },
+        ];
+        (client, server)
+    }
+
+    // changing any of these breaks clients built before the change
+    #[test]
+    fn messages_encode_to_their_golden_bytes() {
+        let (client, server) = samples();
+        let client_bytes: Vec<Vec<u8>> = vec![
+            vec![0, 11, 6, 3, b'0', b'.', b'1', 1, 2, b'e', b'x', 1, b'1'],
+            [&[1, 38, 2, b'A', b'l'][..], &[7; 32], &[2, 1, 2]].concat(),
+            vec![2, 0],
+            vec![3, 3, 2, b'h', b'i'],
+            vec![4, 3, 2, b'/', b'k'],
+            vec![
+                5, 19, 172, 2, 0, 0, 128, 63, 0, 0, 128, 191, 0, 0, 0, 0, 0, 0, 0, 63, 1,
+            ],
+            vec![6, 4, 2, 1, 128, 1],
+            vec![7, 4, 0, 0, 3, 3],
+        ];
+        let server_bytes: Vec<Vec<u8>> = vec![
+            [&[0, 34, 1, b'm'][..], &[9; 32]].concat(),
+            vec![1, 3, 0, 6, 7],
+            vec![2, 4, 8, 1, 1, b'x'],
+            vec![3, 4, 2, 2, b'A', b'l'],
+            vec![4, 1, 2],
+            vec![5, 4, 0, 2, b'h', b'i'],
+            vec![
+                6, 43, 1, 2, 0, 1, b'A', 1, 1, b'B', 2, 1, b'a', 0, 1, 0, 0, 0, 0, 0, 1, b's', 1,
+                0, 0, 0, 128, 63, 1, 1, b't', 1, b'b', 1, b's', 1, b's', 1, b's', 1, b's', 1, 1,
+                b'c', 20,
+            ],
+            vec![7, 3, 2, b'o', b'k'],
+            vec![8, 5, 2, 1, b'a', 1, b'b'],
+            vec![9, 2, 1, b's'],
+            vec![10, 7, 1, 0, 2, 2, 255, 0, 1],
+            vec![11, 4, 4, 0, 5, 2],
+            // offsets from the change before, 100 64 -100 then 1 0 0 then -1 -1 1
+            vec![12, 16, 3, 200, 1, 128, 1, 199, 1, 1, 2, 0, 0, 0, 1, 1, 2, 2],
+            vec![13, 5, 0, 2, 0, 200, 1],
+            vec![
+                14, 26, 5, 0, 0, 0, 63, 0, 0, 128, 63, 0, 0, 0, 191, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 1,
+            ],
+            vec![15, 2, 1, 10],
+            vec![16, 1, 0],
+            vec![17, 1, 3],
+            vec![18, 4, 3, b'b', b'y', b'e'],
+            vec![19, 7, 172, 2, 2, 1, 1, 1, 7],
+            vec![20, 2, 172, 2],
+            vec![21, 6, 3, 1, 1, 0, 1, 1],
+            vec![22, 9, 4, 1, 1, 1, 0, 2, 5, 6, 0],
+        ];
+
+        assert_eq!(client.len(), client_bytes.len());
+        for (message, bytes) in client.into_iter().zip(client_bytes) {
+            assert_eq!(message.encode(), bytes, "{:?}", message);
+            assert_eq!(ClientMessage::decode(&bytes), Ok(message));
+        }
+        assert_eq!(server.len(), server_bytes.len());
+        for (message, bytes) in server.into_iter().zip(server_bytes) {
+            assert_eq!(message.encode(), bytes, "{:?}", message);
+            assert_eq!(ServerMessage::decode(&bytes), Ok(message));
+        }
+    }
+
+    #[test]
+    fn messages_go_on_their_channels() {
+        use Channel::*;
+        let (client, server) = samples();
+        let client_channels = [
+            Control, Control, Control, Control, Control, Movement, WorldEdits, WorldEdits,
+        ];
+        let server_channels = [
+            Control, Control, Control, Control, Control, Chat, Control, Control, Control, Control,
+            Bulk, WorldEdits, WorldEdits, WorldEdits, Movement, Control, Control, Control, Control,
+            Entities, Entities, Entities, Movement,
+        ];
+
+        assert_eq!(client.len(), client_channels.len());
+        for (message, channel) in client.iter().zip(client_channels) {
+            assert_eq!(message.channel(), channel, "{:?}", message);
+        }
+        assert_eq!(server.len(), server_channels.len());
+        for (message, channel) in server.iter().zip(server_channels) {
+            assert_eq!(message.channel(), channel, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn messages_from_other_versions_still_decode() {
+        // a newer server's chat message with a field this build doesn't know
+        let mut writer = Writer::default();
+        writer.message(3, |w| {
+            w.put("hi");
+            w.put(&42u32);
+        });
+        assert_eq!(
+            ClientMessage::decode(&writer.into_bytes()),
+            Ok(ClientMessage::ChatMessage {
+                message: "hi".to_string()
+            })
+        );
+
+        // an older build's message without a field added since
+        let bytes = ServerMessage::QueuePosition { position: 3 }.encode();
+        let (_, mut body) = Reader::new(&bytes).message().unwrap();
+        assert_eq!(body.get::<usize>(), Ok(3));
+        assert_eq!(body.optional::<String>(), Ok(None));
+        assert_eq!(
+            ServerMessage::decode(&[11, 3, 4, 0, 5]),
+            Ok(ServerMessage::UnloadChunk {
+                pos: ChunkPos::new(2, 0, -3),
+                load: 0,
+            })
+        );
+
+        // the tick a message is stamped with follows it
+        let message = ServerMessage::QueuePosition { position: 3 };
+        let bytes = message.encode_stamped(300);
+        assert_eq!(ServerMessage::decode(&bytes), Ok(message));
+        let mut reader = Reader::new(&bytes);
+        reader.message().unwrap();
+        assert_eq!(reader.get::<u64>(), Ok(300));
+        assert_eq!(reader.get::<u8>(), Err(DecodeError::UnexpectedEnd));
+
+        // reasons that are newer than this build, which may have fields
+        assert_eq!(
+            ServerMessage::decode(&[2, 3, 10, 1, b'x']),
+            Ok(ServerMessage::JoinRejected {
+                reason: RejectReason::Unknown(10)
+            })
+        );
+        assert_eq!(
+            ServerMessage::decode(&[16, 1, 2]),
+            Ok(ServerMessage::ServerStopping {
+                reason: StopReason::Unknown(2)
+            })
+        );
+
+        // a message that is newer than this build
+        assert_eq!(
+            ServerMessage::decode(&[200, 1, 0]),
+            Err(DecodeError::UnknownMessage(200))
+        );
+        assert_eq!(
+            ServerMessage::decode(&[18, 4, 3, b'b']),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
 }
@@ -0,0 +1,175 @@
+//! The client's half of the protocol: encoding what it sends and decoding
+//! what the server sends back.
+
+use bevy_quinnet::shared::ClientId;
+use modcraft_lib::{config::DEFAULT_TICK_RATE, mods::GAME_VERSION, world::BlockPos};
+
+use super::{Channel, ClientMessage, ServerMessage};
+use crate::{
+    handshake::PROTOCOL_VERSION,
+    mods::LoadedMods,
+    wire::{DecodeError, Reader, Writer},
+};
+
+impl ClientMessage {
+    /// The hello message describing this build.
+    pub(crate) fn hello(loaded_mods: &LoadedMods) -> Self {
+        ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            game_version: GAME_VERSION.to_string(),
+            mods: loaded_mods.mods.clone(),
+        }
+    }
+
+    pub(crate) fn channel(&self) -> Channel {
+        match self {
+            // commands come as chat, and their order matters
+            ClientMessage::Hello { .. }
+            | ClientMessage::Join { .. }
+            | ClientMessage::Disconnect {}
+            | ClientMessage::ChatMessage { .. }
+            | ClientMessage::CompleteCommand { .. } => Channel::Control,
+            ClientMessage::PlayerInput { .. } => Channel::Movement,
+            ClientMessage::BreakBlock { .. } | ClientMessage::PlaceBlock { .. } => {
+                Channel::WorldEdits
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        match self {
+            ClientMessage::Hello {
+                protocol_version,
+                game_version,
+                mods,
+            } => writer.message(0, |w| {
+                w.put(protocol_version);
+                w.put(game_version);
+                w.put(mods);
+            }),
+            ClientMessage::Join {
+                name,
+                public_key,
+                signature,
+            } => writer.message(1, |w| {
+                w.put(name);
+                w.put(public_key);
+                w.put(signature);
+            }),
+            ClientMessage::Disconnect {} => writer.message(2, |_| {}),
+            ClientMessage::ChatMessage { message } => writer.message(3, |w| w.put(message)),
+            ClientMessage::CompleteCommand { line } => writer.message(4, |w| w.put(line)),
+            ClientMessage::PlayerInput { sequence, input } => writer.message(5, |w| {
+                w.put(sequence);
+                w.put(input);
+            }),
+            ClientMessage::BreakBlock { pos } => writer.message(6, |w| w.put(pos)),
+            ClientMessage::PlaceBlock { pos, block } => writer.message(7, |w| {
+                w.put(pos);
+                w.put(block);
+            }),
+        }
+        writer.into_bytes()
+    }
+}
+
+impl ServerMessage {
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (id, mut r) = Reader::new(bytes).message()?;
+        Ok(match id {
+            0 => ServerMessage::HandshakeAccepted {
+                motd: r.get()?,
+                challenge: r.get()?,
+            },
+            1 => ServerMessage::HandshakeRejected { reason: r.get()? },
+            2 => ServerMessage::JoinRejected { reason: r.get()? },
+            3 => ServerMessage::ClientConnected {
+                client_id: r.get()?,
+                username: r.get()?,
+            },
+            4 => ServerMessage::ClientDisconnected {
+                client_id: r.get()?,
+            },
+            5 => ServerMessage::ChatMessage {
+                client_id: r.get()?,
+                message: r.get()?,
+            },
+            6 => ServerMessage::InitClient {
+                client_id: r.get()?,
+                usernames: r.get::<Vec<(ClientId, String)>>()?.into_iter().collect(),
+                blocks: r.get()?,
+                components: r.get()?,
+                tick_rate: r.optional()?.unwrap_or(DEFAULT_TICK_RATE),
+            },
+            7 => ServerMessage::CommandOutput { message: r.get()? },
+            8 => ServerMessage::CommandSuggestions {
+                suggestions: r.get()?,
+            },
+            9 => ServerMessage::SystemMessage { message: r.get()? },
+            10 => ServerMessage::ChunkData {
+                pos: r.get()?,
+                data: r.get()?,
+                load: r.optional()?.unwrap_or(0),
+            },
+            11 => ServerMessage::UnloadChunk {
+                pos: r.get()?,
+                load: r.optional()?.unwrap_or(0),
+            },
+            12 => {
+                let count: usize = r.get()?;
+                let mut changes = Vec::new();
+                let mut previous = BlockPos::new(0, 0, 0);
+                for _ in 0..count {
+                    let pos = BlockPos::new(
+                        offset(previous.x, r.signed()?)?,
+                        offset(previous.y, r.signed()?)?,
+                        offset(previous.z, r.signed()?)?,
+                    );
+                    changes.push((pos, r.get()?));
+                    previous = pos;
+                }
+                ServerMessage::BlockChanges { changes }
+            }
+            13 => ServerMessage::BlockCorrection {
+                pos: r.get()?,
+                block: r.get()?,
+            },
+            14 => ServerMessage::PlayerState {
+                sequence: r.get()?,
+                body: r.get()?,
+            },
+            15 => ServerMessage::StoppingSoon {
+                reason: r.get()?,
+                seconds: r.get()?,
+            },
+            16 => ServerMessage::ServerStopping { reason: r.get()? },
+            17 => ServerMessage::QueuePosition { position: r.get()? },
+            18 => ServerMessage::Kicked { reason: r.get()? },
+            19 => ServerMessage::EntitySpawned {
+                entity: r.get()?,
+                tick: r.get()?,
+                components: r.get()?,
+            },
+            20 => ServerMessage::EntityDespawned { entity: r.get()? },
+            21 => ServerMessage::EntityStates {
+                tick: r.get()?,
+                entities: r.get()?,
+            },
+            22 => ServerMessage::EntityUpdates {
+                tick: r.get()?,
+                entities: r.get()?,
+            },
+            id => return Err(DecodeError::UnknownMessage(id)),
+        })
+    }
+}
+
+// a coordinate from the one before it and the offset between them
+fn offset(previous: i32, offset: i64) -> Result<i32, DecodeError> {
+    let value = i64::from(previous) + offset;
+    i32::try_from(value).map_err(|_| DecodeError::InvalidValue {
+        what: "coordinate",
+        value: value as u64,
+    })
+}
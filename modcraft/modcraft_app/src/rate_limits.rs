@@ -0,0 +1,157 @@
+//! Keeps one client from making the server do more than its share.
+//!
+//! Each client has a token bucket for every kind of message it can send,
+//! filled at the rate the config allows up to its burst. A message without a
+//! token left is dropped and counts against the client, and a client that
+//! keeps going over its limits is kicked.
+
+use std::{collections::HashMap, time::Duration};
+
+use bevy::prelude::*;
+use bevy_quinnet::shared::ClientId;
+use modcraft_lib::config::{RateLimit, RateLimits};
+
+use crate::protocol::ClientMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum MessageKind {
+    Handshake,
+    Chat,
+    Completion,
+    Input,
+    BlockAction,
+}
+
+impl MessageKind {
+    /// None for messages that are never limited.
+    pub(crate) fn of(message: &ClientMessage) -> Option<Self> {
+        match message {
+            ClientMessage::Hello { .. } | ClientMessage::Join { .. } => {
+                Some(MessageKind::Handshake)
+            }
+            ClientMessage::Disconnect {} => None,
+            ClientMessage::ChatMessage { .. } => Some(MessageKind::Chat),
+            ClientMessage::CompleteCommand { .. } => Some(MessageKind::Completion),
+            ClientMessage::PlayerInput { .. } => Some(MessageKind::Input),
+            ClientMessage::BreakBlock { .. } | ClientMessage::PlaceBlock { .. } => {
+                Some(MessageKind::BlockAction)
+            }
+        }
+    }
+
+    fn limit(self, limits: &RateLimits) -> RateLimit {
+        match self {
+            MessageKind::Handshake => limits.handshake,
+            MessageKind::Chat => limits.chat,
+            MessageKind::Completion => limits.completions,
+            MessageKind::Input => limits.inputs,
+            MessageKind::BlockAction => limits.block_actions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allow,
+    Drop,
+    Kick,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ClientBudget {
+    // tokens left for each kind, buckets start full
+    tokens: HashMap<MessageKind, f32>,
+    // messages dropped and not yet forgiven
+    strikes: f32,
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct RateLimiter {
+    clients: HashMap<ClientId, ClientBudget>,
+}
+
+impl RateLimiter {
+    /// Fills every bucket for the time since the last tick, and forgets
+    /// clients that aren't connected anymore.
+    pub(crate) fn refill(&mut self, limits: &RateLimits, delta: Duration, connected: &[ClientId]) {
+        let seconds = delta.as_secs_f32();
+        self.clients
+            .retain(|client_id, _| connected.contains(client_id));
+        for budget in self.clients.values_mut() {
+            for (kind, tokens) in &mut budget.tokens {
+                let limit = kind.limit(limits);
+                *tokens = (*tokens + limit.per_second * seconds).min(limit.burst);
+            }
+            budget.strikes = (budget.strikes - seconds).max(0.0);
+        }
+    }
+
+    /// Whether a message from `client_id` should be handled.
+    pub(crate) fn check(
+        &mut self,
+        limits: &RateLimits,
+        client_id: ClientId,
+        message: &ClientMessage,
+    ) -> Verdict {
+        let Some(kind) = MessageKind::of(message) else {
+            return Verdict::Allow;
+        };
+        let budget = self.clients.entry(client_id).or_default();
+        let tokens = budget
+            .tokens
+            .entry(kind)
+            .or_insert(kind.limit(limits).burst);
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            return Verdict::Allow;
+        }
+        budget.strikes += 1.0;
+        if budget.strikes > limits.kick_after as f32 {
+            Verdict::Kick
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_over_their_limits_are_dropped_then_kicked() {
+        let limits = RateLimits {
+            kick_after: 2,
+            chat: RateLimit::new(1.0, 2.0),
+            ..default()
+        };
+        let chat = ClientMessage::ChatMessage {
+            message: "hi".to_string(),
+        };
+        let mut limiter = RateLimiter::default();
+
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Allow);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Allow);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Drop);
+        // other clients and other kinds have their own buckets
+        assert_eq!(limiter.check(&limits, 2, &chat), Verdict::Allow);
+        let complete = ClientMessage::CompleteCommand {
+            line: "/".to_string(),
+        };
+        assert_eq!(limiter.check(&limits, 1, &complete), Verdict::Allow);
+        assert_eq!(
+            limiter.check(&limits, 1, &ClientMessage::Disconnect {}),
+            Verdict::Allow
+        );
+
+        limiter.refill(&limits, Duration::from_secs(1), &[1, 2]);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Allow);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Drop);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Drop);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Kick);
+
+        // a client that reconnects with the same id starts over
+        limiter.refill(&limits, Duration::ZERO, &[2]);
+        assert_eq!(limiter.check(&limits, 1, &chat), Verdict::Allow);
+    }
+}
@@ -0,0 +1,259 @@
+//! The client's replicas of the entities the server replicates to it.
+//!
+//! Entity messages are queued as they arrive and applied once the rest are
+//! handled, with the whole world at hand, since components are inserted by
+//! the functions the replication registry keeps for them. Every component
+//! of a replica remembers the tick it was last set at, so an unreliable update
+//! that arrives after a newer state is dropped.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use modcraft_lib::replication::ReplicationRegistry;
+
+use crate::protocol::{EntityChanges, ServerMessage};
+
+/// Marks the client's replica of a server entity, holding the server's id for
+/// it.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RemoteEntity(pub(crate) u64);
+
+#[derive(Debug, Clone)]
+struct Replica {
+    entity: Entity,
+    // the tick each component was last set or removed at
+    ticks: HashMap<usize, u64>,
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct RemoteEntities {
+    replicas: HashMap<u64, Replica>,
+    // this build's id for each of the server's components, in the server's
+    // order, None for the ones it doesn't know
+    components: Vec<Option<usize>>,
+    // messages received and not applied yet
+    queued: Vec<ServerMessage>,
+}
+
+impl RemoteEntities {
+    /// Matches the server's components with this build's by name.
+    pub(crate) fn new(names: &[String], registry: &ReplicationRegistry) -> Self {
+        let components = names
+            .iter()
+            .map(|name| {
+                let id = registry.id(name);
+                if id.is_none() {
+                    warn!(
+                        "The server replicates {}, which this client doesn't know",
+                        name
+                    );
+                }
+                id
+            })
+            .collect();
+        RemoteEntities {
+            components,
+            ..default()
+        }
+    }
+
+    pub(crate) fn queue(&mut self, message: ServerMessage) {
+        self.queued.push(message);
+    }
+}
+
+// sets a component of a replica, or removes it without bytes, if the tick is
+// newer than the one it was last set at
+fn set_component(
+    world: &mut World,
+    registry: &ReplicationRegistry,
+    components: &[Option<usize>],
+    replica: &mut Replica,
+    id: usize,
+    tick: u64,
+    bytes: Option<&[u8]>,
+) {
+    let last = replica.ticks.entry(id).or_default();
+    if *last >= tick {
+        return;
+    }
+    *last = tick;
+    let Some(component) = components
+        .get(id)
+        .copied()
+        .flatten()
+        .and_then(|id| registry.get(id))
+    else {
+        return;
+    };
+    let mut entity = world.entity_mut(replica.entity);
+    match bytes {
+        Some(bytes) => {
+            if let Err(e) = component.insert(&mut entity, bytes) {
+                warn!("Server sent an invalid {}: {}", component.name(), e);
+            }
+        }
+        None => component.remove(&mut entity),
+    }
+}
+
+fn apply_changes(
+    world: &mut World,
+    registry: &ReplicationRegistry,
+    remote_entities: &mut RemoteEntities,
+    tick: u64,
+    entities: Vec<EntityChanges>,
+) {
+    for changes in entities {
+        // the entity may not have been spawned yet, or already be gone
+        let Some(replica) = remote_entities.replicas.get_mut(&changes.entity) else {
+            continue;
+        };
+        let components = &remote_entities.components;
+        for (id, bytes) in changes.changed {
+            set_component(world, registry, components, replica, id, tick, Some(&bytes));
+        }
+        for id in changes.removed {
+            set_component(world, registry, components, replica, id, tick, None);
+        }
+    }
+}
+
+pub(crate) fn apply_entity_messages(world: &mut World) {
+    world.resource_scope(|world, mut remote_entities: Mut<RemoteEntities>| {
+        world.resource_scope(|world, registry: Mut<ReplicationRegistry>| {
+            for message in std::mem::take(&mut remote_entities.queued) {
+                match message {
+                    ServerMessage::EntitySpawned {
+                        entity,
+                        tick,
+                        components,
+                    } => {
+                        if let Some(old) = remote_entities.replicas.remove(&entity) {
+                            world.despawn(old.entity);
+                        }
+                        let mut replica = Replica {
+                            entity: world.spawn(RemoteEntity(entity)).id(),
+                            ticks: HashMap::new(),
+                        };
+                        for (id, bytes) in components {
+                            set_component(
+                                world,
+                                &registry,
+                                &remote_entities.components,
+                                &mut replica,
+                                id,
+                                tick,
+                                Some(&bytes),
+                            );
+                        }
+                        remote_entities.replicas.insert(entity, replica);
+                    }
+                    ServerMessage::EntityDespawned { entity } => {
+                        if let Some(replica) = remote_entities.replicas.remove(&entity) {
+                            world.despawn(replica.entity);
+                        }
+                    }
+                    ServerMessage::EntityStates { tick, entities }
+                    | ServerMessage::EntityUpdates { tick, entities } => {
+                        apply_changes(world, &registry, &mut remote_entities, tick, entities);
+                    }
+                    other => warn!("Queued a message that isn't about entities: {:?}", other),
+                }
+            }
+        });
+    });
+}
+
+pub(crate) fn clear_remote_entities(
+    mut commands: Commands,
+    remote_entities: Query<Entity, With<RemoteEntity>>,
+) {
+    for entity in &remote_entities {
+        commands.entity(entity).despawn();
+    }
+    commands.remove_resource::<RemoteEntities>();
+}
+
+#[cfg(test)]
+mod tests {
+    use modcraft_lib::replication::PlayerName;
+
+    use super::*;
+
+    #[test]
+    fn older_changes_than_a_replica_has_are_dropped() {
+        let mut world = World::new();
+        let registry = ReplicationRegistry::default();
+        // the server knows a component this client doesn't, which is skipped
+        let names = [
+            "other:thing".to_string(),
+            "modcraft:player_name".to_string(),
+        ];
+        world.insert_resource(RemoteEntities::new(&names, &registry));
+        world.insert_resource(registry.clone());
+        let name = |name: &str| {
+            let mut server = World::new();
+            let entity = server.spawn(PlayerName(name.to_string())).id();
+            registry.components()[1]
+                .serialize(&server.entity(entity))
+                .unwrap()
+        };
+        let changes = |changed: Vec<(usize, Vec<u8>)>, removed: Vec<usize>| {
+            vec![EntityChanges {
+                entity: 7,
+                changed,
+                removed,
+            }]
+        };
+        let apply = |world: &mut World, messages: Vec<ServerMessage>| {
+            for message in messages {
+                world.resource_mut::<RemoteEntities>().queue(message);
+            }
+            apply_entity_messages(world);
+            let mut replicas = world.query::<(&RemoteEntity, Option<&PlayerName>)>();
+            replicas
+                .iter(world)
+                .map(|(remote, name)| (remote.0, name.map(|name| name.0.clone())))
+                .collect::<Vec<_>>()
+        };
+
+        let spawned = ServerMessage::EntitySpawned {
+            entity: 7,
+            tick: 1,
+            components: vec![(0, vec![1]), (1, name("Alex"))],
+        };
+        assert_eq!(
+            apply(&mut world, vec![spawned]),
+            [(7, Some("Alex".to_string()))]
+        );
+
+        // a lost update is made up for by the state, and an update arriving
+        // after a newer state changes nothing
+        let late = ServerMessage::EntityUpdates {
+            tick: 2,
+            entities: changes(vec![(1, name("Bo"))], vec![]),
+        };
+        let state = ServerMessage::EntityStates {
+            tick: 3,
+            entities: changes(vec![(1, name("Cy"))], vec![]),
+        };
+        assert_eq!(
+            apply(&mut world, vec![state, late]),
+            [(7, Some("Cy".to_string()))]
+        );
+
+        let removed = ServerMessage::EntityStates {
+            tick: 4,
+            entities: changes(vec![], vec![1]),
+        };
+        assert_eq!(apply(&mut world, vec![removed]), [(7, None)]);
+        assert_eq!(
+            apply(
+                &mut world,
+                vec![ServerMessage::EntityDespawned { entity: 7 }]
+            ),
+            []
+        );
+    }
+}
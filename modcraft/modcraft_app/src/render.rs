@@ -0,0 +1,525 @@
+//! Draws the chunks the client has been sent.
+//!
+//! Block textures are stacked into one array texture, each chunk is greedy
+//! meshed on the async compute pool whenever it or a neighbor changes, and
+//! the meshes are drawn with a small shader that picks the texture layer per
+//! vertex. The camera follows the local player, which is moved with the
+//! keyboard and mouse, and the other players are drawn as boxes.
+
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::{
+    asset::LoadedFolder,
+    input::mouse::MouseMotion,
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster},
+    prelude::*,
+    reflect::TypePath,
+    render::{
+        mesh::{Indices, MeshVertexAttribute, MeshVertexBufferLayout, VertexAttributeValues},
+        render_resource::{
+            AsBindGroup, Extent3d, PrimitiveTopology, RenderPipelineDescriptor, ShaderRef,
+            SpecializedMeshPipelineError, TextureDimension, VertexFormat,
+        },
+        texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor},
+    },
+    tasks::{block_on, AsyncComputeTaskPool, Task},
+    window::{CursorGrabMode, PrimaryWindow},
+};
+use modcraft_lib::{
+    blocks::BlockFace,
+    chunk::Chunk,
+    meshing::{mesh_chunk, BlockAppearances, ChunkMesh},
+    physics::{PlayerInput, PLAYER_HEIGHT, PLAYER_WIDTH},
+    replication::PlayerName,
+    world::ChunkPos,
+};
+
+use crate::{
+    client::{ChunkUpdated, ClientState, ServerBlocks, ServerWorld},
+    prediction::{LocalPlayer, MovementInput},
+    remote_entities::RemoteEntity,
+};
+
+const BLOCK_TEXTURE_DIR: &str = "textures/block";
+const CHUNK_SHADER: &str = "shaders/chunk.wgsl";
+
+// chunks meshed at once, so a burst of chunks doesn't starve the task pool
+const MAX_MESHING_TASKS: usize = 8;
+
+// radians turned per pixel of mouse movement
+const MOUSE_SENSITIVITY: f32 = 0.003;
+
+const ATTRIBUTE_TEXTURE_LAYER: MeshVertexAttribute =
+    MeshVertexAttribute::new("TextureLayer", 988_540_917, VertexFormat::Uint32);
+
+#[derive(Asset, AsBindGroup, TypePath, Debug, Clone)]
+struct ChunkMaterial {
+    #[texture(0, dimension = "2d_array")]
+    #[sampler(1)]
+    atlas: Handle<Image>,
+}
+
+impl Material for ChunkMaterial {
+    fn vertex_shader() -> ShaderRef {
+        CHUNK_SHADER.into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        CHUNK_SHADER.into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+            ATTRIBUTE_TEXTURE_LAYER.at_shader_location(3),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
+
+#[derive(Resource)]
+struct BlockTextureFolder(Handle<LoadedFolder>);
+
+/// Every block texture as one layer of an array texture.
+#[derive(Resource)]
+struct BlockAtlas {
+    layers: HashMap<String, u32>,
+    material: Handle<ChunkMaterial>,
+}
+
+#[derive(Resource, Default)]
+struct ChunkMeshes {
+    entities: HashMap<ChunkPos, Entity>,
+    dirty: Vec<ChunkPos>,
+    tasks: HashMap<ChunkPos, Task<ChunkMesh>>,
+    // None until the atlas is built and the server has sent its blocks
+    appearances: Option<Arc<BlockAppearances>>,
+}
+
+impl ChunkMeshes {
+    fn mark_dirty(&mut self, pos: ChunkPos) {
+        if !self.dirty.contains(&pos) {
+            self.dirty.push(pos);
+        }
+    }
+}
+
+fn load_block_textures(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(BlockTextureFolder(
+        asset_server.load_folder(BLOCK_TEXTURE_DIR),
+    ));
+}
+
+fn spawn_camera(mut commands: Commands) {
+    commands.spawn(Camera3dBundle {
+        transform: Transform::from_xyz(-24.0, 96.0, -24.0)
+            .looking_at(Vec3::new(8.0, 64.0, 8.0), Vec3::Y),
+        ..default()
+    });
+}
+
+fn build_block_atlas(
+    mut commands: Commands,
+    folder: Res<BlockTextureFolder>,
+    folders: Res<Assets<LoadedFolder>>,
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<ChunkMaterial>>,
+) {
+    if !asset_server.is_loaded_with_dependencies(&folder.0) {
+        return;
+    }
+    let Some(loaded) = folders.get(&folder.0) else {
+        return;
+    };
+    commands.remove_resource::<BlockTextureFolder>();
+
+    let mut textures: Vec<(String, &Image)> = loaded
+        .handles
+        .iter()
+        .filter_map(|handle| {
+            let handle = handle.clone().typed::<Image>();
+            let name = asset_server
+                .get_path(handle.id())?
+                .path()
+                .file_stem()?
+                .to_str()?
+                .to_string();
+            Some((name, images.get(&handle)?))
+        })
+        .collect();
+    textures.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let Some((_, first)) = textures.first() else {
+        error!("No block textures found in {}", BLOCK_TEXTURE_DIR);
+        return;
+    };
+
+    let size = first.texture_descriptor.size;
+    let format = first.texture_descriptor.format;
+    let mut layers = HashMap::new();
+    let mut data = Vec::new();
+    for (name, image) in &textures {
+        if image.texture_descriptor.size != size || image.texture_descriptor.format != format {
+            warn!(
+                "Block texture {} doesn't match the size and format of the others, skipping it",
+                name
+            );
+            continue;
+        }
+        layers.insert(name.clone(), layers.len() as u32);
+        data.extend_from_slice(&image.data);
+    }
+
+    let mut atlas = Image::new(
+        Extent3d {
+            width: size.width,
+            height: size.height * layers.len() as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        format,
+    );
+    atlas.reinterpret_stacked_2d_as_array(layers.len() as u32);
+    // merged faces cover several blocks, so the texture repeats across them
+    atlas.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..ImageSamplerDescriptor::nearest()
+    });
+
+    info!("Built block texture atlas with {} layers", layers.len());
+    let material = materials.add(ChunkMaterial {
+        atlas: images.add(atlas),
+    });
+    commands.insert_resource(BlockAtlas { layers, material });
+}
+
+fn update_block_appearances(
+    atlas: Option<Res<BlockAtlas>>,
+    server_blocks: Option<Res<ServerBlocks>>,
+    server_world: Option<Res<ServerWorld>>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+) {
+    let (Some(atlas), Some(server_blocks)) = (atlas, server_blocks) else {
+        chunk_meshes.appearances = None;
+        return;
+    };
+    if chunk_meshes.appearances.is_some() && !atlas.is_changed() && !server_blocks.is_changed() {
+        return;
+    }
+
+    chunk_meshes.appearances = Some(Arc::new(BlockAppearances::new(&server_blocks, |texture| {
+        atlas.layers.get(texture).copied().unwrap_or_else(|| {
+            warn!("Missing block texture {}", texture);
+            0
+        })
+    })));
+    // everything has to be meshed again with the new textures
+    if let Some(server_world) = server_world {
+        for (pos, _) in server_world.chunks() {
+            chunk_meshes.mark_dirty(pos);
+        }
+    }
+}
+
+fn mark_dirty_chunks(
+    mut chunk_updated_events: EventReader<ChunkUpdated>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+) {
+    for ChunkUpdated(pos) in chunk_updated_events.read() {
+        chunk_meshes.mark_dirty(*pos);
+    }
+}
+
+fn start_meshing_tasks(
+    mut commands: Commands,
+    server_world: Res<ServerWorld>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+) {
+    let Some(appearances) = chunk_meshes.appearances.clone() else {
+        return;
+    };
+    let pool = AsyncComputeTaskPool::get();
+
+    let mut index = 0;
+    while index < chunk_meshes.dirty.len() && chunk_meshes.tasks.len() < MAX_MESHING_TASKS {
+        let pos = chunk_meshes.dirty[index];
+        // wait for the running task, its result would be out of date anyway
+        if chunk_meshes.tasks.contains_key(&pos) {
+            index += 1;
+            continue;
+        }
+        chunk_meshes.dirty.remove(index);
+
+        let Some(chunk) = server_world.chunk(pos) else {
+            // unloaded, or a neighbor of a chunk the server hasn't sent
+            if let Some(entity) = chunk_meshes.entities.remove(&pos) {
+                commands.entity(entity).despawn();
+            }
+            continue;
+        };
+        let chunk = chunk.clone();
+        let neighbors: [Option<Chunk>; 6] = BlockFace::ALL.map(|face| {
+            let (dx, dy, dz) = face.offset();
+            server_world
+                .chunk(ChunkPos::new(pos.x + dx, pos.y + dy, pos.z + dz))
+                .cloned()
+        });
+        let appearances = appearances.clone();
+        let task = pool.spawn(async move {
+            let [top, bottom, north, south, east, west] = &neighbors;
+            let neighbors = [top, bottom, north, south, east, west].map(Option::as_ref);
+            mesh_chunk(&chunk, &neighbors, &appearances)
+        });
+        chunk_meshes.tasks.insert(pos, task);
+    }
+}
+
+fn to_bevy_mesh(chunk_mesh: ChunkMesh) -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, chunk_mesh.positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, chunk_mesh.normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, chunk_mesh.uvs);
+    mesh.insert_attribute(
+        ATTRIBUTE_TEXTURE_LAYER,
+        VertexAttributeValues::Uint32(chunk_mesh.layers),
+    );
+    mesh.set_indices(Some(Indices::U32(chunk_mesh.indices)));
+    mesh
+}
+
+fn finish_meshing_tasks(
+    mut commands: Commands,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+    atlas: Option<Res<BlockAtlas>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    let Some(atlas) = atlas else {
+        return;
+    };
+    let finished: Vec<_> = chunk_meshes
+        .tasks
+        .iter()
+        .filter(|(_, task)| task.is_finished())
+        .map(|(pos, _)| *pos)
+        .collect();
+
+    for pos in finished {
+        let task = chunk_meshes
+            .tasks
+            .remove(&pos)
+            .expect("Finished tasks exist");
+        let chunk_mesh = block_on(task);
+        let entity = chunk_meshes.entities.remove(&pos);
+        if chunk_mesh.is_empty() {
+            if let Some(entity) = entity {
+                commands.entity(entity).despawn();
+            }
+            continue;
+        }
+
+        let mesh = meshes.add(to_bevy_mesh(chunk_mesh));
+        let entity = match entity {
+            Some(entity) => {
+                commands.entity(entity).insert(mesh);
+                entity
+            }
+            None => {
+                let origin = pos.origin();
+                commands
+                    .spawn((
+                        MaterialMeshBundle {
+                            mesh,
+                            material: atlas.material.clone(),
+                            transform: Transform::from_xyz(
+                                origin.x as f32,
+                                origin.y as f32,
+                                origin.z as f32,
+                            ),
+                            ..default()
+                        },
+                        NotShadowCaster,
+                    ))
+                    .id()
+            }
+        };
+        chunk_meshes.entities.insert(pos, entity);
+    }
+}
+
+fn set_cursor_grab(window: &mut Window, grab: bool) {
+    window.cursor.grab_mode = if grab {
+        CursorGrabMode::Locked
+    } else {
+        CursorGrabMode::None
+    };
+    window.cursor.visible = !grab;
+}
+
+fn grab_cursor(mut windows: Query<&mut Window, With<PrimaryWindow>>) {
+    if let Ok(mut window) = windows.get_single_mut() {
+        set_cursor_grab(&mut window, true);
+    }
+}
+
+fn release_cursor(mut windows: Query<&mut Window, With<PrimaryWindow>>) {
+    if let Ok(mut window) = windows.get_single_mut() {
+        set_cursor_grab(&mut window, false);
+    }
+}
+
+// escape lets go of the mouse, clicking in the window takes it again
+fn toggle_cursor_grab(
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
+    if keys.just_pressed(KeyCode::Escape) {
+        set_cursor_grab(&mut window, false);
+    } else if mouse_buttons.just_pressed(MouseButton::Left) {
+        set_cursor_grab(&mut window, true);
+    }
+}
+
+pub(crate) fn read_movement_input(
+    keys: Res<Input<KeyCode>>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mut movement_input: ResMut<MovementInput>,
+) {
+    let grabbed = windows
+        .get_single()
+        .is_ok_and(|window| window.cursor.grab_mode != CursorGrabMode::None);
+    let mut look = Vec2::ZERO;
+    for motion in mouse_motion.read() {
+        look += motion.delta;
+    }
+    if !grabbed {
+        **movement_input = PlayerInput {
+            yaw: movement_input.yaw,
+            pitch: movement_input.pitch,
+            ..default()
+        };
+        return;
+    }
+
+    let axis = |positive: KeyCode, negative: KeyCode| {
+        keys.pressed(positive) as i32 as f32 - keys.pressed(negative) as i32 as f32
+    };
+    let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+    **movement_input = PlayerInput {
+        forward: axis(KeyCode::W, KeyCode::S),
+        right: axis(KeyCode::D, KeyCode::A),
+        yaw: (movement_input.yaw - look.x * MOUSE_SENSITIVITY) % std::f32::consts::TAU,
+        pitch: (movement_input.pitch - look.y * MOUSE_SENSITIVITY).clamp(-limit, limit),
+        jump: keys.pressed(KeyCode::Space),
+    };
+}
+
+fn follow_local_player(
+    local_player: Option<Res<LocalPlayer>>,
+    movement_input: Res<MovementInput>,
+    mut cameras: Query<&mut Transform, With<Camera3d>>,
+) {
+    let Some(body) = local_player.and_then(|local_player| local_player.body) else {
+        return;
+    };
+    for mut transform in &mut cameras {
+        transform.translation = body.eye_position();
+        transform.rotation =
+            Quat::from_euler(EulerRot::YXZ, movement_input.yaw, movement_input.pitch, 0.0);
+    }
+}
+
+// players are replicated with where their feet are, so the box stands on
+// its origin
+fn show_remote_players(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    players: Query<Entity, (With<RemoteEntity>, Added<PlayerName>)>,
+) {
+    for entity in &players {
+        let half_width = PLAYER_WIDTH / 2.0;
+        let body = shape::Box {
+            min_x: -half_width,
+            max_x: half_width,
+            min_y: 0.0,
+            max_y: PLAYER_HEIGHT,
+            min_z: -half_width,
+            max_z: half_width,
+        };
+        commands.entity(entity).insert((
+            meshes.add(body.into()),
+            materials.add(Color::rgb(0.8, 0.3, 0.2).into()),
+            GlobalTransform::default(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+fn despawn_chunk_meshes(mut commands: Commands, mut chunk_meshes: ResMut<ChunkMeshes>) {
+    for (_, entity) in chunk_meshes.entities.drain() {
+        commands.entity(entity).despawn();
+    }
+    chunk_meshes.dirty.clear();
+    chunk_meshes.tasks.clear();
+}
+
+pub(crate) struct ChunkRenderPlugin;
+
+impl Plugin for ChunkRenderPlugin {
+    fn build(&self, app: &mut App) {
+        // the prepass shaders don't know about the texture layer attribute
+        app.add_plugins(MaterialPlugin::<ChunkMaterial> {
+            prepass_enabled: false,
+            ..default()
+        });
+
+        app.init_resource::<ChunkMeshes>();
+
+        app.add_systems(Startup, (load_block_textures, spawn_camera));
+        app.add_systems(
+            Update,
+            build_block_atlas.run_if(resource_exists::<BlockTextureFolder>()),
+        );
+        app.add_systems(
+            Update,
+            (
+                update_block_appearances,
+                mark_dirty_chunks,
+                start_meshing_tasks,
+                finish_meshing_tasks,
+            )
+                .chain()
+                .run_if(in_state(ClientState::InGame)),
+        );
+        app.add_systems(
+            Update,
+            (toggle_cursor_grab, read_movement_input, follow_local_player)
+                .chain()
+                .run_if(in_state(ClientState::InGame)),
+        );
+        app.add_systems(
+            Update,
+            show_remote_players.run_if(in_state(ClientState::InGame)),
+        );
+        app.add_systems(OnEnter(ClientState::InGame), grab_cursor);
+        app.add_systems(
+            OnExit(ClientState::InGame),
+            (despawn_chunk_meshes, release_cursor),
+        );
+    }
+}
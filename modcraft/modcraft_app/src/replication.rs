@@ -0,0 +1,319 @@
+//! Keeps every client in sync with the replicated entities around its player.
+//!
+//! Each tick the replicated components of every entity marked `Replicated`
+//! are serialized and compared with what each client was last sent.
+//! Entities coming into a client's view are spawned on it with all of their
+//! components and the ones leaving it are despawned, both reliably.
+//! Components that changed go in unreliable updates, since the next change
+//! makes up for a lost one, and once a component stops changing its last
+//! value is sent again reliably, so the client always ends up with it.
+//! Removed components are sent reliably too.
+//!
+//! A client's own player isn't sent to it, it is told where it is with
+//! player states instead.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::{ecs::system::SystemState, prelude::*};
+use bevy_quinnet::shared::ClientId;
+use modcraft_lib::{
+    chunk::CHUNK_SIZE,
+    replication::{Replicated, ReplicationRegistry},
+};
+
+use crate::{
+    players::Player,
+    protocol::{EntityChanges, ServerMessage},
+    streaming::ViewDistance,
+    ticks::ServerTick,
+    transport::ServerTransport,
+    wire::Writer,
+};
+
+// how far past the view distance an entity has to go to be despawned, so
+// entities at the edge don't keep coming and going
+const VIEW_MARGIN: f32 = 8.0;
+// the most the changes to one entity may take in an unreliable update, so
+// updates fit in a datagram; bigger changes are sent reliably
+const MAX_UPDATE_BYTES: usize = 1000;
+
+// a replicated entity as it is this tick
+#[derive(Debug, Clone)]
+struct Snapshot {
+    entity: Entity,
+    position: Option<Vec3>,
+    // the bytes of every registered component, None for the ones it lacks
+    components: Vec<Option<Vec<u8>>>,
+}
+
+// what a client was last sent of an entity
+#[derive(Debug, Clone)]
+struct SentEntity {
+    components: Vec<Option<Vec<u8>>>,
+    // components whose last change was sent unreliably
+    unconfirmed: HashSet<usize>,
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct Replication {
+    clients: HashMap<ClientId, HashMap<Entity, SentEntity>>,
+}
+
+fn encoded_len(changes: &EntityChanges) -> usize {
+    let mut writer = Writer::default();
+    writer.put(changes);
+    writer.into_bytes().len()
+}
+
+// what a client viewing from `center` needs to be up to date, with `own` its
+// own player
+fn client_messages(
+    sent: &mut HashMap<Entity, SentEntity>,
+    snapshots: &[Snapshot],
+    own: Entity,
+    center: Vec3,
+    distance: f32,
+    tick: u64,
+) -> Vec<ServerMessage> {
+    let mut messages = Vec::new();
+    let mut states = Vec::new();
+    let mut updates = Vec::new();
+    let mut in_view = HashSet::new();
+    for snapshot in snapshots {
+        let entity = snapshot.entity;
+        let margin = if sent.contains_key(&entity) {
+            VIEW_MARGIN
+        } else {
+            0.0
+        };
+        let far = snapshot
+            .position
+            .is_some_and(|pos| pos.distance(center) > distance + margin);
+        if entity == own || far {
+            continue;
+        }
+        in_view.insert(entity);
+
+        let Some(last) = sent.get_mut(&entity) else {
+            let components = snapshot
+                .components
+                .iter()
+                .enumerate()
+                .filter_map(|(id, bytes)| Some((id, bytes.clone()?)))
+                .collect();
+            messages.push(ServerMessage::EntitySpawned {
+                entity: entity.to_bits(),
+                tick,
+                components,
+            });
+            let sent_entity = SentEntity {
+                components: snapshot.components.clone(),
+                unconfirmed: HashSet::new(),
+            };
+            sent.insert(entity, sent_entity);
+            continue;
+        };
+        let mut state = EntityChanges {
+            entity: entity.to_bits(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        };
+        let mut update = state.clone();
+        for (id, now) in snapshot.components.iter().enumerate() {
+            if *now == last.components[id] {
+                if last.unconfirmed.remove(&id) {
+                    state.changed.extend(now.clone().map(|bytes| (id, bytes)));
+                }
+                continue;
+            }
+            match now {
+                Some(bytes) => {
+                    update.changed.push((id, bytes.clone()));
+                    last.unconfirmed.insert(id);
+                }
+                None => {
+                    state.removed.push(id);
+                    last.unconfirmed.remove(&id);
+                }
+            }
+            last.components[id] = now.clone();
+        }
+        if encoded_len(&update) > MAX_UPDATE_BYTES {
+            for (id, _) in &update.changed {
+                last.unconfirmed.remove(id);
+            }
+            state.changed.append(&mut update.changed);
+        }
+        if !state.changed.is_empty() || !state.removed.is_empty() {
+            states.push(state);
+        }
+        if !update.changed.is_empty() {
+            updates.push(update);
+        }
+    }
+
+    sent.retain(|entity, _| {
+        let keep = in_view.contains(entity);
+        if !keep {
+            messages.push(ServerMessage::EntityDespawned {
+                entity: entity.to_bits(),
+            });
+        }
+        keep
+    });
+    if !states.is_empty() {
+        messages.push(ServerMessage::EntityStates {
+            tick,
+            entities: states,
+        });
+    }
+    // as many entities in each update as fit in a datagram
+    let mut batch = Vec::new();
+    let mut batch_len = 0;
+    for update in updates {
+        let len = encoded_len(&update);
+        if batch_len + len > MAX_UPDATE_BYTES {
+            messages.push(ServerMessage::EntityUpdates {
+                tick,
+                entities: std::mem::take(&mut batch),
+            });
+            batch_len = 0;
+        }
+        batch_len += len;
+        batch.push(update);
+    }
+    if !batch.is_empty() {
+        messages.push(ServerMessage::EntityUpdates {
+            tick,
+            entities: batch,
+        });
+    }
+    messages
+}
+
+// components are read through the whole world, since the registry only
+// knows them by their functions
+pub(crate) fn replicate_entities(world: &mut World) {
+    let snapshots = world.resource_scope(|world, registry: Mut<ReplicationRegistry>| {
+        let mut entities = world.query_filtered::<(Entity, Option<&Transform>), With<Replicated>>();
+        let world = &*world;
+        entities
+            .iter(world)
+            .map(|(entity, transform)| Snapshot {
+                entity,
+                position: transform.map(|transform| transform.translation),
+                components: registry
+                    .components()
+                    .iter()
+                    .map(|component| component.serialize(&world.entity(entity)))
+                    .collect(),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut state = SystemState::<(
+        ResMut<Replication>,
+        Res<ServerTick>,
+        Res<ViewDistance>,
+        ServerTransport,
+        Query<(Entity, &Player)>,
+    )>::new(world);
+    let (mut replication, tick, view_distance, transport, players) = state.get_mut(world);
+    let tick = tick.0;
+    let distance = (view_distance.0 * CHUNK_SIZE as i32) as f32;
+
+    // players that left start over if they come back
+    replication.clients.retain(|client_id, _| {
+        players
+            .iter()
+            .any(|(_, player)| player.client_id == *client_id)
+    });
+    for (own, player) in &players {
+        let sent = replication.clients.entry(player.client_id).or_default();
+        let center = player.body.position;
+        for message in client_messages(sent, &snapshots, own, center, distance, tick) {
+            if let Err(e) = transport.send(player.client_id, message) {
+                warn!("Failed to send entities to {}: {}", player.client_id, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use modcraft_lib::{physics::PlayerBody, replication::PlayerName};
+
+    use super::*;
+    use crate::{server::loopback_server_app, ticks::start_tick, transport::LOCAL_CLIENT_ID};
+
+    #[test]
+    fn clients_get_the_entities_they_can_see() {
+        let (mut app, mut loopback_client) = loopback_server_app();
+        app.insert_resource(ViewDistance(1))
+            .add_systems(Update, (start_tick, replicate_entities).chain());
+        let body = PlayerBody::new(Vec3::ZERO);
+        let uuid = default();
+        app.world.spawn(Player::new(LOCAL_CLIENT_ID, uuid, body));
+        let name = PlayerName("Alex".to_string());
+        let near = app
+            .world
+            .spawn((Replicated, Transform::default(), name))
+            .id();
+        let far = Transform::from_xyz(100.0, 0.0, 0.0);
+        app.world.spawn((Replicated, far));
+        let mut receive =
+            || std::iter::from_fn(|| loopback_client.try_receive()).collect::<Vec<_>>();
+
+        // only the entity within a chunk of the player is spawned
+        app.update();
+        let bits = near.to_bits();
+        let messages = receive();
+        assert!(matches!(
+            messages[..],
+            [ServerMessage::EntitySpawned { entity, tick: 1, ref components }]
+                if entity == bits && components.len() == 2
+        ));
+
+        // a change goes unreliably, then reliably once it settles
+        app.world.get_mut::<Transform>(near).unwrap().translation.x = 1.0;
+        app.update();
+        let moved = app.world.resource::<ReplicationRegistry>().components()[0]
+            .serialize(&app.world.entity(near))
+            .unwrap();
+        let changes = vec![EntityChanges {
+            entity: bits,
+            changed: vec![(0, moved)],
+            removed: Vec::new(),
+        }];
+        assert_eq!(
+            receive(),
+            [ServerMessage::EntityUpdates {
+                tick: 2,
+                entities: changes.clone(),
+            }]
+        );
+        app.update();
+        assert_eq!(
+            receive(),
+            [ServerMessage::EntityStates {
+                tick: 3,
+                entities: changes,
+            }]
+        );
+        app.update();
+        assert_eq!(receive(), []);
+
+        // removing a component is sent reliably, and moving out of view
+        // despawns the entity
+        app.world.entity_mut(near).remove::<PlayerName>();
+        app.update();
+        assert!(matches!(
+            receive()[..],
+            [ServerMessage::EntityStates { ref entities, .. }] if entities[0].removed == [1]
+        ));
+        app.world.get_mut::<Transform>(near).unwrap().translation.x = 30.0;
+        app.update();
+        assert_eq!(receive(), [ServerMessage::EntityDespawned { entity: bits }]);
+    }
+}
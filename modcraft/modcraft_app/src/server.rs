@@ -1,74 +1,345 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    time::Duration,
+};
 
-use bevy::{app::AppExit, prelude::*};
+use bevy::{ecs::system::SystemState, prelude::*, utils::Uuid};
 use bevy_quinnet::{
     server::{
-        certificate::CertificateRetrievalMode, ConnectionLostEvent, Endpoint, QuinnetServerPlugin,
-        Server, ServerConfiguration,
+        certificate::CertificateRetrievalMode, ConnectionLostEvent, Server, ServerConfiguration,
     },
-    shared::{channel::ChannelId, ClientId},
+    shared::{channel::ChannelType, ClientId, QuinnetError},
+};
+use modcraft_lib::{
+    blocks::BlockRegistry,
+    commands::{is_command, run_command, ChatCommands},
+    config::{CertificateMode, ServerConfig},
+    replication::ReplicationRegistry,
+    world::VoxelWorld,
+    worldgen::WorldGenerator,
 };
 
-use crate::protocol::{ClientMessage, ServerMessage};
-
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Default, States)]
-pub(crate) enum InternalServerState {
-    #[default]
-    Off,
-    Launching,
-    Running,
-}
+use crate::{
+    auth::{new_challenge, write_private, Challenge, KnownPlayers},
+    bans::{Bans, Whitelist},
+    block_actions::BlockAction,
+    commands::player_context,
+    generation::ChunkGeneration,
+    handshake::{check_hello, RejectReason},
+    mods::LoadedMods,
+    persistence::{AutosaveTimer, GameTime},
+    players::Player,
+    protocol::{ClientMessage, ServerMessage},
+    rate_limits::{RateLimiter, Verdict},
+    replication::Replication,
+    streaming::ChunkViewers,
+    ticks::{ServerTick, TickTimes},
+    transport::{ServerChannels, ServerTransport, LOCAL_CLIENT_ID},
+};
+#[cfg(any(test, feature = "dedicated-server"))]
+pub(crate) mod dedicated;
+#[cfg(not(feature = "dedicated-server"))]
+pub(crate) mod internal;
 
 #[derive(Resource, Debug, Clone, Default)]
 pub(crate) struct Users {
     names: HashMap<ClientId, String>,
+    // clients whose hello was accepted, allowed to join by signing this
+    challenges: HashMap<ClientId, Challenge>,
+    uuids: HashMap<ClientId, Uuid>,
+    // joined while the server was full, waiting in order for a place
+    queue: VecDeque<(ClientId, String)>,
+}
+
+impl Users {
+    pub(crate) fn contains(&self, client_id: ClientId) -> bool {
+        self.names.contains_key(&client_id)
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.names.keys().copied()
+    }
+
+    pub(crate) fn uuid(&self, client_id: ClientId) -> Option<Uuid> {
+        self.uuids.get(&client_id).copied()
+    }
+
+    pub(crate) fn name(&self, client_id: ClientId) -> Option<&str> {
+        self.names.get(&client_id).map(String::as_str)
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.names.values().cloned().collect()
+    }
+
+    /// The client playing as `name`, ignoring case.
+    pub(crate) fn find(&self, name: &str) -> Option<ClientId> {
+        self.names
+            .iter()
+            .find(|(_, other)| other.eq_ignore_ascii_case(name))
+            .map(|(client_id, _)| *client_id)
+    }
+
+    // where a client waits in the join queue, counting from 1
+    fn queue_position(&self, client_id: ClientId) -> Option<usize> {
+        self.queue
+            .iter()
+            .position(|(queued, _)| *queued == client_id)
+            .map(|index| index + 1)
+    }
+
+    pub(crate) fn find_uuid(&self, uuid: Uuid) -> Option<ClientId> {
+        self.uuids
+            .iter()
+            .find(|(_, other)| **other == uuid)
+            .map(|(client_id, _)| *client_id)
+    }
 }
 
+// commands from chat, run once the messages have been handled
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct PendingCommands(Vec<(ClientId, String)>);
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
 enum ServerSystems {
-    Startup,
     FixedUpdate,
-    OnExit,
 }
 
-pub(crate) fn handle_client_messages(mut server: ResMut<Server>, mut users: ResMut<Users>) {
-    let endpoint = server.endpoint_mut();
-    for client_id in endpoint.clients() {
-        while let Some(message) = endpoint.try_receive_message_from::<ClientMessage>(client_id) {
+// how often players in the join queue hear where they are, which also keeps
+// their clients from giving up on joining
+const QUEUE_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+// lets a player that joined into the game
+fn admit(
+    transport: &mut ServerTransport,
+    users: &mut Users,
+    block_registry: &BlockRegistry,
+    replication_registry: &ReplicationRegistry,
+    tick_rate: u32,
+    client_id: ClientId,
+    name: String,
+) {
+    info!("{} connected", name);
+    users.names.insert(client_id, name.clone());
+    // a client that left in the meantime is cleaned up once it is noticed
+    if let Err(e) = transport.send(
+        client_id,
+        ServerMessage::InitClient {
+            client_id,
+            usernames: users.names.clone(),
+            blocks: block_registry.blocks().to_vec(),
+            components: replication_registry
+                .components()
+                .iter()
+                .map(|component| component.name().to_string())
+                .collect(),
+            tick_rate,
+        },
+    ) {
+        warn!("Failed to send {} the world they joined: {}", client_id, e);
+    }
+    if let Err(e) = transport.send_group(
+        users.names.keys(),
+        ServerMessage::ClientConnected {
+            client_id,
+            username: name,
+        },
+    ) {
+        warn!("Failed to tell players {} joined: {}", client_id, e);
+    }
+}
+
+/// Tells a client why it is being removed, disconnects it, and tells the
+/// players left if it was playing.
+pub(crate) fn kick_client(
+    transport: &mut ServerTransport,
+    users: &mut ResMut<Users>,
+    client_id: ClientId,
+    reason: String,
+) {
+    let name = users.name(client_id).map(str::to_string);
+    if let Err(e) = transport.send(client_id, ServerMessage::Kicked { reason }) {
+        warn!("Failed to tell {} they were kicked: {}", client_id, e);
+    }
+    if let Err(e) = transport.disconnect_client(client_id) {
+        warn!("Failed to disconnect {}: {}", client_id, e);
+    }
+    handle_disconnect(transport, users, client_id);
+    if let Some(name) = name {
+        let message = ServerMessage::SystemMessage {
+            message: format!("{} was removed from the game", name),
+        };
+        if let Err(e) = transport.send_group(users.names.keys(), message) {
+            warn!("Failed to tell players {} was removed: {}", name, e);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn handle_client_messages(
+    time: Res<Time<Fixed>>,
+    mut transport: ServerTransport,
+    mut users: ResMut<Users>,
+    mut pending_commands: ResMut<PendingCommands>,
+    block_registry: Res<BlockRegistry>,
+    replication_registry: Res<ReplicationRegistry>,
+    mut players: Query<&mut Player>,
+    loaded_mods: Res<LoadedMods>,
+    config: Res<ServerConfig>,
+    mut known_players: ResMut<KnownPlayers>,
+    chat_commands: Res<ChatCommands>,
+    bans: Res<Bans>,
+    whitelist: Res<Whitelist>,
+    mut rate_limiter: ResMut<RateLimiter>,
+) {
+    let limits = &config.rate_limits;
+    let clients = transport.clients();
+    rate_limiter.refill(limits, time.timestep(), &clients);
+    for client_id in clients {
+        // the rest wait for the next ticks, so no client can hold up the tick
+        for _ in 0..limits.messages_per_tick {
+            let Some(message) = transport.try_receive_message_from(client_id) else {
+                break;
+            };
+            // the player hosting the world isn't limited
+            let verdict = if client_id == LOCAL_CLIENT_ID {
+                Verdict::Allow
+            } else {
+                rate_limiter.check(limits, client_id, &message)
+            };
+            match verdict {
+                Verdict::Allow => {}
+                Verdict::Drop => continue,
+                Verdict::Kick => {
+                    info!("Kicking {} for sending too many messages", client_id);
+                    let reason = "Kicked for sending too many messages".to_string();
+                    kick_client(&mut transport, &mut users, client_id, reason);
+                    break;
+                }
+            }
             match message {
-                ClientMessage::Join { name } => {
-                    if users.names.contains_key(&client_id) {
+                ClientMessage::Hello {
+                    protocol_version,
+                    game_version,
+                    mods,
+                } => {
+                    let checked = check_hello(
+                        protocol_version,
+                        &game_version,
+                        &mods,
+                        &loaded_mods,
+                        users.names.len() + users.queue.len(),
+                        config.max_players,
+                        config.join_queue,
+                    );
+                    let message = match checked {
+                        Ok(()) => {
+                            let challenge = new_challenge();
+                            users.challenges.insert(client_id, challenge);
+                            ServerMessage::HandshakeAccepted {
+                                motd: config.motd.clone(),
+                                challenge,
+                            }
+                        }
+                        Err(reason) => {
+                            info!("Rejected client {}: {}", client_id, reason);
+                            ServerMessage::HandshakeRejected { reason }
+                        }
+                    };
+                    if let Err(e) = transport.send(client_id, message) {
+                        warn!("Failed to answer hello from {}: {}", client_id, e);
+                    }
+                }
+                ClientMessage::Join {
+                    name,
+                    public_key,
+                    signature,
+                } => {
+                    // players in the join queue have joined too
+                    if users.uuids.contains_key(&client_id) {
                         warn!(
                             "Received a Join from an already connected client: {}",
                             client_id
                         );
+                        continue;
+                    }
+                    // a challenge only signs one join, retrying takes a new hello
+                    let Some(challenge) = users.challenges.remove(&client_id) else {
+                        warn!("Received a Join before a hello from client {}", client_id);
+                        continue;
+                    };
+                    // the host always gets in, everyone else queues once the server is full
+                    let full =
+                        client_id != LOCAL_CLIENT_ID && users.names.len() >= config.max_players;
+                    let joined = if full && users.queue.len() >= config.join_queue {
+                        Err(RejectReason::ServerFull {
+                            max_players: config.max_players,
+                        })
+                    } else if config.whitelist && !whitelist.contains(&name) {
+                        Err(RejectReason::NotWhitelisted)
                     } else {
-                        info!("{} connected", name);
-                        users.names.insert(client_id, name.clone());
-                        endpoint
-                            .send_message(
-                                client_id,
-                                ServerMessage::InitClient {
-                                    client_id,
-                                    usernames: users.names.clone(),
-                                },
-                            )
-                            .expect("Failed to send init client message to new client");
-                        endpoint
-                            .send_group_message(
-                                users.names.keys().into_iter(),
-                                ServerMessage::ClientConnected {
-                                    client_id,
-                                    username: name,
-                                },
-                            )
-                            .expect("Failed to send client connected message to clients");
+                        known_players
+                            .join(&challenge, &name, &public_key, &signature, |uuid| {
+                                users.uuids.values().any(|online| *online == uuid)
+                            })
+                            .and_then(|uuid| match bans.player(uuid) {
+                                Some(ban) => Err(RejectReason::Banned(ban.reason.clone())),
+                                None => Ok(uuid),
+                            })
+                    };
+                    let uuid = match joined {
+                        Ok(uuid) => uuid,
+                        Err(reason) => {
+                            info!("Rejected {} joining as {}: {}", client_id, name, reason);
+                            let message = ServerMessage::JoinRejected { reason };
+                            if let Err(e) = transport.send(client_id, message) {
+                                warn!("Failed to answer join from {}: {}", client_id, e);
+                            }
+                            continue;
+                        }
+                    };
+                    users.uuids.insert(client_id, uuid);
+                    if full {
+                        users.queue.push_back((client_id, name.clone()));
+                        let position = users.queue.len();
+                        info!("{} is number {} in the join queue", name, position);
+                        let message = ServerMessage::QueuePosition { position };
+                        if let Err(e) = transport.send(client_id, message) {
+                            warn!("Failed to answer join from {}: {}", client_id, e);
+                        }
+                        continue;
                     }
+                    admit(
+                        &mut transport,
+                        &mut users,
+                        &block_registry,
+                        &replication_registry,
+                        config.tick_rate,
+                        client_id,
+                        name,
+                    );
                 }
                 ClientMessage::Disconnect {} => {
-                    // add something to disconnect clients if host quits
-                    endpoint.disconnect_client(client_id).unwrap();
-                    handle_disconnect(endpoint, &mut users, client_id);
+                    transport.disconnect_client(client_id).unwrap();
+                    handle_disconnect(&transport, &mut users, client_id);
+                }
+                // players in the join queue can't chat yet
+                ClientMessage::ChatMessage { .. } if !users.contains(client_id) => {}
+                ClientMessage::ChatMessage { message } if is_command(&message) => {
+                    info!("Command | {:?}: {}", users.names.get(&client_id), message);
+                    pending_commands.0.push((client_id, message));
+                }
+                ClientMessage::CompleteCommand { line } => {
+                    let Some(context) = player_context(client_id, &users, &known_players) else {
+                        continue;
+                    };
+                    let suggestions = chat_commands.complete(&line, &context);
+                    let message = ServerMessage::CommandSuggestions { suggestions };
+                    if let Err(e) = transport.send(client_id, message) {
+                        warn!("Failed to send suggestions to {}: {}", client_id, e);
+                    }
                 }
                 ClientMessage::ChatMessage { message } => {
                     info!(
@@ -76,37 +347,140 @@ pub(crate) fn handle_client_messages(mut server: ResMut<Server>, mut users: ResM
                         users.names.get(&client_id),
                         message
                     );
-                    endpoint
-                        .send_group_message_on(
-                            users.names.keys().into_iter(),
-                            ChannelId::UnorderedReliable,
-                            ServerMessage::ChatMessage { client_id, message },
-                        )
-                        .expect("Failed to send group message with chat");
+                    if let Err(e) = transport.send_group(
+                        users.names.keys(),
+                        ServerMessage::ChatMessage { client_id, message },
+                    ) {
+                        warn!("Failed to send chat from {}: {}", client_id, e);
+                    }
+                }
+                ClientMessage::PlayerInput { sequence, input } => {
+                    // inputs sent before the player spawned are dropped
+                    if let Some(mut player) = players
+                        .iter_mut()
+                        .find(|player| player.client_id == client_id)
+                    {
+                        player.queue_input(sequence, input);
+                    }
+                }
+                ClientMessage::BreakBlock { pos } => {
+                    if let Some(mut player) = players
+                        .iter_mut()
+                        .find(|player| player.client_id == client_id)
+                    {
+                        player.queue_block_action(BlockAction::Break(pos));
+                    }
+                }
+                ClientMessage::PlaceBlock { pos, block } => {
+                    if let Some(mut player) = players
+                        .iter_mut()
+                        .find(|player| player.client_id == client_id)
+                    {
+                        player.queue_block_action(BlockAction::Place(pos, block));
+                    }
                 }
             }
         }
     }
 }
 
+/// Lets players in the join queue in as places free up, and tells the rest
+/// where they are.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn admit_queued_players(
+    time: Res<Time<Fixed>>,
+    mut since_update: Local<Duration>,
+    mut last_length: Local<usize>,
+    mut transport: ServerTransport,
+    mut users: ResMut<Users>,
+    config: Res<ServerConfig>,
+    block_registry: Res<BlockRegistry>,
+    replication_registry: Res<ReplicationRegistry>,
+) {
+    while users.names.len() < config.max_players {
+        let Some((client_id, name)) = users.queue.pop_front() else {
+            break;
+        };
+        admit(
+            &mut transport,
+            &mut users,
+            &block_registry,
+            &replication_registry,
+            config.tick_rate,
+            client_id,
+            name,
+        );
+    }
+
+    *since_update += time.timestep();
+    // players that left or got in moved everyone behind them up
+    let moved = users.queue.len() < *last_length;
+    *last_length = users.queue.len();
+    if !moved && *since_update < QUEUE_UPDATE_INTERVAL {
+        return;
+    }
+    *since_update = Duration::ZERO;
+    for (index, (client_id, _)) in users.queue.iter().enumerate() {
+        let message = ServerMessage::QueuePosition {
+            position: index + 1,
+        };
+        if let Err(e) = transport.send(*client_id, message) {
+            warn!("Failed to send {} their queue position: {}", client_id, e);
+        }
+    }
+}
+
+pub(crate) fn run_chat_commands(world: &mut World) {
+    let pending_commands = std::mem::take(&mut world.resource_mut::<PendingCommands>().0);
+    for (client_id, line) in pending_commands {
+        // players can be kicked by an earlier command
+        let Some(context) = player_context(
+            client_id,
+            world.resource::<Users>(),
+            world.resource::<KnownPlayers>(),
+        ) else {
+            continue;
+        };
+        let (Ok(message) | Err(message)) = run_command(world, &context, &line);
+
+        let mut transport_state = SystemState::<ServerTransport>::new(world);
+        if let Err(e) = transport_state
+            .get_mut(world)
+            .send(client_id, ServerMessage::CommandOutput { message })
+        {
+            warn!("Failed to send command output to {}: {}", client_id, e);
+        }
+    }
+}
+
 fn handle_server_events(
     mut connection_lost_events: EventReader<ConnectionLostEvent>,
-    mut server: ResMut<Server>,
+    transport: ServerTransport,
     mut users: ResMut<Users>,
 ) {
     for client in connection_lost_events.read() {
-        handle_disconnect(server.endpoint_mut(), &mut users, client.id);
+        handle_disconnect(&transport, &mut users, client.id);
     }
 }
 
-fn handle_disconnect(endpoint: &mut Endpoint, users: &mut ResMut<Users>, client_id: ClientId) {
-    if let Some(username) = users.names.remove(&client_id) {
-        endpoint
-            .send_group_message(
-                users.names.keys().into_iter(),
-                ServerMessage::ClientDisconnected { client_id },
-            )
-            .expect("Failed to send user disconnected group message");
+pub(crate) fn handle_disconnect(
+    transport: &ServerTransport,
+    users: &mut ResMut<Users>,
+    client_id: ClientId,
+) {
+    users.challenges.remove(&client_id);
+    users.uuids.remove(&client_id);
+    if let Some(position) = users.queue_position(client_id) {
+        if let Some((_, name)) = users.queue.remove(position - 1) {
+            info!("{} left the join queue", name);
+        }
+    } else if let Some(username) = users.names.remove(&client_id) {
+        if let Err(e) = transport.send_group(
+            users.names.keys(),
+            ServerMessage::ClientDisconnected { client_id },
+        ) {
+            warn!("Failed to tell players {} left: {}", username, e);
+        }
         info!("{} disconnected", username);
     } else {
         warn!(
@@ -116,97 +490,294 @@ fn handle_disconnect(endpoint: &mut Endpoint, users: &mut ResMut<Users>, client_
     }
 }
 
-fn start_listening(mut server: ResMut<Server>) {
-    info!("Starting endpoint!");
+fn certificate_retrieval_mode(certificate: &CertificateMode) -> CertificateRetrievalMode {
+    match certificate {
+        CertificateMode::SelfSigned { hostname } => CertificateRetrievalMode::GenerateSelfSigned {
+            server_hostname: hostname.clone(),
+        },
+        CertificateMode::Persistent {
+            cert_file,
+            key_file,
+            ..
+        }
+        | CertificateMode::Files {
+            cert_file,
+            key_file,
+        } => CertificateRetrievalMode::LoadFromFile {
+            cert_file: cert_file.display().to_string(),
+            key_file: key_file.display().to_string(),
+        },
+    }
+}
 
-    server
-        .start_endpoint(
-            ServerConfiguration::from_string("0.0.0.0:6006").unwrap(),
-            CertificateRetrievalMode::GenerateSelfSigned {
-                server_hostname: "127.0.0.1".to_string(),
-            },
-        )
-        .expect("Server failed to start endpoint");
+// quinnet signs a certificate again when saving it, so the saved one wouldn't
+// be the one it served the first time; persistent ones are made here instead
+fn save_new_certificate(
+    hostname: &str,
+    cert_file: &Path,
+    key_file: &Path,
+) -> Result<(), QuinnetError> {
+    let certificate = rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
+    for file in [cert_file, key_file] {
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(cert_file, certificate.serialize_pem()?)?;
+    write_private(key_file, certificate.serialize_private_key_pem())?;
+    info!("Saved a new certificate to {}", cert_file.display());
+    Ok(())
 }
 
-fn handle_app_exit(
-    app_exit_events: EventReader<AppExit>,
-    server: ResMut<Server>,
-    users: Res<Users>,
-) {
-    if !app_exit_events.is_empty() {
-        on_server_exit(server, users);
+// whether the certificate in the file is for the hostname, None if it can't
+// be read
+fn certificate_is_for(cert_file: &Path, hostname: &str) -> Option<bool> {
+    let pem = fs::read(cert_file).ok()?;
+    let der = rustls_pemfile::certs(&mut pem.as_slice())
+        .ok()?
+        .into_iter()
+        .next()?;
+    let certificate = webpki::EndEntityCert::try_from(der.as_slice()).ok()?;
+    let name = webpki::SubjectNameRef::try_from_ascii_str(hostname).ok()?;
+    Some(certificate.verify_is_valid_for_subject_name(name).is_ok())
+}
+
+// generates the certificate the first time, and reuses it after that so
+// clients that pinned it keep trusting the server
+fn prepare_persistent_certificate(
+    hostname: &str,
+    cert_file: &Path,
+    key_file: &Path,
+) -> Result<(), QuinnetError> {
+    if !cert_file.exists() || !key_file.exists() {
+        return save_new_certificate(hostname, cert_file, key_file);
     }
+    if certificate_is_for(cert_file, hostname) == Some(false) {
+        warn!(
+            "The certificate in {} is not for {}, delete it and {} to generate a new one",
+            cert_file.display(),
+            hostname,
+            key_file.display()
+        );
+    }
+    Ok(())
 }
 
-fn on_server_exit(mut server: ResMut<Server>, users: Res<Users>) {
+fn open_endpoint(
+    server: &mut Server,
+    config: &ServerConfig,
+) -> Result<ServerChannels, QuinnetError> {
+    info!("Starting endpoint!");
+
+    if let CertificateMode::Persistent {
+        hostname,
+        cert_file,
+        key_file,
+    } = &config.certificate
+    {
+        prepare_persistent_certificate(hostname, cert_file, key_file)?;
+    }
+
+    let (certificate, _) = server.start_endpoint(
+        ServerConfiguration::from_ip(config.bind_address, config.port),
+        certificate_retrieval_mode(&config.certificate),
+    )?;
+    // players can check this against what their client pinned
+    info!("Certificate fingerprint: {}", certificate.fingerprint);
+    let endpoint = server.endpoint_mut();
+    Ok(ServerChannels {
+        world_edits: endpoint.open_channel(ChannelType::OrderedReliable)?,
+        bulk: endpoint.open_channel(ChannelType::OrderedReliable)?,
+        entities: endpoint.open_channel(ChannelType::OrderedReliable)?,
+    })
+}
+
+// players have already been told by now
+fn on_server_exit(mut transport: ServerTransport) {
     info!("Server exiting!");
 
-    let endpoint = server.endpoint();
-    endpoint
-        .send_group_message(
-            users.names.keys().into_iter(),
-            ServerMessage::ServerStopping {},
-        )
-        .expect("Server failed to send group message that it is stopping");
-    server
-        .stop_endpoint()
+    transport
+        .stop()
         .expect("Server failed to stop its endpoint");
 }
 
-fn set_internal_server_ready(
-    mut next_internal_server_state: ResMut<NextState<InternalServerState>>,
+// the resources both server plugins start with, each adds the ones that come
+// from the config itself
+fn init_server_resources(app: &mut App) {
+    app.init_resource::<Users>()
+        .init_resource::<KnownPlayers>()
+        .init_resource::<Bans>()
+        .init_resource::<Whitelist>()
+        .init_resource::<RateLimiter>()
+        .init_resource::<PendingCommands>()
+        .init_resource::<BlockRegistry>()
+        .init_resource::<ChatCommands>()
+        .init_resource::<VoxelWorld>()
+        .init_resource::<WorldGenerator>()
+        .init_resource::<ChunkGeneration>()
+        .init_resource::<ChunkViewers>()
+        .init_resource::<Replication>()
+        .init_resource::<ReplicationRegistry>()
+        .init_resource::<ServerTick>()
+        .init_resource::<TickTimes>()
+        .init_resource::<GameTime>()
+        .init_resource::<AutosaveTimer>();
+}
+
+/// An app with the resources the server's systems use and a loopback
+/// server, along with the client end of the loopback.
+#[cfg(test)]
+pub(crate) fn loopback_server_app() -> (App, crate::transport::loopback::LoopbackClient) {
+    let (loopback_server, loopback_client) = crate::transport::loopback::loopback_pair();
+    let mut app = App::new();
+    app.insert_resource(loopback_server)
+        .init_resource::<Time<Fixed>>()
+        .init_resource::<ServerConfig>()
+        .init_resource::<LoadedMods>();
+    init_server_resources(&mut app);
+    (app, loopback_client)
+}
+
+/// Says hello over the loopback and answers the challenge with a new
+/// player's join, which the server handles on the next update.
+#[cfg(test)]
+pub(crate) fn join_loopback(
+    app: &mut App,
+    client: &mut crate::transport::loopback::LoopbackClient,
+    name: &str,
 ) {
-    next_internal_server_state.set(InternalServerState::Running);
-}
-
-fn clear_users(mut users: ResMut<Users>) {
-    (*users).names.clear();
-}
-
-pub(crate) struct InternalServerPlugin;
-impl Plugin for InternalServerPlugin {
-    fn build(&self, app: &mut App) {
-        // TODO these need to be centralized
-        let startup_systems = (start_listening,);
-        let fixed_update_systems = (handle_client_messages, handle_server_events);
-        let exit_systems = (on_server_exit, clear_users);
-
-        app.add_plugins(QuinnetServerPlugin::default())
-            .add_state::<InternalServerState>()
-            .init_resource::<Users>()
-            .add_systems(
-                OnEnter(InternalServerState::Launching),
-                startup_systems.in_set(ServerSystems::Startup),
-            )
-            .add_systems(
-                OnEnter(InternalServerState::Launching),
-                set_internal_server_ready.after(ServerSystems::Startup),
-            )
-            .add_systems(
-                FixedUpdate,
-                fixed_update_systems
-                    .run_if(in_state(InternalServerState::Running))
-                    .in_set(ServerSystems::FixedUpdate),
-            )
-            .add_systems(
-                OnExit(InternalServerState::Running),
-                exit_systems.in_set(ServerSystems::OnExit),
-            ); // how does this work?
-    }
-}
-
-pub(crate) struct DedicatedServerPlugin;
-impl Plugin for DedicatedServerPlugin {
-    fn build(&self, app: &mut App) {
-        let startup_systems = (start_listening,);
-        let fixed_update_systems = (handle_client_messages, handle_server_events);
-        let post_update_systems = (handle_app_exit,);
-
-        app.add_plugins((MinimalPlugins, QuinnetServerPlugin::default()))
-            .init_resource::<Users>()
-            .add_systems(Startup, startup_systems)
-            .add_systems(FixedUpdate, fixed_update_systems)
-            .add_systems(PostUpdate, post_update_systems);
+    client
+        .send(ClientMessage::hello(&LoadedMods::default()))
+        .unwrap();
+    app.update();
+    match client.try_receive() {
+        Some(ServerMessage::HandshakeAccepted { challenge, .. }) => client
+            .send(crate::identity::Identity::generate(name).join(&challenge))
+            .unwrap(),
+        other => panic!("Unexpected message: {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_over_loopback() {
+        let (mut app, mut loopback_client) = loopback_server_app();
+        app.add_systems(Update, handle_client_messages);
+
+        // joining before the handshake is ignored
+        loopback_client
+            .send(crate::identity::Identity::generate("early").join(&[0; 32]))
+            .unwrap();
+        app.update();
+        assert!(loopback_client.try_receive().is_none());
+        assert!(!app.world.resource::<Users>().contains(LOCAL_CLIENT_ID));
+
+        // with the whitelist on, only the names on it get in
+        app.world.resource_mut::<ServerConfig>().whitelist = true;
+        join_loopback(&mut app, &mut loopback_client, "local");
+        app.update();
+        assert!(matches!(
+            loopback_client.try_receive(),
+            Some(ServerMessage::JoinRejected {
+                reason: RejectReason::NotWhitelisted
+            })
+        ));
+        // the rejected join used up its challenge, so retrying takes a new hello
+        loopback_client
+            .send(crate::identity::Identity::generate("local").join(&[0; 32]))
+            .unwrap();
+        app.update();
+        assert!(loopback_client.try_receive().is_none());
+        app.world
+            .resource_mut::<Whitelist>()
+            .0
+            .push("LOCAL".to_string());
+
+        join_loopback(&mut app, &mut loopback_client, "local");
+        app.update();
+
+        match loopback_client.try_receive() {
+            Some(ServerMessage::InitClient {
+                client_id,
+                usernames,
+                blocks,
+                components,
+                tick_rate,
+            }) => {
+                assert_eq!(client_id, LOCAL_CLIENT_ID);
+                assert_eq!(usernames.get(&LOCAL_CLIENT_ID).unwrap(), "local");
+                assert_eq!(blocks, BlockRegistry::default().blocks());
+                assert_eq!(components, ["modcraft:transform", "modcraft:player_name"]);
+                assert_eq!(tick_rate, ServerConfig::default().tick_rate);
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(matches!(
+            loopback_client.try_receive(),
+            Some(ServerMessage::ClientConnected { .. })
+        ));
+    }
+
+    #[test]
+    fn queued_players_get_in_as_places_free_up() {
+        let (mut app, mut loopback_client) = loopback_server_app();
+        app.add_systems(Update, admit_queued_players);
+        app.world.resource_mut::<ServerConfig>().max_players = 1;
+        // the host is never queued, so it stands in for a remote player here
+        let mut users = app.world.resource_mut::<Users>();
+        users.names.insert(5, "playing".to_string());
+        users.queue.push_back((7, "first".to_string()));
+        users
+            .queue
+            .push_back((LOCAL_CLIENT_ID, "local".to_string()));
+        app.update();
+        assert!(loopback_client.try_receive().is_none());
+
+        app.world.resource_mut::<Users>().queue.pop_front();
+        app.update();
+        assert!(matches!(
+            loopback_client.try_receive(),
+            Some(ServerMessage::QueuePosition { position: 1 })
+        ));
+
+        app.world.resource_mut::<Users>().names.remove(&5);
+        app.update();
+        assert!(matches!(
+            loopback_client.try_receive(),
+            Some(ServerMessage::InitClient { .. })
+        ));
+        assert!(app.world.resource::<Users>().contains(LOCAL_CLIENT_ID));
+        assert_eq!(
+            app.world
+                .resource::<Users>()
+                .queue_position(LOCAL_CLIENT_ID),
+            None
+        );
+    }
+
+    #[test]
+    fn persistent_certificates_are_made_once() {
+        let dir = std::env::temp_dir().join("modcraft_persistent_certificate");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+
+        prepare_persistent_certificate("127.0.0.1", &cert_file, &key_file).unwrap();
+        let saved = fs::read(&cert_file).unwrap();
+        assert_eq!(certificate_is_for(&cert_file, "127.0.0.1"), Some(true));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // a new hostname keeps the certificate clients pinned, with a warning
+        prepare_persistent_certificate("example.com", &cert_file, &key_file).unwrap();
+        assert_eq!(fs::read(&cert_file).unwrap(), saved);
+        assert_eq!(certificate_is_for(&cert_file, "example.com"), Some(false));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,138 @@
+//! The server on its own, configured by `server.toml` and the command line
+//! and run from a console.
+
+use bevy::{app::AppExit, log::LogPlugin, prelude::*};
+use bevy_quinnet::server::{QuinnetServerPlugin, Server};
+use modcraft_lib::{
+    commands::ChatCommands,
+    config::{ConfigArgs, ServerConfig},
+    save::WorldSave,
+};
+
+use super::{
+    admit_queued_players, handle_client_messages, handle_server_events, init_server_resources,
+    on_server_exit, open_endpoint, run_chat_commands, ServerSystems, Users,
+};
+use crate::{
+    block_actions::apply_block_actions,
+    commands::register_builtin_commands,
+    console::{register_console_commands, run_console_commands, start_console},
+    generation::{finish_generation_tasks, request_spawn_chunks, start_generation_tasks},
+    mods::load_mods,
+    persistence::{
+        advance_game_time, autosave, open_world, save_world_on_exit, NewWorldSeed, WorldDir,
+    },
+    players::{move_players, send_player_states, sync_player_transforms, sync_players},
+    protocol::{ServerMessage, StopReason},
+    replication::replicate_entities,
+    streaming::{send_block_changes, stream_chunks, ViewDistance},
+    ticks::{finish_tick, start_tick},
+    transport::ServerTransport,
+};
+
+fn start_listening(mut commands: Commands, mut server: ResMut<Server>, config: Res<ServerConfig>) {
+    let channels = open_endpoint(&mut server, &config).expect("Server failed to start endpoint");
+    commands.insert_resource(channels);
+}
+
+fn handle_app_exit(
+    app_exit_events: EventReader<AppExit>,
+    transport: ServerTransport,
+    users: Res<Users>,
+) {
+    if !app_exit_events.is_empty() {
+        if let Err(e) = transport.send_group(
+            users.names.keys(),
+            ServerMessage::ServerStopping {
+                reason: StopReason::Shutdown,
+            },
+        ) {
+            warn!("Server failed to tell clients that it is stopping: {}", e);
+        }
+        on_server_exit(transport);
+    }
+}
+
+// stops the server with the reason if the config or arguments are invalid
+fn load_config() -> ServerConfig {
+    let loaded = ConfigArgs::parse(std::env::args().skip(1))
+        .and_then(|args| ServerConfig::load(&args.path, &args.overrides));
+    match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub(crate) struct DedicatedServerPlugin;
+impl Plugin for DedicatedServerPlugin {
+    fn build(&self, app: &mut App) {
+        let startup_systems = (
+            start_listening,
+            start_console,
+            (open_world, apply_deferred, request_spawn_chunks).chain(),
+        );
+        let fixed_update_systems = (
+            (
+                handle_client_messages,
+                admit_queued_players,
+                run_chat_commands,
+                run_console_commands,
+                sync_players,
+                apply_deferred,
+                move_players,
+                apply_block_actions,
+                send_player_states,
+                sync_player_transforms,
+                replicate_entities,
+                send_block_changes,
+                stream_chunks,
+            )
+                .chain(),
+            handle_server_events,
+            (start_generation_tasks, finish_generation_tasks).chain(),
+            advance_game_time,
+            autosave.run_if(resource_exists::<WorldSave>()),
+        );
+        let post_update_systems = (
+            handle_app_exit,
+            save_world_on_exit.run_if(on_event::<AppExit>()),
+        );
+
+        // a config inserted before the plugin is used instead of server.toml
+        if !app.world.contains_resource::<ServerConfig>() {
+            app.insert_resource(load_config());
+        }
+        let config = app.world.resource::<ServerConfig>().clone();
+
+        init_server_resources(app);
+        app.add_plugins((
+            MinimalPlugins,
+            LogPlugin::default(),
+            QuinnetServerPlugin::default(),
+        ))
+        .insert_resource(ViewDistance(config.view_distance))
+        .insert_resource(WorldDir(config.world_dir.clone()))
+        .insert_resource(NewWorldSeed(config.seed))
+        .add_systems(Startup, startup_systems)
+        .add_systems(
+            FixedUpdate,
+            (
+                start_tick.before(ServerSystems::FixedUpdate),
+                fixed_update_systems.in_set(ServerSystems::FixedUpdate),
+                finish_tick.after(ServerSystems::FixedUpdate),
+            ),
+        )
+        .add_systems(PostUpdate, post_update_systems);
+        app.world
+            .resource_mut::<Time<Fixed>>()
+            .set_timestep_hz(config.tick_rate as f64);
+
+        let mut chat_commands = app.world.resource_mut::<ChatCommands>();
+        register_builtin_commands(&mut chat_commands);
+        register_console_commands(&mut chat_commands);
+        load_mods(app);
+    }
+}
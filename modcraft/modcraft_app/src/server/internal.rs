@@ -0,0 +1,183 @@
+//! The server a client runs for its own worlds, which other players can
+//! join too. It starts when the player opens a world and stops when they
+//! leave it, ready to open another.
+
+use bevy::prelude::*;
+use bevy_quinnet::server::{QuinnetServerPlugin, Server};
+use modcraft_lib::{
+    commands::ChatCommands, config::ServerConfig, save::WorldSave, world::VoxelWorld,
+};
+
+use super::{
+    admit_queued_players, handle_client_messages, handle_server_events, init_server_resources,
+    on_server_exit, open_endpoint, run_chat_commands, ServerSystems, Users,
+};
+use crate::{
+    block_actions::apply_block_actions,
+    commands::register_builtin_commands,
+    generation::{
+        finish_generation_tasks, request_spawn_chunks, start_generation_tasks, ChunkGeneration,
+    },
+    mods::load_mods,
+    persistence::{
+        advance_game_time, autosave, open_world, save_world_on_exit, NewWorldSeed, WorldDir,
+    },
+    players::{move_players, send_player_states, sync_player_transforms, sync_players, Player},
+    replication::{replicate_entities, Replication},
+    shutdown::{begin_shutdown, clear_shutdown, run_shutdown},
+    streaming::{send_block_changes, stream_chunks, ChunkViewers, ViewDistance},
+    ticks::{finish_tick, start_tick, TickTimes},
+    transport::{loopback::loopback_pair, LoopbackServer, ServerChannels},
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default, States)]
+pub(crate) enum InternalServerState {
+    #[default]
+    Off,
+    Launching,
+    Running,
+    /// Counting remote players down before stopping.
+    Stopping,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
+enum InternalServerSystems {
+    Startup,
+    OnExit,
+}
+
+fn start_internal_server(
+    mut commands: Commands,
+    mut server: ResMut<Server>,
+    mut time: ResMut<Time<Fixed>>,
+    config: Res<ServerConfig>,
+) {
+    time.set_timestep_hz(config.tick_rate as f64);
+
+    // the local player always connects in-process, so a busy port only
+    // means other players can't join
+    let (loopback_server, loopback_client) = loopback_pair();
+    commands.insert_resource(loopback_server);
+    commands.insert_resource(loopback_client);
+
+    match open_endpoint(&mut server, &config) {
+        Ok(channels) => commands.insert_resource(channels),
+        Err(e) => warn!("Internal server is not reachable over the network: {}", e),
+    }
+}
+
+fn close_loopback(mut commands: Commands) {
+    commands.remove_resource::<LoopbackServer>();
+    commands.remove_resource::<ServerChannels>();
+}
+
+fn set_internal_server_ready(
+    mut next_internal_server_state: ResMut<NextState<InternalServerState>>,
+) {
+    next_internal_server_state.set(InternalServerState::Running);
+}
+
+// the next world starts without anything left from this one
+fn clear_server_state(mut commands: Commands, players: Query<Entity, With<Player>>) {
+    for entity in &players {
+        commands.entity(entity).despawn();
+    }
+    commands.insert_resource(Users::default());
+    commands.insert_resource(Replication::default());
+    commands.insert_resource(ChunkViewers::default());
+    commands.insert_resource(TickTimes::default());
+}
+
+fn clear_world(mut voxel_world: ResMut<VoxelWorld>, mut generation: ResMut<ChunkGeneration>) {
+    // dropping the generation tasks cancels them
+    *generation = ChunkGeneration::default();
+
+    let stats = voxel_world.memory_stats();
+    info!(
+        "Unloading {} chunks ({} uniform) using {} bytes",
+        stats.chunks, stats.uniform_chunks, stats.bytes
+    );
+    voxel_world.clear();
+}
+
+pub(crate) struct InternalServerPlugin;
+impl Plugin for InternalServerPlugin {
+    fn build(&self, app: &mut App) {
+        // TODO these need to be centralized
+        let startup_systems = (
+            start_internal_server,
+            (open_world, apply_deferred, request_spawn_chunks).chain(),
+        );
+        let fixed_update_systems = (
+            (
+                handle_client_messages,
+                admit_queued_players,
+                run_chat_commands,
+                sync_players,
+                apply_deferred,
+                move_players,
+                apply_block_actions,
+                send_player_states,
+                sync_player_transforms,
+                replicate_entities,
+                send_block_changes,
+                stream_chunks,
+            )
+                .chain(),
+            handle_server_events,
+            (start_generation_tasks, finish_generation_tasks).chain(),
+            advance_game_time,
+            autosave.run_if(resource_exists::<WorldSave>()),
+        );
+        let exit_systems = (
+            (on_server_exit, close_loopback).chain(),
+            clear_shutdown,
+            clear_server_state,
+            (save_world_on_exit, clear_world).chain(),
+        );
+
+        init_server_resources(app);
+        app.add_plugins(QuinnetServerPlugin::default())
+            .add_state::<InternalServerState>()
+            .init_resource::<ServerConfig>()
+            .init_resource::<ViewDistance>()
+            .init_resource::<WorldDir>()
+            .init_resource::<NewWorldSeed>()
+            .add_systems(
+                OnEnter(InternalServerState::Launching),
+                startup_systems.in_set(InternalServerSystems::Startup),
+            )
+            .add_systems(
+                OnEnter(InternalServerState::Launching),
+                set_internal_server_ready.after(InternalServerSystems::Startup),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    start_tick.before(ServerSystems::FixedUpdate),
+                    fixed_update_systems.in_set(ServerSystems::FixedUpdate),
+                    finish_tick
+                        .after(ServerSystems::FixedUpdate)
+                        .after(run_shutdown),
+                )
+                    .run_if(
+                        in_state(InternalServerState::Running)
+                            .or_else(in_state(InternalServerState::Stopping)),
+                    ),
+            )
+            .add_systems(OnEnter(InternalServerState::Stopping), begin_shutdown)
+            .add_systems(
+                FixedUpdate,
+                run_shutdown
+                    .after(ServerSystems::FixedUpdate)
+                    .run_if(in_state(InternalServerState::Stopping)),
+            )
+            .add_systems(
+                OnExit(InternalServerState::Stopping),
+                exit_systems.in_set(InternalServerSystems::OnExit),
+            ); // how does this work?
+
+        register_builtin_commands(&mut app.world.resource_mut::<ChatCommands>());
+        load_mods(app);
+    }
+}
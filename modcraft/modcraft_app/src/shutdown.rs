@@ -0,0 +1,143 @@
+//! Stopping an internal server that remote players are on.
+//!
+//! When the host quits, remote players are counted down to the server
+//! stopping while the game goes on, then told why it stopped. The endpoint
+//! is only stopped once they have all disconnected, or after a short wait
+//! so the last messages have a chance to reach them.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::{
+    protocol::{ServerMessage, StopReason},
+    server::{internal::InternalServerState, Users},
+    transport::{ServerTransport, LOCAL_CLIENT_ID},
+};
+
+// dedicated servers stop without counting down
+const SHUTDOWN_COUNTDOWN: Duration = Duration::from_secs(5);
+// how long disconnected players get to receive the last messages
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Resource, Debug, Clone)]
+pub(crate) struct Shutdown {
+    reason: StopReason,
+    countdown: Timer,
+    // the seconds left that players were last told about
+    announced: Option<u32>,
+    // started once players are told the server is stopping
+    flush: Option<Timer>,
+}
+
+impl Shutdown {
+    fn new(reason: StopReason, countdown: Duration) -> Self {
+        Shutdown {
+            reason,
+            countdown: Timer::new(countdown, TimerMode::Once),
+            announced: None,
+            flush: None,
+        }
+    }
+
+    // what players should be told after this much time has passed
+    fn tick(&mut self, delta: Duration) -> Option<ServerMessage> {
+        if let Some(flush) = &mut self.flush {
+            flush.tick(delta);
+            return None;
+        }
+        if self.countdown.tick(delta).finished() {
+            self.flush = Some(Timer::new(FLUSH_TIMEOUT, TimerMode::Once));
+            return Some(ServerMessage::ServerStopping {
+                reason: self.reason,
+            });
+        }
+        let seconds = self.countdown.remaining().as_secs_f32().ceil() as u32;
+        if self.announced == Some(seconds) {
+            return None;
+        }
+        self.announced = Some(seconds);
+        Some(ServerMessage::StoppingSoon {
+            reason: self.reason,
+            seconds,
+        })
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.flush.as_ref().is_some_and(Timer::finished)
+    }
+}
+
+// remote players get a countdown, without any the server stops right away
+pub(crate) fn begin_shutdown(mut commands: Commands, users: Res<Users>) {
+    let countdown = if users.ids().any(|id| id != LOCAL_CLIENT_ID) {
+        info!("Stopping in {} seconds", SHUTDOWN_COUNTDOWN.as_secs());
+        SHUTDOWN_COUNTDOWN
+    } else {
+        Duration::ZERO
+    };
+    commands.insert_resource(Shutdown::new(StopReason::HostQuit, countdown));
+}
+
+pub(crate) fn run_shutdown(
+    time: Res<Time>,
+    mut shutdown: ResMut<Shutdown>,
+    transport: ServerTransport,
+    users: Res<Users>,
+    mut next_internal_server_state: ResMut<NextState<InternalServerState>>,
+) {
+    if let Some(message) = shutdown.tick(time.delta()) {
+        let remote: Vec<_> = users.ids().filter(|id| *id != LOCAL_CLIENT_ID).collect();
+        if let Err(e) = transport.send_group(remote.iter(), message) {
+            warn!("Failed to tell players the server is stopping: {}", e);
+        }
+    }
+
+    let connected = transport
+        .clients()
+        .into_iter()
+        .any(|client_id| client_id != LOCAL_CLIENT_ID);
+    if shutdown.flush.is_some() && (!connected || shutdown.is_flushed()) {
+        next_internal_server_state.set(InternalServerState::Off);
+    }
+}
+
+pub(crate) fn clear_shutdown(mut commands: Commands) {
+    commands.remove_resource::<Shutdown>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_down_before_stopping() {
+        let mut shutdown = Shutdown::new(StopReason::HostQuit, Duration::from_secs(3));
+        let step = Duration::from_millis(250);
+        let mut messages = Vec::new();
+        for _ in 0..16 {
+            messages.extend(shutdown.tick(step));
+        }
+        let countdown: Vec<_> = messages
+            .iter()
+            .map(|message| match message {
+                ServerMessage::StoppingSoon { seconds, .. } => Some(*seconds),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(countdown, [Some(3), Some(2), Some(1), None]);
+        assert!(matches!(
+            messages.last(),
+            Some(ServerMessage::ServerStopping {
+                reason: StopReason::HostQuit
+            })
+        ));
+
+        // waits a while for the last message to be sent
+        assert!(!shutdown.is_flushed());
+        for _ in 0..8 {
+            assert!(shutdown.tick(step).is_none());
+        }
+        assert!(shutdown.is_flushed());
+    }
+}
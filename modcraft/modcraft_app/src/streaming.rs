@@ -0,0 +1,258 @@
+//! Keeps every client supplied with the chunks around it.
+//!
+//! Each client has a view centered on its player's chunk. Chunks within the
+//! view distance are sent as they become available, closest first, chunks
+//! that fall out of view are unloaded again and changes to loaded chunks are
+//! sent as small deltas instead of whole chunks.
+//!
+//! Chunks go on the bulk channel and changes and unloads on the world edits
+//! one, so each time a chunk is sent to a client is numbered as a load, for
+//! the client to tell which changes and unloads go with which chunk data.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use bevy_quinnet::shared::ClientId;
+use modcraft_lib::{
+    config::DEFAULT_VIEW_DISTANCE,
+    world::{ChunkPos, VoxelWorld},
+    worldgen::WORLD_HEIGHT_CHUNKS,
+};
+
+use crate::{
+    generation::ChunkGeneration, players::Player, protocol::ServerMessage, server::Users,
+    transport::ServerTransport,
+};
+
+// chunks sent to each client per tick, so joining doesn't flood the connection
+const MAX_CHUNKS_PER_TICK: usize = 16;
+
+/// How many chunks around its center a client gets, horizontally. Every
+/// chunk of the world's height is sent.
+#[derive(Resource, Debug, Clone, Copy)]
+pub(crate) struct ViewDistance(pub(crate) i32);
+
+impl Default for ViewDistance {
+    fn default() -> Self {
+        ViewDistance(DEFAULT_VIEW_DISTANCE)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ClientView {
+    pub(crate) center: ChunkPos,
+    // chunks the client has been sent and not told to unload
+    loaded: HashSet<ChunkPos>,
+    // the number of the last load of every chunk sent
+    loads: HashMap<ChunkPos, u32>,
+}
+
+impl Default for ClientView {
+    fn default() -> Self {
+        ClientView {
+            center: ChunkPos::new(0, 0, 0),
+            loaded: HashSet::new(),
+            loads: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct ChunkViewers {
+    views: HashMap<ClientId, ClientView>,
+}
+
+// closest columns first, bottom to top
+fn chunks_in_view(center: ChunkPos, distance: i32) -> Vec<ChunkPos> {
+    let mut chunks = Vec::new();
+    for x in center.x - distance..=center.x + distance {
+        for z in center.z - distance..=center.z + distance {
+            for y in 0..WORLD_HEIGHT_CHUNKS {
+                chunks.push(ChunkPos::new(x, y, z));
+            }
+        }
+    }
+    chunks.sort_by_key(|pos| ((pos.x - center.x).pow(2) + (pos.z - center.z).pow(2), pos.y));
+    chunks
+}
+
+pub(crate) fn send_block_changes(
+    transport: ServerTransport,
+    mut voxel_world: ResMut<VoxelWorld>,
+    viewers: Res<ChunkViewers>,
+) {
+    let changes = voxel_world.take_changes();
+    if changes.is_empty() {
+        return;
+    }
+    for (client_id, view) in &viewers.views {
+        let changes: Vec<_> = changes
+            .iter()
+            .filter(|(pos, _)| view.loaded.contains(&pos.chunk()))
+            .copied()
+            .collect();
+        if changes.is_empty() {
+            continue;
+        }
+        if let Err(e) = transport.send(*client_id, ServerMessage::BlockChanges { changes }) {
+            warn!("Failed to send block changes to {}: {}", client_id, e);
+        }
+    }
+}
+
+pub(crate) fn stream_chunks(
+    transport: ServerTransport,
+    users: Res<Users>,
+    voxel_world: Res<VoxelWorld>,
+    view_distance: Res<ViewDistance>,
+    mut viewers: ResMut<ChunkViewers>,
+    mut generation: ResMut<ChunkGeneration>,
+    players: Query<&Player>,
+) {
+    viewers
+        .views
+        .retain(|client_id, _| users.contains(*client_id));
+    for client_id in users.ids() {
+        viewers.views.entry(client_id).or_default();
+    }
+    // views follow players around
+    for player in &players {
+        if let Some(view) = viewers.views.get_mut(&player.client_id) {
+            view.center = player.body.block().chunk();
+        }
+    }
+
+    let mut wanted = HashSet::new();
+    for (client_id, view) in &mut viewers.views {
+        let in_view = chunks_in_view(view.center, view_distance.0);
+        let in_view_set: HashSet<_> = in_view.iter().copied().collect();
+        wanted.extend(&in_view_set);
+
+        let out_of_view: Vec<_> = view.loaded.difference(&in_view_set).copied().collect();
+        for pos in out_of_view {
+            view.loaded.remove(&pos);
+            let load = view.loads[&pos];
+            if let Err(e) = transport.send(*client_id, ServerMessage::UnloadChunk { pos, load }) {
+                warn!("Failed to unload chunk {:?} for {}: {}", pos, client_id, e);
+            }
+        }
+
+        let mut sent = 0;
+        for pos in in_view {
+            if sent == MAX_CHUNKS_PER_TICK {
+                break;
+            }
+            if view.loaded.contains(&pos) {
+                continue;
+            }
+            let Some(chunk) = voxel_world.chunk(pos) else {
+                generation.request(pos);
+                continue;
+            };
+            let load = view.loads.get(&pos).map_or(1, |load| load + 1);
+            let message = ServerMessage::ChunkData {
+                pos,
+                data: chunk.encode(),
+                load,
+            };
+            if let Err(e) = transport.send(*client_id, message) {
+                warn!("Failed to send chunk {:?} to {}: {}", pos, client_id, e);
+                break;
+            }
+            view.loaded.insert(pos);
+            view.loads.insert(pos, load);
+            sent += 1;
+        }
+    }
+    generation.drop_unwanted(&wanted);
+}
+
+#[cfg(test)]
+mod tests {
+    use modcraft_lib::{chunk::Chunk, world::BlockPos};
+
+    use super::*;
+    use crate::{
+        server::{handle_client_messages, join_loopback, loopback_server_app},
+        transport::{loopback::LoopbackClient, LOCAL_CLIENT_ID},
+    };
+
+    fn received(client: &mut LoopbackClient) -> Vec<ServerMessage> {
+        std::iter::from_fn(|| client.try_receive()).collect()
+    }
+
+    #[test]
+    fn streams_chunks_changes_and_unloads() {
+        let (mut app, mut loopback_client) = loopback_server_app();
+        app.insert_resource(ViewDistance(0)).add_systems(
+            Update,
+            (handle_client_messages, send_block_changes, stream_chunks).chain(),
+        );
+
+        let mut voxel_world = app.world.resource_mut::<VoxelWorld>();
+        for y in 0..WORLD_HEIGHT_CHUNKS {
+            voxel_world.insert_chunk(ChunkPos::new(0, y, 0), Chunk::filled(y as u16));
+        }
+        join_loopback(&mut app, &mut loopback_client, "local");
+        app.update();
+
+        let chunks: Vec<_> = received(&mut loopback_client)
+            .into_iter()
+            .filter_map(|message| match message {
+                ServerMessage::ChunkData { pos, data, load } => {
+                    assert_eq!(load, 1);
+                    Some((pos, Chunk::decode(&data).unwrap()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(chunks.len(), WORLD_HEIGHT_CHUNKS as usize);
+        assert_eq!(chunks[2], (ChunkPos::new(0, 2, 0), Chunk::filled(2)));
+
+        // changes are only sent for chunks the client has
+        let mut voxel_world = app.world.resource_mut::<VoxelWorld>();
+        voxel_world.set_block(BlockPos::new(1, 2, 3), 7);
+        voxel_world.set_block(BlockPos::new(100, 2, 3), 7);
+        app.update();
+        match received(&mut loopback_client).as_slice() {
+            [ServerMessage::BlockChanges { changes }] => {
+                assert_eq!(changes, &[(BlockPos::new(1, 2, 3), 7)])
+            }
+            other => panic!("Unexpected messages: {:?}", other),
+        }
+
+        app.world
+            .resource_mut::<ChunkViewers>()
+            .views
+            .get_mut(&LOCAL_CLIENT_ID)
+            .unwrap()
+            .center = ChunkPos::new(5, 0, 0);
+        app.update();
+        let unloaded = received(&mut loopback_client)
+            .iter()
+            .filter(|message| matches!(message, ServerMessage::UnloadChunk { load: 1, .. }))
+            .count();
+        assert_eq!(unloaded, WORLD_HEIGHT_CHUNKS as usize);
+        assert!(app
+            .world
+            .resource::<ChunkGeneration>()
+            .is_pending(ChunkPos::new(5, 0, 0)));
+
+        // coming back is the chunks' second load
+        app.world
+            .resource_mut::<ChunkViewers>()
+            .views
+            .get_mut(&LOCAL_CLIENT_ID)
+            .unwrap()
+            .center = ChunkPos::new(0, 0, 0);
+        app.update();
+        assert!(received(&mut loopback_client)
+            .iter()
+            .any(|message| matches!(message, ServerMessage::ChunkData { load: 2, .. })));
+        // and the chunks left behind aren't loaded for nobody
+        assert!(!app
+            .world
+            .resource::<ChunkGeneration>()
+            .is_pending(ChunkPos::new(5, 0, 0)));
+    }
+}
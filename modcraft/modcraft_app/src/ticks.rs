@@ -0,0 +1,161 @@
+//! Numbers the server's ticks and keeps track of how long they take.
+//!
+//! The simulation runs in `FixedUpdate` at the configured tick rate, and Bevy
+//! runs as many fixed updates each frame as the time that passed calls for.
+//! A frame that takes too long is only caught up on in part, so a server
+//! whose ticks take longer than the tick rate allows falls behind for good,
+//! which is warned about every so often.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+// how many of the latest ticks the stats cover
+const TICK_SAMPLES: usize = 1000;
+// how far behind the server gets before it is warned about
+const MAX_BEHIND: Duration = Duration::from_secs(2);
+const WARNING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Counts the server's ticks from 1, and never goes back, unlike the world's
+/// time. Every message the server sends is stamped with it.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ServerTick(pub(crate) u64);
+
+#[derive(Debug, Clone, Copy)]
+struct TickSample {
+    start: Instant,
+    duration: Duration,
+}
+
+/// How long the latest ticks took, as `/tps` reports it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TickStats {
+    pub(crate) ticks: usize,
+    /// 0 with a single tick, which doesn't say how often they run.
+    pub(crate) ticks_per_second: f64,
+    pub(crate) mean: Duration,
+    pub(crate) p99: Duration,
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub(crate) struct TickTimes {
+    samples: VecDeque<TickSample>,
+    // when the tick running now started
+    started: Option<Instant>,
+    // the time that passed between ticks and wasn't simulated
+    behind: Duration,
+    last_warning: Option<Instant>,
+}
+
+impl TickTimes {
+    fn start(&mut self, now: Instant, timestep: Duration) {
+        // ticks run back to back to catch up take it back off
+        if let Some(last) = self.samples.back() {
+            self.behind = (self.behind + (now - last.start)).saturating_sub(timestep);
+        }
+        self.started = Some(now);
+    }
+
+    /// Records the tick that started last, returning how far behind the
+    /// server is if that should be warned about.
+    fn finish(&mut self, now: Instant) -> Option<Duration> {
+        let start = self.started.take()?;
+        if self.samples.len() == TICK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(TickSample {
+            start,
+            duration: now - start,
+        });
+
+        let warned_lately = self
+            .last_warning
+            .is_some_and(|warning| now - warning < WARNING_INTERVAL);
+        if self.behind <= MAX_BEHIND || warned_lately {
+            return None;
+        }
+        // the time is lost, so it is only warned about once
+        self.last_warning = Some(now);
+        Some(std::mem::take(&mut self.behind))
+    }
+
+    pub(crate) fn stats(&self) -> Option<TickStats> {
+        let first = self.samples.front()?;
+        let last = self.samples.back()?;
+        let mut durations: Vec<Duration> = self.samples.iter().map(|tick| tick.duration).collect();
+        durations.sort();
+        let ticks = durations.len();
+        let span = (last.start - first.start).as_secs_f64();
+        Some(TickStats {
+            ticks,
+            ticks_per_second: if span > 0.0 {
+                (ticks - 1) as f64 / span
+            } else {
+                0.0
+            },
+            mean: durations.iter().sum::<Duration>() / ticks as u32,
+            p99: durations[(ticks * 99).div_ceil(100) - 1],
+        })
+    }
+}
+
+pub(crate) fn start_tick(
+    time: Res<Time<Fixed>>,
+    mut tick: ResMut<ServerTick>,
+    mut tick_times: ResMut<TickTimes>,
+) {
+    tick.0 += 1;
+    tick_times.start(Instant::now(), time.timestep());
+}
+
+pub(crate) fn finish_tick(time: Res<Time<Fixed>>, mut tick_times: ResMut<TickTimes>) {
+    if let Some(behind) = tick_times.finish(Instant::now()) {
+        warn!(
+            "Can't keep up! Is the server overloaded? Running {} ms or {} ticks behind",
+            behind.as_millis(),
+            behind.as_nanos() / time.timestep().as_nanos()
+        );
+    }
+}
+
+// the tick keeps counting, only the times start over
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falling_behind_is_warned_about_once_in_a_while() {
+        let timestep = Duration::from_millis(50);
+        let ms = Duration::from_millis;
+        let origin = Instant::now();
+        let mut tick_times = TickTimes::default();
+        let mut tick = |start: Duration, took: Duration| {
+            tick_times.start(origin + start, timestep);
+            let behind = tick_times.finish(origin + start + took);
+            (behind, tick_times.stats().unwrap())
+        };
+
+        // a late tick is caught up on by the ones after it
+        let mut stats = None;
+        for i in 0..100 {
+            let start = ms(50 * i + if i == 10 { 40 } else { 0 });
+            let took = if i == 99 { ms(100) } else { ms(1) };
+            let (behind, now) = tick(start, took);
+            assert_eq!(behind, None);
+            stats = Some(now);
+        }
+        let stats = stats.unwrap();
+        assert_eq!(stats.ticks, 100);
+        assert!((stats.ticks_per_second - 20.0).abs() < 1e-9);
+        assert_eq!(stats.mean, Duration::from_micros(1990));
+        assert_eq!(stats.p99, ms(1));
+
+        // a long stall is warned about, and not again for a while
+        assert_eq!(tick(ms(8000), ms(1)).0, Some(ms(3000)));
+        assert_eq!(tick(ms(11000), ms(1)).0, None);
+        assert_eq!(tick(ms(30000), ms(1)).0, Some(ms(21900)));
+    }
+}
@@ -0,0 +1,260 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_quinnet::{
+    server::{Endpoint, Server},
+    shared::{channel::ChannelId, ClientId, QuinnetError},
+};
+use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    protocol::{Channel, ClientMessage, ServerMessage},
+    ticks::ServerTick,
+};
+
+#[cfg(not(feature = "dedicated-server"))]
+pub(crate) mod client;
+#[cfg(any(test, not(feature = "dedicated-server")))]
+pub(crate) mod loopback;
+
+// quinnet hands out client ids starting at 1, so 0 is free for the local player
+pub(crate) const LOCAL_CLIENT_ID: ClientId = 0;
+
+// channels the server opens on top of quinnet's defaults, which already
+// have the control, chat and movement ones
+#[derive(Resource, Debug, Clone, Copy)]
+pub(crate) struct ServerChannels {
+    pub(crate) world_edits: ChannelId,
+    pub(crate) bulk: ChannelId,
+    pub(crate) entities: ChannelId,
+}
+
+// server half of an in-process connection, used for singleplayer
+#[derive(Resource)]
+pub(crate) struct LoopbackServer {
+    to_client: UnboundedSender<ServerMessage>,
+    from_client: UnboundedReceiver<ClientMessage>,
+    connected: bool,
+}
+
+impl LoopbackServer {
+    fn send(&self, message: ServerMessage) -> Result<(), QuinnetError> {
+        if !self.connected {
+            return Err(QuinnetError::ClientAlreadyDisconnected(LOCAL_CLIENT_ID));
+        }
+        self.to_client
+            .send(message)
+            .map_err(|_| QuinnetError::InternalChannelClosed)
+    }
+
+    fn try_receive(&mut self) -> Option<ClientMessage> {
+        if !self.connected {
+            return None;
+        }
+        match self.from_client.try_recv() {
+            Ok(message) => Some(message),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+}
+
+/// Everything the server needs to talk to its clients, whether they are
+/// remote (quinnet) or the local player of an internal server (loopback).
+#[derive(SystemParam)]
+pub(crate) struct ServerTransport<'w> {
+    quinnet: Option<ResMut<'w, Server>>,
+    loopback: Option<ResMut<'w, LoopbackServer>>,
+    channels: Option<Res<'w, ServerChannels>>,
+    tick: Option<Res<'w, ServerTick>>,
+}
+
+impl<'w> ServerTransport<'w> {
+    fn is_local(&self, client_id: ClientId) -> bool {
+        client_id == LOCAL_CLIENT_ID && self.loopback.is_some()
+    }
+
+    // the local player gets messages as they are, so only remote ones are
+    // stamped
+    fn payload(&self, message: &ServerMessage) -> Vec<u8> {
+        message.encode_stamped(self.tick.as_ref().map_or(0, |tick| tick.0))
+    }
+
+    pub(crate) fn clients(&self) -> Vec<ClientId> {
+        let mut clients = self
+            .quinnet
+            .as_ref()
+            .and_then(|server| server.get_endpoint())
+            .map(|endpoint| endpoint.clients())
+            .unwrap_or_default();
+        if let Some(loopback) = &self.loopback {
+            if loopback.connected {
+                clients.push(LOCAL_CLIENT_ID);
+            }
+        }
+        clients
+    }
+
+    pub(crate) fn try_receive_message_from(
+        &mut self,
+        client_id: ClientId,
+    ) -> Option<ClientMessage> {
+        if self.is_local(client_id) {
+            return self.loopback.as_mut()?.try_receive();
+        }
+        let endpoint = self.quinnet.as_mut()?.get_endpoint_mut()?;
+        // a message that can't be decoded is skipped rather than ending the
+        // connection, it may just be one this build doesn't know yet
+        loop {
+            let payload = endpoint.try_receive_payload_from(client_id)?;
+            match ClientMessage::decode(&payload) {
+                Ok(message) => return Some(message),
+                Err(err) => warn!(
+                    "Skipped a message from client {} that couldn't be decoded: {}",
+                    client_id, err
+                ),
+            }
+        }
+    }
+
+    /// Sends a message on the channel its kind goes on.
+    pub(crate) fn send(
+        &self,
+        client_id: ClientId,
+        message: ServerMessage,
+    ) -> Result<(), QuinnetError> {
+        if self.is_local(client_id) {
+            return self.loopback.as_ref().unwrap().send(message);
+        }
+        let endpoint = self.endpoint()?;
+        match self.channel_id(message.channel())? {
+            Some(channel_id) => {
+                endpoint.send_payload_on(client_id, channel_id, self.payload(&message))
+            }
+            None => endpoint.send_payload(client_id, self.payload(&message)),
+        }
+    }
+
+    /// Sends a message to several clients, encoding it once.
+    pub(crate) fn send_group<'a, I: Iterator<Item = &'a ClientId>>(
+        &self,
+        client_ids: I,
+        message: ServerMessage,
+    ) -> Result<(), QuinnetError> {
+        let (local, remote): (Vec<&ClientId>, Vec<&ClientId>) =
+            client_ids.partition(|client_id| self.is_local(**client_id));
+
+        if !local.is_empty() {
+            self.loopback.as_ref().unwrap().send(message.clone())?;
+        }
+        if !remote.is_empty() {
+            let endpoint = self.endpoint()?;
+            let channel_id = self.channel_id(message.channel())?;
+            let payload = self.payload(&message);
+            for client_id in remote {
+                match channel_id {
+                    Some(channel_id) => {
+                        endpoint.send_payload_on(*client_id, channel_id, payload.clone())
+                    }
+                    None => endpoint.send_payload(*client_id, payload.clone()),
+                }?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn disconnect_client(&mut self, client_id: ClientId) -> Result<(), QuinnetError> {
+        if self.is_local(client_id) {
+            self.loopback.as_mut().unwrap().connected = false;
+            return Ok(());
+        }
+        self.quinnet
+            .as_mut()
+            .and_then(|server| server.get_endpoint_mut())
+            .ok_or(QuinnetError::EndpointAlreadyClosed)?
+            .disconnect_client(client_id)
+    }
+
+    // stops accepting remote clients and drops the local one
+    pub(crate) fn stop(&mut self) -> Result<(), QuinnetError> {
+        if let Some(loopback) = &mut self.loopback {
+            loopback.connected = false;
+        }
+        match &mut self.quinnet {
+            Some(server) if server.is_listening() => server.stop_endpoint(),
+            _ => Ok(()),
+        }
+    }
+
+    fn endpoint(&self) -> Result<&Endpoint, QuinnetError> {
+        self.quinnet
+            .as_ref()
+            .and_then(|server| server.get_endpoint())
+            .ok_or(QuinnetError::EndpointAlreadyClosed)
+    }
+
+    // None for the endpoint's default channel
+    fn channel_id(&self, channel: Channel) -> Result<Option<ChannelId>, QuinnetError> {
+        let opened = |pick: fn(&ServerChannels) -> ChannelId| {
+            self.channels
+                .as_ref()
+                .map(|channels| Some(pick(channels)))
+                .ok_or(QuinnetError::EndpointAlreadyClosed)
+        };
+        match channel {
+            Channel::Control => Ok(None),
+            Channel::Chat => Ok(Some(ChannelId::UnorderedReliable)),
+            Channel::Movement => Ok(Some(ChannelId::Unreliable)),
+            Channel::WorldEdits => opened(|channels| channels.world_edits),
+            Channel::Bulk => opened(|channels| channels.bulk),
+            Channel::Entities => opened(|channels| channels.entities),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{loopback::loopback_pair, *};
+    use crate::protocol::StopReason;
+
+    #[test]
+    fn loopback_round_trip() {
+        let (mut server, mut client) = loopback_pair();
+
+        client
+            .send(ClientMessage::ChatMessage {
+                message: "hello".to_string(),
+            })
+            .unwrap();
+        match server.try_receive() {
+            Some(ClientMessage::ChatMessage { message }) => assert_eq!(message, "hello"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        server
+            .send(ServerMessage::ServerStopping {
+                reason: StopReason::Shutdown,
+            })
+            .unwrap();
+        assert!(matches!(
+            client.try_receive(),
+            Some(ServerMessage::ServerStopping { .. })
+        ));
+        assert!(client.try_receive().is_none());
+    }
+
+    #[test]
+    fn loopback_client_drop_disconnects() {
+        let (mut server, client) = loopback_pair();
+        drop(client);
+
+        assert!(server.try_receive().is_none());
+        assert!(!server.connected);
+        assert!(server
+            .send(ServerMessage::ServerStopping {
+                reason: StopReason::Shutdown,
+            })
+            .is_err());
+    }
+}
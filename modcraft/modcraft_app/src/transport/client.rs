@@ -0,0 +1,77 @@
+//! How the client reaches the server it plays on.
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_quinnet::{
+    client::Client,
+    shared::{channel::ChannelId, QuinnetError},
+};
+
+use super::loopback::LoopbackClient;
+use crate::protocol::{Channel, ClientMessage, ServerMessage};
+
+// channels the client opens on top of quinnet's defaults
+#[derive(Resource, Debug, Clone, Copy)]
+pub(crate) struct ClientChannels {
+    pub(crate) world_edits: ChannelId,
+}
+
+/// The client's connection to a server, either remote (quinnet) or the
+/// internal server running in this process (loopback).
+#[derive(SystemParam)]
+pub(crate) struct ClientTransport<'w> {
+    quinnet: Option<ResMut<'w, Client>>,
+    loopback: Option<ResMut<'w, LoopbackClient>>,
+    channels: Option<Res<'w, ClientChannels>>,
+}
+
+impl<'w> ClientTransport<'w> {
+    pub(crate) fn is_loopback(&self) -> bool {
+        self.loopback.is_some()
+    }
+
+    /// Sends a message on the channel its kind goes on.
+    pub(crate) fn send(&self, message: ClientMessage) -> Result<(), QuinnetError> {
+        if let Some(loopback) = &self.loopback {
+            return loopback.send(message);
+        }
+        let connection = self
+            .quinnet
+            .as_ref()
+            .and_then(|client| client.get_connection())
+            .ok_or(QuinnetError::ConnectionClosed)?;
+        let channel_id = match message.channel() {
+            Channel::Control => None,
+            Channel::Chat => Some(ChannelId::UnorderedReliable),
+            Channel::Movement => Some(ChannelId::Unreliable),
+            // clients send nothing big and no entities, so those would share
+            // the edits stream
+            Channel::WorldEdits | Channel::Bulk | Channel::Entities => Some(
+                self.channels
+                    .as_ref()
+                    .ok_or(QuinnetError::ConnectionClosed)?
+                    .world_edits,
+            ),
+        };
+        match channel_id {
+            Some(channel_id) => connection.send_payload_on(channel_id, message.encode()),
+            None => connection.send_payload(message.encode()),
+        }
+    }
+
+    pub(crate) fn try_receive_message(&mut self) -> Option<ServerMessage> {
+        if let Some(loopback) = &mut self.loopback {
+            return loopback.try_receive();
+        }
+        let connection = self.quinnet.as_mut()?.get_connection_mut()?;
+        loop {
+            let payload = connection.try_receive_payload()?;
+            match ServerMessage::decode(&payload) {
+                Ok(message) => return Some(message),
+                Err(err) => warn!(
+                    "Skipped a message from the server that couldn't be decoded: {}",
+                    err
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! The in-process connection the local player talks to an internal server
+//! through, and that tests join servers with.
+
+use bevy::prelude::*;
+use bevy_quinnet::shared::QuinnetError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::LoopbackServer;
+use crate::protocol::{ClientMessage, ServerMessage};
+
+// client half of an in-process connection, used for singleplayer
+#[derive(Resource)]
+pub(crate) struct LoopbackClient {
+    to_server: UnboundedSender<ClientMessage>,
+    from_server: UnboundedReceiver<ServerMessage>,
+}
+
+pub(crate) fn loopback_pair() -> (LoopbackServer, LoopbackClient) {
+    let (to_client, from_server) = mpsc::unbounded_channel();
+    let (to_server, from_client) = mpsc::unbounded_channel();
+
+    (
+        LoopbackServer {
+            to_client,
+            from_client,
+            connected: true,
+        },
+        LoopbackClient {
+            to_server,
+            from_server,
+        },
+    )
+}
+
+impl LoopbackClient {
+    pub(crate) fn send(&self, message: ClientMessage) -> Result<(), QuinnetError> {
+        self.to_server
+            .send(message)
+            .map_err(|_| QuinnetError::InternalChannelClosed)
+    }
+
+    pub(crate) fn try_receive(&mut self) -> Option<ServerMessage> {
+        self.from_server.try_recv().ok()
+    }
+}
@@ -0,0 +1,395 @@
+//! The compact binary format messages travel in.
+//!
+//! Unsigned integers are LEB128 varints, and signed ones are zigzag encoded
+//! first so small negative numbers stay small. Floats are 4 little-endian
+//! bytes, strings and lists start with their length.
+//!
+//! Every message is its id followed by the length of its body, so a reader
+//! can skip a message it doesn't know and the fields at the end of one that
+//! were added after it was built. Fields are only ever added to the end of a
+//! message, and read with [`Reader::optional`] so messages from builds that
+//! don't send them yet still decode.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DecodeError {
+    UnexpectedEnd,
+    VarintTooLong,
+    InvalidUtf8,
+    /// A number that doesn't fit where it goes, or isn't one of the values a
+    /// field can have.
+    InvalidValue {
+        what: &'static str,
+        value: u64,
+    },
+    UnknownMessage(u64),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "The message ended early"),
+            DecodeError::VarintTooLong => write!(f, "A number is longer than 64 bits"),
+            DecodeError::InvalidUtf8 => write!(f, "A string is not valid UTF-8"),
+            DecodeError::InvalidValue { what, value } => {
+                write!(f, "{} is not a valid {}", value, what)
+            }
+            DecodeError::UnknownMessage(id) => write!(f, "Unknown message id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub(crate) trait Encode {
+    fn encode(&self, writer: &mut Writer);
+}
+
+pub(crate) trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub(crate) fn put<T: Encode + ?Sized>(&mut self, value: &T) {
+        value.encode(self);
+    }
+
+    pub(crate) fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    pub(crate) fn signed(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    /// Writes a message with its id and the length of its body.
+    pub(crate) fn message(&mut self, id: u64, body: impl FnOnce(&mut Writer)) {
+        let mut writer = Writer::default();
+        body(&mut writer);
+        self.varint(id);
+        self.varint(writer.bytes.len() as u64);
+        self.bytes.extend(writer.bytes);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub(crate) fn get<T: Decode>(&mut self) -> Result<T, DecodeError> {
+        T::decode(self)
+    }
+
+    /// Skips what is left, for values whose fields this build doesn't know.
+    pub(crate) fn skip_rest(&mut self) {
+        self.bytes = &[];
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if count > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::VarintTooLong)
+    }
+
+    pub(crate) fn signed(&mut self) -> Result<i64, DecodeError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Reads a message's id and a reader for its body. Whatever the body
+    /// has after the fields read from it is skipped.
+    pub(crate) fn message(&mut self) -> Result<(u64, Reader<'a>), DecodeError> {
+        let id = self.varint()?;
+        let length = self.get::<usize>()?;
+        Ok((id, Reader::new(self.take(length)?)))
+    }
+}
+
+// no message clients send has gained fields yet, so only clients need this
+#[cfg(any(test, not(feature = "dedicated-server")))]
+impl Reader<'_> {
+    /// A field added after the first version of a message, `None` if the
+    /// sender's build didn't have it yet.
+    pub(crate) fn optional<T: Decode>(&mut self) -> Result<Option<T>, DecodeError> {
+        if self.bytes.is_empty() {
+            return Ok(None);
+        }
+        self.get().map(Some)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, writer: &mut Writer) {
+        (**self).encode(writer);
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut Writer) {
+        writer.bytes.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match reader.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(DecodeError::InvalidValue {
+                what: "bool",
+                value: value.into(),
+            }),
+        }
+    }
+}
+
+// bytes are written as they are rather than as varints
+impl Encode for u8 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.bytes.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(reader.take(1)?[0])
+    }
+}
+
+macro_rules! varint {
+    ($($int:ty),*) => {$(
+        impl Encode for $int {
+            fn encode(&self, writer: &mut Writer) {
+                writer.varint(*self as u64);
+            }
+        }
+
+        impl Decode for $int {
+            fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+                let value = reader.varint()?;
+                <$int>::try_from(value).map_err(|_| DecodeError::InvalidValue {
+                    what: stringify!($int),
+                    value,
+                })
+            }
+        }
+    )*};
+}
+
+varint!(u16, u32, u64, usize);
+
+impl Encode for i32 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.signed((*self).into());
+    }
+}
+
+impl Decode for i32 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let value = reader.signed()?;
+        i32::try_from(value).map_err(|_| DecodeError::InvalidValue {
+            what: "i32",
+            value: value as u64,
+        })
+    }
+}
+
+impl Encode for f32 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.bytes.extend(self.to_le_bytes());
+    }
+}
+
+impl Decode for f32 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(f32::from_le_bytes(reader.get()?))
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.len());
+        writer.bytes.extend(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(self.as_str());
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let length = reader.get()?;
+        let bytes = reader.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+}
+
+// arrays have a fixed length, so it isn't written
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, writer: &mut Writer) {
+        for item in self {
+            writer.put(item);
+        }
+    }
+}
+
+impl<T: Decode, const N: usize> Decode for [T; N] {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let items = (0..N)
+            .map(|_| reader.get())
+            .collect::<Result<Vec<T>, _>>()?;
+        Ok(items
+            .try_into()
+            .unwrap_or_else(|_| unreachable!("read exactly {} items", N)))
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.len());
+        for item in self {
+            writer.put(item);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(self.as_slice());
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let length: usize = reader.get()?;
+        // every item takes at least a byte, so a bogus length fails here
+        // instead of allocating
+        if length > reader.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        (0..length).map(|_| reader.get()).collect()
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.is_some());
+        if let Some(value) = self {
+            writer.put(value);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match reader.get()? {
+            true => reader.get().map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.0);
+        writer.put(&self.1);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok((reader.get()?, reader.get()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<T: Encode>(value: T) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.put(&value);
+        writer.into_bytes()
+    }
+
+    fn decode<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+        Reader::new(bytes).get()
+    }
+
+    #[test]
+    fn values_round_trip_compactly() {
+        assert_eq!(encode(0u32), [0x00]);
+        assert_eq!(encode(127u32), [0x7f]);
+        assert_eq!(encode(300u32), [0xac, 0x02]);
+        assert_eq!(encode(u64::MAX).len(), 10);
+        assert_eq!(encode(-1i32), [0x01]);
+        assert_eq!(encode(1i32), [0x02]);
+        assert_eq!(encode(-65i32), [0x81, 0x01]);
+        assert_eq!(encode(1.5f32), [0x00, 0x00, 0xc0, 0x3f]);
+        assert_eq!(encode("hi"), [0x02, b'h', b'i']);
+        assert_eq!(encode(Some(vec![200u8])), [0x01, 0x01, 200]);
+
+        for value in [i32::MIN, -1, 0, 1, i32::MAX] {
+            assert_eq!(decode::<i32>(&encode(value)), Ok(value));
+        }
+        assert_eq!(decode::<u64>(&encode(u64::MAX)), Ok(u64::MAX));
+        assert_eq!(
+            decode::<(String, bool)>(&encode(("name".to_string(), true))),
+            Ok(("name".to_string(), true))
+        );
+
+        assert_eq!(decode::<u32>(&[0x80]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(decode::<u64>(&[0xff; 11]), Err(DecodeError::VarintTooLong));
+        assert!(matches!(
+            decode::<u16>(&encode(70_000u32)),
+            Err(DecodeError::InvalidValue { what: "u16", .. })
+        ));
+        assert_eq!(
+            decode::<bool>(&[2]).unwrap_err().to_string(),
+            "2 is not a valid bool"
+        );
+        assert_eq!(
+            decode::<Vec<u8>>(&[0xff, 0xff, 0x03]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(decode::<String>(&[1, 0xff]), Err(DecodeError::InvalidUtf8));
+    }
+}
@@ -0,0 +1,355 @@
+//! Blocks known to the game, including the ones added by mods.
+
+use std::{collections::HashMap, fmt};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub type BlockId = u16;
+
+/// Air is always registered first so that empty space is id 0.
+pub const AIR: BlockId = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockRegistryError {
+    /// Names have to look like `namespace:path`, using only `a-z`, `0-9`
+    /// and `_`.
+    InvalidName(String),
+    Duplicate(String),
+    Full,
+}
+
+impl fmt::Display for BlockRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockRegistryError::InvalidName(name) => write!(
+                f,
+                "Block name {} is not of the form namespace:path using a-z, 0-9 and _",
+                name
+            ),
+            BlockRegistryError::Duplicate(name) => {
+                write!(f, "Block {} is already registered", name)
+            }
+            BlockRegistryError::Full => write!(f, "No block ids are left"),
+        }
+    }
+}
+
+impl std::error::Error for BlockRegistryError {}
+
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    let valid_part = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    };
+    match name.split_once(':') {
+        Some((namespace, path)) => valid_part(namespace) && valid_part(path),
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockFace {
+    Top,
+    Bottom,
+    North,
+    South,
+    East,
+    West,
+}
+
+impl BlockFace {
+    pub const ALL: [BlockFace; 6] = [
+        BlockFace::Top,
+        BlockFace::Bottom,
+        BlockFace::North,
+        BlockFace::South,
+        BlockFace::East,
+        BlockFace::West,
+    ];
+
+    /// Direction the face points in. North is towards negative z and east
+    /// towards positive x.
+    pub fn offset(self) -> (i32, i32, i32) {
+        match self {
+            BlockFace::Top => (0, 1, 0),
+            BlockFace::Bottom => (0, -1, 0),
+            BlockFace::North => (0, 0, -1),
+            BlockFace::South => (0, 0, 1),
+            BlockFace::East => (1, 0, 0),
+            BlockFace::West => (-1, 0, 0),
+        }
+    }
+
+    pub fn opposite(self) -> BlockFace {
+        match self {
+            BlockFace::Top => BlockFace::Bottom,
+            BlockFace::Bottom => BlockFace::Top,
+            BlockFace::North => BlockFace::South,
+            BlockFace::South => BlockFace::North,
+            BlockFace::East => BlockFace::West,
+            BlockFace::West => BlockFace::East,
+        }
+    }
+}
+
+/// Texture names for each face, matching files in `assets/textures/block`
+/// without the extension.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockTextures {
+    faces: [String; 6],
+}
+
+impl BlockTextures {
+    pub fn all(texture: &str) -> Self {
+        BlockTextures {
+            faces: std::array::from_fn(|_| texture.to_string()),
+        }
+    }
+
+    pub fn top_side_bottom(top: &str, side: &str, bottom: &str) -> Self {
+        let mut textures = BlockTextures::all(side);
+        textures.faces[BlockFace::Top as usize] = top.to_string();
+        textures.faces[BlockFace::Bottom as usize] = bottom.to_string();
+        textures
+    }
+
+    /// Takes the textures in the order of [`BlockFace::ALL`].
+    pub fn from_faces(faces: [String; 6]) -> Self {
+        BlockTextures { faces }
+    }
+
+    pub fn get(&self, face: BlockFace) -> &str {
+        &self.faces[face as usize]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockProperties {
+    /// Whether players and other entities collide with the block.
+    pub solid: bool,
+    /// Whether neighboring faces stay visible behind the block.
+    pub transparent: bool,
+    /// How long the block takes to break, negative for unbreakable.
+    pub hardness: f32,
+    /// `None` for blocks that are never drawn, like air.
+    pub textures: Option<BlockTextures>,
+}
+
+impl BlockProperties {
+    pub fn air() -> Self {
+        BlockProperties {
+            solid: false,
+            transparent: true,
+            hardness: 0.0,
+            textures: None,
+        }
+    }
+
+    /// An opaque, solid block with a hardness of 1.
+    pub fn solid(textures: BlockTextures) -> Self {
+        BlockProperties {
+            solid: true,
+            transparent: false,
+            hardness: 1.0,
+            textures: Some(textures),
+        }
+    }
+
+    pub fn with_hardness(mut self, hardness: f32) -> Self {
+        self.hardness = hardness;
+        self
+    }
+
+    pub fn with_transparency(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        self
+    }
+
+    pub fn is_breakable(&self) -> bool {
+        self.hardness >= 0.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Block {
+    pub name: String,
+    pub properties: BlockProperties,
+}
+
+/// Maps block names like `modcraft:dirt` to compact ids. Ids are handed out
+/// in registration order, starting with the built-in blocks.
+#[derive(Resource, Debug, Clone)]
+pub struct BlockRegistry {
+    blocks: Vec<Block>,
+    ids: HashMap<String, BlockId>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        let mut registry = BlockRegistry::empty();
+        registry.register_builtin_blocks();
+        registry
+    }
+}
+
+impl BlockRegistry {
+    fn empty() -> Self {
+        BlockRegistry {
+            blocks: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    fn register_builtin_blocks(&mut self) {
+        let builtin_blocks = [
+            ("modcraft:air", BlockProperties::air()),
+            (
+                "modcraft:bedrock",
+                BlockProperties::solid(BlockTextures::all("bedrock")).with_hardness(-1.0),
+            ),
+            (
+                "modcraft:stone",
+                BlockProperties::solid(BlockTextures::all("stone2")).with_hardness(1.5),
+            ),
+            (
+                "modcraft:dirt",
+                BlockProperties::solid(BlockTextures::all("dirt")).with_hardness(0.5),
+            ),
+            (
+                "modcraft:grass_path",
+                BlockProperties::solid(BlockTextures::top_side_bottom(
+                    "grass_path_top",
+                    "grass_path_side",
+                    "dirt",
+                ))
+                .with_hardness(0.6),
+            ),
+        ];
+        for (name, properties) in builtin_blocks {
+            self.register(name, properties)
+                .expect("Built-in blocks are valid");
+        }
+    }
+
+    /// Rebuilds a registry from the blocks another registry lists, keeping
+    /// their ids. Used by clients to mirror the server's registry.
+    pub fn from_blocks(blocks: Vec<Block>) -> Result<Self, BlockRegistryError> {
+        let mut registry = BlockRegistry::empty();
+        for block in blocks {
+            registry.register(block.name, block.properties)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        properties: BlockProperties,
+    ) -> Result<BlockId, BlockRegistryError> {
+        let name = name.into();
+        if !is_valid_name(&name) {
+            return Err(BlockRegistryError::InvalidName(name));
+        }
+        if self.ids.contains_key(&name) {
+            return Err(BlockRegistryError::Duplicate(name));
+        }
+        let id = BlockId::try_from(self.blocks.len()).map_err(|_| BlockRegistryError::Full)?;
+        self.ids.insert(name.clone(), id);
+        self.blocks.push(Block { name, properties });
+        Ok(id)
+    }
+
+    pub fn id(&self, name: &str) -> Option<BlockId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn get(&self, id: BlockId) -> Option<&Block> {
+        self.blocks.get(id as usize)
+    }
+
+    pub fn name(&self, id: BlockId) -> Option<&str> {
+        self.get(id).map(|block| block.name.as_str())
+    }
+
+    pub fn properties(&self, id: BlockId) -> Option<&BlockProperties> {
+        self.get(id).map(|block| &block.properties)
+    }
+
+    /// All blocks in id order.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_blocks_come_first() {
+        let registry = BlockRegistry::default();
+        assert_eq!(registry.id("modcraft:air"), Some(AIR));
+        assert!(!registry.properties(AIR).unwrap().solid);
+
+        let grass_path = registry.id("modcraft:grass_path").unwrap();
+        let textures = registry
+            .properties(grass_path)
+            .unwrap()
+            .textures
+            .as_ref()
+            .unwrap();
+        assert_eq!(textures.get(BlockFace::Top), "grass_path_top");
+        assert_eq!(textures.get(BlockFace::North), "grass_path_side");
+        assert_eq!(textures.get(BlockFace::Bottom), "dirt");
+    }
+
+    #[test]
+    fn ids_follow_registration_order() {
+        let mut registry = BlockRegistry::default();
+        let ore = BlockProperties::solid(BlockTextures::all("stone2"));
+        let ore_id = registry.register("example_mod:ore", ore.clone()).unwrap();
+        assert_eq!(ore_id as usize, registry.len() - 1);
+        assert_eq!(
+            registry.register("example_mod:ore", ore.clone()),
+            Err(BlockRegistryError::Duplicate("example_mod:ore".to_string()))
+        );
+        assert_eq!(registry.name(ore_id), Some("example_mod:ore"));
+        assert_eq!(registry.name(ore_id + 1), None);
+
+        for name in ["ore", "Example:ore", "example_mod:", ":ore", "a:b:c"] {
+            assert_eq!(
+                registry.register(name, ore.clone()),
+                Err(BlockRegistryError::InvalidName(name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn mirrored_registry_keeps_ids() {
+        let mut registry = BlockRegistry::default();
+        registry
+            .register(
+                "example_mod:ore",
+                BlockProperties::solid(BlockTextures::all("stone2")),
+            )
+            .unwrap();
+
+        let mirrored = BlockRegistry::from_blocks(registry.blocks().to_vec()).unwrap();
+        assert_eq!(mirrored.blocks(), registry.blocks());
+        assert_eq!(
+            mirrored.id("example_mod:ore"),
+            registry.id("example_mod:ore")
+        );
+    }
+}
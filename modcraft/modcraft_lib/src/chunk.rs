@@ -0,0 +1,345 @@
+//! A 16x16x16 section of the world, stored as indices into a palette of
+//! block ids.
+//!
+//! Most chunks only use a handful of different blocks, so each block is
+//! stored with as few bits as the palette needs: a chunk of only air takes no
+//! index data at all, a chunk with two blocks takes one bit per block and so
+//! on. Modded blocks are just more ids, so they are stored the same way.
+
+use std::fmt;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::blocks::{BlockId, AIR};
+
+pub const CHUNK_SIZE: usize = 16;
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+/// Index of a block inside a chunk, `x` changing fastest.
+pub fn block_index(x: usize, y: usize, z: usize) -> usize {
+    debug_assert!(x < CHUNK_SIZE && y < CHUNK_SIZE && z < CHUNK_SIZE);
+    (y * CHUNK_SIZE + z) * CHUNK_SIZE + x
+}
+
+/// The inverse of [`block_index`].
+pub fn block_coords(index: usize) -> (usize, usize, usize) {
+    (
+        index % CHUNK_SIZE,
+        index / (CHUNK_SIZE * CHUNK_SIZE),
+        (index / CHUNK_SIZE) % CHUNK_SIZE,
+    )
+}
+
+// a full palette of distinct blocks plus 16 bits per block, with room to spare
+const MAX_ENCODED_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ChunkDecodeError {
+    Decompress(lz4_flex::block::DecompressError),
+    TooLarge(usize),
+    Deserialize(bincode::Error),
+    /// The data decoded but does not describe a valid chunk.
+    Invalid,
+}
+
+impl fmt::Display for ChunkDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkDecodeError::Decompress(e) => write!(f, "Could not decompress chunk: {}", e),
+            ChunkDecodeError::TooLarge(size) => {
+                write!(f, "Chunk claims to be {} bytes, which is too large", size)
+            }
+            ChunkDecodeError::Deserialize(e) => write!(f, "Could not deserialize chunk: {}", e),
+            ChunkDecodeError::Invalid => write!(f, "Chunk data is inconsistent"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkDecodeError {}
+
+fn chunk_encoding() -> impl Options {
+    bincode::options().with_limit(MAX_ENCODED_SIZE as u64)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    palette: Vec<BlockId>,
+    bits_per_block: u8,
+    // indices never straddle two words, so some high bits may go unused
+    data: Vec<u64>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::filled(AIR)
+    }
+}
+
+fn bits_for_palette(len: usize) -> u8 {
+    match len {
+        0 | 1 => 0,
+        len => (usize::BITS - (len - 1).leading_zeros()) as u8,
+    }
+}
+
+fn words_for_bits(bits_per_block: u8) -> usize {
+    match bits_per_block {
+        0 => 0,
+        bits => CHUNK_VOLUME.div_ceil(64 / bits as usize),
+    }
+}
+
+impl Chunk {
+    pub fn filled(block: BlockId) -> Self {
+        Chunk {
+            palette: vec![block],
+            bits_per_block: 0,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn bits_per_block(&self) -> u8 {
+        self.bits_per_block
+    }
+
+    /// The distinct blocks this chunk may contain. Can hold blocks that were
+    /// overwritten until [`Chunk::compact`] is called.
+    pub fn palette(&self) -> &[BlockId] {
+        &self.palette
+    }
+
+    /// Returns the block if every block in the chunk is the same.
+    pub fn uniform_block(&self) -> Option<BlockId> {
+        if self.bits_per_block == 0 {
+            Some(self.palette[0])
+        } else {
+            None
+        }
+    }
+
+    fn palette_index(&self, index: usize) -> usize {
+        if self.bits_per_block == 0 {
+            return 0;
+        }
+        let bits = self.bits_per_block as usize;
+        let per_word = 64 / bits;
+        let word = self.data[index / per_word];
+        let shift = (index % per_word) * bits;
+        ((word >> shift) & ((1 << bits) - 1)) as usize
+    }
+
+    fn set_palette_index(&mut self, index: usize, palette_index: usize) {
+        let bits = self.bits_per_block as usize;
+        let per_word = 64 / bits;
+        let shift = (index % per_word) * bits;
+        let mask = ((1u64 << bits) - 1) << shift;
+        let word = &mut self.data[index / per_word];
+        *word = (*word & !mask) | ((palette_index as u64) << shift);
+    }
+
+    fn repack(&mut self, bits_per_block: u8, remap: impl Fn(usize) -> usize) {
+        let old = std::mem::replace(
+            self,
+            Chunk {
+                palette: Vec::new(),
+                bits_per_block,
+                data: vec![0; words_for_bits(bits_per_block)],
+            },
+        );
+        self.palette = old.palette.clone();
+        if bits_per_block == 0 {
+            return;
+        }
+        for index in 0..CHUNK_VOLUME {
+            self.set_palette_index(index, remap(old.palette_index(index)));
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> BlockId {
+        self.palette[self.palette_index(block_index(x, y, z))]
+    }
+
+    /// Sets a block and returns the block that was there before.
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block: BlockId) -> BlockId {
+        let index = block_index(x, y, z);
+        let previous = self.palette[self.palette_index(index)];
+        if previous == block {
+            return previous;
+        }
+
+        let palette_index = match self.palette.iter().position(|&id| id == block) {
+            Some(palette_index) => palette_index,
+            None => {
+                self.palette.push(block);
+                let bits = bits_for_palette(self.palette.len());
+                if bits != self.bits_per_block {
+                    self.repack(bits, |palette_index| palette_index);
+                }
+                self.palette.len() - 1
+            }
+        };
+        self.set_palette_index(index, palette_index);
+        previous
+    }
+
+    /// Every block in the chunk with its local coordinates.
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize, usize), BlockId)> + '_ {
+        (0..CHUNK_VOLUME)
+            .map(|index| (block_coords(index), self.palette[self.palette_index(index)]))
+    }
+
+    /// Drops palette entries that are no longer used and shrinks the indices
+    /// to match.
+    pub fn compact(&mut self) {
+        let mut used = vec![false; self.palette.len()];
+        for index in 0..CHUNK_VOLUME {
+            used[self.palette_index(index)] = true;
+        }
+        if used.iter().all(|&used| used) {
+            return;
+        }
+
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::new();
+        for (palette_index, &block) in self.palette.iter().enumerate() {
+            if used[palette_index] {
+                remap[palette_index] = palette.len();
+                palette.push(block);
+            }
+        }
+
+        let bits = bits_for_palette(palette.len());
+        self.repack(bits, |palette_index| remap[palette_index]);
+        self.palette = palette;
+    }
+
+    /// Serializes and compresses the chunk, for the network and for saving.
+    pub fn encode(&self) -> Vec<u8> {
+        let bytes = chunk_encoding()
+            .serialize(self)
+            .expect("Chunks always fit in the encoding limit");
+        lz4_flex::compress_prepend_size(&bytes)
+    }
+
+    /// The inverse of [`Chunk::encode`], rejecting anything that does not
+    /// decode to a well formed chunk.
+    pub fn decode(bytes: &[u8]) -> Result<Chunk, ChunkDecodeError> {
+        let size = bytes
+            .get(..4)
+            .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+            .unwrap_or(0);
+        if size > MAX_ENCODED_SIZE {
+            return Err(ChunkDecodeError::TooLarge(size));
+        }
+        let bytes =
+            lz4_flex::decompress_size_prepended(bytes).map_err(ChunkDecodeError::Decompress)?;
+        let chunk: Chunk = chunk_encoding()
+            .deserialize(&bytes)
+            .map_err(ChunkDecodeError::Deserialize)?;
+        if chunk.is_valid() {
+            Ok(chunk)
+        } else {
+            Err(ChunkDecodeError::Invalid)
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.palette.is_empty()
+            && self.bits_per_block == bits_for_palette(self.palette.len())
+            && self.data.len() == words_for_bits(self.bits_per_block)
+            && (0..CHUNK_VOLUME).all(|index| self.palette_index(index) < self.palette.len())
+    }
+
+    /// Heap memory used by the chunk in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.palette.capacity() * std::mem::size_of::<BlockId>()
+            + self.data.capacity() * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_round_trip() {
+        for index in [0, 1, 15, 16, 255, 256, CHUNK_VOLUME - 1] {
+            let (x, y, z) = block_coords(index);
+            assert_eq!(block_index(x, y, z), index);
+        }
+    }
+
+    #[test]
+    fn palette_grows_and_compacts() {
+        let mut chunk = Chunk::default();
+        assert_eq!(chunk.uniform_block(), Some(AIR));
+        assert_eq!(chunk.memory_usage(), chunk.palette().len() * 2);
+
+        assert_eq!(chunk.set(1, 2, 3, 7), AIR);
+        assert_eq!(chunk.bits_per_block(), 1);
+        assert_eq!(chunk.get(1, 2, 3), 7);
+        assert_eq!(chunk.get(3, 2, 1), AIR);
+
+        // 17 distinct blocks need 5 bits
+        for block in 8..24 {
+            chunk.set(block as usize - 8, 15, 15, block);
+        }
+        assert_eq!(chunk.bits_per_block(), 5);
+        assert_eq!(chunk.get(1, 2, 3), 7);
+        for block in 8..24 {
+            assert_eq!(chunk.get(block as usize - 8, 15, 15), block);
+        }
+
+        for x in 0..CHUNK_SIZE {
+            chunk.set(x, 15, 15, AIR);
+        }
+        chunk.compact();
+        assert_eq!(chunk.palette(), &[AIR, 7]);
+        assert_eq!(chunk.bits_per_block(), 1);
+        assert_eq!(chunk.get(1, 2, 3), 7);
+
+        chunk.set(1, 2, 3, AIR);
+        chunk.compact();
+        assert_eq!(chunk, Chunk::default());
+    }
+
+    #[test]
+    fn encoding_round_trip() {
+        let mut chunk = Chunk::filled(3);
+        for x in 0..CHUNK_SIZE {
+            chunk.set(x, x, 15 - x, x as BlockId);
+        }
+        let bytes = chunk.encode();
+        assert_eq!(Chunk::decode(&bytes).unwrap(), chunk);
+
+        assert!(Chunk::decode(&bytes[..bytes.len() / 2]).is_err());
+        assert!(matches!(
+            Chunk::decode(&[0xff, 0xff, 0xff, 0xff]),
+            Err(ChunkDecodeError::TooLarge(_))
+        ));
+
+        // two palette entries need one bit per block, not zero
+        let mut invalid = Chunk::filled(1);
+        invalid.palette.push(2);
+        assert!(matches!(
+            Chunk::decode(&invalid.encode()),
+            Err(ChunkDecodeError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn iter_visits_every_block() {
+        let mut chunk = Chunk::filled(3);
+        chunk.set(4, 5, 6, 9);
+        let blocks = chunk.iter().collect::<Vec<_>>();
+        assert_eq!(blocks.len(), CHUNK_VOLUME);
+        assert_eq!(
+            blocks
+                .iter()
+                .filter(|(_, block)| *block == 9)
+                .collect::<Vec<_>>(),
+            vec![&((4, 5, 6), 9)]
+        );
+    }
+}
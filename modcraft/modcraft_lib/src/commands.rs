@@ -0,0 +1,403 @@
+//! Chat commands that players and the console run on the server with
+//! `/name args`.
+//!
+//! Commands declare their arguments, which are parsed and checked before the
+//! handler runs, so handlers get typed values and a wrong line is answered
+//! with the command's usage. The same declarations drive `/help` and the
+//! suggestions sent to clients while they type.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bevy::prelude::*;
+
+/// What a sender is allowed to run. Each level can run everything the levels
+/// below it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum PermissionLevel {
+    #[default]
+    Player,
+    Operator,
+    Console,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandSender {
+    Player { client_id: u64, name: String },
+    Console,
+}
+
+impl CommandSender {
+    /// The name other players see messages from this sender under.
+    pub fn name(&self) -> &str {
+        match self {
+            CommandSender::Player { name, .. } => name,
+            CommandSender::Console => "Server",
+        }
+    }
+}
+
+/// Who ran a command and what the server knew at the time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandContext {
+    pub sender: CommandSender,
+    pub level: PermissionLevel,
+    /// The names of the players online, which player arguments are checked
+    /// against.
+    pub players: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A single word.
+    Word,
+    /// The rest of the line, so it has to be the last argument.
+    Text,
+    Integer,
+    Number,
+    /// The name of a player that is online.
+    Player,
+    /// One of a fixed set of words.
+    Choice(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandArg {
+    pub name: String,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArgValue {
+    Text(String),
+    Integer(i64),
+    Number(f64),
+}
+
+/// The arguments a command was run with, by name. Optional arguments that
+/// were left out are missing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandArgs {
+    values: HashMap<String, ArgValue>,
+}
+
+impl CommandArgs {
+    /// A word, text, player or choice argument. Player names are spelled the
+    /// way the player spells them.
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.values.get(name)? {
+            ArgValue::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.values.get(name)? {
+            ArgValue::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        match self.values.get(name)? {
+            ArgValue::Number(value) => Some(*value),
+            ArgValue::Integer(value) => Some(*value as f64),
+            ArgValue::Text(_) => None,
+        }
+    }
+}
+
+/// The reply for the sender, or what went wrong.
+pub type CommandResult = Result<String, String>;
+
+/// Runs a command with its parsed arguments.
+pub type CommandHandler = fn(&mut World, &CommandContext, &CommandArgs) -> CommandResult;
+
+#[derive(Debug, Clone)]
+pub struct ChatCommand {
+    pub name: String,
+    pub help: String,
+    pub permission: PermissionLevel,
+    pub args: Vec<CommandArg>,
+    pub handler: CommandHandler,
+}
+
+impl ChatCommand {
+    /// A command anyone can run, without arguments.
+    pub fn new(name: impl Into<String>, help: impl Into<String>, handler: CommandHandler) -> Self {
+        ChatCommand {
+            name: name.into(),
+            help: help.into(),
+            permission: PermissionLevel::Player,
+            args: Vec::new(),
+            handler,
+        }
+    }
+
+    pub fn with_permission(mut self, permission: PermissionLevel) -> Self {
+        self.permission = permission;
+        self
+    }
+
+    pub fn with_arg(mut self, name: impl Into<String>, kind: ArgKind) -> Self {
+        self.args.push(CommandArg {
+            name: name.into(),
+            kind,
+            optional: false,
+        });
+        self
+    }
+
+    /// Optional arguments have to come after the required ones.
+    pub fn with_optional_arg(mut self, name: impl Into<String>, kind: ArgKind) -> Self {
+        self.args.push(CommandArg {
+            name: name.into(),
+            kind,
+            optional: true,
+        });
+        self
+    }
+
+    /// Like `/tp <player> <x> <y> <z>`, with optional arguments in brackets.
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in &self.args {
+            let name = match &arg.kind {
+                ArgKind::Choice(choices) => choices.join("|"),
+                _ => arg.name.clone(),
+            };
+            if arg.optional {
+                usage.push_str(&format!(" [{}]", name));
+            } else {
+                usage.push_str(&format!(" <{}>", name));
+            }
+        }
+        usage
+    }
+
+    pub fn parse_args(&self, line: &str, players: &[String]) -> Result<CommandArgs, String> {
+        let mut args = CommandArgs::default();
+        let mut rest = line.trim();
+        for arg in &self.args {
+            let word = if arg.kind == ArgKind::Text {
+                std::mem::take(&mut rest)
+            } else {
+                let (word, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                rest = after.trim_start();
+                word
+            };
+            if word.is_empty() {
+                if arg.optional {
+                    break;
+                }
+                return Err(format!("Missing <{}>", arg.name));
+            }
+            args.values
+                .insert(arg.name.clone(), parse_value(arg, word, players)?);
+        }
+        if !rest.is_empty() {
+            return Err(format!("Unexpected \"{}\"", rest));
+        }
+        Ok(args)
+    }
+
+    // suggestions for the word being typed at the end of the argument line
+    fn complete_args(&self, line: &str, players: &[String]) -> Vec<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (index, partial) = match words.last() {
+            Some(last) if !line.ends_with(char::is_whitespace) => (words.len() - 1, *last),
+            _ => (words.len(), ""),
+        };
+        if self.args[..index.min(self.args.len())]
+            .iter()
+            .any(|arg| arg.kind == ArgKind::Text)
+        {
+            return Vec::new();
+        }
+        let Some(arg) = self.args.get(index) else {
+            return Vec::new();
+        };
+        let starts_with = |candidate: &&String| {
+            candidate
+                .to_lowercase()
+                .starts_with(&partial.to_lowercase())
+        };
+        match &arg.kind {
+            ArgKind::Player => players.iter().filter(starts_with).cloned().collect(),
+            ArgKind::Choice(choices) => choices.iter().filter(starts_with).cloned().collect(),
+            // nothing to offer but a reminder of what goes here
+            _ if partial.is_empty() => vec![format!("<{}>", arg.name)],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn parse_value(arg: &CommandArg, word: &str, players: &[String]) -> Result<ArgValue, String> {
+    match &arg.kind {
+        ArgKind::Word | ArgKind::Text => Ok(ArgValue::Text(word.to_string())),
+        ArgKind::Integer => word
+            .parse()
+            .map(ArgValue::Integer)
+            .map_err(|_| format!("<{}> has to be a whole number, not \"{}\"", arg.name, word)),
+        ArgKind::Number => word
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .map(ArgValue::Number)
+            .ok_or_else(|| format!("<{}> has to be a number, not \"{}\"", arg.name, word)),
+        ArgKind::Player => players
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(word))
+            .map(|name| ArgValue::Text(name.clone()))
+            .ok_or_else(|| format!("No player called {} is online", word)),
+        ArgKind::Choice(choices) => choices
+            .iter()
+            .find(|choice| choice.eq_ignore_ascii_case(word))
+            .map(|choice| ArgValue::Text(choice.clone()))
+            .ok_or_else(|| format!("Expected {}, not \"{}\"", choices.join(" or "), word)),
+    }
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ChatCommands {
+    commands: BTreeMap<String, ChatCommand>,
+}
+
+impl ChatCommands {
+    /// Registers a command, replacing any earlier command with the same name.
+    pub fn register(&mut self, command: ChatCommand) {
+        self.commands.insert(command.name.clone(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ChatCommand> {
+        self.commands.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChatCommand> {
+        self.commands.values()
+    }
+
+    /// The commands a sender with `level` may run.
+    pub fn available(&self, level: PermissionLevel) -> impl Iterator<Item = &ChatCommand> {
+        self.iter()
+            .filter(move |command| command.permission <= level)
+    }
+
+    /// Suggestions for the last word of a partly typed line: command names,
+    /// then the players or choices an argument takes.
+    pub fn complete(&self, line: &str, context: &CommandContext) -> Vec<String> {
+        let Some(line) = line.strip_prefix('/') else {
+            return Vec::new();
+        };
+        match line.split_once(char::is_whitespace) {
+            None => self
+                .available(context.level)
+                .filter(|command| command.name.starts_with(line))
+                .map(|command| format!("/{}", command.name))
+                .collect(),
+            Some((name, args)) => match self.get(name) {
+                Some(command) if command.permission <= context.level => {
+                    command.complete_args(args, &context.players)
+                }
+                _ => Vec::new(),
+            },
+        }
+    }
+}
+
+/// Returns true for chat lines that should be run as a command.
+pub fn is_command(line: &str) -> bool {
+    line.starts_with('/')
+}
+
+/// Runs a line like `/name args` for the sender in `context`.
+pub fn run_command(world: &mut World, context: &CommandContext, line: &str) -> CommandResult {
+    let line = line.strip_prefix('/').unwrap_or(line);
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let command = world
+        .get_resource::<ChatCommands>()
+        .and_then(|commands| commands.get(name))
+        .filter(|command| command.permission <= context.level)
+        .cloned()
+        .ok_or_else(|| format!("Unknown command /{}, see /help", name))?;
+    let args = command
+        .parse_args(args, &context.players)
+        .map_err(|e| format!("{}. Usage: {}", e, command.usage()))?;
+    (command.handler)(world, context, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn give(_world: &mut World, _context: &CommandContext, args: &CommandArgs) -> CommandResult {
+        Ok(format!(
+            "{} gets {} {}",
+            args.text("player").unwrap(),
+            args.integer("count").unwrap_or(1),
+            args.text("item").unwrap()
+        ))
+    }
+
+    #[test]
+    fn commands_are_parsed_and_completed() {
+        let mut world = World::new();
+        let mut commands = ChatCommands::default();
+        commands.register(
+            ChatCommand::new("give", "Gives a player items", give)
+                .with_permission(PermissionLevel::Operator)
+                .with_arg("player", ArgKind::Player)
+                .with_arg("item", ArgKind::Choice(vec!["dirt".into(), "stone".into()]))
+                .with_optional_arg("count", ArgKind::Integer),
+        );
+        world.insert_resource(commands);
+
+        let mut context = CommandContext {
+            sender: CommandSender::Console,
+            level: PermissionLevel::Player,
+            players: vec!["Steve".to_string(), "Alex".to_string()],
+        };
+        let run =
+            |world: &mut World, context: &CommandContext, line| run_command(world, context, line);
+        assert_eq!(
+            run(&mut world, &context, "/give steve dirt"),
+            Err("Unknown command /give, see /help".to_string())
+        );
+        assert!(world
+            .resource::<ChatCommands>()
+            .complete("/g", &context)
+            .is_empty());
+
+        context.level = PermissionLevel::Console;
+        assert_eq!(
+            run(&mut world, &context, "/give steve dirt"),
+            Ok("Steve gets 1 dirt".to_string())
+        );
+        assert_eq!(
+            run(&mut world, &context, "/give Alex  stone 5"),
+            Ok("Alex gets 5 stone".to_string())
+        );
+        assert_eq!(
+            run(&mut world, &context, "/give Alex stone five"),
+            Err("<count> has to be a whole number, not \"five\". \
+                 Usage: /give <player> <dirt|stone> [count]"
+                .to_string())
+        );
+        assert!(run(&mut world, &context, "/give Herobrine dirt").is_err());
+        assert!(run(&mut world, &context, "/give Steve").is_err());
+        assert!(run(&mut world, &context, "/give Steve dirt 1 2").is_err());
+
+        let commands = world.resource::<ChatCommands>();
+        assert_eq!(commands.complete("/g", &context), ["/give"]);
+        assert_eq!(commands.complete("/give ", &context), ["Steve", "Alex"]);
+        assert_eq!(commands.complete("/give a", &context), ["Alex"]);
+        assert_eq!(commands.complete("/give Alex s", &context), ["stone"]);
+        assert_eq!(
+            commands.complete("/give Alex stone ", &context),
+            ["<count>"]
+        );
+        assert!(commands
+            .complete("/give Alex stone 5 ", &context)
+            .is_empty());
+    }
+}
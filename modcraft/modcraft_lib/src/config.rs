@@ -0,0 +1,638 @@
+//! Server configuration, read from a `server.toml` and the command line.
+//!
+//! Every key the game knows is checked when the config is loaded, so a typo
+//! or a value out of range stops the server with the key at fault instead
+//! of being silently ignored. Tables the game doesn't know are kept for mods,
+//! which read their own with [`ServerConfig::section`].
+//!
+//! Any key can be overridden from the command line with `--key value`, using
+//! dots for keys in tables, like `--certificate.hostname example.com`.
+
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+
+pub const CONFIG_FILE: &str = "server.toml";
+
+pub const DEFAULT_PORT: u16 = 6006;
+pub const DEFAULT_MAX_PLAYERS: usize = 20;
+/// Players that may wait for a place once the server is full.
+pub const DEFAULT_JOIN_QUEUE: usize = 20;
+pub const DEFAULT_MOTD: &str = "A ModCraft server";
+/// In chunks around the player's chunk.
+pub const DEFAULT_VIEW_DISTANCE: i32 = 4;
+/// Fixed updates per second.
+pub const DEFAULT_TICK_RATE: u32 = 64;
+pub const DEFAULT_WORLD_DIR: &str = "world";
+pub const DEFAULT_HOSTNAME: &str = "127.0.0.1";
+pub const DEFAULT_CERT_FILE: &str = "certificate.pem";
+pub const DEFAULT_KEY_FILE: &str = "certificate_key.pem";
+
+const MAX_VIEW_DISTANCE: i32 = 32;
+const MAX_TICK_RATE: u32 = 1000;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidValue {
+        key: String,
+        message: String,
+    },
+    UnknownKey(String),
+    /// A command line argument that isn't a `--key value` pair.
+    InvalidArgument(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Could not parse {}: {}", path.display(), e),
+            ConfigError::InvalidValue { key, message } => {
+                write!(f, "Invalid value for `{}`: {}", key, message)
+            }
+            ConfigError::UnknownKey(key) => write!(f, "Unknown config key `{}`", key),
+            ConfigError::InvalidArgument(argument) => write!(
+                f,
+                "Invalid argument `{}`, expected `--key value` or `--config path`",
+                argument
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// How many messages of a kind a client may send: `burst` at once, and
+/// `per_second` more every second after that.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f32,
+    pub burst: f32,
+}
+
+impl RateLimit {
+    pub const fn new(per_second: f32, burst: f32) -> Self {
+        RateLimit { per_second, burst }
+    }
+}
+
+/// Limits on what each client can make the server do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    /// Messages handled from one client each tick, the rest wait for the
+    /// next ticks.
+    pub messages_per_tick: usize,
+    /// Messages over the limits a client gets away with before it is
+    /// kicked. One is forgiven every second.
+    pub kick_after: u32,
+    /// Hellos and joins.
+    pub handshake: RateLimit,
+    /// Chat messages and commands.
+    pub chat: RateLimit,
+    pub completions: RateLimit,
+    pub inputs: RateLimit,
+    /// Breaking and placing blocks.
+    pub block_actions: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits::for_tick_rate(DEFAULT_TICK_RATE)
+    }
+}
+
+impl RateLimits {
+    /// The default limits for a server running `tick_rate` ticks a second.
+    pub fn for_tick_rate(tick_rate: u32) -> Self {
+        RateLimits {
+            messages_per_tick: 16,
+            kick_after: 50,
+            handshake: RateLimit::new(1.0, 4.0),
+            chat: RateLimit::new(2.0, 8.0),
+            completions: RateLimit::new(5.0, 10.0),
+            // clients send one every tick and can fall behind for a while
+            inputs: RateLimit::new(2.0 * tick_rate as f32, 64.0),
+            block_actions: RateLimit::new(20.0, 40.0),
+        }
+    }
+}
+
+/// Where the server gets its TLS certificate from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertificateMode {
+    /// A new certificate for `hostname` every time the server starts.
+    SelfSigned { hostname: String },
+    /// A certificate for `hostname` generated the first time the server
+    /// starts and loaded from the files after that, so clients that pinned
+    /// it keep trusting the server.
+    Persistent {
+        hostname: String,
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    },
+    /// A certificate the owner provides, like one signed by a real authority.
+    Files {
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    },
+}
+
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub max_players: usize,
+    /// How many players can wait for a place when the server is full, 0
+    /// turns them away instead.
+    pub join_queue: usize,
+    /// Shown to players when they join.
+    pub motd: String,
+    pub view_distance: i32,
+    pub tick_rate: u32,
+    pub world_dir: PathBuf,
+    /// Only used when creating a new world.
+    pub seed: Option<u64>,
+    /// Only lets in players named in the world's whitelist.
+    pub whitelist: bool,
+    pub rate_limits: RateLimits,
+    pub certificate: CertificateMode,
+    // tables the game doesn't know, for mods
+    sections: toml::Table,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            max_players: DEFAULT_MAX_PLAYERS,
+            join_queue: DEFAULT_JOIN_QUEUE,
+            motd: DEFAULT_MOTD.to_string(),
+            view_distance: DEFAULT_VIEW_DISTANCE,
+            tick_rate: DEFAULT_TICK_RATE,
+            world_dir: PathBuf::from(DEFAULT_WORLD_DIR),
+            seed: None,
+            whitelist: false,
+            rate_limits: RateLimits::default(),
+            certificate: CertificateMode::Persistent {
+                hostname: DEFAULT_HOSTNAME.to_string(),
+                cert_file: PathBuf::from(DEFAULT_CERT_FILE),
+                key_file: PathBuf::from(DEFAULT_KEY_FILE),
+            },
+            sections: toml::Table::new(),
+        }
+    }
+}
+
+// takes keys out of a table, so whatever is left over is unknown
+struct Fields {
+    table: toml::Table,
+    prefix: String,
+}
+
+impl Fields {
+    fn new(table: toml::Table, prefix: &str) -> Self {
+        Fields {
+            table,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn invalid(&self, key: &str, message: impl fmt::Display) -> ConfigError {
+        ConfigError::InvalidValue {
+            key: self.key(key),
+            message: message.to_string(),
+        }
+    }
+
+    fn take<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, ConfigError> {
+        match self.table.remove(key) {
+            Some(value) => value
+                .try_into()
+                .map(Some)
+                .map_err(|e: toml::de::Error| self.invalid(key, e.message())),
+            None => Ok(None),
+        }
+    }
+
+    fn take_or<T: DeserializeOwned>(&mut self, key: &str, default: T) -> Result<T, ConfigError> {
+        Ok(self.take(key)?.unwrap_or(default))
+    }
+
+    fn check_in_range<T: PartialOrd + fmt::Display>(
+        &self,
+        key: &str,
+        value: T,
+        min: T,
+        max: T,
+    ) -> Result<T, ConfigError> {
+        if value < min || value > max {
+            return Err(self.invalid(key, format!("{} is not between {} and {}", value, min, max)));
+        }
+        Ok(value)
+    }
+
+    // anything left over that isn't a table is a key the game doesn't know
+    fn finish(self) -> Result<toml::Table, ConfigError> {
+        match self.table.iter().find(|(_, value)| !value.is_table()) {
+            Some((key, _)) => Err(ConfigError::UnknownKey(self.key(key))),
+            None => Ok(self.table),
+        }
+    }
+
+    // tables inside the config have no room for mods
+    fn finish_table(self) -> Result<(), ConfigError> {
+        match self.table.keys().next() {
+            Some(key) => Err(ConfigError::UnknownKey(self.key(key))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn certificate_mode(table: toml::Table) -> Result<CertificateMode, ConfigError> {
+    let mut fields = Fields::new(table, "certificate.");
+    let mode: String = fields.take_or("mode", "persistent".to_string())?;
+    let certificate = match mode.as_str() {
+        "self-signed" => CertificateMode::SelfSigned {
+            hostname: fields.take_or("hostname", DEFAULT_HOSTNAME.to_string())?,
+        },
+        "persistent" => CertificateMode::Persistent {
+            hostname: fields.take_or("hostname", DEFAULT_HOSTNAME.to_string())?,
+            cert_file: fields.take_or("cert_file", PathBuf::from(DEFAULT_CERT_FILE))?,
+            key_file: fields.take_or("key_file", PathBuf::from(DEFAULT_KEY_FILE))?,
+        },
+        "files" => {
+            let mut path = |key| {
+                fields
+                    .take::<PathBuf>(key)?
+                    .ok_or_else(|| fields.invalid(key, "required when `mode` is \"files\""))
+            };
+            CertificateMode::Files {
+                cert_file: path("cert_file")?,
+                key_file: path("key_file")?,
+            }
+        }
+        _ => {
+            return Err(fields.invalid(
+                "mode",
+                format!(
+                    "expected \"persistent\", \"self-signed\" or \"files\", found \"{}\"",
+                    mode
+                ),
+            ))
+        }
+    };
+    fields.finish_table()?;
+    Ok(certificate)
+}
+
+fn rate_limit(
+    fields: &mut Fields,
+    key: &str,
+    default: RateLimit,
+) -> Result<RateLimit, ConfigError> {
+    let Some(table) = fields.take::<toml::Table>(key)? else {
+        return Ok(default);
+    };
+    let mut limit = Fields::new(table, &fields.key(&format!("{}.", key)));
+    let per_second: f32 = limit.take_or("per_second", default.per_second)?;
+    if !per_second.is_finite() || per_second <= 0.0 {
+        return Err(limit.invalid("per_second", "has to be a number more than 0"));
+    }
+    let burst: f32 = limit.take_or("burst", default.burst)?;
+    if !burst.is_finite() || burst < 1.0 {
+        return Err(limit.invalid("burst", "has to be a number at least 1"));
+    }
+    limit.finish_table()?;
+    Ok(RateLimit { per_second, burst })
+}
+
+fn rate_limits(table: toml::Table, tick_rate: u32) -> Result<RateLimits, ConfigError> {
+    let defaults = RateLimits::for_tick_rate(tick_rate);
+    let mut fields = Fields::new(table, "rate_limits.");
+    let messages_per_tick = fields.take_or("messages_per_tick", defaults.messages_per_tick)?;
+    let messages_per_tick =
+        fields.check_in_range("messages_per_tick", messages_per_tick, 1, usize::MAX)?;
+    let limits = RateLimits {
+        messages_per_tick,
+        kick_after: fields.take_or("kick_after", defaults.kick_after)?,
+        handshake: rate_limit(&mut fields, "handshake", defaults.handshake)?,
+        chat: rate_limit(&mut fields, "chat", defaults.chat)?,
+        completions: rate_limit(&mut fields, "completions", defaults.completions)?,
+        inputs: rate_limit(&mut fields, "inputs", defaults.inputs)?,
+        block_actions: rate_limit(&mut fields, "block_actions", defaults.block_actions)?,
+    };
+    fields.finish_table()?;
+    Ok(limits)
+}
+
+impl ServerConfig {
+    /// Checks every key the game knows in a parsed config.
+    pub fn from_table(table: toml::Table) -> Result<Self, ConfigError> {
+        let defaults = ServerConfig::default();
+        let mut fields = Fields::new(table, "");
+
+        let bind_address: String =
+            fields.take_or("bind_address", defaults.bind_address.to_string())?;
+        let bind_address = bind_address
+            .parse()
+            .map_err(|e| fields.invalid("bind_address", e))?;
+        let port = fields.take_or("port", defaults.port)?;
+        let port = fields.check_in_range("port", port, 1, u16::MAX)?;
+        let max_players = fields.take_or("max_players", defaults.max_players)?;
+        let max_players = fields.check_in_range("max_players", max_players, 1, usize::MAX)?;
+        let join_queue = fields.take_or("join_queue", defaults.join_queue)?;
+        let motd = fields.take_or("motd", defaults.motd)?;
+        let view_distance = fields.take_or("view_distance", defaults.view_distance)?;
+        let view_distance =
+            fields.check_in_range("view_distance", view_distance, 0, MAX_VIEW_DISTANCE)?;
+        let tick_rate = fields.take_or("tick_rate", defaults.tick_rate)?;
+        let tick_rate = fields.check_in_range("tick_rate", tick_rate, 1, MAX_TICK_RATE)?;
+        let world_dir: PathBuf = fields.take_or("world_dir", defaults.world_dir)?;
+        if world_dir.as_os_str().is_empty() {
+            return Err(fields.invalid("world_dir", "can't be empty"));
+        }
+        let seed = fields.take("seed")?;
+        let whitelist = fields.take_or("whitelist", defaults.whitelist)?;
+        let rate_limits = match fields.take::<toml::Table>("rate_limits")? {
+            Some(table) => rate_limits(table, tick_rate)?,
+            None => RateLimits::for_tick_rate(tick_rate),
+        };
+        let certificate = match fields.take::<toml::Table>("certificate")? {
+            Some(table) => certificate_mode(table)?,
+            None => defaults.certificate,
+        };
+
+        Ok(ServerConfig {
+            bind_address,
+            port,
+            max_players,
+            join_queue,
+            motd,
+            view_distance,
+            tick_rate,
+            world_dir,
+            seed,
+            whitelist,
+            rate_limits,
+            certificate,
+            sections: fields.finish()?,
+        })
+    }
+
+    /// Reads the config at `path`, or uses the defaults if there is no file,
+    /// and sets the overrides on top.
+    pub fn load(path: &Path, overrides: &[(String, String)]) -> Result<Self, ConfigError> {
+        let mut table = match fs::read_to_string(path) {
+            Ok(text) => {
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+        for (key, value) in overrides {
+            set_override(&mut table, key, value)?;
+        }
+        ServerConfig::from_table(table)
+    }
+
+    /// A mod's own table, `[name]` in the config. None if the config doesn't
+    /// have one.
+    pub fn section<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, ConfigError> {
+        match self.sections.get(name) {
+            Some(value) => {
+                value
+                    .clone()
+                    .try_into()
+                    .map(Some)
+                    .map_err(|e| ConfigError::InvalidValue {
+                        key: name.to_string(),
+                        message: e.message().to_string(),
+                    })
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+// values are read as toml if they can be, and as strings otherwise, so
+// `--port 7000` is a number and `--motd hello` doesn't need quotes
+fn set_override(table: &mut toml::Table, key: &str, value: &str) -> Result<(), ConfigError> {
+    let value = format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or_default();
+    let mut table = table;
+    for (i, part) in parts.iter().enumerate() {
+        let entry = table
+            .entry(part.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = entry
+            .as_table_mut()
+            .ok_or_else(|| ConfigError::InvalidValue {
+                key: parts[..=i].join("."),
+                message: "is not a table".to_string(),
+            })?;
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// The config file and overrides given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigArgs {
+    pub path: PathBuf,
+    pub overrides: Vec<(String, String)>,
+}
+
+impl ConfigArgs {
+    /// Reads `--config path` and `--key value` pairs, either as two arguments
+    /// or as `--key=value`. Dashes in keys are read as underscores.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut config_args = ConfigArgs {
+            path: PathBuf::from(CONFIG_FILE),
+            overrides: Vec::new(),
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(key) = arg.strip_prefix("--").filter(|key| !key.is_empty()) else {
+                return Err(ConfigError::InvalidArgument(arg));
+            };
+            let (key, value) = match key.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (key.to_string(), value),
+                    None => return Err(ConfigError::InvalidArgument(arg)),
+                },
+            };
+            let key = key.replace('-', "_");
+            if key == "config" {
+                config_args.path = PathBuf::from(value);
+            } else {
+                config_args.overrides.push((key, value));
+            }
+        }
+        Ok(config_args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    fn load(text: &str, args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        let args = ConfigArgs::parse(args.iter().map(|arg| arg.to_string()))?;
+        let mut table: toml::Table = toml::from_str(text).unwrap();
+        for (key, value) in &args.overrides {
+            set_override(&mut table, key, value)?;
+        }
+        ServerConfig::from_table(table)
+    }
+
+    fn invalid_key(result: Result<ServerConfig, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::InvalidValue { key, .. }) => key,
+            Err(ConfigError::UnknownKey(key)) => format!("unknown {}", key),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn configs_are_checked_and_overridden() {
+        assert_eq!(load("", &[]).unwrap(), ServerConfig::default());
+
+        let config = load(
+            r#"
+            port = 7000
+            motd = "Welcome"
+            seed = 42
+
+            [certificate]
+            mode = "files"
+            cert_file = "cert.pem"
+            key_file = "key.pem"
+
+            [example_mod]
+            ore_attempts = 3
+            "#,
+            &[
+                "--max-players",
+                "5",
+                "--motd=Hi",
+                "--certificate.key_file",
+                "other.pem",
+                "--whitelist",
+                "true",
+                "--rate-limits.chat.burst",
+                "3",
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.max_players, 5);
+        assert_eq!(config.motd, "Hi");
+        assert_eq!(config.seed, Some(42));
+        assert!(config.whitelist);
+        assert_eq!(
+            config.rate_limits.chat,
+            RateLimit::new(RateLimits::default().chat.per_second, 3.0)
+        );
+        // clients send an input every tick, however many that is
+        let slow = load("tick_rate = 20", &[]).unwrap();
+        assert_eq!(slow.rate_limits.inputs.per_second, 40.0);
+        assert_eq!(
+            config.certificate,
+            CertificateMode::Files {
+                cert_file: PathBuf::from("cert.pem"),
+                key_file: PathBuf::from("other.pem"),
+            }
+        );
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct ExampleConfig {
+            ore_attempts: u32,
+        }
+        assert_eq!(
+            config.section("example_mod").unwrap(),
+            Some(ExampleConfig { ore_attempts: 3 })
+        );
+        assert_eq!(config.section::<ExampleConfig>("other_mod").unwrap(), None);
+
+        // generated certificates are kept unless asked not to
+        let config = load("", &["--certificate.hostname", "example.com"]).unwrap();
+        assert_eq!(
+            config.certificate,
+            CertificateMode::Persistent {
+                hostname: "example.com".to_string(),
+                cert_file: PathBuf::from(DEFAULT_CERT_FILE),
+                key_file: PathBuf::from(DEFAULT_KEY_FILE),
+            }
+        );
+        let config = load("[certificate]\nmode = \"self-signed\"", &[]).unwrap();
+        assert_eq!(
+            config.certificate,
+            CertificateMode::SelfSigned {
+                hostname: DEFAULT_HOSTNAME.to_string(),
+            }
+        );
+
+        // errors name the key at fault
+        assert_eq!(invalid_key(load("port = \"fast\"", &[])), "port");
+        assert_eq!(invalid_key(load("", &["--port", "0"])), "port");
+        assert_eq!(
+            invalid_key(load("", &["--view-distance", "99"])),
+            "view_distance"
+        );
+        assert_eq!(
+            invalid_key(load("", &["--bind-address", "nowhere"])),
+            "bind_address"
+        );
+        assert_eq!(invalid_key(load("prot = 7000", &[])), "unknown prot");
+        assert_eq!(
+            invalid_key(load("[certificate]\nmode = \"files\"", &[])),
+            "certificate.cert_file"
+        );
+        assert_eq!(
+            invalid_key(load("", &["--certificate.host", "example.com"])),
+            "unknown certificate.host"
+        );
+        assert_eq!(
+            invalid_key(load("[rate_limits.chat]\nper_second = 0", &[])),
+            "rate_limits.chat.per_second"
+        );
+        // nan compares false with everything, so it has to be caught on its own
+        assert_eq!(
+            invalid_key(load("[rate_limits.chat]\nper_second = nan", &[])),
+            "rate_limits.chat.per_second"
+        );
+        assert_eq!(
+            invalid_key(load("[rate_limits.inputs]\nburst = inf", &[])),
+            "rate_limits.inputs.burst"
+        );
+        assert_eq!(
+            invalid_key(load("[rate_limits]\nchat = { rate = 1 }", &[])),
+            "unknown rate_limits.chat.rate"
+        );
+        assert!(matches!(
+            ConfigArgs::parse(["--port".to_string()]),
+            Err(ConfigError::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,3 +1,15 @@
+pub mod blocks;
+pub mod chunk;
+pub mod commands;
+pub mod config;
+pub mod meshing;
+pub mod mods;
+pub mod physics;
+pub mod replication;
+pub mod save;
+pub mod world;
+pub mod worldgen;
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -0,0 +1,316 @@
+//! Turns chunks into vertex data with greedy meshing.
+//!
+//! Only faces that border a block you can see through are kept, and
+//! neighboring faces with the same texture are merged into one quad. Texture
+//! coordinates are in blocks, so a merged quad repeats its texture once per
+//! block when sampled with a repeating sampler. This is plain data in and
+//! out, so it runs on any thread and doesn't need a GPU.
+
+use crate::{
+    blocks::{BlockFace, BlockId, BlockRegistry},
+    chunk::{Chunk, CHUNK_SIZE},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Appearance {
+    opaque: bool,
+    // texture layer for each face, None for blocks that are never drawn
+    layers: Option<[u32; 6]>,
+}
+
+/// How each block looks, looked up by id while meshing.
+#[derive(Debug, Clone, Default)]
+pub struct BlockAppearances {
+    blocks: Vec<Appearance>,
+}
+
+impl BlockAppearances {
+    /// `layer` maps a texture name to its layer in the block texture atlas.
+    pub fn new(registry: &BlockRegistry, mut layer: impl FnMut(&str) -> u32) -> Self {
+        let blocks = registry
+            .blocks()
+            .iter()
+            .map(|block| Appearance {
+                opaque: !block.properties.transparent,
+                layers: block
+                    .properties
+                    .textures
+                    .as_ref()
+                    .map(|textures| BlockFace::ALL.map(|face| layer(textures.get(face)))),
+            })
+            .collect();
+        BlockAppearances { blocks }
+    }
+
+    // unknown blocks are treated like air
+    fn get(&self, block: BlockId) -> Appearance {
+        self.blocks.get(block as usize).copied().unwrap_or_default()
+    }
+}
+
+/// The chunks next to the one being meshed, indexed by the [`BlockFace`]
+/// pointing at them. Faces bordering a missing neighbor are kept.
+pub type ChunkNeighbors<'a> = [Option<&'a Chunk>; 6];
+
+/// Vertex buffers for one chunk, with positions relative to its origin.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    /// Texture atlas layer of each vertex.
+    pub layers: Vec<u32>,
+    pub indices: Vec<u32>,
+}
+
+impl ChunkMesh {
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn quad_count(&self) -> usize {
+        self.indices.len() / 6
+    }
+
+    fn add_quad(
+        &mut self,
+        face: BlockFace,
+        axes: (usize, usize, usize),
+        plane: usize,
+        (u, v): (usize, usize),
+        (width, height): (usize, usize),
+        layer: u32,
+    ) {
+        let (axis, u_axis, v_axis) = axes;
+        let (dx, dy, dz) = face.offset();
+        let normal = [dx as f32, dy as f32, dz as f32];
+        let first = self.positions.len() as u32;
+
+        for (du, dv) in [(0, 0), (width, 0), (width, height), (0, height)] {
+            let mut position = [0.0; 3];
+            position[axis] = plane as f32;
+            position[u_axis] = (u + du) as f32;
+            position[v_axis] = (v + dv) as f32;
+            self.positions.push(position);
+            self.normals.push(normal);
+            // sides keep the top of the texture facing up
+            self.uvs.push(match face {
+                BlockFace::Top | BlockFace::Bottom => [du as f32, dv as f32],
+                _ => [du as f32, (height - dv) as f32],
+            });
+            self.layers.push(layer);
+        }
+
+        // counter-clockwise when looking at the face from outside
+        let mut u_dir = [0.0; 3];
+        u_dir[u_axis] = 1.0;
+        let mut v_dir = [0.0; 3];
+        v_dir[v_axis] = 1.0;
+        let facing = cross(u_dir, v_dir)
+            .iter()
+            .zip(normal)
+            .map(|(a, b)| a * b)
+            .sum::<f32>();
+        let order = if facing > 0.0 {
+            [0, 1, 2, 0, 2, 3]
+        } else {
+            [0, 2, 1, 0, 3, 2]
+        };
+        self.indices.extend(order.map(|index| first + index));
+    }
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+// the block next to `pos` in the face's direction, None if its chunk is missing
+fn neighbor_block(
+    chunk: &Chunk,
+    neighbors: &ChunkNeighbors,
+    pos: [usize; 3],
+    face: BlockFace,
+) -> Option<BlockId> {
+    let (dx, dy, dz) = face.offset();
+    let next = [pos[0] as i32 + dx, pos[1] as i32 + dy, pos[2] as i32 + dz];
+    let size = CHUNK_SIZE as i32;
+    if next.iter().all(|coord| (0..size).contains(coord)) {
+        return Some(chunk.get(next[0] as usize, next[1] as usize, next[2] as usize));
+    }
+    let [x, y, z] = next.map(|coord| coord.rem_euclid(size) as usize);
+    neighbors[face as usize].map(|neighbor| neighbor.get(x, y, z))
+}
+
+pub fn mesh_chunk(
+    chunk: &Chunk,
+    neighbors: &ChunkNeighbors,
+    appearances: &BlockAppearances,
+) -> ChunkMesh {
+    let mut mesh = ChunkMesh::default();
+    if chunk
+        .uniform_block()
+        .is_some_and(|block| appearances.get(block).layers.is_none())
+    {
+        return mesh;
+    }
+
+    for face in BlockFace::ALL {
+        let (dx, dy, dz) = face.offset();
+        let axes = match (dx, dy) {
+            (0, 0) => (2, 0, 1),
+            (_, 0) => (0, 2, 1),
+            _ => (1, 0, 2),
+        };
+        let (axis, u_axis, v_axis) = axes;
+        let positive = dx + dy + dz > 0;
+
+        for slice in 0..CHUNK_SIZE {
+            // layer of each visible face in this slice, indexed [v][u]
+            let mut mask = [[None; CHUNK_SIZE]; CHUNK_SIZE];
+            for (v, row) in mask.iter_mut().enumerate() {
+                for (u, visible) in row.iter_mut().enumerate() {
+                    let mut pos = [0; 3];
+                    pos[axis] = slice;
+                    pos[u_axis] = u;
+                    pos[v_axis] = v;
+                    let block = chunk.get(pos[0], pos[1], pos[2]);
+                    let Some(layers) = appearances.get(block).layers else {
+                        continue;
+                    };
+                    let hidden =
+                        neighbor_block(chunk, neighbors, pos, face).is_some_and(|neighbor| {
+                            neighbor == block || appearances.get(neighbor).opaque
+                        });
+                    if !hidden {
+                        *visible = Some(layers[face as usize]);
+                    }
+                }
+            }
+
+            let plane = if positive { slice + 1 } else { slice };
+            for v in 0..CHUNK_SIZE {
+                let mut u = 0;
+                while u < CHUNK_SIZE {
+                    let Some(layer) = mask[v][u] else {
+                        u += 1;
+                        continue;
+                    };
+                    let mut width = 1;
+                    while u + width < CHUNK_SIZE && mask[v][u + width] == Some(layer) {
+                        width += 1;
+                    }
+                    let mut height = 1;
+                    while v + height < CHUNK_SIZE
+                        && mask[v + height][u..u + width]
+                            .iter()
+                            .all(|other| *other == Some(layer))
+                    {
+                        height += 1;
+                    }
+                    for row in &mut mask[v..v + height] {
+                        row[u..u + width].fill(None);
+                    }
+                    mesh.add_quad(face, axes, plane, (u, v), (width, height), layer);
+                    u += width;
+                }
+            }
+        }
+    }
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{BlockProperties, BlockTextures, AIR};
+
+    fn appearances() -> (BlockRegistry, BlockAppearances) {
+        let mut registry = BlockRegistry::default();
+        registry
+            .register(
+                "test:glass",
+                BlockProperties::solid(BlockTextures::all("glass")).with_transparency(true),
+            )
+            .unwrap();
+        let names = [
+            "bedrock",
+            "stone2",
+            "dirt",
+            "grass_path_top",
+            "grass_path_side",
+            "glass",
+        ];
+        let appearances = BlockAppearances::new(&registry, |texture| {
+            names.iter().position(|name| *name == texture).unwrap() as u32
+        });
+        (registry, appearances)
+    }
+
+    fn face_normals(mesh: &ChunkMesh) -> Vec<[f32; 3]> {
+        mesh.indices
+            .chunks(3)
+            .map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|i| mesh.positions[triangle[i] as usize]);
+                cross(
+                    [b[0] - a[0], b[1] - a[1], b[2] - a[2]],
+                    [c[0] - a[0], c[1] - a[1], c[2] - a[2]],
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_block_has_six_outward_faces() {
+        let (registry, appearances) = appearances();
+        let mut chunk = Chunk::default();
+        chunk.set(3, 4, 5, registry.id("modcraft:stone").unwrap());
+
+        let mesh = mesh_chunk(&chunk, &[None; 6], &appearances);
+        assert_eq!(mesh.quad_count(), 6);
+        assert_eq!(mesh.positions.len(), 24);
+        assert!(mesh.layers.iter().all(|layer| *layer == 1));
+        for (triangle, normal) in face_normals(&mesh).iter().enumerate() {
+            let vertex_normal = mesh.normals[mesh.indices[triangle * 3] as usize];
+            let facing: f32 = normal.iter().zip(vertex_normal).map(|(a, b)| a * b).sum();
+            assert!(facing > 0.0, "triangle {} faces inwards", triangle);
+        }
+    }
+
+    #[test]
+    fn faces_merge_and_cull() {
+        let (registry, appearances) = appearances();
+        let stone = registry.id("modcraft:stone").unwrap();
+        let solid = Chunk::filled(stone);
+
+        // a whole chunk of one block is one quad per side
+        let mesh = mesh_chunk(&solid, &[None; 6], &appearances);
+        assert_eq!(mesh.quad_count(), 6);
+        assert_eq!(mesh.uvs.iter().map(|uv| uv[0]).fold(0.0, f32::max), 16.0);
+
+        // and nothing when buried between other solid chunks
+        let mesh = mesh_chunk(&solid, &[Some(&solid); 6], &appearances);
+        assert!(mesh.is_empty());
+
+        // grass has a different top texture, so the top can't merge with dirt
+        let mut chunk = Chunk::default();
+        chunk.set(0, 0, 0, registry.id("modcraft:grass_path").unwrap());
+        chunk.set(1, 0, 0, registry.id("modcraft:dirt").unwrap());
+        let mesh = mesh_chunk(&chunk, &[None; 6], &appearances);
+        // only the dirt bottoms merge
+        assert_eq!(mesh.quad_count(), 9);
+
+        // glass shows what's behind it but not the glass next to it
+        let glass = registry.id("test:glass").unwrap();
+        let mut chunk = Chunk::default();
+        chunk.set(0, 0, 0, glass);
+        chunk.set(1, 0, 0, glass);
+        chunk.set(2, 0, 0, stone);
+        let mesh = mesh_chunk(&chunk, &[None; 6], &appearances);
+        assert_eq!(mesh.quad_count(), 6 + 5);
+        assert!(mesh_chunk(&Chunk::filled(AIR), &[None; 6], &appearances).is_empty());
+    }
+}
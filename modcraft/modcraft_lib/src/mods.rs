@@ -0,0 +1,124 @@
+//! The contract between the game and a dynamically loaded mod.
+//!
+//! A mod is a `dylib` that depends on this crate, implements [`Mod`] and
+//! exports itself with [`declare_mod!`]. The game opens the library, checks
+//! the [`ModDeclaration`] it finds and then lets the mod register itself.
+//!
+//! Mods share Bevy with the game, so they have to depend on the same Bevy
+//! version and be built in the same workspace as the game.
+
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    blocks::{BlockId, BlockProperties, BlockRegistry, BlockRegistryError},
+    commands::{ChatCommand, ChatCommands},
+    replication::{ReplicationRegistry, ReplicationRegistryError},
+    worldgen::{GeneratorStage, WorldGenerator},
+};
+
+/// Bumped whenever [`ModDeclaration`], [`Mod`], [`ModAppExt`] or the
+/// resources it registers into change in a way that breaks mods compiled
+/// against an older `modcraft_lib`.
+pub const ABI_VERSION: u32 = 5;
+
+/// The version of the game that a mod was compiled against.
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The symbol [`declare_mod!`] exports and the game looks up.
+pub const DECLARATION_SYMBOL: &[u8] = b"MODCRAFT_MOD_DECLARATION\0";
+
+pub trait Mod: Send + Sync {
+    /// Called once while the server plugins are being built.
+    fn register(&self, app: &mut App);
+}
+
+/// Exported by every mod. `abi_version` must stay the first field so the game
+/// can read it safely before trusting the rest of the layout.
+#[repr(C)]
+pub struct ModDeclaration {
+    pub abi_version: u32,
+    pub game_version: &'static str,
+    pub name: &'static str,
+    pub version: &'static str,
+    pub create: fn() -> Box<dyn Mod>,
+}
+
+/// Registration helpers for mods, implemented for [`App`].
+pub trait ModAppExt {
+    fn register_block(
+        &mut self,
+        name: &str,
+        properties: BlockProperties,
+    ) -> Result<BlockId, BlockRegistryError>;
+
+    fn register_chat_command(&mut self, command: ChatCommand) -> &mut Self;
+
+    fn add_generator_stage(&mut self, stage: impl GeneratorStage + 'static) -> &mut Self;
+
+    /// Sends the component to clients along with the replicated entities
+    /// that have it, see the replication module.
+    fn register_replicated_component<C: Component + Serialize + DeserializeOwned>(
+        &mut self,
+        name: &str,
+    ) -> Result<usize, ReplicationRegistryError>;
+}
+
+impl ModAppExt for App {
+    fn register_block(
+        &mut self,
+        name: &str,
+        properties: BlockProperties,
+    ) -> Result<BlockId, BlockRegistryError> {
+        self.init_resource::<BlockRegistry>()
+            .world
+            .resource_mut::<BlockRegistry>()
+            .register(name, properties)
+    }
+
+    fn register_chat_command(&mut self, command: ChatCommand) -> &mut Self {
+        self.init_resource::<ChatCommands>()
+            .world
+            .resource_mut::<ChatCommands>()
+            .register(command);
+        self
+    }
+
+    fn add_generator_stage(&mut self, stage: impl GeneratorStage + 'static) -> &mut Self {
+        self.init_resource::<WorldGenerator>()
+            .world
+            .resource_mut::<WorldGenerator>()
+            .add_stage(stage);
+        self
+    }
+
+    fn register_replicated_component<C: Component + Serialize + DeserializeOwned>(
+        &mut self,
+        name: &str,
+    ) -> Result<usize, ReplicationRegistryError> {
+        self.init_resource::<ReplicationRegistry>()
+            .world
+            .resource_mut::<ReplicationRegistry>()
+            .register::<C>(name)
+    }
+}
+
+/// Exports a mod from a `dylib`, using the crate's name and version.
+///
+/// ```ignore
+/// modcraft_lib::declare_mod!(MyMod);
+/// ```
+#[macro_export]
+macro_rules! declare_mod {
+    ($constructor:expr) => {
+        #[no_mangle]
+        pub static MODCRAFT_MOD_DECLARATION: $crate::mods::ModDeclaration =
+            $crate::mods::ModDeclaration {
+                abi_version: $crate::mods::ABI_VERSION,
+                game_version: $crate::mods::GAME_VERSION,
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                create: || -> Box<dyn $crate::mods::Mod> { Box::new($constructor) },
+            };
+    };
+}
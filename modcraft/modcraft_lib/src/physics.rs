@@ -0,0 +1,447 @@
+//! Player movement and collision against the blocks of the world.
+//!
+//! The server runs [`step`] to decide where players are and the client runs
+//! the same function to predict its own player until the server's answer
+//! arrives. The world is only seen through a closure, so it works on any
+//! [`VoxelWorld`] or on hand-built blocks. [`raycast`] finds the block a
+//! player is looking at the same way.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blocks::{BlockFace, BlockRegistry},
+    world::BlockPos,
+    world::VoxelWorld,
+};
+
+pub const PLAYER_WIDTH: f32 = 0.6;
+pub const PLAYER_HEIGHT: f32 = 1.8;
+pub const PLAYER_EYE_HEIGHT: f32 = 1.62;
+/// How far from their eyes players can break and place blocks.
+pub const REACH: f32 = 4.5;
+
+// blocks per second
+const WALK_SPEED: f32 = 4.3;
+const JUMP_SPEED: f32 = 8.4;
+const MAX_FALL_SPEED: f32 = 60.0;
+// blocks per second squared
+const GRAVITY: f32 = 32.0;
+
+// a step is split into moves shorter than a block, so fast players can't
+// pass through one
+const MAX_MOVE: f32 = 0.5;
+// keeps boxes that touch a block face from counting as inside the block
+const EPSILON: f32 = 1e-4;
+
+/// What a player wants to do during one tick.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    /// Forwards movement, between -1 and 1.
+    pub forward: f32,
+    /// Sideways movement to the right, between -1 and 1.
+    pub right: f32,
+    /// Radians around the up axis, 0 looks towards -z.
+    pub yaw: f32,
+    /// Radians up from the horizon.
+    pub pitch: f32,
+    pub jump: bool,
+}
+
+impl PlayerInput {
+    /// The direction the player is looking in.
+    pub fn look_direction(&self) -> Vec3 {
+        Vec3::new(
+            -self.yaw.sin() * self.pitch.cos(),
+            self.pitch.sin(),
+            -self.yaw.cos() * self.pitch.cos(),
+        )
+    }
+
+    // input comes from clients, so keep it within what a player could do
+    fn sanitized(&self) -> PlayerInput {
+        let finite = |value: f32| if value.is_finite() { value } else { 0.0 };
+        PlayerInput {
+            forward: finite(self.forward).clamp(-1.0, 1.0),
+            right: finite(self.right).clamp(-1.0, 1.0),
+            yaw: finite(self.yaw),
+            pitch: finite(self.pitch)
+                .clamp(-std::f32::consts::FRAC_PI_2, std::f32::consts::FRAC_PI_2),
+            jump: self.jump,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlayerBody {
+    /// The middle of the bottom of the player's box.
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub on_ground: bool,
+}
+
+impl PlayerBody {
+    pub fn new(position: Vec3) -> Self {
+        PlayerBody {
+            position,
+            velocity: Vec3::ZERO,
+            on_ground: false,
+        }
+    }
+
+    pub fn eye_position(&self) -> Vec3 {
+        self.position + Vec3::Y * PLAYER_EYE_HEIGHT
+    }
+
+    pub fn block(&self) -> BlockPos {
+        block_containing(self.position)
+    }
+
+    /// Whether the player's box overlaps the block, touching doesn't count.
+    pub fn intersects(&self, pos: BlockPos) -> bool {
+        let (min, max) = self.bounds();
+        let block = Vec3::new(pos.x as f32, pos.y as f32, pos.z as f32);
+        (min + EPSILON).cmplt(block + Vec3::ONE).all() && (max - EPSILON).cmpgt(block).all()
+    }
+
+    // the box's lowest and highest corners
+    fn bounds(&self) -> (Vec3, Vec3) {
+        let half = PLAYER_WIDTH / 2.0;
+        (
+            self.position - Vec3::new(half, 0.0, half),
+            self.position + Vec3::new(half, PLAYER_HEIGHT, half),
+        )
+    }
+}
+
+fn block_containing(position: Vec3) -> BlockPos {
+    let floor = position.floor();
+    BlockPos::new(floor.x as i32, floor.y as i32, floor.z as i32)
+}
+
+/// Whether a block stops players in `world`. Chunks that aren't loaded
+/// count as solid, so nobody falls into the world before it exists.
+pub fn is_solid(world: &VoxelWorld, registry: &BlockRegistry, pos: BlockPos) -> bool {
+    match world.get_block(pos) {
+        Some(block) => registry
+            .properties(block)
+            .is_some_and(|properties| properties.solid),
+        None => true,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RayHit {
+    pub block: BlockPos,
+    /// The face the ray entered through, None if it started in the block.
+    pub face: Option<BlockFace>,
+}
+
+/// The first block along the ray, within `max_distance`, that `hit`
+/// accepts.
+pub fn raycast(
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    hit: impl Fn(BlockPos) -> bool,
+) -> Option<RayHit> {
+    let direction = direction.try_normalize()?;
+    let mut block = block_containing(origin);
+    if hit(block) {
+        return Some(RayHit { block, face: None });
+    }
+
+    let step = direction.signum();
+    // distance along the ray to the next block boundary on each axis
+    let mut next = Vec3::ZERO;
+    let mut between = Vec3::ZERO;
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            next[axis] = f32::INFINITY;
+            between[axis] = f32::INFINITY;
+            continue;
+        }
+        let boundary = if step[axis] > 0.0 {
+            origin[axis].floor() + 1.0
+        } else {
+            origin[axis].floor()
+        };
+        next[axis] = (boundary - origin[axis]) / direction[axis];
+        between[axis] = 1.0 / direction[axis].abs();
+    }
+
+    loop {
+        let axis = if next.x <= next.y && next.x <= next.z {
+            0
+        } else if next.y <= next.z {
+            1
+        } else {
+            2
+        };
+        if next[axis] > max_distance {
+            return None;
+        }
+        next[axis] += between[axis];
+        let positive = step[axis] > 0.0;
+        // stepping towards +x enters the next block through its west face
+        let face = match (axis, positive) {
+            (0, true) => BlockFace::West,
+            (0, false) => BlockFace::East,
+            (1, true) => BlockFace::Bottom,
+            (1, false) => BlockFace::Top,
+            (_, true) => BlockFace::North,
+            (_, false) => BlockFace::South,
+        };
+        block = block.neighbor(face.opposite());
+        if hit(block) {
+            return Some(RayHit {
+                block,
+                face: Some(face),
+            });
+        }
+    }
+}
+
+/// Advances a player by `dt` seconds.
+pub fn step(
+    body: &PlayerBody,
+    input: &PlayerInput,
+    dt: f32,
+    is_solid: impl Fn(BlockPos) -> bool,
+) -> PlayerBody {
+    let input = input.sanitized();
+    let mut body = *body;
+
+    let forward = Vec3::new(-input.yaw.sin(), 0.0, -input.yaw.cos());
+    let right = Vec3::new(input.yaw.cos(), 0.0, -input.yaw.sin());
+    let walk = (forward * input.forward + right * input.right).clamp_length_max(1.0) * WALK_SPEED;
+    body.velocity.x = walk.x;
+    body.velocity.z = walk.z;
+    if input.jump && body.on_ground {
+        body.velocity.y = JUMP_SPEED;
+    }
+    body.velocity.y = (body.velocity.y - GRAVITY * dt).max(-MAX_FALL_SPEED);
+
+    let movement = body.velocity * dt;
+    let moves = (movement.abs().max_element() / MAX_MOVE).ceil().max(1.0);
+    body.on_ground = false;
+    for _ in 0..moves as usize {
+        // vertical first, so walking off a ledge and landing stay exact
+        for axis in [1, 0, 2] {
+            let delta = movement[axis] / moves;
+            if delta != 0.0 && move_along(&mut body, axis, delta, &is_solid) {
+                if axis == 1 && delta < 0.0 {
+                    body.on_ground = true;
+                }
+                body.velocity[axis] = 0.0;
+            }
+        }
+    }
+    body
+}
+
+// moves the body up to `delta` along one axis, returning whether a block
+// was in the way
+fn move_along(
+    body: &mut PlayerBody,
+    axis: usize,
+    delta: f32,
+    is_solid: &impl Fn(BlockPos) -> bool,
+) -> bool {
+    let (min, max) = body.bounds();
+    let others: Vec<usize> = (0..3).filter(|other| *other != axis).collect();
+    let span = |other: usize| {
+        (min[other] + EPSILON).floor() as i32..=(max[other] - EPSILON).floor() as i32
+    };
+
+    // only blocks past the leading face, the box may already overlap others
+    let layers: Vec<i32> = if delta > 0.0 {
+        ((max[axis] - EPSILON).ceil() as i32..=(max[axis] + delta - EPSILON).floor() as i32)
+            .collect()
+    } else {
+        ((min[axis] + delta + EPSILON).floor() as i32..=(min[axis] + EPSILON).floor() as i32 - 1)
+            .rev()
+            .collect()
+    };
+
+    for layer in layers {
+        let blocked = span(others[0]).any(|a| {
+            span(others[1]).any(|b| {
+                let mut pos = [0; 3];
+                pos[axis] = layer;
+                pos[others[0]] = a;
+                pos[others[1]] = b;
+                is_solid(BlockPos::new(pos[0], pos[1], pos[2]))
+            })
+        });
+        if blocked {
+            body.position[axis] += if delta > 0.0 {
+                layer as f32 - max[axis]
+            } else {
+                (layer + 1) as f32 - min[axis]
+            };
+            return true;
+        }
+    }
+    body.position[axis] += delta;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chunk::Chunk, world::ChunkPos};
+
+    const DT: f32 = 1.0 / 64.0;
+
+    // a floor of stone below y = 0 with air above it
+    fn floor_world() -> (BlockRegistry, VoxelWorld) {
+        let registry = BlockRegistry::default();
+        let stone = registry.id("modcraft:stone").unwrap();
+        let mut world = VoxelWorld::default();
+        for x in -1..=1 {
+            for z in -1..=1 {
+                world.insert_chunk(ChunkPos::new(x, -1, z), Chunk::filled(stone));
+                world.insert_chunk(ChunkPos::new(x, 0, z), Chunk::default());
+            }
+        }
+        (registry, world)
+    }
+
+    fn run(
+        world: &VoxelWorld,
+        registry: &BlockRegistry,
+        mut body: PlayerBody,
+        input: PlayerInput,
+        ticks: usize,
+    ) -> PlayerBody {
+        for _ in 0..ticks {
+            body = step(&body, &input, DT, |pos| is_solid(world, registry, pos));
+        }
+        body
+    }
+
+    #[test]
+    fn falls_lands_and_jumps() {
+        let (registry, world) = floor_world();
+        let body = run(
+            &world,
+            &registry,
+            PlayerBody::new(Vec3::new(8.5, 10.0, 8.5)),
+            PlayerInput::default(),
+            200,
+        );
+        assert_eq!(body.position, Vec3::new(8.5, 0.0, 8.5));
+        assert!(body.on_ground);
+
+        let jump = PlayerInput {
+            jump: true,
+            ..default()
+        };
+        let body = run(&world, &registry, body, jump, 10);
+        assert!(body.position.y > 0.5 && !body.on_ground);
+        let body = run(&world, &registry, body, PlayerInput::default(), 100);
+        assert_eq!(body.position.y, 0.0);
+
+        // fast enough to cover several blocks a tick, still lands on top
+        let mut body = PlayerBody::new(Vec3::new(8.5, 12.0, 8.5));
+        body.velocity.y = -MAX_FALL_SPEED;
+        let body = run(&world, &registry, body, PlayerInput::default(), 1);
+        assert_eq!(body.position.y, 12.0 - MAX_FALL_SPEED * DT);
+        let body = run(&world, &registry, body, PlayerInput::default(), 20);
+        assert_eq!(body.position.y, 0.0);
+    }
+
+    #[test]
+    fn walls_stop_walking() {
+        let (registry, mut world) = floor_world();
+        for y in 0..2 {
+            for z in 0..16 {
+                world.set_block(BlockPos::new(12, y, z), 1);
+            }
+        }
+        let mut body = PlayerBody::new(Vec3::new(8.5, 0.0, 8.5));
+        body.on_ground = true;
+
+        // yaw a quarter turn to the right looks towards +x
+        let walk_east = PlayerInput {
+            forward: 1.0,
+            yaw: -std::f32::consts::FRAC_PI_2,
+            ..default()
+        };
+        let body = run(&world, &registry, body, walk_east, 100);
+        assert_eq!(body.position.x, 12.0 - PLAYER_WIDTH / 2.0);
+        assert!((body.position.z - 8.5).abs() < 1e-3);
+        assert!(body.on_ground);
+
+        // a one block step can be jumped onto
+        world.set_block(BlockPos::new(12, 1, 8), 0);
+        for x in 13..16 {
+            world.set_block(BlockPos::new(x, 0, 8), 1);
+        }
+        let jump_east = PlayerInput {
+            jump: true,
+            ..walk_east
+        };
+        let body = run(&world, &registry, body, jump_east, 1);
+        let body = run(&world, &registry, body, walk_east, 40);
+        assert_eq!(body.position.y, 1.0);
+        assert!(body.position.x > 12.5);
+
+        // unloaded chunks are walls too
+        let body = run(&world, &registry, body, walk_east, 400);
+        assert_eq!(body.position.x, 32.0 - PLAYER_WIDTH / 2.0);
+    }
+
+    #[test]
+    fn raycast_finds_the_first_block() {
+        let (registry, world) = floor_world();
+        let solid = |pos| is_solid(&world, &registry, pos);
+
+        // straight down onto the floor
+        let hit = raycast(Vec3::new(3.5, 1.6, 3.5), Vec3::NEG_Y, REACH, solid);
+        assert_eq!(
+            hit,
+            Some(RayHit {
+                block: BlockPos::new(3, -1, 3),
+                face: Some(BlockFace::Top),
+            })
+        );
+        // too far away
+        assert_eq!(
+            raycast(Vec3::new(3.5, 6.0, 3.5), Vec3::NEG_Y, REACH, solid),
+            None
+        );
+
+        // at an angle, the floor is entered through its top
+        let looking = PlayerInput {
+            yaw: std::f32::consts::FRAC_PI_4,
+            pitch: -std::f32::consts::FRAC_PI_4,
+            ..default()
+        };
+        let hit = raycast(
+            Vec3::new(3.5, 1.6, 3.5),
+            looking.look_direction(),
+            REACH,
+            solid,
+        )
+        .unwrap();
+        assert_eq!(hit.face, Some(BlockFace::Top));
+        assert_eq!(hit.block.y, -1);
+        assert!(hit.block.x < 3 && hit.block.z < 3);
+    }
+
+    #[test]
+    fn input_is_clamped() {
+        let (registry, world) = floor_world();
+        let mut body = PlayerBody::new(Vec3::new(8.5, 0.0, 8.5));
+        body.on_ground = true;
+        let cheat = PlayerInput {
+            forward: 100.0,
+            right: f32::NAN,
+            ..default()
+        };
+        let body = run(&world, &registry, body, cheat, 1);
+        assert!((body.velocity.length() - WALK_SPEED).abs() < 1e-4);
+    }
+}
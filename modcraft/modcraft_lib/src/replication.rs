@@ -0,0 +1,196 @@
+//! Components the server keeps in sync on its clients.
+//!
+//! Entities spawned with [`Replicated`] are sent to the clients near them,
+//! along with every component of theirs that is in the
+//! [`ReplicationRegistry`]. Components are sent with bincode and matched up
+//! by name, so a mod marks its own components by registering them with
+//! [`ModAppExt::register_replicated_component`](crate::mods::ModAppExt).
+//!
+//! An entity with a [`Transform`] is only sent to clients within their view
+//! distance of it, one without is sent to every client.
+
+use std::{collections::HashMap, fmt};
+
+use bevy::{
+    ecs::world::{EntityRef, EntityWorldMut},
+    prelude::*,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::blocks::is_valid_name;
+
+/// Marks an entity to be sent to clients.
+#[derive(Component, Debug, Clone, Copy, Default)]
+pub struct Replicated;
+
+/// The name of the player an entity is.
+#[derive(Component, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerName(pub String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationRegistryError {
+    /// Names have to look like `namespace:path`, using only `a-z`, `0-9`
+    /// and `_`.
+    InvalidName(String),
+    Duplicate(String),
+}
+
+impl fmt::Display for ReplicationRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationRegistryError::InvalidName(name) => write!(
+                f,
+                "Component name {} is not of the form namespace:path using a-z, 0-9 and _",
+                name
+            ),
+            ReplicationRegistryError::Duplicate(name) => {
+                write!(f, "Component {} is already replicated", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplicationRegistryError {}
+
+/// A replicated component type, with its type erased.
+#[derive(Debug, Clone)]
+pub struct ReplicatedComponent {
+    name: String,
+    serialize: fn(&EntityRef) -> Option<Vec<u8>>,
+    insert: fn(&mut EntityWorldMut, &[u8]) -> bincode::Result<()>,
+    remove: fn(&mut EntityWorldMut),
+}
+
+fn serialize<C: Component + Serialize>(entity: &EntityRef) -> Option<Vec<u8>> {
+    bincode::serialize(entity.get::<C>()?).ok()
+}
+
+fn insert<C: Component + DeserializeOwned>(
+    entity: &mut EntityWorldMut,
+    bytes: &[u8],
+) -> bincode::Result<()> {
+    entity.insert(bincode::deserialize::<C>(bytes)?);
+    Ok(())
+}
+
+fn remove<C: Component>(entity: &mut EntityWorldMut) {
+    entity.remove::<C>();
+}
+
+impl ReplicatedComponent {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The component's bytes, `None` if the entity doesn't have it.
+    pub fn serialize(&self, entity: &EntityRef) -> Option<Vec<u8>> {
+        (self.serialize)(entity)
+    }
+
+    /// Inserts the component the bytes hold, replacing the one the entity
+    /// has.
+    pub fn insert(&self, entity: &mut EntityWorldMut, bytes: &[u8]) -> bincode::Result<()> {
+        (self.insert)(entity, bytes)
+    }
+
+    pub fn remove(&self, entity: &mut EntityWorldMut) {
+        (self.remove)(entity)
+    }
+}
+
+/// The replicated components in the order they were registered, which is
+/// the order the server sends them in.
+#[derive(Resource, Debug, Clone)]
+pub struct ReplicationRegistry {
+    components: Vec<ReplicatedComponent>,
+    ids: HashMap<String, usize>,
+}
+
+impl Default for ReplicationRegistry {
+    fn default() -> Self {
+        let mut registry = ReplicationRegistry {
+            components: Vec::new(),
+            ids: HashMap::new(),
+        };
+        registry
+            .register::<Transform>("modcraft:transform")
+            .and_then(|_| registry.register::<PlayerName>("modcraft:player_name"))
+            .expect("Built-in components have valid, unique names");
+        registry
+    }
+}
+
+impl ReplicationRegistry {
+    pub fn register<C: Component + Serialize + DeserializeOwned>(
+        &mut self,
+        name: impl Into<String>,
+    ) -> Result<usize, ReplicationRegistryError> {
+        let name = name.into();
+        if !is_valid_name(&name) {
+            return Err(ReplicationRegistryError::InvalidName(name));
+        }
+        if self.ids.contains_key(&name) {
+            return Err(ReplicationRegistryError::Duplicate(name));
+        }
+        let id = self.components.len();
+        self.ids.insert(name.clone(), id);
+        self.components.push(ReplicatedComponent {
+            name,
+            serialize: serialize::<C>,
+            insert: insert::<C>,
+            remove: remove::<C>,
+        });
+        Ok(id)
+    }
+
+    pub fn id(&self, name: &str) -> Option<usize> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn get(&self, id: usize) -> Option<&ReplicatedComponent> {
+        self.components.get(id)
+    }
+
+    pub fn components(&self) -> &[ReplicatedComponent] {
+        &self.components
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_round_trip_through_their_bytes() {
+        let mut registry = ReplicationRegistry::default();
+        assert_eq!(
+            registry.register::<PlayerName>("modcraft:player_name"),
+            Err(ReplicationRegistryError::Duplicate(
+                "modcraft:player_name".to_string()
+            ))
+        );
+        assert!(matches!(
+            registry.register::<PlayerName>("Name"),
+            Err(ReplicationRegistryError::InvalidName(_))
+        ));
+
+        let mut world = World::new();
+        let name = registry
+            .get(registry.id("modcraft:player_name").unwrap())
+            .unwrap();
+        let from = world.spawn(PlayerName("Alex".to_string())).id();
+        let bytes = name.serialize(&world.entity(from)).unwrap();
+        let transform = &registry.components()[0];
+        assert_eq!(transform.serialize(&world.entity(from)), None);
+
+        let mut to = world.spawn_empty();
+        name.insert(&mut to, &bytes).unwrap();
+        assert_eq!(
+            to.get::<PlayerName>(),
+            Some(&PlayerName("Alex".to_string()))
+        );
+        assert!(name.insert(&mut to, &[1]).is_err());
+        name.remove(&mut to);
+        assert_eq!(to.get::<PlayerName>(), None);
+    }
+}
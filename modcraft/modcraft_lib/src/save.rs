@@ -0,0 +1,680 @@
+//! Worlds on disk: a `level.toml` describing the world, a `players.toml`
+//! with everyone that has joined it, `bans.toml` and `whitelist.toml` saying
+//! who may join and region files holding its chunks.
+//!
+//! Each region file stores an 8x8x8 cube of chunks. It starts with a header
+//! listing where every chunk is, how long it is and its checksum, followed
+//! by the chunks themselves, each compressed on its own with
+//! [`Chunk::encode`]. Chunks take up whole 512 byte sectors, and a chunk is
+//! always written to free sectors before the header points at it, so a write
+//! cut short leaves the copy saved before it intact.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bevy::{prelude::*, utils::Uuid};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    chunk::{Chunk, ChunkDecodeError},
+    world::{BlockPos, ChunkPos},
+};
+
+/// Chunks along each side of a region.
+pub const REGION_SIZE: i32 = 8;
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+const REGION_MAGIC: &[u8; 4] = b"MCRG";
+const REGION_VERSION: u32 = 1;
+const SECTOR_SIZE: u64 = 512;
+// magic and version, then a sector, length and checksum for every chunk
+const HEADER_SIZE: u64 = 8 + REGION_CHUNKS as u64 * 12;
+const FIRST_DATA_SECTOR: u32 = HEADER_SIZE.div_ceil(SECTOR_SIZE) as u32;
+// far more than any encoded chunk, so a damaged length can't exhaust memory
+const MAX_STORED_CHUNK: u32 = 1 << 20;
+
+const LEVEL_FILE: &str = "level.toml";
+const PLAYERS_FILE: &str = "players.toml";
+const BANS_FILE: &str = "bans.toml";
+const WHITELIST_FILE: &str = "whitelist.toml";
+const REGION_DIR: &str = "region";
+const CORRUPTED_DIR: &str = "corrupted";
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(PathBuf, io::Error),
+    /// The file does not start with a region header this version can read.
+    InvalidRegion(PathBuf),
+    /// The chunk's data does not match the checksum it was saved with.
+    ChecksumMismatch(ChunkPos),
+    Decode(ChunkPos, ChunkDecodeError),
+    /// A toml file in the world is not what this version saves.
+    InvalidFile(PathBuf, toml::de::Error),
+}
+
+impl SaveError {
+    /// Whether the error means a chunk's saved data is unusable, rather than
+    /// the save being unreachable.
+    pub fn is_corrupted_chunk(&self) -> bool {
+        matches!(self, SaveError::ChecksumMismatch(_) | SaveError::Decode(..))
+    }
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            SaveError::InvalidRegion(path) => {
+                write!(f, "{} is not a region file", path.display())
+            }
+            SaveError::ChecksumMismatch(pos) => {
+                write!(f, "Saved chunk {:?} does not match its checksum", pos)
+            }
+            SaveError::Decode(pos, e) => write!(f, "Saved chunk {:?} is invalid: {}", pos, e),
+            SaveError::InvalidFile(path, e) => {
+                write!(f, "Could not read {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelMod {
+    pub name: String,
+    pub version: String,
+}
+
+/// Everything about a world that isn't a chunk.
+#[derive(Resource, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelData {
+    #[serde(with = "signed_seed")]
+    pub seed: u64,
+    pub spawn: BlockPos,
+    /// Server ticks the world has run for.
+    pub game_time: u64,
+    /// Mods loaded when the world was last saved. Their blocks' ids are baked
+    /// into the chunks, so loading without them changes the world.
+    pub mods: Vec<LevelMod>,
+}
+
+/// A player that has joined the world, known by the key they sign in with
+/// rather than by their name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerRecord {
+    pub uuid: Uuid,
+    /// The player's Ed25519 public key, in hex.
+    pub public_key: String,
+    pub name: String,
+    /// Where the player was when they last left, `None` for a player that
+    /// hasn't been saved in the world yet.
+    pub position: Option<Vec3>,
+    #[serde(default)]
+    pub operator: bool,
+}
+
+// toml files have to be a table at the top
+#[derive(Serialize, Deserialize)]
+struct PlayersFile {
+    #[serde(default)]
+    players: Vec<PlayerRecord>,
+}
+
+/// Players that may not join the world.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BanList {
+    #[serde(default)]
+    pub players: Vec<PlayerBan>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerBan {
+    pub uuid: Uuid,
+    /// The player's name when they were banned, for whoever reads the file.
+    pub name: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WhitelistFile {
+    #[serde(default)]
+    names: Vec<String>,
+}
+
+// toml integers are signed, so seeds are stored with the same bits as an i64
+mod signed_seed {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(seed: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(*seed as i64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        i64::deserialize(deserializer).map(|seed| seed as u64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct RegionEntry {
+    // 0 when the chunk is not stored, which is inside the header
+    sector: u32,
+    length: u32,
+    checksum: u32,
+}
+
+impl RegionEntry {
+    fn sectors(&self) -> u32 {
+        (self.length as u64).div_ceil(SECTOR_SIZE) as u32
+    }
+}
+
+struct RegionFile {
+    path: PathBuf,
+    file: File,
+    entries: Vec<RegionEntry>,
+}
+
+impl RegionFile {
+    fn open(path: PathBuf) -> Result<Self, SaveError> {
+        let io_error = |e| SaveError::Io(path.clone(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(io_error)?;
+
+        // only the header is read, the chunks are read as they are loaded
+        let length = file.metadata().map_err(io_error)?.len();
+        if length == 0 {
+            let mut region = RegionFile {
+                path,
+                file,
+                entries: vec![RegionEntry::default(); REGION_CHUNKS],
+            };
+            region.write_header()?;
+            return Ok(region);
+        }
+
+        if length < HEADER_SIZE {
+            return Err(SaveError::InvalidRegion(path));
+        }
+        let mut header = vec![0; HEADER_SIZE as usize];
+        file.read_exact(&mut header).map_err(io_error)?;
+        if &header[..4] != REGION_MAGIC || header[4..8] != REGION_VERSION.to_le_bytes() {
+            return Err(SaveError::InvalidRegion(path));
+        }
+        let entries = header[8..HEADER_SIZE as usize]
+            .chunks_exact(12)
+            .map(|entry| {
+                let field = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+                RegionEntry {
+                    sector: field(0),
+                    length: field(4),
+                    checksum: field(8),
+                }
+            })
+            .collect();
+        Ok(RegionFile {
+            path,
+            file,
+            entries,
+        })
+    }
+
+    fn io_error(&self, e: io::Error) -> SaveError {
+        SaveError::Io(self.path.clone(), e)
+    }
+
+    fn write_header(&mut self) -> Result<(), SaveError> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(REGION_MAGIC);
+        header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            header.extend_from_slice(&entry.sector.to_le_bytes());
+            header.extend_from_slice(&entry.length.to_le_bytes());
+            header.extend_from_slice(&entry.checksum.to_le_bytes());
+        }
+        self.write_at(0, &header)
+    }
+
+    fn write_entry(&mut self, index: usize) -> Result<(), SaveError> {
+        let entry = self.entries[index];
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&entry.sector.to_le_bytes());
+        bytes[4..8].copy_from_slice(&entry.length.to_le_bytes());
+        bytes[8..].copy_from_slice(&entry.checksum.to_le_bytes());
+        self.write_at(8 + index as u64 * 12, &bytes)
+    }
+
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), SaveError> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(bytes))
+            .map_err(|e| self.io_error(e))
+    }
+
+    /// The chunk's bytes as stored, without checking them.
+    fn read(&mut self, index: usize) -> Result<Option<Vec<u8>>, SaveError> {
+        let entry = self.entries[index];
+        if entry.sector == 0 {
+            return Ok(None);
+        }
+        if entry.length > MAX_STORED_CHUNK {
+            return Ok(Some(Vec::new()));
+        }
+        let mut bytes = vec![0; entry.length as usize];
+        let read = self
+            .file
+            .seek(SeekFrom::Start(entry.sector as u64 * SECTOR_SIZE))
+            .and_then(|_| self.file.read_exact(&mut bytes));
+        match read {
+            Ok(()) => Ok(Some(bytes)),
+            // the entry points past the end of the file, so whatever was
+            // there is gone
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(Some(Vec::new())),
+            Err(e) => Err(self.io_error(e)),
+        }
+    }
+
+    // first gap between the stored chunks that fits, or the end of the file
+    fn allocate(&self, sectors: u32) -> u32 {
+        let mut used: Vec<(u32, u32)> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.sector != 0)
+            .map(|entry| (entry.sector, entry.sector + entry.sectors()))
+            .collect();
+        used.sort_unstable();
+
+        let mut start = FIRST_DATA_SECTOR;
+        for (used_start, used_end) in used {
+            if used_start >= start + sectors {
+                break;
+            }
+            start = start.max(used_end);
+        }
+        start
+    }
+
+    // the chunk's old sectors are still allocated while the new ones are
+    // picked, so they are only reused once the header no longer points there
+    fn write(&mut self, index: usize, bytes: &[u8]) -> Result<(), SaveError> {
+        let mut entry = RegionEntry {
+            sector: 0,
+            length: bytes.len() as u32,
+            checksum: crc32fast::hash(bytes),
+        };
+        entry.sector = self.allocate(entry.sectors());
+        self.write_at(entry.sector as u64 * SECTOR_SIZE, bytes)?;
+        self.entries[index] = entry;
+        self.write_entry(index)
+    }
+
+    fn remove(&mut self, index: usize) -> Result<(), SaveError> {
+        self.entries[index] = RegionEntry::default();
+        self.write_entry(index)
+    }
+}
+
+fn region_of(pos: ChunkPos) -> ((i32, i32, i32), usize) {
+    let region = (
+        pos.x.div_euclid(REGION_SIZE),
+        pos.y.div_euclid(REGION_SIZE),
+        pos.z.div_euclid(REGION_SIZE),
+    );
+    let (x, y, z) = (
+        pos.x.rem_euclid(REGION_SIZE),
+        pos.y.rem_euclid(REGION_SIZE),
+        pos.z.rem_euclid(REGION_SIZE),
+    );
+    (region, ((y * REGION_SIZE + z) * REGION_SIZE + x) as usize)
+}
+
+// the open region files, by region
+type Regions = HashMap<(i32, i32, i32), RegionFile>;
+
+/// A world's directory, with its region files opened as they are needed.
+/// Clones share the region files, so chunks can be loaded off the main
+/// thread.
+#[derive(Resource, Clone)]
+pub struct WorldSave {
+    dir: PathBuf,
+    regions: Arc<Mutex<Regions>>,
+}
+
+impl WorldSave {
+    /// Opens the world in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, SaveError> {
+        let dir = dir.into();
+        let region_dir = dir.join(REGION_DIR);
+        fs::create_dir_all(&region_dir).map_err(|e| SaveError::Io(region_dir, e))?;
+        Ok(WorldSave {
+            dir,
+            regions: Arc::default(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn load_toml<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, SaveError> {
+        let path = self.dir.join(name);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(SaveError::Io(path, e)),
+        };
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|e| SaveError::InvalidFile(path, e))
+    }
+
+    fn save_toml(&self, name: &str, value: &impl Serialize) -> Result<(), SaveError> {
+        let text = toml::to_string(value).expect("Saved data is always valid toml");
+        // written next to the old file and swapped in, so a crash while
+        // saving never leaves a half written file behind
+        let path = self.dir.join(name);
+        let temp_path = path.with_extension("toml.tmp");
+        fs::write(&temp_path, text)
+            .and_then(|_| fs::rename(&temp_path, &path))
+            .map_err(|e| SaveError::Io(path, e))
+    }
+
+    /// `None` for a new world that has never been saved.
+    pub fn load_level(&self) -> Result<Option<LevelData>, SaveError> {
+        self.load_toml(LEVEL_FILE)
+    }
+
+    pub fn save_level(&self, level: &LevelData) -> Result<(), SaveError> {
+        self.save_toml(LEVEL_FILE, level)
+    }
+
+    /// Everyone that has joined the world, none for a new one.
+    pub fn load_players(&self) -> Result<Vec<PlayerRecord>, SaveError> {
+        let file: Option<PlayersFile> = self.load_toml(PLAYERS_FILE)?;
+        Ok(file.map_or_else(Vec::new, |file| file.players))
+    }
+
+    pub fn save_players(&self, players: &[PlayerRecord]) -> Result<(), SaveError> {
+        self.save_toml(
+            PLAYERS_FILE,
+            &PlayersFile {
+                players: players.to_vec(),
+            },
+        )
+    }
+
+    /// Nobody is banned from a new world.
+    pub fn load_bans(&self) -> Result<BanList, SaveError> {
+        Ok(self.load_toml(BANS_FILE)?.unwrap_or_default())
+    }
+
+    pub fn save_bans(&self, bans: &BanList) -> Result<(), SaveError> {
+        self.save_toml(BANS_FILE, bans)
+    }
+
+    /// The names allowed to join when the server only lets in whitelisted
+    /// players.
+    pub fn load_whitelist(&self) -> Result<Vec<String>, SaveError> {
+        let file: Option<WhitelistFile> = self.load_toml(WHITELIST_FILE)?;
+        Ok(file.map_or_else(Vec::new, |file| file.names))
+    }
+
+    pub fn save_whitelist(&self, names: &[String]) -> Result<(), SaveError> {
+        self.save_toml(
+            WHITELIST_FILE,
+            &WhitelistFile {
+                names: names.to_vec(),
+            },
+        )
+    }
+
+    // runs `f` on the region's file, holding the lock on all of them
+    fn with_region<T>(
+        &self,
+        region: (i32, i32, i32),
+        f: impl FnOnce(&mut RegionFile) -> Result<T, SaveError>,
+    ) -> Result<T, SaveError> {
+        let mut regions = self.regions.lock().unwrap();
+        let file = match regions.entry(region) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = self
+                    .dir
+                    .join(REGION_DIR)
+                    .join(format!("r.{}.{}.{}.region", region.0, region.1, region.2));
+                let file = match RegionFile::open(path.clone()) {
+                    Err(SaveError::InvalidRegion(_)) => {
+                        // nothing in the file can be trusted, so set all of
+                        // it aside and start the region over
+                        self.quarantine_file(&path)?;
+                        RegionFile::open(path)?
+                    }
+                    file => file?,
+                };
+                entry.insert(file)
+            }
+        };
+        f(file)
+    }
+
+    fn corrupted_path(&self, name: &str) -> Result<PathBuf, SaveError> {
+        let dir = self.dir.join(CORRUPTED_DIR);
+        fs::create_dir_all(&dir).map_err(|e| SaveError::Io(dir.clone(), e))?;
+        Ok(dir.join(name))
+    }
+
+    fn quarantine_file(&self, path: &Path) -> Result<(), SaveError> {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let target = self.corrupted_path(&name)?;
+        fs::rename(path, &target).map_err(|e| SaveError::Io(path.to_path_buf(), e))
+    }
+
+    /// `Ok(None)` if the chunk was never saved.
+    pub fn load_chunk(&self, pos: ChunkPos) -> Result<Option<Chunk>, SaveError> {
+        let (region, index) = region_of(pos);
+        let stored = self.with_region(region, |region| {
+            let checksum = region.entries[index].checksum;
+            Ok(region.read(index)?.map(|bytes| (bytes, checksum)))
+        })?;
+        let Some((bytes, checksum)) = stored else {
+            return Ok(None);
+        };
+        if crc32fast::hash(&bytes) != checksum {
+            return Err(SaveError::ChecksumMismatch(pos));
+        }
+        Chunk::decode(&bytes)
+            .map(Some)
+            .map_err(|e| SaveError::Decode(pos, e))
+    }
+
+    pub fn save_chunk(&self, pos: ChunkPos, chunk: &Chunk) -> Result<(), SaveError> {
+        let (region, index) = region_of(pos);
+        let bytes = chunk.encode();
+        self.with_region(region, |region| region.write(index, &bytes))
+    }
+
+    /// Copies whatever is stored for the chunk into the world's `corrupted`
+    /// directory and forgets it, so the chunk can be generated again.
+    pub fn quarantine_chunk(&self, pos: ChunkPos) -> Result<(), SaveError> {
+        let (region, index) = region_of(pos);
+        let target = self.corrupted_path(&format!("c.{}.{}.{}.chunk", pos.x, pos.y, pos.z))?;
+        self.with_region(region, |region| {
+            let bytes = region.read(index)?.unwrap_or_default();
+            fs::write(&target, bytes).map_err(|e| SaveError::Io(target, e))?;
+            region.remove(index)
+        })
+    }
+
+    /// Makes sure everything saved so far has reached the disk.
+    pub fn flush(&self) -> Result<(), SaveError> {
+        for region in self.regions.lock().unwrap().values() {
+            region
+                .file
+                .sync_data()
+                .map_err(|e| SaveError::Io(region.path.clone(), e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::CHUNK_SIZE;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn noisy_chunk(seed: u16) -> Chunk {
+        let mut chunk = Chunk::default();
+        for (i, x) in (0..CHUNK_SIZE).enumerate() {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(x, (i * 7 + z) % CHUNK_SIZE, z, seed + (x * z % 13) as u16);
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn chunks_and_level_round_trip() {
+        let dir = test_dir("modcraft_save_round_trip");
+        let level = LevelData {
+            seed: u64::MAX - 5,
+            spawn: BlockPos::new(0, 65, -3),
+            game_time: 1200,
+            mods: vec![LevelMod {
+                name: "example_mod".to_string(),
+                version: "0.1.0".to_string(),
+            }],
+        };
+        let positions = [
+            ChunkPos::new(0, 0, 0),
+            ChunkPos::new(7, 1, 0),
+            ChunkPos::new(-1, 3, -9),
+        ];
+
+        let players = vec![PlayerRecord {
+            uuid: Uuid::new_v4(),
+            public_key: "ab".repeat(32),
+            name: "Steve".to_string(),
+            position: Some(Vec3::new(0.5, 66.0, -2.5)),
+            operator: true,
+        }];
+        let bans = BanList {
+            players: vec![PlayerBan {
+                uuid: players[0].uuid,
+                name: "Steve".to_string(),
+                reason: None,
+            }],
+        };
+        let whitelist = vec!["Steve".to_string()];
+
+        let save = WorldSave::open(&dir).unwrap();
+        assert_eq!(save.load_level().unwrap(), None);
+        assert_eq!(save.load_players().unwrap(), []);
+        assert_eq!(save.load_bans().unwrap(), BanList::default());
+        assert!(save.load_whitelist().unwrap().is_empty());
+        save.save_level(&level).unwrap();
+        save.save_players(&players).unwrap();
+        save.save_bans(&bans).unwrap();
+        save.save_whitelist(&whitelist).unwrap();
+        for (i, pos) in positions.into_iter().enumerate() {
+            assert_eq!(save.load_chunk(pos).unwrap(), None);
+            save.save_chunk(pos, &Chunk::filled(i as u16)).unwrap();
+        }
+        // a chunk that outgrows its sectors moves without clobbering others
+        save.save_chunk(positions[0], &noisy_chunk(1)).unwrap();
+        // and one rewritten at the same size never overwrites its old copy
+        let (region, index) = region_of(positions[1]);
+        let sector = |save: &WorldSave| {
+            save.with_region(region, |region| Ok(region.entries[index].sector))
+                .unwrap()
+        };
+        let before = sector(&save);
+        save.save_chunk(positions[1], &Chunk::filled(1)).unwrap();
+        assert_ne!(sector(&save), before);
+        save.flush().unwrap();
+        drop(save);
+
+        let save = WorldSave::open(&dir).unwrap();
+        assert_eq!(save.load_level().unwrap(), Some(level));
+        assert_eq!(save.load_players().unwrap(), players);
+        assert_eq!(save.load_bans().unwrap(), bans);
+        assert_eq!(save.load_whitelist().unwrap(), whitelist);
+        assert_eq!(save.load_chunk(positions[0]).unwrap(), Some(noisy_chunk(1)));
+        assert_eq!(
+            save.load_chunk(positions[1]).unwrap(),
+            Some(Chunk::filled(1))
+        );
+        assert_eq!(
+            save.load_chunk(positions[2]).unwrap(),
+            Some(Chunk::filled(2))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_chunks_are_quarantined() {
+        let dir = test_dir("modcraft_save_corrupted");
+        let pos = ChunkPos::new(2, 2, 2);
+        let save = WorldSave::open(&dir).unwrap();
+        save.save_chunk(pos, &noisy_chunk(4)).unwrap();
+        save.save_chunk(ChunkPos::new(3, 2, 2), &noisy_chunk(5))
+            .unwrap();
+        drop(save);
+
+        // flip a byte in the middle of the first chunk's data
+        let region_path = dir.join(REGION_DIR).join("r.0.0.0.region");
+        let mut bytes = fs::read(&region_path).unwrap();
+        let offset = FIRST_DATA_SECTOR as usize * SECTOR_SIZE as usize + 20;
+        bytes[offset] ^= 0xff;
+        fs::write(&region_path, bytes).unwrap();
+
+        let save = WorldSave::open(&dir).unwrap();
+        let error = save.load_chunk(pos).unwrap_err();
+        assert!(error.is_corrupted_chunk(), "{}", error);
+        save.quarantine_chunk(pos).unwrap();
+        assert_eq!(save.load_chunk(pos).unwrap(), None);
+        assert!(dir.join(CORRUPTED_DIR).join("c.2.2.2.chunk").exists());
+        assert_eq!(
+            save.load_chunk(ChunkPos::new(3, 2, 2)).unwrap(),
+            Some(noisy_chunk(5))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_region_is_set_aside() {
+        let dir = test_dir("modcraft_save_bad_region");
+        let save = WorldSave::open(&dir).unwrap();
+        let region_path = dir.join(REGION_DIR).join("r.0.0.0.region");
+        fs::write(&region_path, b"garbage").unwrap();
+        fs::write(dir.join(LEVEL_FILE), "seed = \"not a number\"").unwrap();
+        assert!(matches!(save.load_level(), Err(SaveError::InvalidFile(..))));
+
+        let save = WorldSave::open(&dir).unwrap();
+        assert_eq!(save.load_chunk(ChunkPos::new(0, 0, 0)).unwrap(), None);
+        assert_eq!(
+            fs::read(dir.join(CORRUPTED_DIR).join("r.0.0.0.region")).unwrap(),
+            b"garbage"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+//! The blocks of the world, split into [`Chunk`]s.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blocks::{BlockFace, BlockId},
+    chunk::{Chunk, CHUNK_SIZE},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ChunkPos {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
+        ChunkPos { x, y, z }
+    }
+
+    /// The block at the chunk's lowest corner.
+    pub fn origin(self) -> BlockPos {
+        let size = CHUNK_SIZE as i32;
+        BlockPos::new(self.x * size, self.y * size, self.z * size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlockPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl BlockPos {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
+        BlockPos { x, y, z }
+    }
+
+    pub fn chunk(self) -> ChunkPos {
+        let size = CHUNK_SIZE as i32;
+        ChunkPos::new(
+            self.x.div_euclid(size),
+            self.y.div_euclid(size),
+            self.z.div_euclid(size),
+        )
+    }
+
+    /// The block on the other side of one of this block's faces.
+    pub fn neighbor(self, face: BlockFace) -> BlockPos {
+        let (dx, dy, dz) = face.offset();
+        BlockPos::new(self.x + dx, self.y + dy, self.z + dz)
+    }
+
+    /// The middle of the block.
+    pub fn center(self) -> Vec3 {
+        Vec3::new(self.x as f32, self.y as f32, self.z as f32) + Vec3::splat(0.5)
+    }
+
+    /// Coordinates inside the block's chunk.
+    pub fn local(self) -> (usize, usize, usize) {
+        let size = CHUNK_SIZE as i32;
+        (
+            self.x.rem_euclid(size) as usize,
+            self.y.rem_euclid(size) as usize,
+            self.z.rem_euclid(size) as usize,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WorldMemoryStats {
+    pub chunks: usize,
+    /// Chunks made of a single block, which store no block data.
+    pub uniform_chunks: usize,
+    pub palette_entries: usize,
+    /// Heap memory used by all chunks in bytes.
+    pub bytes: usize,
+}
+
+/// Every loaded chunk of the world.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct VoxelWorld {
+    chunks: HashMap<ChunkPos, Chunk>,
+    // blocks changed through set_block since the last take_changes
+    changes: Vec<(BlockPos, BlockId)>,
+    unsaved: HashSet<ChunkPos>,
+}
+
+impl VoxelWorld {
+    /// `None` if the block's chunk is not loaded.
+    pub fn get_block(&self, pos: BlockPos) -> Option<BlockId> {
+        let (x, y, z) = pos.local();
+        self.chunks
+            .get(&pos.chunk())
+            .map(|chunk| chunk.get(x, y, z))
+    }
+
+    /// Sets a block, loading an empty chunk if needed, and returns the block
+    /// that was there before. The change is kept until [`VoxelWorld::take_changes`].
+    pub fn set_block(&mut self, pos: BlockPos, block: BlockId) -> BlockId {
+        let (x, y, z) = pos.local();
+        let previous = self
+            .chunks
+            .entry(pos.chunk())
+            .or_default()
+            .set(x, y, z, block);
+        if previous != block {
+            self.changes.push((pos, block));
+            self.unsaved.insert(pos.chunk());
+        }
+        previous
+    }
+
+    /// Blocks changed with [`VoxelWorld::set_block`] since the last call, in
+    /// the order they were changed. Editing chunks directly is not tracked.
+    pub fn take_changes(&mut self) -> Vec<(BlockPos, BlockId)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub fn chunk(&self, pos: ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(&pos)
+    }
+
+    /// Marks the chunk as unsaved, since it may be changed.
+    pub fn chunk_mut(&mut self, pos: ChunkPos) -> Option<&mut Chunk> {
+        let chunk = self.chunks.get_mut(&pos)?;
+        self.unsaved.insert(pos);
+        Some(chunk)
+    }
+
+    /// Marks a chunk as differing from what is saved, for chunks that were
+    /// just generated or inserted.
+    pub fn mark_unsaved(&mut self, pos: ChunkPos) {
+        self.unsaved.insert(pos);
+    }
+
+    /// Chunks changed since the last call that are still loaded.
+    pub fn take_unsaved(&mut self) -> Vec<ChunkPos> {
+        let mut unsaved: Vec<_> = self
+            .unsaved
+            .drain()
+            .filter(|pos| self.chunks.contains_key(pos))
+            .collect();
+        unsaved.sort_unstable();
+        unsaved
+    }
+
+    /// Returns the chunk that was replaced, if any.
+    pub fn insert_chunk(&mut self, pos: ChunkPos, chunk: Chunk) -> Option<Chunk> {
+        self.chunks.insert(pos, chunk)
+    }
+
+    pub fn remove_chunk(&mut self, pos: ChunkPos) -> Option<Chunk> {
+        self.chunks.remove(&pos)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (ChunkPos, &Chunk)> {
+        self.chunks.iter().map(|(pos, chunk)| (*pos, chunk))
+    }
+
+    pub fn chunks_mut(&mut self) -> impl Iterator<Item = (ChunkPos, &mut Chunk)> {
+        self.chunks.iter_mut().map(|(pos, chunk)| (*pos, chunk))
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.changes.clear();
+        self.unsaved.clear();
+    }
+
+    pub fn memory_stats(&self) -> WorldMemoryStats {
+        let mut stats = WorldMemoryStats {
+            bytes: self.chunks.capacity()
+                * (std::mem::size_of::<ChunkPos>() + std::mem::size_of::<Chunk>()),
+            ..default()
+        };
+        for chunk in self.chunks.values() {
+            stats.chunks += 1;
+            stats.palette_entries += chunk.palette().len();
+            stats.bytes += chunk.memory_usage();
+            if chunk.uniform_block().is_some() {
+                stats.uniform_chunks += 1;
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::AIR;
+
+    #[test]
+    fn negative_positions_map_to_chunks() {
+        let pos = BlockPos::new(-1, 16, -17);
+        assert_eq!(pos.chunk(), ChunkPos::new(-1, 1, -2));
+        assert_eq!(pos.local(), (15, 0, 15));
+        assert_eq!(
+            ChunkPos::new(-1, 1, -2).origin(),
+            BlockPos::new(-16, 16, -32)
+        );
+    }
+
+    #[test]
+    fn set_block_loads_chunks() {
+        let mut world = VoxelWorld::default();
+        let pos = BlockPos::new(-5, 3, 40);
+        assert_eq!(world.get_block(pos), None);
+
+        assert_eq!(world.set_block(pos, 2), AIR);
+        assert_eq!(world.get_block(pos), Some(2));
+        assert_eq!(world.get_block(BlockPos::new(-6, 3, 40)), Some(AIR));
+        assert_eq!(world.len(), 1);
+
+        world.set_block(pos, 2);
+        world.set_block(BlockPos::new(-6, 3, 40), 3);
+        assert_eq!(
+            world.take_changes(),
+            vec![(pos, 2), (BlockPos::new(-6, 3, 40), 3)]
+        );
+        assert!(world.take_changes().is_empty());
+        assert_eq!(world.take_unsaved(), vec![pos.chunk()]);
+        assert!(world.take_unsaved().is_empty());
+
+        world.insert_chunk(ChunkPos::new(0, 0, 0), Chunk::filled(4));
+        let stats = world.memory_stats();
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(stats.uniform_chunks, 1);
+        assert_eq!(stats.palette_entries, 4);
+    }
+}
@@ -0,0 +1,359 @@
+//! Seed-driven world generation, built from stages that each get a turn at
+//! every chunk.
+//!
+//! Stages run ordered by their [`GenerationPhase`] and then by the order they
+//! were added in, so mods can add ores or decoration without caring whether
+//! they were loaded before or after the built-in terrain. Generation has to
+//! be a pure function of the seed and chunk position, which makes identical
+//! seeds produce identical chunks.
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+use crate::{
+    blocks::{BlockId, BlockRegistry, AIR},
+    chunk::{Chunk, CHUNK_SIZE},
+    world::ChunkPos,
+};
+
+/// Chunks above this height are never generated.
+pub const WORLD_HEIGHT_CHUNKS: i32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GenerationPhase {
+    /// The basic shape of the world.
+    Terrain,
+    /// Replaces the top of the terrain with soil.
+    Surface,
+    Ores,
+    Structures,
+    Decoration,
+}
+
+pub trait GeneratorStage: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn phase(&self) -> GenerationPhase;
+
+    fn generate(&self, seed: u64, pos: ChunkPos, chunk: &mut Chunk);
+}
+
+/// Mixes a seed with coordinates into a well distributed number.
+pub fn hash(seed: u64, x: i32, y: i32, z: i32) -> u64 {
+    // splitmix64 over each input in turn
+    let mut value = seed;
+    for input in [x, y, z] {
+        value = value
+            .wrapping_add(input as u32 as u64)
+            .wrapping_add(0x9e3779b97f4a7c15);
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+        value ^= value >> 31;
+    }
+    value
+}
+
+fn lattice_value(seed: u64, x: i32, z: i32) -> f64 {
+    (hash(seed, x, 0, z) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Smooth 2D value noise in `0..1` with features about `scale` blocks apart.
+pub fn value_noise(seed: u64, x: f64, z: f64, scale: f64) -> f64 {
+    let (x, z) = (x / scale, z / scale);
+    let (x0, z0) = (x.floor(), z.floor());
+    let smooth = |t: f64| t * t * (3.0 - 2.0 * t);
+    let (tx, tz) = (smooth(x - x0), smooth(z - z0));
+    let (x0, z0) = (x0 as i32, z0 as i32);
+
+    let top = lattice_value(seed, x0, z0) * (1.0 - tx) + lattice_value(seed, x0 + 1, z0) * tx;
+    let bottom =
+        lattice_value(seed, x0, z0 + 1) * (1.0 - tx) + lattice_value(seed, x0 + 1, z0 + 1) * tx;
+    top * (1.0 - tz) + bottom * tz
+}
+
+/// Height of the terrain's top block at a column.
+pub fn terrain_height(seed: u64, x: i32, z: i32) -> i32 {
+    let mut noise = 0.0;
+    let mut amplitude = 1.0;
+    let mut scale = 96.0;
+    for octave in 0..4 {
+        noise += value_noise(seed.wrapping_add(octave), x as f64, z as f64, scale) * amplitude;
+        amplitude /= 2.0;
+        scale /= 2.0;
+    }
+    // the octaves add up to at most 1.875
+    40 + (noise / 1.875 * 40.0) as i32
+}
+
+fn column_origin(pos: ChunkPos) -> (i32, i32, i32) {
+    let origin = pos.origin();
+    (origin.x, origin.y, origin.z)
+}
+
+/// Fills everything below the terrain height with stone, on top of a layer
+/// of bedrock at y 0.
+pub struct TerrainStage {
+    stone: BlockId,
+    bedrock: BlockId,
+}
+
+impl TerrainStage {
+    pub fn new(blocks: &BlockRegistry) -> Self {
+        TerrainStage {
+            stone: blocks.id("modcraft:stone").expect("stone is built in"),
+            bedrock: blocks.id("modcraft:bedrock").expect("bedrock is built in"),
+        }
+    }
+}
+
+impl GeneratorStage for TerrainStage {
+    fn name(&self) -> &str {
+        "modcraft:terrain"
+    }
+
+    fn phase(&self) -> GenerationPhase {
+        GenerationPhase::Terrain
+    }
+
+    fn generate(&self, seed: u64, pos: ChunkPos, chunk: &mut Chunk) {
+        let (origin_x, origin_y, origin_z) = column_origin(pos);
+        for z in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                let height = terrain_height(seed, origin_x + x as i32, origin_z + z as i32);
+                for y in 0..CHUNK_SIZE {
+                    let world_y = origin_y + y as i32;
+                    let block = match world_y {
+                        0 => self.bedrock,
+                        world_y if world_y > 0 && world_y <= height => self.stone,
+                        _ => continue,
+                    };
+                    chunk.set(x, y, z, block);
+                }
+            }
+        }
+    }
+}
+
+/// Covers the terrain with a few blocks of dirt and a top layer of grass.
+pub struct SurfaceStage {
+    stone: BlockId,
+    dirt: BlockId,
+    grass: BlockId,
+}
+
+impl SurfaceStage {
+    const DIRT_DEPTH: i32 = 3;
+
+    pub fn new(blocks: &BlockRegistry) -> Self {
+        SurfaceStage {
+            stone: blocks.id("modcraft:stone").expect("stone is built in"),
+            dirt: blocks.id("modcraft:dirt").expect("dirt is built in"),
+            grass: blocks
+                .id("modcraft:grass_path")
+                .expect("grass path is built in"),
+        }
+    }
+}
+
+impl GeneratorStage for SurfaceStage {
+    fn name(&self) -> &str {
+        "modcraft:surface"
+    }
+
+    fn phase(&self) -> GenerationPhase {
+        GenerationPhase::Surface
+    }
+
+    fn generate(&self, seed: u64, pos: ChunkPos, chunk: &mut Chunk) {
+        let (origin_x, origin_y, origin_z) = column_origin(pos);
+        for z in 0..CHUNK_SIZE {
+            for x in 0..CHUNK_SIZE {
+                let height = terrain_height(seed, origin_x + x as i32, origin_z + z as i32);
+                for y in 0..CHUNK_SIZE {
+                    let depth = height - (origin_y + y as i32);
+                    if !(0..=Self::DIRT_DEPTH).contains(&depth) || chunk.get(x, y, z) != self.stone
+                    {
+                        continue;
+                    }
+                    chunk.set(x, y, z, if depth == 0 { self.grass } else { self.dirt });
+                }
+            }
+        }
+    }
+}
+
+/// Turns the seed and chunk position into chunks by running every stage.
+#[derive(Resource, Clone)]
+pub struct WorldGenerator {
+    seed: u64,
+    stages: Vec<Arc<dyn GeneratorStage>>,
+}
+
+impl FromWorld for WorldGenerator {
+    fn from_world(world: &mut World) -> Self {
+        let blocks = world.get_resource_or_insert_with(BlockRegistry::default);
+        WorldGenerator::with_builtin_stages(0, &blocks)
+    }
+}
+
+impl WorldGenerator {
+    pub fn new(seed: u64) -> Self {
+        WorldGenerator {
+            seed,
+            stages: Vec::new(),
+        }
+    }
+
+    /// A generator with the built-in terrain and surface stages.
+    pub fn with_builtin_stages(seed: u64, blocks: &BlockRegistry) -> Self {
+        let mut generator = WorldGenerator::new(seed);
+        generator.add_stage(TerrainStage::new(blocks));
+        generator.add_stage(SurfaceStage::new(blocks));
+        generator
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Adds a stage after every stage of the same or an earlier phase.
+    pub fn add_stage(&mut self, stage: impl GeneratorStage + 'static) -> &mut Self {
+        let index = self
+            .stages
+            .partition_point(|existing| existing.phase() <= stage.phase());
+        self.stages.insert(index, Arc::new(stage));
+        self
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = &dyn GeneratorStage> {
+        self.stages.iter().map(|stage| stage.as_ref())
+    }
+
+    pub fn generate(&self, pos: ChunkPos) -> Chunk {
+        let mut chunk = Chunk::filled(AIR);
+        if !(0..WORLD_HEIGHT_CHUNKS).contains(&pos.y) {
+            return chunk;
+        }
+        for stage in &self.stages {
+            stage.generate(self.seed, pos, &mut chunk);
+        }
+        chunk.compact();
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(seed: u64) -> WorldGenerator {
+        WorldGenerator::with_builtin_stages(seed, &BlockRegistry::default())
+    }
+
+    // FNV-1a over every block id, so the snapshot stays small
+    fn fingerprint(chunk: &Chunk) -> u64 {
+        chunk.iter().fold(0xcbf29ce484222325, |hash, (_, block)| {
+            block.to_le_bytes().iter().fold(hash, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+        })
+    }
+
+    #[test]
+    fn same_seed_same_chunks() {
+        for pos in [
+            ChunkPos::new(0, 0, 0),
+            ChunkPos::new(-3, 2, 7),
+            ChunkPos::new(12, 3, -40),
+        ] {
+            assert_eq!(generator(42).generate(pos), generator(42).generate(pos));
+        }
+        assert_ne!(
+            generator(42).generate(ChunkPos::new(0, 3, 0)),
+            generator(43).generate(ChunkPos::new(0, 3, 0))
+        );
+    }
+
+    #[test]
+    fn generated_chunk_snapshot() {
+        let generator = generator(42);
+        assert_eq!(
+            fingerprint(&generator.generate(ChunkPos::new(0, 0, 0))),
+            SNAPSHOT_0_0_0
+        );
+        assert_eq!(
+            fingerprint(&generator.generate(ChunkPos::new(-3, 2, 7))),
+            SNAPSHOT_NEG3_2_7
+        );
+    }
+
+    const SNAPSHOT_0_0_0: u64 = 13647462180138575653;
+    const SNAPSHOT_NEG3_2_7: u64 = 1607270571598881573;
+
+    #[test]
+    fn surface_is_layered() {
+        let blocks = BlockRegistry::default();
+        let generator = generator(7);
+        let height = terrain_height(7, 5, 5);
+        let pos = ChunkPos::new(0, height.div_euclid(CHUNK_SIZE as i32), 0);
+        let chunk = generator.generate(pos);
+        let local_y = height.rem_euclid(CHUNK_SIZE as i32) as usize;
+
+        assert_eq!(
+            chunk.get(5, local_y, 5),
+            blocks.id("modcraft:grass_path").unwrap()
+        );
+        if local_y + 1 < CHUNK_SIZE {
+            assert_eq!(chunk.get(5, local_y + 1, 5), AIR);
+        }
+        if local_y > 0 {
+            assert_eq!(
+                chunk.get(5, local_y - 1, 5),
+                blocks.id("modcraft:dirt").unwrap()
+            );
+        }
+
+        let bottom = generator.generate(ChunkPos::new(0, 0, 0));
+        assert_eq!(bottom.get(5, 0, 5), blocks.id("modcraft:bedrock").unwrap());
+        assert_eq!(
+            generator.generate(ChunkPos::new(0, -1, 0)),
+            Chunk::default()
+        );
+    }
+
+    struct MarkerStage(GenerationPhase, &'static str);
+
+    impl GeneratorStage for MarkerStage {
+        fn name(&self) -> &str {
+            self.1
+        }
+
+        fn phase(&self) -> GenerationPhase {
+            self.0
+        }
+
+        fn generate(&self, _seed: u64, _pos: ChunkPos, _chunk: &mut Chunk) {}
+    }
+
+    #[test]
+    fn stages_run_in_phase_order() {
+        let mut generator = WorldGenerator::new(0);
+        generator
+            .add_stage(MarkerStage(GenerationPhase::Decoration, "flowers"))
+            .add_stage(MarkerStage(GenerationPhase::Terrain, "terrain"))
+            .add_stage(MarkerStage(GenerationPhase::Ores, "ore"))
+            .add_stage(MarkerStage(GenerationPhase::Terrain, "caves"));
+        assert_eq!(
+            generator
+                .stages()
+                .map(|stage| stage.name())
+                .collect::<Vec<_>>(),
+            vec!["terrain", "caves", "ore", "flowers"]
+        );
+    }
+}